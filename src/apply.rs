@@ -0,0 +1,168 @@
+use std::collections::HashSet;
+
+use anyhow::{Context, Result};
+
+use crate::bbox::TileRange;
+use crate::diff::DELETED_TILES_TABLE;
+use crate::mbtiles::MbtilesReader;
+use crate::progress::{NoProgress, Progress};
+use crate::sink::OutputFormat;
+use crate::source::{open_source, TileSource};
+
+/// Apply a diff written by [`crate::diff`] to `base_path`, writing the
+/// updated tileset to `output_path`. The output takes the diff's metadata.
+/// Returns the number of tiles written.
+pub fn apply(base_path: &str, diff_path: &str, output_path: &str) -> Result<u64> {
+    apply_with_progress(base_path, diff_path, output_path, &NoProgress)
+}
+
+/// Like [`apply`], reporting each tile written to `progress`
+pub fn apply_with_progress(base_path: &str, diff_path: &str, output_path: &str, progress: &dyn Progress) -> Result<u64> {
+    let base = open_source(base_path)?;
+    let patch = MbtilesReader::open(diff_path)?;
+
+    // Base tiles to leave out: removed ones and those the diff replaces
+    let mut skip: HashSet<(i32, i32, i32)> = HashSet::new();
+    {
+        let mut stmt = patch
+            .connection()
+            .prepare(&format!("SELECT zoom_level, tile_column, tile_row FROM {}", DELETED_TILES_TABLE))
+            .context(format!("{} is not a diff file", diff_path))?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+        for row in rows {
+            skip.insert(row?);
+        }
+    }
+    let mut stmt = patch.connection().prepare("SELECT zoom_level, tile_column, tile_row FROM tiles")?;
+    let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+    for row in rows {
+        skip.insert(row?);
+    }
+
+    let base_zooms = base.zoom_levels()?;
+    let patch_zooms = patch.zoom_levels()?;
+    progress.start(total_tiles(base.as_ref(), &base_zooms)? + total_tiles(&patch, &patch_zooms)?);
+
+    let mut sink = OutputFormat::from_path(output_path).create_sink(output_path)?;
+    let mut written = 0;
+    for zoom in base_zooms {
+        base.for_each_tile(&TileRange::full(zoom)?, &mut |tile| {
+            progress.advance(1);
+            if skip.contains(&(tile.zoom, tile.x, tile.y)) {
+                return Ok(());
+            }
+            written += 1;
+            sink.write_tile(&tile)
+        })?;
+    }
+    for zoom in patch_zooms {
+        patch.for_each_tile(&TileRange::full(zoom)?, |tile| {
+            progress.advance(1);
+            written += 1;
+            sink.write_tile(&tile)
+        })?;
+    }
+
+    for (name, value) in patch.metadata()? {
+        sink.write_metadata(&name, &value)?;
+    }
+    sink.finish()?;
+    progress.finish();
+    Ok(written)
+}
+
+fn total_tiles(source: &dyn TileSource, zooms: &[i32]) -> Result<u64> {
+    let mut total = 0;
+    for &zoom in zooms {
+        total += source.count_tiles(&TileRange::full(zoom)?)?;
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    use crate::diff::diff;
+    use crate::mbtiles::MbtilesWriter;
+    use crate::tile::Tile;
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("mbtiles-apply-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path.to_string_lossy().into_owned()
+    }
+
+    fn write(name: &str, metadata: &[(&str, &str)], tiles: &BTreeMap<(i32, i32, i32), Vec<u8>>) -> String {
+        let path = temp_path(name);
+        let writer = MbtilesWriter::create(&path).unwrap();
+        for (name, value) in metadata {
+            writer.insert_metadata(name, value).unwrap();
+        }
+        for (&(zoom, x, y), data) in tiles {
+            writer.insert_tile(&Tile { zoom, x, y, data: data.clone() }).unwrap();
+        }
+        path
+    }
+
+    type Contents = (BTreeMap<(i32, i32, i32), Vec<u8>>, BTreeMap<String, String>);
+
+    fn read(path: &str) -> Contents {
+        let reader = MbtilesReader::open(path).unwrap();
+        let mut tiles = BTreeMap::new();
+        for zoom in reader.zoom_levels().unwrap() {
+            reader
+                .for_each_tile(&TileRange::full(zoom).unwrap(), |tile| {
+                    tiles.insert((tile.zoom, tile.x, tile.y), tile.data);
+                    Ok(())
+                })
+                .unwrap();
+        }
+        (tiles, reader.metadata().unwrap().into_iter().collect())
+    }
+
+    #[test]
+    fn applying_a_diff_recreates_the_new_tileset() {
+        let mut old = BTreeMap::new();
+        for zoom in 0..=2 {
+            for x in 0..1 << zoom {
+                for y in 0..1 << zoom {
+                    old.insert((zoom, x, y), format!("{}/{}/{}", zoom, x, y).into_bytes());
+                }
+            }
+        }
+        let mut new = old.clone();
+        new.insert((1, 0, 1), b"changed".to_vec());
+        new.insert((2, 3, 3), b"changed".to_vec());
+        new.insert((3, 5, 2), b"added".to_vec());
+        for x in 0..4 {
+            new.remove(&(2, x, 0));
+        }
+        new.remove(&(0, 0, 0));
+        let old_path = write("old.mbtiles", &[("name", "old"), ("description", "gone"), ("format", "png")], &old);
+        let new_path = write("new.mbtiles", &[("name", "new"), ("format", "png"), ("version", "2")], &new);
+
+        let diff_path = temp_path("diff.mbtiles");
+        let report = diff(&old_path, &new_path, Some(&diff_path)).unwrap();
+        assert_eq!((report.added(), report.changed(), report.removed()), (1, 2, 5));
+        assert_eq!(report.unchanged(), 21 - 2 - 5);
+        assert_eq!(report.metadata, ["description", "name", "version"]);
+
+        let output = temp_path("output.mbtiles");
+        assert_eq!(apply(&old_path, &diff_path, &output).unwrap(), new.len() as u64);
+        assert_eq!(read(&output), read(&new_path));
+        assert_eq!(read(&output).0, new);
+
+        // Applying the diff of identical files changes nothing
+        let empty = temp_path("empty-diff.mbtiles");
+        assert!(diff(&new_path, &new_path, Some(&empty)).unwrap().is_empty());
+        let again = temp_path("again.mbtiles");
+        apply(&output, &empty, &again).unwrap();
+        assert_eq!(read(&again), read(&new_path));
+
+        for path in [old_path, new_path, diff_path, output, empty, again] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
@@ -0,0 +1,310 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result, anyhow};
+
+use crate::coord::{TileCoord, MAX_ZOOM};
+use crate::tile::{tile_to_lon_lat, Scheme};
+
+/// Geographic bounding box in degrees
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoundingBox {
+    pub north: f64,
+    pub east: f64,
+    pub south: f64,
+    pub west: f64,
+}
+
+/// Order of the four values in a bounding box argument
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BBoxOrder {
+    /// West, south, east, north, as used by GDAL, tippecanoe and the `bounds` metadata key
+    #[default]
+    Wsen,
+    /// North, east, south, west
+    Nesw,
+}
+
+impl BBoxOrder {
+    fn as_str(&self) -> &'static str {
+        match self {
+            BBoxOrder::Wsen => "W,S,E,N",
+            BBoxOrder::Nesw => "N,E,S,W",
+        }
+    }
+}
+
+/// Inclusive range of TMS tile coordinates at a single zoom level
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TileRange {
+    pub zoom: i32,
+    pub x_min: i32,
+    pub x_max: i32,
+    pub y_min: i32,
+    pub y_max: i32,
+}
+
+impl TileRange {
+    /// Every tile at `zoom`, which must be from 0 to [`MAX_ZOOM`]
+    pub fn full(zoom: i32) -> Result<Self> {
+        if !(0..=MAX_ZOOM).contains(&zoom) {
+            return Err(anyhow!("Invalid zoom level {}, expected 0 to {}", zoom, MAX_ZOOM));
+        }
+        let max = (1 << zoom) - 1;
+        Ok(TileRange { zoom, x_min: 0, x_max: max, y_min: 0, y_max: max })
+    }
+
+    /// A range containing just the tile (x, y)
+    pub fn single(zoom: i32, x: i32, y: i32) -> Self {
+        TileRange { zoom, x_min: x, x_max: x, y_min: y, y_max: y }
+    }
+
+    pub fn contains(&self, zoom: i32, x: i32, y: i32) -> bool {
+        zoom == self.zoom
+            && (self.x_min..=self.x_max).contains(&x)
+            && (self.y_min..=self.y_max).contains(&y)
+    }
+
+    /// The same tiles with rows numbered in `scheme` instead of TMS
+    pub fn to_scheme(&self, scheme: Scheme) -> TileRange {
+        let (a, b) = (scheme.from_tms(self.zoom, self.y_min), scheme.from_tms(self.zoom, self.y_max));
+        TileRange { y_min: a.min(b), y_max: a.max(b), ..*self }
+    }
+
+    /// Grow the range to include tile (x, y)
+    pub fn include(&mut self, x: i32, y: i32) {
+        self.x_min = self.x_min.min(x);
+        self.x_max = self.x_max.max(x);
+        self.y_min = self.y_min.min(y);
+        self.y_max = self.y_max.max(y);
+    }
+
+    /// The tiles covered by any of `ranges`, without overlaps. Rows with
+    /// the same columns are grouped back into rectangles.
+    pub fn union(ranges: &[TileRange]) -> Vec<TileRange> {
+        let mut rows: BTreeMap<(i32, i32), Vec<(i32, i32)>> = BTreeMap::new();
+        for range in ranges {
+            for y in range.y_min..=range.y_max {
+                rows.entry((range.zoom, y)).or_default().push((range.x_min, range.x_max));
+            }
+        }
+
+        let mut result: Vec<TileRange> = Vec::new();
+        // Zoom, row and merged column spans of the last row seen
+        let mut previous_row = None;
+        let mut previous_spans = Vec::new();
+        for ((zoom, y), mut spans) in rows {
+            spans.sort_unstable();
+            let mut merged: Vec<(i32, i32)> = Vec::new();
+            for (x_min, x_max) in spans {
+                match merged.last_mut() {
+                    Some(last) if x_min <= last.1 + 1 => last.1 = last.1.max(x_max),
+                    _ => merged.push((x_min, x_max)),
+                }
+            }
+
+            // Extend the rectangles started on the row below when the columns match
+            if previous_row == Some((zoom, y - 1)) && previous_spans == merged {
+                let start = result.len() - merged.len();
+                for range in &mut result[start..] {
+                    range.y_max = y;
+                }
+            } else {
+                result.extend(merged.iter().map(|&(x_min, x_max)| TileRange { zoom, x_min, x_max, y_min: y, y_max: y }));
+            }
+            previous_row = Some((zoom, y));
+            previous_spans = merged;
+        }
+        result
+    }
+
+    /// The range grown by `rings` tiles on every side. Rows are clamped at
+    /// the poles while columns wrap around the antimeridian, so the result
+    /// may be two ranges.
+    pub fn buffered(&self, rings: i32) -> Vec<TileRange> {
+        let n = 2_i32.pow(self.zoom as u32);
+        let grown = TileRange {
+            x_min: self.x_min - rings,
+            x_max: self.x_max + rings,
+            y_min: (self.y_min - rings).max(0),
+            y_max: (self.y_max + rings).min(n - 1),
+            ..*self
+        };
+        if grown.x_max - grown.x_min + 1 >= n {
+            vec![TileRange { x_min: 0, x_max: n - 1, ..grown }]
+        } else if grown.x_min < 0 {
+            vec![TileRange { x_min: 0, ..grown }, TileRange { x_min: grown.x_min + n, x_max: n - 1, ..grown }]
+        } else if grown.x_max >= n {
+            vec![TileRange { x_max: n - 1, ..grown }, TileRange { x_min: 0, x_max: grown.x_max - n, ..grown }]
+        } else {
+            vec![grown]
+        }
+    }
+
+    /// Geographic extent of the outer edges of the range
+    pub fn bounds(&self) -> BoundingBox {
+        let (west, south) = tile_to_lon_lat(self.x_min, self.y_min, self.zoom);
+        let (east, north) = tile_to_lon_lat(self.x_max + 1, self.y_max + 1, self.zoom);
+        BoundingBox { north, east, south, west }
+    }
+}
+
+impl BoundingBox {
+    /// Parse four comma separated values in `order`. North must not be
+    /// below south and all values must be valid degrees; west may be greater
+    /// than east for a box crossing the antimeridian.
+    pub fn parse(bbox_str: &str, order: BBoxOrder) -> Result<Self> {
+        let parts: Vec<&str> = bbox_str.split(',').collect();
+        if parts.len() != 4 {
+            return Err(anyhow!("Bounding box must have 4 values: {}", order.as_str()));
+        }
+
+        let value = |index: usize, name: &str| -> Result<f64> {
+            parts[index].trim().parse().context(format!("Invalid {} value", name))
+        };
+        let bbox = match order {
+            BBoxOrder::Wsen => BoundingBox {
+                west: value(0, "west")?,
+                south: value(1, "south")?,
+                east: value(2, "east")?,
+                north: value(3, "north")?,
+            },
+            BBoxOrder::Nesw => BoundingBox {
+                north: value(0, "north")?,
+                east: value(1, "east")?,
+                south: value(2, "south")?,
+                west: value(3, "west")?,
+            },
+        };
+
+        for (name, lat) in [("north", bbox.north), ("south", bbox.south)] {
+            if !(-90.0..=90.0).contains(&lat) {
+                return Err(anyhow!("{} value {} is not between -90 and 90 (values are {})", name, lat, order.as_str()));
+            }
+        }
+        for (name, lon) in [("east", bbox.east), ("west", bbox.west)] {
+            if !(-180.0..=180.0).contains(&lon) {
+                return Err(anyhow!("{} value {} is not between -180 and 180 (values are {})", name, lon, order.as_str()));
+            }
+        }
+        if bbox.north < bbox.south {
+            return Err(anyhow!(
+                "north ({}) is less than south ({}) (values are {})",
+                bbox.north,
+                bbox.south,
+                order.as_str()
+            ));
+        }
+        Ok(bbox)
+    }
+
+    /// Read one bounding box per line with values in `order`. Blank lines
+    /// and lines starting with `#` are skipped.
+    pub fn list_from_file(path: &str, order: BBoxOrder) -> Result<Vec<Self>> {
+        let text = std::fs::read_to_string(path).context(format!("Failed to read bounding box file: {}", path))?;
+        let mut boxes = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let bbox = Self::parse(line, order).map_err(|e| anyhow!("Invalid bounding box in {} line {}: {}", path, number + 1, e))?;
+            boxes.push(bbox);
+        }
+        if boxes.is_empty() {
+            return Err(anyhow!("No bounding boxes in {}", path));
+        }
+        Ok(boxes)
+    }
+
+    /// Smallest box containing both boxes
+    pub fn union(&self, other: &BoundingBox) -> BoundingBox {
+        BoundingBox {
+            north: self.north.max(other.north),
+            east: self.east.max(other.east),
+            south: self.south.min(other.south),
+            west: self.west.min(other.west),
+        }
+    }
+
+    /// Overlap of two boxes, `None` if they are disjoint
+    pub fn intersection(&self, other: &BoundingBox) -> Option<BoundingBox> {
+        let result = BoundingBox {
+            north: self.north.min(other.north),
+            east: self.east.min(other.east),
+            south: self.south.max(other.south),
+            west: self.west.max(other.west),
+        };
+        (result.north >= result.south && result.east >= result.west).then_some(result)
+    }
+
+    /// Parse a `bounds` metadata value (W,S,E,N), `None` if malformed
+    pub fn from_metadata(value: &str) -> Option<Self> {
+        let parts = value.split(',').map(|p| p.trim().parse::<f64>().ok()).collect::<Option<Vec<_>>>()?;
+        match parts[..] {
+            [west, south, east, north] => Some(BoundingBox { north, east, south, west }),
+            _ => None,
+        }
+    }
+
+    /// Format as the `bounds` metadata value: W,S,E,N rounded to 6 decimals
+    pub fn to_metadata(&self) -> String {
+        let round = |v: f64| (v * 1e6).round() / 1e6;
+        format!("{},{},{},{}", round(self.west), round(self.south), round(self.east), round(self.north))
+    }
+
+    /// A box whose west edge is east of its east edge wraps around the
+    /// antimeridian, e.g. 0,-170,-20,170 for Fiji
+    pub fn crosses_antimeridian(&self) -> bool {
+        self.west > self.east
+    }
+
+    /// Center point as (lon, lat), on the antimeridian side for wrapping boxes
+    pub fn center(&self) -> (f64, f64) {
+        let mut lon = (self.west + self.east) / 2.0;
+        if self.crosses_antimeridian() {
+            lon += if lon > 0.0 { -180.0 } else { 180.0 };
+        }
+        (lon, (self.south + self.north) / 2.0)
+    }
+
+    /// TMS tile ranges covering this bounding box at `zoom`: one range, or
+    /// two when the box crosses the antimeridian
+    pub fn tile_ranges(&self, zoom: i32) -> Vec<TileRange> {
+        if !self.crosses_antimeridian() {
+            return vec![self.tile_bounds(zoom)];
+        }
+        let east_half = BoundingBox { east: 180.0, ..*self };
+        let west_half = BoundingBox { west: -180.0, ..*self };
+        TileRange::union(&[west_half.tile_bounds(zoom), east_half.tile_bounds(zoom)])
+    }
+
+    /// TMS tile range covering this bounding box at `zoom`, ignoring any
+    /// antimeridian crossing
+    pub fn tile_bounds(&self, zoom: i32) -> TileRange {
+        let north_west = TileCoord::from_lon_lat(self.west, self.north, zoom);
+        let south_east = TileCoord::from_lon_lat(self.east, self.south, zoom);
+        TileRange {
+            zoom,
+            x_min: north_west.x,
+            x_max: south_east.x,
+            y_min: south_east.tms_y(),
+            y_max: north_west.tms_y(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_range() {
+        let range = TileRange::full(2).unwrap();
+        assert_eq!((range.x_min, range.x_max, range.y_min, range.y_max), (0, 3, 0, 3));
+        let range = TileRange::full(MAX_ZOOM).unwrap();
+        assert_eq!((range.x_max, range.y_max), ((1 << MAX_ZOOM) - 1, (1 << MAX_ZOOM) - 1));
+        assert!(TileRange::full(MAX_ZOOM + 1).is_err());
+        assert!(TileRange::full(31).is_err());
+        assert!(TileRange::full(-1).is_err());
+    }
+}
@@ -0,0 +1,473 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::path::Path;
+
+use anyhow::{Result, anyhow};
+use rusqlite::{OptionalExtension, params};
+
+use crate::mbtiles::{MbtilesReader, MbtilesSchema, MbtilesWriter};
+use crate::progress::{NoProgress, Progress};
+use crate::raster;
+use crate::tile::{decompress, detect_compression, detect_format, Compression, Tile, TileFormat};
+
+/// Quality used when regenerated tiles are JPEG or WebP
+const REGENERATE_QUALITY: f32 = 85.0;
+/// Metadata keys the MBTiles spec requires
+const REQUIRED_METADATA: [&str; 2] = ["name", "format"];
+/// Last chunk of every complete PNG
+const PNG_END: [u8; 12] = [0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82];
+
+/// Outcome of checking the integrity of an MBTiles file
+#[derive(Debug, Clone, Default)]
+pub struct CheckReport {
+    /// Problems reported by SQLite's `PRAGMA integrity_check`
+    pub integrity_errors: Vec<String>,
+    /// Whether the tile table has a unique index on zoom, column and row
+    pub has_tile_index: bool,
+    /// Metadata format the blobs were checked against, if declared
+    pub format: Option<TileFormat>,
+    /// Tile rows scanned
+    pub tiles: u64,
+    pub bad_tiles: Vec<BadTile>,
+    /// Required metadata keys the file doesn't have
+    pub missing_metadata: Vec<String>,
+}
+
+impl CheckReport {
+    pub fn is_ok(&self) -> bool {
+        self.integrity_errors.is_empty()
+            && self.has_tile_index
+            && self.bad_tiles.is_empty()
+            && self.missing_metadata.is_empty()
+    }
+}
+
+/// Outcome of repairing an MBTiles file
+#[derive(Debug, Clone, Default)]
+pub struct RepairReport {
+    /// The check run before repairing
+    pub check: CheckReport,
+    /// Bad tile rows deleted, including extra copies of duplicated tiles
+    pub deleted: u64,
+    /// Deleted tiles written again from an ancestor tile
+    pub regenerated: u64,
+    /// Whether the missing unique tile index was created
+    pub index_created: bool,
+    /// Required metadata keys added, with the values inferred for them
+    pub metadata_added: Vec<(String, String)>,
+}
+
+impl RepairReport {
+    /// Whether the file passes the check now, with its bad tiles deleted
+    pub fn is_ok(&self) -> bool {
+        let added = |name: &String| self.metadata_added.iter().any(|(added, _)| added == name);
+        self.check.integrity_errors.is_empty()
+            && (self.check.has_tile_index || self.index_created)
+            && self.check.missing_metadata.iter().all(added)
+    }
+}
+
+/// A tile row whose data can't be served, addressed as stored (TMS)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BadTile {
+    pub zoom: i32,
+    pub x: i32,
+    pub y: i32,
+    pub problem: TileProblem,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TileProblem {
+    /// NULL tile data, or a normalized map row without its image
+    Missing,
+    /// Zero-length blob
+    Empty,
+    /// Magic bytes of another format, or of none (`found: None`)
+    WrongFormat { found: Option<TileFormat>, expected: TileFormat },
+    /// Data that ends before its format's end marker, or compressed data
+    /// that doesn't inflate
+    Truncated,
+    /// Stored in this many rows, possible without the unique tile index
+    Duplicate { rows: u64 },
+}
+
+impl fmt::Display for TileProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TileProblem::Missing => f.write_str("no tile data"),
+            TileProblem::Empty => f.write_str("a zero-length blob"),
+            TileProblem::WrongFormat { found: Some(found), expected } => {
+                write!(f, "{} data but the format is {}", found, expected)
+            }
+            TileProblem::WrongFormat { found: None, expected } => {
+                write!(f, "unrecognized data but the format is {}", expected)
+            }
+            TileProblem::Truncated => f.write_str("truncated data"),
+            TileProblem::Duplicate { rows } => write!(f, "{} rows", rows),
+        }
+    }
+}
+
+/// Run SQLite's integrity check on the MBTiles file at `path`, verify the
+/// unique tile index and the required metadata exist, and scan every tile
+/// for missing, empty or truncated data, for magic bytes that don't match
+/// the `format` metadata and for coordinates stored more than once
+pub fn check(path: &str) -> Result<CheckReport> {
+    check_with_progress(path, &NoProgress)
+}
+
+/// Like [`check`], reporting each scanned tile to `progress`
+pub fn check_with_progress(path: &str, progress: &dyn Progress) -> Result<CheckReport> {
+    let reader = MbtilesReader::open(path)?;
+    let conn = reader.connection();
+    let mut report = CheckReport::default();
+
+    let mut stmt = conn.prepare("PRAGMA integrity_check")?;
+    report.integrity_errors = stmt.query_map([], |row| row.get::<_, String>(0))?
+        .filter(|message| !matches!(message.as_deref(), Ok("ok")))
+        .collect::<Result<_, _>>()?;
+
+    // Normalized map rows whose image is gone show up as NULL data
+    let (table, select) = match reader.schema() {
+        MbtilesSchema::Flat => ("tiles", "SELECT zoom_level, tile_column, tile_row, tile_data FROM tiles"),
+        MbtilesSchema::Normalized => (
+            "map",
+            "SELECT map.zoom_level, map.tile_column, map.tile_row, images.tile_data
+             FROM map LEFT JOIN images ON images.tile_id = map.tile_id",
+        ),
+    };
+    report.has_tile_index = has_tile_index(&reader, table)?;
+    report.format = reader.metadata_value("format")?.as_deref().and_then(TileFormat::from_metadata);
+    for name in REQUIRED_METADATA {
+        if reader.metadata_value(name)?.is_none() {
+            report.missing_metadata.push(name.to_string());
+        }
+    }
+
+    let count: i64 = conn.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| row.get(0))?;
+    progress.start(count as u64);
+    let mut stmt = conn.prepare(select)?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let data: Option<Vec<u8>> = row.get(3)?;
+        if let Some(problem) = tile_problem(data.as_deref(), report.format) {
+            report.bad_tiles.push(BadTile { zoom: row.get(0)?, x: row.get(1)?, y: row.get(2)?, problem });
+        }
+        report.tiles += 1;
+        progress.advance(1);
+    }
+    progress.finish();
+
+    if !report.has_tile_index {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT zoom_level, tile_column, tile_row, COUNT(*) FROM {}
+             GROUP BY zoom_level, tile_column, tile_row HAVING COUNT(*) > 1",
+            table
+        ))?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let problem = TileProblem::Duplicate { rows: row.get(3)? };
+            report.bad_tiles.push(BadTile { zoom: row.get(0)?, x: row.get(1)?, y: row.get(2)?, problem });
+        }
+    }
+
+    Ok(report)
+}
+
+/// [`check`] the MBTiles file at `path` and fix what it found. Of a tile
+/// stored more than once the first good copy is kept and the missing unique
+/// tile index created; other bad tile rows are deleted. Missing `name` and
+/// `format` keys are set to the file name and the format of a stored tile.
+///
+/// With `regenerate`, which needs a raster format, each deleted tile is then
+/// rebuilt by upscaling its part of the nearest ancestor tile that decodes,
+/// working down from the lowest zoom so rebuilt tiles can serve their own
+/// descendants. Tiles without such an ancestor stay deleted. Database
+/// corruption is reported but not repaired.
+pub fn repair(path: &str, regenerate: bool) -> Result<RepairReport> {
+    repair_with_progress(path, regenerate, &NoProgress)
+}
+
+/// Like [`repair`], reporting the check and then each regenerated tile to `progress`
+pub fn repair_with_progress(path: &str, regenerate: bool, progress: &dyn Progress) -> Result<RepairReport> {
+    let reader = MbtilesReader::open(path)?;
+    let format = match reader.metadata_value("format")?.as_deref().and_then(TileFormat::from_metadata) {
+        Some(format) => Some(format),
+        None => reader.sample_tile()?.filter(|data| !data.is_empty()).as_deref().and_then(identify),
+    };
+    drop(reader);
+    if regenerate && !matches!(format, Some(TileFormat::Png | TileFormat::Jpg | TileFormat::Webp)) {
+        return Err(anyhow!("Tiles can only be regenerated in raster tilesets"));
+    }
+
+    let check = check_with_progress(path, progress)?;
+    let mut report = RepairReport::default();
+    let writer = MbtilesWriter::open(path)?;
+    let conn = writer.connection();
+    let (table, index, select) = match writer.schema() {
+        MbtilesSchema::Flat => (
+            "tiles",
+            "tile_index",
+            "SELECT rowid, tile_data FROM tiles WHERE zoom_level = ? AND tile_column = ? AND tile_row = ? ORDER BY rowid",
+        ),
+        MbtilesSchema::Normalized => (
+            "map",
+            "map_index",
+            "SELECT map.rowid, images.tile_data FROM map LEFT JOIN images ON images.tile_id = map.tile_id
+             WHERE map.zoom_level = ? AND map.tile_column = ? AND map.tile_row = ? ORDER BY map.rowid",
+        ),
+    };
+    let tx = conn.unchecked_transaction()?;
+
+    // Duplicated tiles whose kept copy is good are fixed by deleting the others
+    let mut fixed = HashSet::new();
+    for tile in check.bad_tiles.iter().filter(|tile| matches!(tile.problem, TileProblem::Duplicate { .. })) {
+        let rows: Vec<(i64, Option<Vec<u8>>)> = conn
+            .prepare_cached(select)?
+            .query_map(params![tile.zoom, tile.x, tile.y], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        let keep = rows.iter().position(|(_, data)| tile_problem(data.as_deref(), check.format).is_none());
+        for (i, (rowid, _)) in rows.iter().enumerate() {
+            if i != keep.unwrap_or(0) {
+                conn.prepare_cached(&format!("DELETE FROM {} WHERE rowid = ?", table))?.execute(params![rowid])?;
+                report.deleted += 1;
+            }
+        }
+        if keep.is_some() {
+            fixed.insert((tile.zoom, tile.x, tile.y));
+        }
+    }
+    if !check.has_tile_index {
+        conn.execute_batch(&format!(
+            "CREATE UNIQUE INDEX {} ON {} (zoom_level, tile_column, tile_row)",
+            index, table
+        ))?;
+        report.index_created = true;
+    }
+
+    let mut bad: Vec<(i32, i32, i32)> = check
+        .bad_tiles
+        .iter()
+        .map(|tile| (tile.zoom, tile.x, tile.y))
+        .filter(|tile| !fixed.contains(tile))
+        .collect();
+    bad.sort_unstable();
+    bad.dedup();
+    for &(zoom, x, y) in &bad {
+        report.deleted += writer.delete_tile(zoom, x, y)? as u64;
+    }
+    writer.prune_images()?;
+
+    if let (true, Some(format)) = (regenerate, format) {
+        progress.start(bad.len() as u64);
+        for &(zoom, x, y) in &bad {
+            if let Some(data) = upscale_ancestor(&writer, zoom, x, y, format)? {
+                writer.insert_tile(&Tile { zoom, x, y, data })?;
+                report.regenerated += 1;
+            }
+            progress.advance(1);
+        }
+        progress.finish();
+    }
+
+    for name in &check.missing_metadata {
+        let value = match name.as_str() {
+            "name" => Path::new(path).file_stem().map(|stem| stem.to_string_lossy().into_owned()),
+            "format" => format.map(|format| format.to_string()),
+            _ => None,
+        };
+        if let Some(value) = value {
+            writer.set_metadata(name, &value)?;
+            report.metadata_added.push((name.clone(), value));
+        }
+    }
+    tx.commit()?;
+
+    report.check = check;
+    Ok(report)
+}
+
+/// The part of the nearest decodable ancestor of `zoom`/`x`/`y` (TMS)
+/// covering that tile, scaled up to a full tile
+fn upscale_ancestor(writer: &MbtilesWriter, zoom: i32, x: i32, y: i32, format: TileFormat) -> Result<Option<Vec<u8>>> {
+    let mut stmt = writer.connection().prepare_cached(
+        "SELECT tile_data FROM tiles WHERE zoom_level = ? AND tile_column = ? AND tile_row = ?",
+    )?;
+    for levels in 1..=zoom {
+        let data: Option<Option<Vec<u8>>> =
+            stmt.query_row(params![zoom - levels, x >> levels, y >> levels], |row| row.get(0)).optional()?;
+        let Some(Some(data)) = data else { continue };
+        let Ok(image) = raster::decode(&data, detect_format(&data)) else { continue };
+
+        // TMS rows grow northwards, image rows southwards
+        let mask = (1 << levels) - 1;
+        let Some(scaled) = raster::upscale_part(&image, levels, (x & mask) as u32, (mask - (y & mask)) as u32) else {
+            break;
+        };
+        return raster::encode(&scaled, format, REGENERATE_QUALITY, false).map(Some);
+    }
+    Ok(None)
+}
+
+/// What is wrong with a tile's data, if anything, given the declared format
+fn tile_problem(data: Option<&[u8]>, format: Option<TileFormat>) -> Option<TileProblem> {
+    let data = match data {
+        None => return Some(TileProblem::Missing),
+        Some([]) => return Some(TileProblem::Empty),
+        Some(data) => data,
+    };
+    let found = identify(data);
+    match format {
+        Some(expected) if found != Some(expected) => Some(TileProblem::WrongFormat { found, expected }),
+        _ => found.is_some_and(|found| is_truncated(data, found)).then_some(TileProblem::Truncated),
+    }
+}
+
+/// True if a blob of `format` is cut short: images missing the end of
+/// their container and vector tiles whose compressed stream ends early
+fn is_truncated(data: &[u8], format: TileFormat) -> bool {
+    match format {
+        TileFormat::Png => !data.ends_with(&PNG_END),
+        // Some encoders pad the file past the end of image marker
+        TileFormat::Jpg => {
+            let end = data.iter().rposition(|&byte| byte != 0).map_or(0, |last| last + 1);
+            !data[..end].ends_with(&[0xFF, 0xD9])
+        }
+        TileFormat::Webp => {
+            let size = u32::from_le_bytes([data[4], data[5], data[6], data[7]]) as usize;
+            data.len() < size + 8
+        }
+        TileFormat::Pbf => detect_compression(data) != Compression::None && decompress(data).is_err(),
+    }
+}
+
+/// Format of a blob, or None when its first bytes match no format. Vector
+/// tiles have no magic of their own: they are accepted when compressed or
+/// starting with a layer field, as every non-empty MVT does.
+fn identify(data: &[u8]) -> Option<TileFormat> {
+    match detect_format(data) {
+        TileFormat::Pbf if detect_compression(data) == Compression::None && data[0] != 0x1A => None,
+        format => Some(format),
+    }
+}
+
+/// True if `table` has a unique index on exactly its zoom, column and row
+fn has_tile_index(reader: &MbtilesReader, table: &str) -> Result<bool> {
+    let conn = reader.connection();
+    let mut stmt = conn.prepare("SELECT name FROM pragma_index_list(?) WHERE \"unique\"")?;
+    let indexes = stmt.query_map(params![table], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut stmt = conn.prepare("SELECT name FROM pragma_index_info(?) ORDER BY name")?;
+    for index in indexes {
+        let columns = stmt.query_map(params![index], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        if columns == ["tile_column", "tile_row", "zoom_level"] {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, Rgba, RgbaImage};
+
+    use crate::tile::compress;
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("mbtiles-check-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path.to_string_lossy().into_owned()
+    }
+
+    fn image(format: TileFormat) -> Vec<u8> {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(256, 256, Rgba([200, 40, 40, 255])));
+        raster::encode(&image, format, 90.0, false).unwrap()
+    }
+
+    #[test]
+    fn truncated_blobs() {
+        let vector = compress(&[0x1A, 0x02, 0x0A, 0x00], Compression::Gzip).unwrap();
+        for (data, format) in [
+            (image(TileFormat::Png), TileFormat::Png),
+            (image(TileFormat::Jpg), TileFormat::Jpg),
+            (image(TileFormat::Webp), TileFormat::Webp),
+            (vector, TileFormat::Pbf),
+        ] {
+            assert_eq!(tile_problem(Some(&data), Some(format)), None, "{}", format);
+            let cut = &data[..data.len() - 3];
+            assert_eq!(tile_problem(Some(cut), Some(format)), Some(TileProblem::Truncated), "{}", format);
+        }
+        let mut padded = image(TileFormat::Jpg);
+        padded.extend([0; 4]);
+        assert_eq!(tile_problem(Some(&padded), None), None);
+    }
+
+    #[test]
+    fn check_reports_and_repair_fixes() {
+        // Without the unique index, so tiles can be stored twice
+        let path = temp_path("broken.mbtiles");
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE metadata (name TEXT, value TEXT);
+             CREATE TABLE tiles (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER, tile_data BLOB);
+             INSERT INTO metadata VALUES ('name', 'broken');",
+        )
+        .unwrap();
+        let png = image(TileFormat::Png);
+        let truncated = png[..png.len() / 2].to_vec();
+        let tiles = [
+            ((0, 0, 0), &png),
+            ((1, 0, 0), &truncated),
+            ((1, 1, 0), &truncated),
+            ((1, 1, 0), &png),
+            ((1, 0, 1), &png),
+            ((1, 0, 1), &png),
+            ((1, 1, 1), &Vec::new()),
+        ];
+        for ((zoom, x, y), data) in tiles {
+            conn.execute("INSERT INTO tiles VALUES (?, ?, ?, ?)", params![zoom, x, y, data]).unwrap();
+        }
+        drop(conn);
+
+        let report = check(&path).unwrap();
+        assert!(!report.is_ok());
+        assert!(report.integrity_errors.is_empty());
+        assert!(!report.has_tile_index);
+        assert_eq!(report.format, None);
+        assert_eq!(report.missing_metadata, ["format"]);
+        assert_eq!(report.tiles, 7);
+        let bad = |zoom, x, y, problem| BadTile { zoom, x, y, problem };
+        assert_eq!(
+            report.bad_tiles,
+            [
+                bad(1, 0, 0, TileProblem::Truncated),
+                bad(1, 1, 0, TileProblem::Truncated),
+                bad(1, 1, 1, TileProblem::Empty),
+                bad(1, 0, 1, TileProblem::Duplicate { rows: 2 }),
+                bad(1, 1, 0, TileProblem::Duplicate { rows: 2 }),
+            ]
+        );
+
+        // The good copy of 1/1/0 is kept, the other bad tiles are rebuilt from 0/0/0
+        let fixed = repair(&path, true).unwrap();
+        assert!(fixed.is_ok());
+        assert_eq!(fixed.deleted, 4);
+        assert_eq!(fixed.regenerated, 2);
+        assert!(fixed.index_created);
+        assert_eq!(fixed.metadata_added, [("format".to_string(), "png".to_string())]);
+
+        let report = check(&path).unwrap();
+        assert!(report.is_ok(), "{:?}", report);
+        assert_eq!(report.tiles, 5);
+        let reader = MbtilesReader::open(&path).unwrap();
+        assert_eq!(reader.tile(1, 1, 0).unwrap(), Some(png));
+        drop(reader);
+        // Nothing left to fix
+        let fixed = repair(&path, false).unwrap();
+        assert_eq!((fixed.deleted, fixed.index_created, fixed.metadata_added.len()), (0, false, 0));
+        std::fs::remove_file(path).unwrap();
+    }
+}
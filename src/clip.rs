@@ -0,0 +1,395 @@
+//! Clipping tile geometry to an arbitrary (multi)polygon, such as an
+//! extraction region, in floating point tile coordinates.
+//!
+//! Rings are open, their last vertex not repeating the first, and the
+//! inside of a set of rings follows the even-odd rule, so holes need no
+//! particular orientation.
+
+/// An open ring of vertices
+pub(crate) type Ring = Vec<(f64, f64)>;
+
+/// True if `p` is inside `rings` by the even-odd rule
+pub(crate) fn contains(rings: &[Ring], p: (f64, f64)) -> bool {
+    rings.iter().filter(|ring| ring_contains(ring, p)).count() % 2 == 1
+}
+
+fn ring_contains(ring: &[(f64, f64)], p: (f64, f64)) -> bool {
+    let mut inside = false;
+    for (i, &a) in ring.iter().enumerate() {
+        let b = ring[(i + 1) % ring.len()];
+        if (a.1 > p.1) != (b.1 > p.1) && p.0 < a.0 + (p.1 - a.1) / (b.1 - a.1) * (b.0 - a.0) {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+/// The x coordinates, sorted, where `rings` cross the horizontal line at
+/// `y`; by the even-odd rule each pair bounds a span inside them
+pub(crate) fn row_crossings(rings: &[Ring], y: f64) -> Vec<f64> {
+    let mut crossings = Vec::new();
+    for ring in rings {
+        for (i, &a) in ring.iter().enumerate() {
+            let b = ring[(i + 1) % ring.len()];
+            if (a.1 > y) != (b.1 > y) {
+                crossings.push(a.0 + (y - a.1) / (b.1 - a.1) * (b.0 - a.0));
+            }
+        }
+    }
+    crossings.sort_by(|a, b| a.total_cmp(b));
+    crossings
+}
+
+/// True if an edge of `rings` passes through the square from `min` to `max`
+pub(crate) fn crosses_square(rings: &[Ring], min: f64, max: f64) -> bool {
+    rings.iter().any(|ring| {
+        ring.iter().enumerate().any(|(i, &a)| {
+            let b = ring[(i + 1) % ring.len()];
+            segment_in_square(a, b, min, max)
+        })
+    })
+}
+
+/// Liang-Barsky test of segment `a`-`b` against the square
+fn segment_in_square(a: (f64, f64), b: (f64, f64), min: f64, max: f64) -> bool {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let (mut t0, mut t1) = (0.0_f64, 1.0_f64);
+    for (p, q) in [(-dx, a.0 - min), (dx, max - a.0), (-dy, a.1 - min), (dy, max - a.1)] {
+        if p == 0.0 {
+            if q < 0.0 {
+                return false;
+            }
+        } else if p < 0.0 {
+            t0 = t0.max(q / p);
+        } else {
+            t1 = t1.min(q / p);
+        }
+    }
+    t0 <= t1
+}
+
+/// Sutherland-Hodgman: each ring cut to the square from `min` to `max`,
+/// leaving out rings with fewer than three vertices left. Concave rings
+/// can come out with zero width bridges along the square's sides, which
+/// don't change what the even-odd rule counts as inside.
+pub(crate) fn clip_to_square(rings: &[Ring], min: f64, max: f64) -> Vec<Ring> {
+    let mut clipped = Vec::new();
+    for ring in rings {
+        let mut points = ring.clone();
+        for (axis, bound, above) in [(0, min, true), (0, max, false), (1, min, true), (1, max, false)] {
+            let coordinate = |p: (f64, f64)| if axis == 0 { p.0 } else { p.1 };
+            let inside = |p: (f64, f64)| if above { coordinate(p) >= bound } else { coordinate(p) <= bound };
+            let input = std::mem::take(&mut points);
+            for (i, &current) in input.iter().enumerate() {
+                let previous = input[(i + input.len() - 1) % input.len()];
+                let crossing = || {
+                    let t = (bound - coordinate(previous)) / (coordinate(current) - coordinate(previous));
+                    (previous.0 + t * (current.0 - previous.0), previous.1 + t * (current.1 - previous.1))
+                };
+                match (inside(previous), inside(current)) {
+                    (true, true) => points.push(current),
+                    (true, false) => points.push(crossing()),
+                    (false, true) => {
+                        points.push(crossing());
+                        points.push(current);
+                    }
+                    (false, false) => {}
+                }
+            }
+        }
+        if points.len() >= 3 {
+            clipped.push(points);
+        }
+    }
+    clipped
+}
+
+/// Parameters `(t, u)` where segment `a`-`b` crosses `c`-`d` at
+/// `a + t * (b - a)`, both strictly inside their segments
+fn crossing(a: (f64, f64), b: (f64, f64), c: (f64, f64), d: (f64, f64)) -> Option<(f64, f64)> {
+    let (r, s) = ((b.0 - a.0, b.1 - a.1), (d.0 - c.0, d.1 - c.1));
+    let denominator = r.0 * s.1 - r.1 * s.0;
+    if denominator == 0.0 {
+        return None;
+    }
+    let (qx, qy) = (c.0 - a.0, c.1 - a.1);
+    let t = (qx * s.1 - qy * s.0) / denominator;
+    let u = (qx * r.1 - qy * r.0) / denominator;
+    (t > 0.0 && t < 1.0 && u > 0.0 && u < 1.0).then_some((t, u))
+}
+
+/// The pieces of `line` inside `clip`
+pub(crate) fn clip_line(line: &[(f64, f64)], clip: &[Ring]) -> Vec<Vec<(f64, f64)>> {
+    let mut pieces = Vec::new();
+    let mut current: Vec<(f64, f64)> = Vec::new();
+    for pair in line.windows(2) {
+        let (a, b) = (pair[0], pair[1]);
+        let mut cuts = vec![0.0, 1.0];
+        for ring in clip {
+            for (i, &c) in ring.iter().enumerate() {
+                cuts.extend(crossing(a, b, c, ring[(i + 1) % ring.len()]).map(|(t, _)| t));
+            }
+        }
+        cuts.sort_by(|x, y| x.total_cmp(y));
+        let at = |t: f64| (a.0 + t * (b.0 - a.0), a.1 + t * (b.1 - a.1));
+        for span in cuts.windows(2) {
+            if contains(clip, at((span[0] + span[1]) / 2.0)) {
+                if current.is_empty() {
+                    current.push(at(span[0]));
+                }
+                current.push(at(span[1]));
+            } else if !current.is_empty() {
+                pieces.push(std::mem::take(&mut current));
+            }
+        }
+    }
+    if !current.is_empty() {
+        pieces.push(current);
+    }
+    pieces
+}
+
+/// A vertex of the Greiner-Hormann lists: an original vertex, or a crossing
+/// linked to its twin in the other polygon's list
+struct Node {
+    point: (f64, f64),
+    next: usize,
+    prev: usize,
+    twin: Option<usize>,
+    entry: bool,
+    visited: bool,
+}
+
+/// Greiner-Hormann: the intersection of `subject` and `clip` as rings in
+/// no particular orientation or order, see [`nest`]
+pub(crate) fn clip_polygon(subject: &[Ring], clip: &[Ring]) -> Vec<Ring> {
+    let mut nodes: Vec<Node> = Vec::new();
+    // Crossings per edge of each ring, as (parameter along the edge, node)
+    let mut subject_cuts: Vec<Vec<Vec<(f64, usize)>>> = subject.iter().map(|ring| vec![Vec::new(); ring.len()]).collect();
+    let mut clip_cuts: Vec<Vec<Vec<(f64, usize)>>> = clip.iter().map(|ring| vec![Vec::new(); ring.len()]).collect();
+    for (si, ring) in subject.iter().enumerate() {
+        for (sk, &a) in ring.iter().enumerate() {
+            let b = ring[(sk + 1) % ring.len()];
+            for (ci, other) in clip.iter().enumerate() {
+                for (ck, &c) in other.iter().enumerate() {
+                    let Some((t, u)) = crossing(a, b, c, other[(ck + 1) % other.len()]) else { continue };
+                    let point = (a.0 + t * (b.0 - a.0), a.1 + t * (b.1 - a.1));
+                    let (s, k) = (nodes.len(), nodes.len() + 1);
+                    for twin in [k, s] {
+                        nodes.push(Node { point, next: 0, prev: 0, twin: Some(twin), entry: false, visited: false });
+                    }
+                    subject_cuts[si][sk].push((t, s));
+                    clip_cuts[ci][ck].push((u, k));
+                }
+            }
+        }
+    }
+
+    let mut result = Vec::new();
+    let mut link = |rings: &[Ring], cuts: Vec<Vec<Vec<(f64, usize)>>>, other: &[Ring], nodes: &mut Vec<Node>| {
+        for (ring, mut cuts) in rings.iter().zip(cuts) {
+            if cuts.iter().all(Vec::is_empty) {
+                // Untouched rings are either wholly inside the other polygon or outside it
+                if contains(other, ring[0]) {
+                    result.push(ring.clone());
+                }
+                continue;
+            }
+            let mut order = Vec::new();
+            for (k, &point) in ring.iter().enumerate() {
+                order.push(nodes.len());
+                nodes.push(Node { point, next: 0, prev: 0, twin: None, entry: false, visited: false });
+                cuts[k].sort_by(|a, b| a.0.total_cmp(&b.0));
+                order.extend(cuts[k].iter().map(|&(_, node)| node));
+            }
+            let mut inside = contains(other, ring[0]);
+            for (i, &node) in order.iter().enumerate() {
+                nodes[node].next = order[(i + 1) % order.len()];
+                nodes[node].prev = order[(i + order.len() - 1) % order.len()];
+                if nodes[node].twin.is_some() {
+                    nodes[node].entry = !inside;
+                    inside = !inside;
+                }
+            }
+        }
+    };
+    link(subject, subject_cuts, clip, &mut nodes);
+    link(clip, clip_cuts, subject, &mut nodes);
+
+    for start in 0..nodes.len() {
+        if nodes[start].twin.is_none() || nodes[start].visited {
+            continue;
+        }
+        let mut ring = vec![nodes[start].point];
+        let mut current = start;
+        loop {
+            nodes[current].visited = true;
+            let twin = nodes[current].twin.expect("traversal stops at crossings");
+            nodes[twin].visited = true;
+            let forward = nodes[current].entry;
+            loop {
+                current = if forward { nodes[current].next } else { nodes[current].prev };
+                ring.push(nodes[current].point);
+                if nodes[current].twin.is_some() {
+                    break;
+                }
+            }
+            current = nodes[current].twin.expect("crossing has a twin");
+            if nodes[current].visited {
+                break;
+            }
+        }
+        while ring.len() > 1 && ring.first() == ring.last() {
+            ring.pop();
+        }
+        if ring.len() >= 3 {
+            result.push(ring);
+        }
+    }
+    result
+}
+
+/// Twice the signed area of `ring`, positive for MVT exterior rings
+pub(crate) fn signed_area(ring: &[(f64, f64)]) -> f64 {
+    ring.iter().enumerate().map(|(i, &a)| {
+        let b = ring[(i + 1) % ring.len()];
+        a.0 * b.1 - b.0 * a.1
+    }).sum()
+}
+
+/// Order non-crossing rings as MVT polygons: each exterior, wound with
+/// positive area, followed by its holes, wound the other way. Rings inside
+/// an even number of others are exteriors.
+pub(crate) fn nest(rings: Vec<Ring>) -> Vec<Ring> {
+    // A point on each ring's first edge, to test which rings contain it
+    let probes: Vec<(f64, f64)> =
+        rings.iter().map(|ring| ((ring[0].0 + ring[1].0) / 2.0, (ring[0].1 + ring[1].1) / 2.0)).collect();
+    let areas: Vec<f64> = rings.iter().map(|ring| signed_area(ring).abs()).collect();
+    let parents: Vec<Vec<usize>> = (0..rings.len())
+        .map(|i| (0..rings.len()).filter(|&j| j != i && ring_contains(&rings[j], probes[i])).collect())
+        .collect();
+
+    let mut rings: Vec<Option<Ring>> = rings.into_iter().map(Some).collect();
+    let orient = |mut ring: Ring, exterior: bool| {
+        if (signed_area(&ring) > 0.0) != exterior {
+            ring.reverse();
+        }
+        ring
+    };
+    let mut nested = Vec::new();
+    for i in 0..rings.len() {
+        if parents[i].len() % 2 == 1 {
+            continue;
+        }
+        let Some(exterior) = rings[i].take() else { continue };
+        nested.push(orient(exterior, true));
+        // Holes whose innermost enclosing ring is this exterior
+        for j in 0..rings.len() {
+            if parents[j].len() % 2 == 1 && parents[j].contains(&i) {
+                let innermost = parents[j].iter().copied().min_by(|&a, &b| areas[a].total_cmp(&areas[b]));
+                if innermost == Some(i)
+                    && let Some(hole) = rings[j].take()
+                {
+                    nested.push(orient(hole, false));
+                }
+            }
+        }
+    }
+    nested
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect(x0: f64, y0: f64, x1: f64, y1: f64) -> Ring {
+        vec![(x0, y0), (x1, y0), (x1, y1), (x0, y1)]
+    }
+
+    /// Area inside `rings` by the even-odd rule, once nested
+    fn area(rings: &[Ring]) -> f64 {
+        nest(rings.to_vec()).iter().map(|ring| signed_area(ring)).sum::<f64>() / 2.0
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    /// A U open at the top: 9 by 9 with a 3 wide gap down to y = 3
+    fn u_shape() -> Ring {
+        vec![(0.0, 0.0), (9.0, 0.0), (9.0, 9.0), (6.0, 9.0), (6.0, 3.0), (3.0, 3.0), (3.0, 9.0), (0.0, 9.0)]
+    }
+
+    #[test]
+    fn even_odd_containment() {
+        let rings = [rect(0.0, 0.0, 10.0, 10.0), rect(3.0, 3.0, 7.0, 7.0)];
+        assert!(contains(&rings, (1.0, 1.0)));
+        assert!(!contains(&rings, (5.0, 5.0)));
+        assert!(!contains(&rings, (11.0, 5.0)));
+        assert!(contains(&[u_shape()], (1.5, 8.0)) && !contains(&[u_shape()], (4.5, 8.0)));
+        assert_eq!(row_crossings(&rings, 5.0), [0.0, 3.0, 7.0, 10.0]);
+        assert!(crosses_square(&rings, 9.0, 12.0) && !crosses_square(&rings, 4.0, 6.0) && !crosses_square(&rings, 11.0, 12.0));
+    }
+
+    #[test]
+    fn rings_crossing_the_edge() {
+        let clipped = clip_polygon(&[rect(0.0, 0.0, 10.0, 10.0)], &[rect(5.0, 5.0, 15.0, 15.0)]);
+        assert_eq!(clipped.len(), 1);
+        assert!(close(area(&clipped), 25.0));
+        assert!(clipped[0].iter().all(|&(x, y)| (5.0..=10.0).contains(&x) && (5.0..=10.0).contains(&y)));
+
+        // A diamond over the square cuts off its corners
+        let square = clip_to_square(&[vec![(-2.5, 5.0), (5.0, -2.5), (12.5, 5.0), (5.0, 12.5)]], 0.0, 10.0);
+        assert!(close(area(&square), 100.0 - 4.0 * 3.125));
+
+        let pieces = clip_line(&[(-5.0, 5.0), (5.0, 5.0), (5.0, 20.0)], &[rect(0.0, 0.0, 10.0, 10.0)]);
+        assert_eq!(pieces, vec![vec![(0.0, 5.0), (5.0, 5.0), (5.0, 10.0)]]);
+    }
+
+    #[test]
+    fn holes() {
+        let subject = [rect(0.0, 0.0, 10.0, 10.0), rect(3.0, 3.0, 7.0, 7.0)];
+        // The left half, cutting through the hole
+        let clipped = clip_polygon(&subject, &[rect(-1.0, -1.0, 5.0, 11.0)]);
+        assert!(close(area(&clipped), 50.0 - 8.0));
+        // Around the hole, which stays a hole after its exterior
+        let clipped = nest(clip_polygon(&subject, &[rect(1.0, 1.0, 9.0, 9.0)]));
+        assert_eq!(clipped.len(), 2);
+        assert!(signed_area(&clipped[0]) > 0.0 && signed_area(&clipped[1]) < 0.0);
+        assert!(close(area(&clipped), 64.0 - 16.0));
+        // Wholly inside the hole
+        assert!(clip_polygon(&subject, &[rect(4.0, 4.0, 6.0, 6.0)]).is_empty());
+        // A clip area with a hole of its own
+        let clipped = clip_polygon(&[rect(2.0, 2.0, 8.0, 8.0)], &subject);
+        assert!(close(area(&clipped), 36.0 - 16.0));
+    }
+
+    #[test]
+    fn concave_regions() {
+        let bar = [rect(-1.0, 5.0, 10.0, 7.0)];
+        let clipped = clip_polygon(&bar, &[u_shape()]);
+        assert_eq!(clipped.len(), 2);
+        assert!(close(area(&clipped), 12.0));
+        // And the other way round
+        assert!(close(area(&clip_polygon(&[u_shape()], &bar)), 12.0));
+
+        let pieces = clip_line(&[(-1.0, 6.0), (10.0, 6.0)], &[u_shape()]);
+        assert_eq!(pieces, vec![vec![(0.0, 6.0), (3.0, 6.0)], vec![(6.0, 6.0), (9.0, 6.0)]]);
+        // Entering and leaving twice along a bent line makes one piece per visit
+        let pieces = clip_line(&[(1.5, 10.0), (1.5, 1.5), (7.5, 1.5), (7.5, 10.0)], &[u_shape()]);
+        assert_eq!(pieces, vec![vec![(1.5, 9.0), (1.5, 1.5), (7.5, 1.5), (7.5, 9.0)]]);
+    }
+
+    #[test]
+    fn wholly_inside_or_outside() {
+        let inner = [rect(2.0, 2.0, 4.0, 4.0)];
+        let outer = [rect(0.0, 0.0, 10.0, 10.0)];
+        assert_eq!(clip_polygon(&inner, &outer), inner);
+        assert_eq!(clip_polygon(&outer, &inner), inner);
+        assert!(clip_polygon(&[rect(20.0, 20.0, 30.0, 30.0)], &outer).is_empty());
+        assert_eq!(clip_line(&[(1.0, 1.0), (9.0, 9.0)], &outer), vec![vec![(1.0, 1.0), (9.0, 9.0)]]);
+        assert!(clip_line(&[(11.0, 1.0), (19.0, 9.0)], &outer).is_empty());
+        assert_eq!(clip_to_square(&inner, 0.0, 10.0), inner);
+        assert!(clip_to_square(&outer, 20.0, 30.0).is_empty());
+    }
+}
@@ -0,0 +1,130 @@
+use std::path::Path;
+
+use anyhow::{Result, anyhow};
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, RgbaImage};
+
+use crate::bbox::TileRange;
+use crate::progress::{NoProgress, Progress};
+use crate::raster;
+use crate::sink::OutputFormat;
+use crate::source::{open_source, TileSource};
+use crate::tile::{detect_format, TileFormat};
+
+/// Quality used when composited tiles are JPEG or WebP
+const COMPOSITE_QUALITY: f32 = 85.0;
+
+/// Write every tile of the raster tileset `base_path` to a new tileset at
+/// `output_path` with the matching tile of `overlay_path` alpha blended on
+/// top, keeping the base's metadata and tile format. Where the overlay has
+/// no tile at that zoom level, the matching part of its nearest ancestor is
+/// scaled up instead; base tiles with neither are copied as they are.
+/// Returns the number of tiles written and how many of them were blended.
+pub fn composite(base_path: &str, overlay_path: &str, output_path: &str) -> Result<(u64, u64)> {
+    composite_with_progress(base_path, overlay_path, output_path, &NoProgress)
+}
+
+/// Like [`composite`], reporting each base tile to `progress`
+pub fn composite_with_progress(
+    base_path: &str,
+    overlay_path: &str,
+    output_path: &str,
+    progress: &dyn Progress,
+) -> Result<(u64, u64)> {
+    if Path::new(output_path).exists() {
+        return Err(anyhow!("Output file already exists: {}", output_path));
+    }
+    let base = open_source(base_path)?;
+    let overlay = Overlay::open(overlay_path)?;
+    let mut sink = OutputFormat::from_path(output_path).create_sink(output_path)?;
+
+    let zooms = base.zoom_levels()?;
+    let mut total = 0;
+    for &zoom in &zooms {
+        total += base.count_tiles(&TileRange::full(zoom)?)?;
+    }
+    progress.start(total);
+
+    let mut cache = None;
+    let (mut written, mut blended) = (0, 0);
+    for zoom in zooms {
+        base.for_each_tile(&TileRange::full(zoom)?, &mut |mut tile| {
+            if let Some(top) = overlay.image(tile.zoom, tile.x, tile.y, &mut cache)? {
+                let format = detect_format(&tile.data);
+                if format == TileFormat::Pbf {
+                    return Err(anyhow!("Can only composite raster tiles, {} has vector tiles", base_path));
+                }
+                let mut image = raster::decode(&tile.data, format)?.to_rgba8();
+                let top = if top.dimensions() == image.dimensions() {
+                    top
+                } else {
+                    imageops::resize(&top, image.width(), image.height(), FilterType::Triangle)
+                };
+                raster::blend(&mut image, &top, 0, 0, 1.0);
+                tile.data = raster::encode(&DynamicImage::ImageRgba8(image), format, COMPOSITE_QUALITY, false)?;
+                blended += 1;
+            }
+            written += 1;
+            progress.advance(1);
+            sink.write_tile(&tile)
+        })?;
+    }
+
+    for (name, value) in base.metadata()? {
+        sink.write_metadata(&name, &value)?;
+    }
+    sink.finish()?;
+    progress.finish();
+    Ok((written, blended))
+}
+
+/// The overlay tileset, read tile by tile as base tiles need it
+struct Overlay {
+    path: String,
+    source: Box<dyn TileSource>,
+    zooms: Vec<i32>,
+}
+
+/// The overlay tile decoded last, as (zoom, column, TMS row) and its image,
+/// so the base tiles below an ancestor only decode it once
+type Ancestor = Option<((i32, i32, i32), DynamicImage)>;
+
+impl Overlay {
+    fn open(path: &str) -> Result<Self> {
+        let source = open_source(path)?;
+        let zooms = source.zoom_levels()?;
+        Ok(Overlay { path: path.to_string(), source, zooms })
+    }
+
+    /// The overlay over tile `zoom`/`x`/`y` (TMS): its own tile, or the part
+    /// of its nearest ancestor scaled up. `None` if the overlay has neither.
+    fn image(&self, zoom: i32, x: i32, y: i32, cache: &mut Ancestor) -> Result<Option<RgbaImage>> {
+        for levels in 0..=zoom {
+            let key = (zoom - levels, x >> levels, y >> levels);
+            if !self.zooms.contains(&key.0) {
+                continue;
+            }
+            if cache.as_ref().is_none_or(|(cached, _)| *cached != key) {
+                let Some(data) = self.source.tile(key.0, key.1, key.2)? else { continue };
+                *cache = Some((key, self.decode(&data)?));
+            }
+            let (_, image) = cache.as_ref().expect("cached above");
+            if levels == 0 {
+                return Ok(Some(image.to_rgba8()));
+            }
+            let mask = (1 << levels) - 1;
+            // TMS rows grow northwards, image rows southwards
+            let (dx, dy) = ((x & mask) as u32, (mask - (y & mask)) as u32);
+            return Ok(raster::upscale_part(image, levels, dx, dy).map(|part| part.to_rgba8()));
+        }
+        Ok(None)
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<DynamicImage> {
+        let format = detect_format(data);
+        if format == TileFormat::Pbf {
+            return Err(anyhow!("Can only composite raster tiles, {} has vector tiles", self.path));
+        }
+        raster::decode(data, format)
+    }
+}
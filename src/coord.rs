@@ -0,0 +1,186 @@
+use std::f64::consts::PI;
+use std::fmt;
+
+use anyhow::{anyhow, Result};
+
+use crate::bbox::BoundingBox;
+use crate::tile::Scheme;
+
+/// Latitude where Web Mercator turns the world into a square; points
+/// further north or south have no tile
+pub const MAX_LATITUDE: f64 = 85.051_128_779_806_59;
+
+/// Highest zoom level whose coordinates fit in an `i32` and a quadkey
+pub const MAX_ZOOM: i32 = 30;
+
+/// Address of a single tile, with rows counted from the north (XYZ)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TileCoord {
+    pub zoom: i32,
+    pub x: i32,
+    pub y: i32,
+}
+
+impl TileCoord {
+    /// A tile in XYZ (slippy map) numbering
+    pub fn new(zoom: i32, x: i32, y: i32) -> Self {
+        TileCoord { zoom, x, y }
+    }
+
+    /// A tile whose row is counted from the south, as stored in MBTiles
+    pub fn from_tms(zoom: i32, x: i32, tms_y: i32) -> Self {
+        Self::from_scheme(zoom, x, tms_y, Scheme::Tms)
+    }
+
+    /// A tile whose row is numbered in `scheme`
+    pub fn from_scheme(zoom: i32, x: i32, y: i32, scheme: Scheme) -> Self {
+        TileCoord { zoom, x, y: Scheme::Xyz.from_tms(zoom, scheme.to_tms(zoom, y)) }
+    }
+
+    /// Row counted from the south
+    pub fn tms_y(&self) -> i32 {
+        Scheme::Xyz.to_tms(self.zoom, self.y)
+    }
+
+    /// Row numbered in `scheme`
+    pub fn y_in(&self, scheme: Scheme) -> i32 {
+        scheme.from_tms(self.zoom, self.tms_y())
+    }
+
+    /// The tile containing a point. Longitudes are clamped to ±180 and
+    /// latitudes to ±[`MAX_LATITUDE`], so every point maps to a real tile.
+    pub fn from_lon_lat(lon: f64, lat: f64, zoom: i32) -> Self {
+        let n = 2_f64.powi(zoom);
+        let max = (1_i64 << zoom) - 1;
+        let lon = lon.clamp(-180.0, 180.0);
+        let lat = lat.clamp(-MAX_LATITUDE, MAX_LATITUDE).to_radians();
+        let x = ((lon + 180.0) / 360.0 * n).floor() as i64;
+        let y = ((1.0 - lat.tan().asinh() / PI) / 2.0 * n).floor() as i64;
+        TileCoord { zoom, x: x.clamp(0, max) as i32, y: y.clamp(0, max) as i32 }
+    }
+
+    /// Longitude and latitude of the north-west corner
+    pub fn lon_lat(&self) -> (f64, f64) {
+        let n = 2_f64.powi(self.zoom);
+        let lon = self.x as f64 / n * 360.0 - 180.0;
+        let lat = (PI * (1.0 - 2.0 * self.y as f64 / n)).sinh().atan().to_degrees();
+        (lon, lat)
+    }
+
+    /// Geographic extent of the tile
+    pub fn bounds(&self) -> BoundingBox {
+        let (west, north) = self.lon_lat();
+        let (east, south) = TileCoord::new(self.zoom, self.x + 1, self.y + 1).lon_lat();
+        BoundingBox { north, east, south, west }
+    }
+
+    /// True if the zoom is supported and the tile lies inside the world
+    pub fn is_valid(&self) -> bool {
+        (0..=MAX_ZOOM).contains(&self.zoom)
+            && (0..1 << self.zoom).contains(&self.x)
+            && (0..1 << self.zoom).contains(&self.y)
+    }
+
+    /// Bing Maps quadkey: one base-4 digit per zoom level, bit 0 from the
+    /// column and bit 1 from the row
+    pub fn quadkey(&self) -> String {
+        (1..=self.zoom)
+            .rev()
+            .map(|level| {
+                let mask = 1 << (level - 1);
+                let digit = (self.x & mask != 0) as u8 + 2 * (self.y & mask != 0) as u8;
+                char::from(b'0' + digit)
+            })
+            .collect()
+    }
+
+    pub fn from_quadkey(key: &str) -> Result<Self> {
+        if key.len() > MAX_ZOOM as usize {
+            return Err(anyhow!("Quadkey {:?} is longer than {} digits", key, MAX_ZOOM));
+        }
+        let (mut x, mut y) = (0, 0);
+        for c in key.chars() {
+            let digit = c.to_digit(4).ok_or_else(|| anyhow!("Invalid quadkey {:?}: digits must be 0-3", key))? as i32;
+            x = x << 1 | digit & 1;
+            y = y << 1 | digit >> 1;
+        }
+        Ok(TileCoord { zoom: key.len() as i32, x, y })
+    }
+}
+
+impl fmt::Display for TileCoord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}/{}", self.zoom, self.x, self.y)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn lon_lat_to_tile() {
+        assert_eq!(TileCoord::from_lon_lat(13.405, 52.52, 10), TileCoord::new(10, 550, 335));
+        assert_eq!(TileCoord::from_lon_lat(-122.4194, 37.7749, 12), TileCoord::new(12, 655, 1583));
+        assert_eq!(TileCoord::from_lon_lat(139.6917, 35.6895, 15), TileCoord::new(15, 29099, 12902));
+        assert_eq!(TileCoord::from_lon_lat(0.0, 0.0, 1), TileCoord::new(1, 1, 1));
+    }
+
+    #[test]
+    fn lon_lat_is_clamped() {
+        assert_eq!(TileCoord::from_lon_lat(180.0, 90.0, 3), TileCoord::new(3, 7, 0));
+        assert_eq!(TileCoord::from_lon_lat(-200.0, -90.0, 3), TileCoord::new(3, 0, 7));
+        assert_eq!(TileCoord::from_lon_lat(0.0, 89.0, 0), TileCoord::new(0, 0, 0));
+    }
+
+    #[test]
+    fn tile_to_lon_lat() {
+        let (lon, lat) = TileCoord::new(0, 0, 0).lon_lat();
+        assert!(close(lon, -180.0) && close(lat, MAX_LATITUDE));
+
+        let (lon, lat) = TileCoord::new(10, 550, 335).lon_lat();
+        assert!(close(lon, 13.359375) && close(lat, 52.696361078274485));
+
+        let bounds = TileCoord::new(1, 1, 1).bounds();
+        assert!(close(bounds.north, 0.0) && close(bounds.west, 0.0));
+        assert!(close(bounds.south, -MAX_LATITUDE) && close(bounds.east, 180.0));
+    }
+
+    #[test]
+    fn scheme_conversions() {
+        let tile = TileCoord::from_tms(3, 3, 2);
+        assert_eq!(tile, TileCoord::new(3, 3, 5));
+        assert_eq!(tile.tms_y(), 2);
+        assert_eq!(tile.y_in(Scheme::Xyz), 5);
+        assert_eq!(TileCoord::from_scheme(3, 3, 5, Scheme::Xyz), tile);
+        assert_eq!(TileCoord::from_tms(0, 0, 0), TileCoord::new(0, 0, 0));
+    }
+
+    #[test]
+    fn quadkeys() {
+        // Example from the Bing Maps tile system documentation
+        assert_eq!(TileCoord::new(3, 3, 5).quadkey(), "213");
+        assert_eq!(TileCoord::from_quadkey("213").unwrap(), TileCoord::new(3, 3, 5));
+        assert_eq!(TileCoord::new(0, 0, 0).quadkey(), "");
+        assert_eq!(TileCoord::from_quadkey("").unwrap(), TileCoord::new(0, 0, 0));
+        assert_eq!(TileCoord::new(12, 655, 1583).quadkey(), "023010203333");
+
+        for key in ["0", "3", "1202102332221212", "333333333333333333333333333333"] {
+            assert_eq!(TileCoord::from_quadkey(key).unwrap().quadkey(), key);
+        }
+        assert!(TileCoord::from_quadkey("0124").is_err());
+        assert!(TileCoord::from_quadkey(&"0".repeat(31)).is_err());
+    }
+
+    #[test]
+    fn validity() {
+        assert!(TileCoord::new(2, 3, 3).is_valid());
+        assert!(!TileCoord::new(2, 4, 0).is_valid());
+        assert!(!TileCoord::new(2, 0, -1).is_valid());
+        assert!(!TileCoord::new(31, 0, 0).is_valid());
+    }
+}
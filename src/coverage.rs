@@ -0,0 +1,192 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{Result, anyhow};
+use serde_json::{json, Value};
+
+use crate::source::{is_mbtiles, open_source};
+use crate::tile::{tile_to_lon_lat, Scheme};
+
+/// Where a tileset has tiles at one zoom level, as the outlines of the
+/// merged tile footprints
+#[derive(Debug, Clone)]
+pub struct Coverage {
+    pub zoom: i32,
+    pub tiles: u64,
+    /// Polygons of lon/lat rings: the counterclockwise exterior first, then
+    /// any clockwise holes, each ring closed
+    pub polygons: Vec<Vec<Vec<(f64, f64)>>>,
+}
+
+impl Coverage {
+    /// A FeatureCollection with one MultiPolygon feature
+    pub fn to_geojson(&self) -> Value {
+        let polygons: Vec<Value> = self
+            .polygons
+            .iter()
+            .map(|rings| {
+                rings.iter().map(|ring| ring.iter().map(|&(lon, lat)| json!([lon, lat])).collect::<Value>()).collect()
+            })
+            .collect();
+        json!({
+            "type": "FeatureCollection",
+            "features": [{
+                "type": "Feature",
+                "properties": { "zoom": self.zoom, "tiles": self.tiles },
+                "geometry": { "type": "MultiPolygon", "coordinates": polygons },
+            }],
+        })
+    }
+}
+
+/// Outline the tiles of `input_path` at `zoom`, or at its highest zoom level
+pub fn coverage(input_path: &str, zoom: Option<i32>) -> Result<Coverage> {
+    let source = open_source(input_path)?;
+    let declared = source.metadata()?.into_iter().find(|(name, _)| name == "scheme").map(|(_, value)| value);
+    let scheme = match declared.as_deref().and_then(Scheme::from_metadata) {
+        Some(scheme) if is_mbtiles(input_path) => scheme,
+        _ => Scheme::Tms,
+    };
+    let zooms = source.zoom_info()?;
+    let info = match zoom {
+        Some(zoom) => zooms.iter().find(|info| info.zoom == zoom),
+        None => zooms.last(),
+    };
+    let Some(info) = info else {
+        return Err(match zoom {
+            Some(zoom) => anyhow!("No tiles at zoom {} in {}", zoom, input_path),
+            None => anyhow!("No tiles in {}", input_path),
+        });
+    };
+    let zoom = info.zoom;
+
+    let mut tiles = HashSet::new();
+    source.for_each_tile_size(&info.range(), &mut |x, y, _| {
+        tiles.insert((x, scheme.to_tms(zoom, y)));
+        Ok(())
+    })?;
+
+    let corner = |(x, y): (i32, i32)| tile_to_lon_lat(x, y, zoom);
+    let polygons = outline(&tiles)
+        .into_iter()
+        .map(|rings| rings.into_iter().map(|ring| ring.into_iter().map(corner).collect()).collect())
+        .collect();
+    Ok(Coverage { zoom, tiles: tiles.len() as u64, polygons })
+}
+
+/// Rings around a set of grid cells (column, row growing north), as
+/// polygons of tile corner coordinates. Exteriors run counterclockwise and
+/// holes clockwise, with cells touching only at a corner kept apart.
+fn outline(cells: &HashSet<(i32, i32)>) -> Vec<Vec<Vec<(i32, i32)>>> {
+    // Every cell side without a neighbour becomes an edge with the cell on its left
+    let mut edges: HashMap<(i32, i32), Vec<(i32, i32)>> = HashMap::new();
+    for &(x, y) in cells {
+        let sides = [
+            ((x, y - 1), (x, y), (x + 1, y)),
+            ((x + 1, y), (x + 1, y), (x + 1, y + 1)),
+            ((x, y + 1), (x + 1, y + 1), (x, y + 1)),
+            ((x - 1, y), (x, y + 1), (x, y)),
+        ];
+        for (neighbour, from, to) in sides {
+            if !cells.contains(&neighbour) {
+                edges.entry(from).or_default().push(to);
+            }
+        }
+    }
+
+    let mut rings = Vec::new();
+    let mut starts: Vec<(i32, i32)> = edges.keys().copied().collect();
+    starts.sort_unstable();
+    for start in starts {
+        while let Some(next) = take_edge(&mut edges, start, None) {
+            let mut ring = vec![start];
+            let (mut previous, mut current) = (start, next);
+            while current != start {
+                ring.push(current);
+                let next = take_edge(&mut edges, current, Some(previous)).expect("cell outlines are closed");
+                (previous, current) = (current, next);
+            }
+            rings.push(simplify(ring));
+        }
+    }
+
+    // Attach each hole to the smallest exterior around the cell on its left
+    let (exteriors, holes): (Vec<_>, Vec<_>) = rings.into_iter().partition(|ring| area(ring) > 0);
+    let mut polygons: Vec<Vec<Vec<(i32, i32)>>> = exteriors.into_iter().map(|ring| vec![ring]).collect();
+    for hole in holes {
+        let (a, b) = (hole[0], hole[1]);
+        let inside = ((a.0 + b.0) as f64 / 2.0 - (b.1 - a.1).signum() as f64 / 2.0,
+            (a.1 + b.1) as f64 / 2.0 + (b.0 - a.0).signum() as f64 / 2.0);
+        let owner = polygons
+            .iter_mut()
+            .filter(|rings| contains(&rings[0], inside))
+            .min_by_key(|rings| area(&rings[0]));
+        if let Some(rings) = owner {
+            rings.push(hole);
+        }
+    }
+
+    for rings in &mut polygons {
+        for ring in rings.iter_mut() {
+            ring.push(ring[0]);
+        }
+    }
+    polygons
+}
+
+/// Remove and return an edge leaving `from`, turning as far left as
+/// possible after arriving from `previous`
+fn take_edge(
+    edges: &mut HashMap<(i32, i32), Vec<(i32, i32)>>,
+    from: (i32, i32),
+    previous: Option<(i32, i32)>,
+) -> Option<(i32, i32)> {
+    let targets = edges.get_mut(&from)?;
+    let index = match previous {
+        Some(previous) if targets.len() > 1 => {
+            let incoming = (from.0 - previous.0, from.1 - previous.1);
+            let left = (-incoming.1, incoming.0);
+            targets.iter().position(|&to| (to.0 - from.0, to.1 - from.1) == left).unwrap_or(0)
+        }
+        _ => 0,
+    };
+    let to = targets.swap_remove(index);
+    if targets.is_empty() {
+        edges.remove(&from);
+    }
+    Some(to)
+}
+
+/// Drop the vertices in the middle of straight runs
+fn simplify(ring: Vec<(i32, i32)>) -> Vec<(i32, i32)> {
+    let n = ring.len();
+    (0..n)
+        .filter(|&i| {
+            let (a, b, c) = (ring[(i + n - 1) % n], ring[i], ring[(i + 1) % n]);
+            (b.0 - a.0) * (c.1 - b.1) != (b.1 - a.1) * (c.0 - b.0)
+        })
+        .map(|i| ring[i])
+        .collect()
+}
+
+/// Twice the signed area of an open ring, positive when counterclockwise
+fn area(ring: &[(i32, i32)]) -> i64 {
+    (0..ring.len())
+        .map(|i| {
+            let (a, b) = (ring[i], ring[(i + 1) % ring.len()]);
+            a.0 as i64 * b.1 as i64 - b.0 as i64 * a.1 as i64
+        })
+        .sum()
+}
+
+/// Even-odd test of a point against an open ring
+fn contains(ring: &[(i32, i32)], (x, y): (f64, f64)) -> bool {
+    let mut inside = false;
+    for i in 0..ring.len() {
+        let (a, b) = (ring[i], ring[(i + 1) % ring.len()]);
+        let (ax, ay, bx, by) = (a.0 as f64, a.1 as f64, b.0 as f64, b.1 as f64);
+        if (ay > y) != (by > y) && x < ax + (y - ay) / (by - ay) * (bx - ax) {
+            inside = !inside;
+        }
+    }
+    inside
+}
@@ -0,0 +1,87 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result, anyhow};
+use rusqlite::{Connection, params};
+
+use crate::mbtiles::{MbtilesSchema, create_normalized_tables, tile_hash};
+use crate::progress::{NoProgress, Progress};
+use crate::timestamps::{create_triggers, timestamps_enabled};
+
+/// Outcome of converting a file to the normalized schema
+#[derive(Debug, Clone, Copy)]
+pub struct DedupeReport {
+    pub tiles: u64,
+    /// Distinct tile blobs left in `images`
+    pub unique: u64,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+impl DedupeReport {
+    pub fn bytes_saved(&self) -> i64 {
+        self.bytes_before as i64 - self.bytes_after as i64
+    }
+}
+
+/// Convert a flat MBTiles file to the normalized map/images schema in place,
+/// storing identical tiles once, then VACUUM to return the space
+pub fn dedupe(path: &str) -> Result<DedupeReport> {
+    dedupe_with_progress(path, &NoProgress)
+}
+
+/// Like [`dedupe`], reporting each converted tile to `progress`
+pub fn dedupe_with_progress(path: &str, progress: &dyn Progress) -> Result<DedupeReport> {
+    if !Path::new(path).exists() {
+        return Err(anyhow!("Input file not found: {}", path));
+    }
+    let bytes_before = fs::metadata(path)?.len();
+    let mut conn = Connection::open(path).context(format!("Failed to open {}", path))?;
+
+    if MbtilesSchema::detect(&conn, "main")? == MbtilesSchema::Normalized {
+        return Err(anyhow!("{} already uses the normalized schema", path));
+    }
+
+    let tiles: i64 = conn.query_row("SELECT COUNT(*) FROM tiles", [], |row| row.get(0))?;
+    progress.start(tiles as u64);
+
+    let tx = conn.transaction()?;
+    tx.execute_batch("ALTER TABLE tiles RENAME TO flat_tiles")?;
+    create_normalized_tables(&tx)?;
+    {
+        let mut select = tx.prepare("SELECT zoom_level, tile_column, tile_row, tile_data FROM flat_tiles")?;
+        let mut insert_image = tx.prepare("INSERT OR IGNORE INTO images (tile_data, tile_id) VALUES (?, ?)")?;
+        let mut insert_map =
+            tx.prepare("INSERT INTO map (zoom_level, tile_column, tile_row, tile_id) VALUES (?, ?, ?, ?)")?;
+
+        let mut rows = select.query([])?;
+        while let Some(row) = rows.next()? {
+            let zoom: i32 = row.get(0)?;
+            let x: i32 = row.get(1)?;
+            let y: i32 = row.get(2)?;
+            let data: Vec<u8> = row.get(3)?;
+            let tile_id = tile_hash(&data);
+            insert_image.execute(params![data, tile_id])?;
+            insert_map.execute(params![zoom, x, y, tile_id])?;
+            progress.advance(1);
+        }
+    }
+    let unique: i64 = tx.query_row("SELECT COUNT(*) FROM images", [], |row| row.get(0))?;
+    tx.execute_batch("DROP TABLE flat_tiles")?;
+    // The timestamp triggers went with the flat table
+    if timestamps_enabled(&tx)? {
+        create_triggers(&tx, MbtilesSchema::Normalized)?;
+    }
+    tx.commit()?;
+
+    conn.execute_batch("VACUUM")?;
+    drop(conn);
+    progress.finish();
+
+    Ok(DedupeReport {
+        tiles: tiles as u64,
+        unique: unique as u64,
+        bytes_before,
+        bytes_after: fs::metadata(path)?.len(),
+    })
+}
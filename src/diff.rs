@@ -0,0 +1,162 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use anyhow::Result;
+use rusqlite::params;
+
+use crate::bbox::TileRange;
+use crate::mbtiles::MbtilesWriter;
+use crate::progress::{NoProgress, Progress};
+use crate::source::{open_source, TileSource};
+
+/// Table of a diff file listing tiles removed from the base tileset
+pub const DELETED_TILES_TABLE: &str = "deleted_tiles";
+
+/// Tile changes between two tilesets at one zoom level
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ZoomDiff {
+    pub zoom: i32,
+    pub added: u64,
+    pub changed: u64,
+    pub removed: u64,
+    pub unchanged: u64,
+}
+
+/// Differences between an old and a new tileset
+#[derive(Debug, Clone, Default)]
+pub struct DiffReport {
+    pub zooms: Vec<ZoomDiff>,
+    /// Metadata names added, changed or removed
+    pub metadata: Vec<String>,
+}
+
+impl DiffReport {
+    pub fn added(&self) -> u64 {
+        self.zooms.iter().map(|z| z.added).sum()
+    }
+
+    pub fn changed(&self) -> u64 {
+        self.zooms.iter().map(|z| z.changed).sum()
+    }
+
+    pub fn removed(&self) -> u64 {
+        self.zooms.iter().map(|z| z.removed).sum()
+    }
+
+    pub fn unchanged(&self) -> u64 {
+        self.zooms.iter().map(|z| z.unchanged).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.metadata.is_empty() && self.added() + self.changed() + self.removed() == 0
+    }
+}
+
+/// Compare two tilesets by tile content hash.
+///
+/// When `output_path` is given, an MBTiles diff is written there holding the
+/// added and changed tiles, the complete metadata of `new_path`, and the
+/// coordinates of removed tiles in a `deleted_tiles` table.
+pub fn diff(old_path: &str, new_path: &str, output_path: Option<&str>) -> Result<DiffReport> {
+    diff_with_progress(old_path, new_path, output_path, &NoProgress)
+}
+
+/// Like [`diff`], reporting each tile read from either side to `progress`
+pub fn diff_with_progress(
+    old_path: &str,
+    new_path: &str,
+    output_path: Option<&str>,
+    progress: &dyn Progress,
+) -> Result<DiffReport> {
+    let old = open_source(old_path)?;
+    let new = open_source(new_path)?;
+
+    let old_metadata: BTreeMap<String, String> = old.metadata()?.into_iter().collect();
+    let new_metadata: BTreeMap<String, String> = new.metadata()?.into_iter().collect();
+    let names: BTreeSet<&String> = old_metadata.keys().chain(new_metadata.keys()).collect();
+    let metadata = names
+        .into_iter()
+        .filter(|&name| old_metadata.get(name) != new_metadata.get(name))
+        .cloned()
+        .collect();
+
+    let zooms: BTreeSet<i32> = old.zoom_levels()?.into_iter().chain(new.zoom_levels()?).collect();
+    progress.start(total_tiles(old.as_ref(), &zooms)? + total_tiles(new.as_ref(), &zooms)?);
+
+    let writer = match output_path {
+        Some(path) => {
+            let writer = MbtilesWriter::create(path)?;
+            writer.connection().execute_batch(&format!(
+                "CREATE TABLE {} (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER);
+                 BEGIN;",
+                DELETED_TILES_TABLE
+            ))?;
+            for (name, value) in &new_metadata {
+                writer.insert_metadata(name, value)?;
+            }
+            Some(writer)
+        }
+        None => None,
+    };
+
+    let mut report = DiffReport { zooms: Vec::new(), metadata };
+    for zoom in zooms {
+        let range = TileRange::full(zoom)?;
+        let mut zoom_diff = ZoomDiff { zoom, ..Default::default() };
+
+        // Hashes of the old tiles at this zoom; entries left over are removed tiles
+        let mut hashes = HashMap::new();
+        old.for_each_tile(&range, &mut |tile| {
+            hashes.insert((tile.x, tile.y), md5::compute(&tile.data).0);
+            progress.advance(1);
+            Ok(())
+        })?;
+
+        new.for_each_tile(&range, &mut |tile| {
+            progress.advance(1);
+            let changed = match hashes.remove(&(tile.x, tile.y)) {
+                Some(hash) if hash == md5::compute(&tile.data).0 => {
+                    zoom_diff.unchanged += 1;
+                    return Ok(());
+                }
+                Some(_) => true,
+                None => false,
+            };
+            if changed {
+                zoom_diff.changed += 1;
+            } else {
+                zoom_diff.added += 1;
+            }
+            if let Some(writer) = &writer {
+                writer.insert_tile(&tile)?;
+            }
+            Ok(())
+        })?;
+
+        zoom_diff.removed = hashes.len() as u64;
+        if let Some(writer) = &writer {
+            let mut insert = writer.connection().prepare_cached(&format!(
+                "INSERT INTO {} (zoom_level, tile_column, tile_row) VALUES (?, ?, ?)",
+                DELETED_TILES_TABLE
+            ))?;
+            for (x, y) in hashes.keys() {
+                insert.execute(params![zoom, x, y])?;
+            }
+        }
+
+        report.zooms.push(zoom_diff);
+    }
+
+    if let Some(writer) = writer {
+        writer.connection().execute_batch("COMMIT")?;
+    }
+    progress.finish();
+    Ok(report)
+}
+
+fn total_tiles(source: &dyn TileSource, zooms: &BTreeSet<i32>) -> Result<u64> {
+    let mut total = 0;
+    for &zoom in zooms {
+        total += source.count_tiles(&TileRange::full(zoom)?)?;
+    }
+    Ok(total)
+}
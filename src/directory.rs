@@ -0,0 +1,235 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, anyhow};
+use serde_json::{Map, Value};
+
+use crate::bbox::TileRange;
+use crate::progress::{NoProgress, Progress};
+use crate::sink::{OutputFormat, TileSink};
+use crate::source::open_source;
+use crate::tile::{detect_format, Scheme, Tile, TileFormat};
+
+/// Writes tiles as `{root}/{z}/{x}/{y}.{ext}` files plus a `metadata.json`
+pub struct DirectoryWriter {
+    root: PathBuf,
+    scheme: Scheme,
+    metadata: Map<String, Value>,
+}
+
+impl DirectoryWriter {
+    pub fn create(root: &str, scheme: Scheme) -> Result<Self> {
+        fs::create_dir_all(root).context(format!("Failed to create output directory: {}", root))?;
+        Ok(DirectoryWriter { root: PathBuf::from(root), scheme, metadata: Map::new() })
+    }
+
+    /// File extension from the `format` metadata, or the tile's own magic bytes
+    fn extension(&self, data: &[u8]) -> &'static str {
+        self.metadata
+            .get("format")
+            .and_then(Value::as_str)
+            .and_then(TileFormat::from_metadata)
+            .unwrap_or_else(|| detect_format(data))
+            .as_str()
+    }
+}
+
+impl TileSink for DirectoryWriter {
+    fn write_metadata(&mut self, name: &str, value: &str) -> Result<()> {
+        self.metadata.insert(name.to_string(), Value::String(value.to_string()));
+        Ok(())
+    }
+
+    fn write_tile(&mut self, tile: &Tile) -> Result<()> {
+        let y = self.scheme.from_tms(tile.zoom, tile.y);
+        let dir = self.root.join(tile.zoom.to_string()).join(tile.x.to_string());
+        fs::create_dir_all(&dir).context(format!("Failed to create directory: {}", dir.display()))?;
+
+        let path = dir.join(format!("{}.{}", y, self.extension(&tile.data)));
+        fs::write(&path, &tile.data).context(format!("Failed to write tile: {}", path.display()))?;
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<()> {
+        let path = self.root.join("metadata.json");
+        let json = serde_json::to_string_pretty(&Value::Object(self.metadata))?;
+        fs::write(&path, json).context(format!("Failed to write {}", path.display()))?;
+        Ok(())
+    }
+}
+
+/// Write every tile of `input_path` within the zoom range into a z/x/y
+/// directory tree at `output_dir`. Returns the number of tiles written.
+pub fn export_dir(
+    input_path: &str,
+    output_dir: &str,
+    scheme: Scheme,
+    min_zoom: Option<i32>,
+    max_zoom: Option<i32>,
+) -> Result<usize> {
+    export_dir_with_progress(input_path, output_dir, scheme, min_zoom, max_zoom, &NoProgress)
+}
+
+/// Like [`export_dir`], reporting progress per tile written
+pub fn export_dir_with_progress(
+    input_path: &str,
+    output_dir: &str,
+    scheme: Scheme,
+    min_zoom: Option<i32>,
+    max_zoom: Option<i32>,
+    progress: &dyn Progress,
+) -> Result<usize> {
+    let source = open_source(input_path)?;
+    let mut sink: Box<dyn TileSink> = Box::new(DirectoryWriter::create(output_dir, scheme)?);
+
+    for (name, value) in source.metadata()? {
+        sink.write_metadata(&name, &value)?;
+    }
+
+    let zooms: Vec<i32> = source.zoom_levels()?
+        .into_iter()
+        .filter(|&zoom| zoom >= min_zoom.unwrap_or(0) && zoom <= max_zoom.unwrap_or(i32::MAX))
+        .collect();
+    let mut total = 0;
+    for &zoom in &zooms {
+        total += source.count_tiles(&TileRange::full(zoom)?)?;
+    }
+    progress.start(total);
+
+    let mut written = 0;
+    for zoom in zooms {
+        source.for_each_tile(&TileRange::full(zoom)?, &mut |tile| {
+            written += 1;
+            progress.advance(1);
+            sink.write_tile(&tile)
+        })?;
+    }
+
+    sink.finish()?;
+    progress.finish();
+    Ok(written)
+}
+
+/// Build a tileset at `output_path` from a `{z}/{x}/{y}.{ext}` directory tree.
+///
+/// The zoom range and bounds are inferred from the files found. Values from
+/// a `metadata.json` in the tree take precedence over inferred ones; an
+/// explicit `format` overrides both, otherwise it falls back to the first
+/// tile's magic bytes.
+pub fn import_dir(
+    input_dir: &str,
+    output_path: &str,
+    format: Option<TileFormat>,
+    scheme: Scheme,
+) -> Result<usize> {
+    let root = PathBuf::from(input_dir);
+    if !root.is_dir() {
+        return Err(anyhow!("Input directory not found: {}", input_dir));
+    }
+
+    let mut sink = OutputFormat::from_path(output_path).create_sink(output_path)?;
+    let mut inferred = InferredMetadata::default();
+    let mut imported = 0;
+
+    for (zoom, zoom_dir) in numbered_entries(&root)? {
+        for (x, x_dir) in numbered_entries(&zoom_dir)? {
+            for (y, path) in numbered_entries(&x_dir)? {
+                if !path.is_file() {
+                    continue;
+                }
+                let data = fs::read(&path).context(format!("Failed to read tile: {}", path.display()))?;
+                let tile = Tile { zoom, x, y: scheme.to_tms(zoom, y), data };
+                inferred.add(&tile);
+                sink.write_tile(&tile)?;
+                imported += 1;
+            }
+        }
+    }
+
+    let mut metadata = BTreeMap::new();
+    let metadata_path = root.join("metadata.json");
+    if metadata_path.is_file() {
+        let text = fs::read_to_string(&metadata_path)?;
+        let json: Value = serde_json::from_str(&text).context(format!("Invalid {}", metadata_path.display()))?;
+        if let Value::Object(members) = json {
+            for (name, value) in members {
+                let value = match value {
+                    Value::String(s) => s,
+                    other => other.to_string(),
+                };
+                metadata.insert(name, value);
+            }
+        }
+    }
+
+    let name = root.file_name().map_or("tiles".into(), |n| n.to_string_lossy().into_owned());
+    metadata.entry("name".to_string()).or_insert(name);
+    inferred.fill(&mut metadata, format);
+
+    for (name, value) in &metadata {
+        sink.write_metadata(name, value)?;
+    }
+    sink.finish()?;
+    Ok(imported)
+}
+
+/// Metadata of an import inferred from the tiles imported: zoom range,
+/// bounds and format
+#[derive(Debug, Default)]
+pub(crate) struct InferredMetadata {
+    /// Format of the first tile
+    format: Option<TileFormat>,
+    min_zoom: Option<i32>,
+    /// Tile extent at the highest zoom seen so far, used to compute bounds
+    extent: Option<TileRange>,
+}
+
+impl InferredMetadata {
+    pub(crate) fn add(&mut self, tile: &Tile) {
+        self.format.get_or_insert_with(|| detect_format(&tile.data));
+        self.min_zoom = Some(self.min_zoom.map_or(tile.zoom, |min| min.min(tile.zoom)));
+        match &mut self.extent {
+            Some(e) if e.zoom > tile.zoom => {}
+            Some(e) if e.zoom == tile.zoom => e.include(tile.x, tile.y),
+            _ => self.extent = Some(TileRange::single(tile.zoom, tile.x, tile.y)),
+        }
+    }
+
+    /// Add the inferred values missing from `metadata`. An explicit `format`
+    /// replaces any given or detected one.
+    pub(crate) fn fill(&self, metadata: &mut BTreeMap<String, String>, format: Option<TileFormat>) {
+        match (format, self.format) {
+            (Some(format), _) => {
+                metadata.insert("format".to_string(), format.to_string());
+            }
+            (None, Some(format)) => {
+                metadata.entry("format".to_string()).or_insert_with(|| format.to_string());
+            }
+            (None, None) => {}
+        }
+        if let (Some(min_zoom), Some(e)) = (self.min_zoom, self.extent) {
+            metadata.entry("minzoom".to_string()).or_insert_with(|| min_zoom.to_string());
+            metadata.entry("maxzoom".to_string()).or_insert_with(|| e.zoom.to_string());
+            metadata.entry("bounds".to_string()).or_insert_with(|| e.bounds().to_metadata());
+        }
+    }
+}
+
+/// Entries of `dir` whose name (ignoring any extension) is a number,
+/// sorted by that number
+fn numbered_entries(dir: &Path) -> Result<Vec<(i32, PathBuf)>> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir).context(format!("Failed to read directory: {}", dir.display()))? {
+        let path = entry?.path();
+        let number = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse().ok());
+        if let Some(number) = number {
+            entries.push((number, path));
+        }
+    }
+    entries.sort();
+    Ok(entries)
+}
@@ -0,0 +1,88 @@
+use anyhow::Result;
+use rusqlite::params;
+
+use crate::bbox::BoundingBox;
+use crate::extract::Area;
+use crate::mbtiles::{MbtilesReader, MbtilesSchema, MbtilesWriter};
+use crate::tile::Scheme;
+
+/// Delete the tiles of the MBTiles file at `path` that intersect `area`, or
+/// with `invert` those that don't, then VACUUM. Only zoom levels within
+/// `min_zoom..=max_zoom` are touched. `bounds`, `minzoom` and `maxzoom` are
+/// updated to match the remaining tiles. Returns the number of tiles deleted.
+pub fn erase(path: &str, area: &Area, invert: bool, min_zoom: Option<i32>, max_zoom: Option<i32>) -> Result<u64> {
+    let writer = MbtilesWriter::open(path)?;
+    let conn = writer.connection();
+    let table = match writer.schema() {
+        MbtilesSchema::Flat => "tiles",
+        MbtilesSchema::Normalized => "map",
+    };
+
+    let scheme = {
+        let reader = MbtilesReader::open(path)?;
+        reader.metadata_value("scheme")?.as_deref().and_then(Scheme::from_metadata).unwrap_or(Scheme::Tms)
+    };
+
+    // The tile ranges of the area at each affected zoom, matched with EXISTS
+    conn.execute_batch(
+        "CREATE TEMP TABLE erase_ranges (zoom_level INTEGER, x_min INTEGER, x_max INTEGER, y_min INTEGER, y_max INTEGER)",
+    )?;
+    let zooms: Vec<i32> = {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT DISTINCT zoom_level FROM {} WHERE zoom_level BETWEEN ? AND ?",
+            table
+        ))?;
+        stmt.query_map(params![min_zoom.unwrap_or(0), max_zoom.unwrap_or(i32::MAX)], |row| row.get(0))?
+            .collect::<Result<_, _>>()?
+    };
+
+    let tx = conn.unchecked_transaction()?;
+    {
+        let mut insert = tx.prepare("INSERT INTO erase_ranges VALUES (?, ?, ?, ?, ?)")?;
+        for &zoom in &zooms {
+            for range in area.tile_ranges(zoom)? {
+                let r = range.to_scheme(scheme);
+                insert.execute(params![r.zoom, r.x_min, r.x_max, r.y_min, r.y_max])?;
+            }
+        }
+    }
+    let deleted = tx.execute(
+        &format!(
+            "DELETE FROM {table} WHERE zoom_level BETWEEN ?1 AND ?2 AND {} EXISTS (
+                 SELECT 1 FROM erase_ranges r WHERE r.zoom_level = {table}.zoom_level
+                 AND {table}.tile_column BETWEEN r.x_min AND r.x_max AND {table}.tile_row BETWEEN r.y_min AND r.y_max)",
+            if invert { "NOT" } else { "" },
+            table = table
+        ),
+        params![min_zoom.unwrap_or(0), max_zoom.unwrap_or(i32::MAX)],
+    )?;
+    tx.execute_batch("DROP TABLE erase_ranges")?;
+    writer.prune_images()?;
+
+    let zooms = writer.zoom_info()?;
+    if let (Some(first), Some(last)) = (zooms.first(), zooms.last()) {
+        // Bounds of the remaining tiles, no wider than before
+        let mut extent = last.range();
+        if scheme == Scheme::Xyz {
+            extent = extent.to_scheme(Scheme::Xyz);
+        }
+        let tile_bounds = extent.bounds();
+        let old_bounds = conn
+            .query_row("SELECT value FROM metadata WHERE name = 'bounds'", [], |row| row.get::<_, String>(0))
+            .ok()
+            .and_then(|value| BoundingBox::from_metadata(&value));
+        let bounds = old_bounds.and_then(|b| b.intersection(&tile_bounds)).unwrap_or(tile_bounds);
+
+        for (name, value) in [
+            ("bounds", bounds.to_metadata()),
+            ("minzoom", first.zoom.to_string()),
+            ("maxzoom", last.zoom.to_string()),
+        ] {
+            writer.set_metadata(name, &value)?;
+        }
+    }
+    tx.commit()?;
+
+    conn.execute_batch("VACUUM")?;
+    Ok(deleted as u64)
+}
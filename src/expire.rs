@@ -0,0 +1,72 @@
+use std::collections::HashSet;
+
+use anyhow::Result;
+use rusqlite::params;
+
+use crate::bbox::TileRange;
+use crate::mbtiles::{MbtilesReader, MbtilesSchema, MbtilesWriter};
+use crate::tile::Scheme;
+use crate::tile_list::TileList;
+
+/// Delete the tiles of `tiles`, as listed in an imposm or osm2pgsql expiry
+/// file, from the MBTiles file at `path` so a renderer can regenerate them.
+/// With `ancestors` the tiles containing them at lower zooms go too, with
+/// `descendants` those they contain at higher zooms. Metadata is left alone
+/// and the file isn't vacuumed, as its tiles are expected back. Returns the
+/// number of tiles deleted.
+pub fn expire(path: &str, tiles: &TileList, ancestors: bool, descendants: bool) -> Result<u64> {
+    let writer = MbtilesWriter::open(path)?;
+    let conn = writer.connection();
+    let table = match writer.schema() {
+        MbtilesSchema::Flat => "tiles",
+        MbtilesSchema::Normalized => "map",
+    };
+
+    let (scheme, max_zoom) = {
+        let reader = MbtilesReader::open(path)?;
+        let scheme = reader.metadata_value("scheme")?.as_deref().and_then(Scheme::from_metadata).unwrap_or(Scheme::Tms);
+        (scheme, reader.zoom_levels()?.last().copied().unwrap_or(0))
+    };
+
+    // Row numbers halve from one zoom to the next whether they count from the
+    // north or the south, so the lineage can be worked out in TMS throughout
+    let mut ranges = HashSet::new();
+    for (zoom, x, y) in tiles.tiles() {
+        ranges.insert(TileRange::single(zoom, x, y));
+        if ancestors {
+            for parent in 0..zoom {
+                let shift = zoom - parent;
+                ranges.insert(TileRange::single(parent, x >> shift, y >> shift));
+            }
+        }
+        if descendants {
+            for child in zoom + 1..=max_zoom {
+                let shift = child - zoom;
+                ranges.insert(TileRange {
+                    zoom: child,
+                    x_min: x << shift,
+                    x_max: ((x + 1) << shift) - 1,
+                    y_min: y << shift,
+                    y_max: ((y + 1) << shift) - 1,
+                });
+            }
+        }
+    }
+
+    // One indexed delete per range, expiry lists being small next to the file
+    let tx = conn.unchecked_transaction()?;
+    let mut deleted = 0;
+    {
+        let mut delete = tx.prepare(&format!(
+            "DELETE FROM {} WHERE zoom_level = ? AND tile_column BETWEEN ? AND ? AND tile_row BETWEEN ? AND ?",
+            table
+        ))?;
+        for range in &ranges {
+            let r = range.to_scheme(scheme);
+            deleted += delete.execute(params![r.zoom, r.x_min, r.x_max, r.y_min, r.y_max])? as u64;
+        }
+    }
+    writer.prune_images()?;
+    tx.commit()?;
+    Ok(deleted)
+}
@@ -0,0 +1,1063 @@
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::path::Path;
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use anyhow::{Context, Result, anyhow};
+use rusqlite::OptionalExtension;
+
+use crate::bbox::{BoundingBox, TileRange};
+use crate::clip;
+use crate::grids;
+use crate::http;
+use crate::interrupt;
+use crate::mbtiles::{MbtilesReader, MbtilesSchema, MbtilesWriter, TRANSACTION_TILES, read_only_uri};
+use crate::mvt::VectorTile;
+use crate::plugin::TilePlugin;
+use crate::progress::{NoProgress, Progress};
+use crate::raster;
+use crate::region::Region;
+use crate::route::Route;
+use crate::sink::{OutputFormat, TileSink};
+use crate::tile::{compress, decompress, detect_compression, detect_format, Scheme, Tile, TileFormat};
+use crate::tile_list::TileList;
+use crate::transform::TileTransform;
+use crate::source::{is_mbtiles, open_source_with};
+use crate::tar::StdinSpool;
+
+/// The geographic area whose tiles are extracted
+#[derive(Debug, Clone)]
+pub enum Area {
+    /// Every tile, for copies filtered only by zoom
+    World,
+    BBox(BoundingBox),
+    /// Union of several bounding boxes (at least one)
+    BBoxes(Vec<BoundingBox>),
+    Region(Region),
+    Route(Route),
+    Tiles(TileList),
+    /// Exactly these TMS tile ranges, at any zoom levels (at least one)
+    Ranges(Vec<TileRange>),
+}
+
+impl Area {
+    /// Tile ranges covering the area at `zoom`, which must be from 0 to
+    /// [`MAX_ZOOM`](crate::coord::MAX_ZOOM)
+    pub fn tile_ranges(&self, zoom: i32) -> Result<Vec<TileRange>> {
+        let world = TileRange::full(zoom)?;
+        Ok(match self {
+            Area::World => vec![world],
+            Area::BBox(bbox) => bbox.tile_ranges(zoom),
+            Area::BBoxes(boxes) => {
+                let ranges: Vec<TileRange> = boxes.iter().flat_map(|bbox| bbox.tile_ranges(zoom)).collect();
+                TileRange::union(&ranges)
+            }
+            Area::Region(region) => region.tile_ranges(zoom),
+            Area::Route(route) => route.tile_ranges(zoom),
+            Area::Tiles(list) => list.tile_ranges(zoom),
+            Area::Ranges(ranges) => {
+                let ranges: Vec<TileRange> = ranges.iter().filter(|range| range.zoom == zoom).copied().collect();
+                TileRange::union(&ranges)
+            }
+        })
+    }
+}
+
+impl Area {
+    /// Bounding box enclosing the whole area
+    pub fn bbox(&self) -> BoundingBox {
+        match self {
+            Area::World => TileRange { zoom: 0, x_min: 0, x_max: 0, y_min: 0, y_max: 0 }.bounds(),
+            Area::BBox(bbox) => *bbox,
+            Area::BBoxes(boxes) => boxes[1..].iter().fold(boxes[0], |a, b| a.union(b)),
+            Area::Region(region) => region.bbox(),
+            Area::Route(route) => route.bbox(),
+            Area::Tiles(list) => list.bbox(),
+            Area::Ranges(ranges) => ranges[1..].iter().fold(ranges[0].bounds(), |a, b| a.union(&b.bounds())),
+        }
+    }
+}
+
+impl From<BoundingBox> for Area {
+    fn from(bbox: BoundingBox) -> Self {
+        Area::BBox(bbox)
+    }
+}
+
+impl From<Vec<BoundingBox>> for Area {
+    fn from(mut boxes: Vec<BoundingBox>) -> Self {
+        match boxes.len() {
+            1 => Area::BBox(boxes.remove(0)),
+            _ => Area::BBoxes(boxes),
+        }
+    }
+}
+
+impl From<Region> for Area {
+    fn from(region: Region) -> Self {
+        Area::Region(region)
+    }
+}
+
+impl From<Route> for Area {
+    fn from(route: Route) -> Self {
+        Area::Route(route)
+    }
+}
+
+impl From<TileList> for Area {
+    fn from(list: TileList) -> Self {
+        Area::Tiles(list)
+    }
+}
+
+/// What to do when the output file already exists
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputMode {
+    /// Refuse to touch an existing output
+    #[default]
+    Create,
+    /// Replace the existing output once the extract succeeds
+    Overwrite,
+    /// Add tiles to an existing MBTiles output, keeping any tile it already has
+    Append,
+}
+
+/// Filters applied when extracting tiles
+#[derive(Debug, Clone)]
+pub struct ExtractOptions {
+    pub area: Area,
+    /// Extra rings of tiles copied around the area at every zoom level
+    pub buffer: i32,
+    /// Clip tiles to the polygon of an [`Area::Region`], instead of copying
+    /// whole tiles that touch it: vector features are cut to it and PNG or
+    /// WebP pixels outside it made transparent. Tiles left empty are skipped.
+    pub clip_to_region: bool,
+    /// Copy every tile at zoom levels below this one, whatever the area
+    pub global_below_zoom: Option<i32>,
+    /// Lowest zoom level to copy (inclusive)
+    pub min_zoom: Option<i32>,
+    /// Highest zoom level to copy (inclusive)
+    pub max_zoom: Option<i32>,
+    /// Output container, guessed from the output path when `None`
+    pub output_format: Option<OutputFormat>,
+    /// Row numbering of an MBTiles input. When `None` it is read from the
+    /// `scheme` metadata key, defaulting to TMS. Output is always TMS.
+    pub input_scheme: Option<Scheme>,
+    /// Number of reader threads. With 1 an MBTiles to MBTiles extract is
+    /// done entirely inside SQLite.
+    pub jobs: usize,
+    /// Store MBTiles output in the normalized map/images schema so
+    /// identical tiles are only stored once
+    pub dedupe: bool,
+    /// Changes made to every copied tile
+    pub transform: TileTransform,
+    /// WASI module run for every tile after `transform` that rewrites or
+    /// drops it, see `copy --transform`
+    pub transform_plugin: Option<String>,
+    /// Handling of an existing output file
+    pub mode: OutputMode,
+    /// Write to `<output>.partial`, keep it if the extract fails and pick up
+    /// where an earlier run with the same options left off. MBTiles only.
+    pub resume: bool,
+    /// Open an MBTiles input as immutable, skipping SQLite's locking. Only
+    /// safe when nothing writes to the input during the extract.
+    pub immutable_input: bool,
+}
+
+impl ExtractOptions {
+    pub fn new(area: impl Into<Area>) -> Self {
+        ExtractOptions {
+            area: area.into(),
+            buffer: 0,
+            clip_to_region: false,
+            global_below_zoom: None,
+            min_zoom: None,
+            max_zoom: None,
+            output_format: None,
+            input_scheme: None,
+            jobs: 1,
+            dedupe: false,
+            transform: TileTransform::default(),
+            transform_plugin: None,
+            mode: OutputMode::Create,
+            resume: false,
+            immutable_input: false,
+        }
+    }
+}
+
+/// Copy every tile of `input_path` matching `options` into a new MBTiles
+/// file at `output_path`. Returns the number of tiles copied.
+pub fn extract(input_path: &str, output_path: &str, options: &ExtractOptions) -> Result<usize> {
+    extract_with_progress(input_path, output_path, options, &NoProgress)
+}
+
+/// Like [`extract`], reporting the number of matching tiles to `progress`
+/// up front and then each batch of tiles as it is copied
+pub fn extract_with_progress(
+    input_path: &str,
+    output_path: &str,
+    options: &ExtractOptions,
+    progress: &dyn Progress,
+) -> Result<usize> {
+    if let (Some(min), Some(max)) = (options.min_zoom, options.max_zoom)
+        && min > max
+    {
+        return Err(anyhow!("minzoom ({}) is greater than maxzoom ({})", min, max));
+    }
+
+    // Readers open the input once per thread, so a stream on stdin is
+    // copied to a file they can all open first
+    let spool = if input_path == "-" { Some(StdinSpool::create()?) } else { None };
+    let input_path = spool.as_ref().map_or(input_path, |spool| spool.path());
+    if !http::is_url(input_path) && !Path::new(input_path).exists() {
+        return Err(anyhow!("Input file not found: {}", input_path));
+    }
+    let output_format = options.output_format.unwrap_or_else(|| OutputFormat::from_path(output_path));
+    // A stream to stdout can't be put in place afterwards, it goes out as written
+    if output_path == "-" {
+        if output_format != OutputFormat::Tar || options.resume {
+            return Err(anyhow!("Only a tar archive can be written to stdout, and it can't be resumed"));
+        }
+        return extract_to_sink(input_path, output_path, output_format, options, progress);
+    }
+    if Path::new(output_path).exists() {
+        match options.mode {
+            OutputMode::Create => {
+                return Err(anyhow!(
+                    "Output file already exists: {} (use --overwrite to replace it or --append to add to it)",
+                    output_path
+                ));
+            }
+            OutputMode::Overwrite => {}
+            OutputMode::Append if output_format != OutputFormat::Mbtiles => {
+                return Err(anyhow!("Can only append to MBTiles output, not {}", output_path));
+            }
+            // The sink keeps all its writes in one transaction, so a failed
+            // append leaves the existing file as it was
+            OutputMode::Append => return extract_to_sink(input_path, output_path, output_format, options, progress),
+        }
+    }
+
+    // Only an MBTiles to MBTiles copy without tile changes can stay inside
+    // SQLite. Deduplicated output needs tile hashes, which a normalized input
+    // already has.
+    let sql_copy = output_format == OutputFormat::Mbtiles
+        && is_mbtiles(input_path)
+        && options.jobs <= 1
+        && options.transform.is_identity()
+        && options.transform_plugin.is_none()
+        && !options.clip_to_region
+        && (!options.dedupe || MbtilesReader::open(input_path)?.schema() == MbtilesSchema::Normalized);
+
+    if options.resume && output_format != OutputFormat::Mbtiles {
+        return Err(anyhow!("Can only resume extracts to MBTiles output, not {}", output_path));
+    }
+
+    // Work on a temporary file next to the output so a failed extract never
+    // leaves a partial output behind. Resumable runs need a predictable name.
+    let temp_path = if options.resume {
+        format!("{}.partial", output_path)
+    } else {
+        format!("{}.{}.tmp", output_path, std::process::id())
+    };
+    tracing::info!(input = input_path, output = output_path, sql_copy, "extract started");
+    let started = Instant::now();
+    let result = if sql_copy {
+        extract_with_sqlite(input_path, &temp_path, options, progress)
+    } else {
+        extract_to_sink(input_path, &temp_path, output_format, options, progress)
+    };
+    match result {
+        Ok(copied) => {
+            tracing::info!(tiles = copied, elapsed_ms = started.elapsed().as_millis() as u64, "extract finished");
+            std::fs::rename(&temp_path, output_path)
+                .context(format!("Failed to move {} to {}", temp_path, output_path))?;
+            Ok(copied)
+        }
+        Err(e) => {
+            if !options.resume {
+                let _ = std::fs::remove_file(&temp_path);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// A writer for a new MBTiles extract, plus the ranges already copied when
+/// resuming from the partial file of an earlier run
+fn create_output(
+    output_path: &str,
+    schema: MbtilesSchema,
+    options: &ExtractOptions,
+) -> Result<(MbtilesWriter, HashSet<TileRange>)> {
+    if !options.resume {
+        return Ok((MbtilesWriter::create_with_schema(output_path, schema)?, HashSet::new()));
+    }
+    let mut writer = if Path::new(output_path).exists() {
+        let mut writer = MbtilesWriter::open(output_path)?;
+        // A batch of an unfinished range may already be in
+        writer.ignore_existing_tiles();
+        writer
+    } else {
+        MbtilesWriter::create_with_schema(output_path, schema)?
+    };
+    let done = writer.track_progress()?;
+    Ok((writer, done))
+}
+
+/// Tiles an extract would copy at one zoom level
+#[derive(Debug, Clone)]
+pub struct ZoomEstimate {
+    pub zoom: i32,
+    pub tiles: u64,
+    /// Matching tiles times the input's average tile size at this zoom
+    pub bytes: u64,
+}
+
+/// Count the tiles `options` selects from `input_path` and estimate their
+/// size without writing anything
+pub fn estimate_extract(input_path: &str, options: &ExtractOptions) -> Result<Vec<ZoomEstimate>> {
+    if !http::is_url(input_path) && input_path != "-" && !Path::new(input_path).exists() {
+        return Err(anyhow!("Input file not found: {}", input_path));
+    }
+    let source = open_source_with(input_path, options.immutable_input)?;
+    let scheme = source_scheme(input_path, &source.metadata()?, options);
+
+    let mut estimates = Vec::new();
+    for info in source.zoom_info()? {
+        if info.zoom < options.min_zoom.unwrap_or(0) || info.zoom > options.max_zoom.unwrap_or(i32::MAX) {
+            continue;
+        }
+        let mut tiles = 0;
+        for range in area_ranges(options, info.zoom)? {
+            tiles += source.count_tiles(&range.to_scheme(scheme))?;
+        }
+        if tiles == 0 {
+            continue;
+        }
+        let bytes = (info.bytes as f64 / info.tiles.max(1) as f64 * tiles as f64).round() as u64;
+        estimates.push(ZoomEstimate { zoom: info.zoom, tiles, bytes });
+    }
+    Ok(estimates)
+}
+
+/// Extract with `INSERT ... SELECT` statements per range on the attached
+/// input, so tile data never passes through Rust
+fn extract_with_sqlite(
+    input_path: &str,
+    output_path: &str,
+    options: &ExtractOptions,
+    progress: &dyn Progress,
+) -> Result<usize> {
+    let schema = if options.dedupe { MbtilesSchema::Normalized } else { MbtilesSchema::Flat };
+    let (writer, done) = create_output(output_path, schema, options)?;
+    let schema = writer.schema();
+    let output_conn = writer.connection();
+
+    // Attach input database
+    output_conn.execute(
+        "ATTACH DATABASE ? AS input",
+        rusqlite::params![read_only_uri(input_path, options.immutable_input)]
+    )?;
+
+    // Copy metadata; rows are renumbered to TMS so a scheme key no longer applies.
+    // A resumed partial file already has a copy.
+    output_conn.execute("DELETE FROM metadata", [])?;
+    output_conn.execute(
+        "INSERT INTO metadata SELECT name, value FROM input.metadata WHERE name != 'scheme'",
+        []
+    )?;
+
+    let declared_scheme: Option<String> = output_conn
+        .query_row("SELECT value FROM input.metadata WHERE name = 'scheme'", [], |row| row.get(0))
+        .optional()?;
+    let scheme = input_scheme(options, declared_scheme.as_deref());
+    let input_tiles = MbtilesSchema::detect(output_conn, "input")
+        .context(format!("Failed to read {}", input_path))?
+        .tiles_source("input");
+
+    // Get the zoom levels present in the database within the requested range
+    let zoom_levels: Vec<i32> = {
+        let mut stmt = output_conn.prepare(&format!(
+            "SELECT DISTINCT zoom_level FROM {}
+             WHERE zoom_level BETWEEN ? AND ? ORDER BY zoom_level",
+            input_tiles
+        ))?;
+        stmt.query_map(
+            rusqlite::params![options.min_zoom.unwrap_or(0), options.max_zoom.unwrap_or(i32::MAX)],
+            |row| row.get(0),
+        )?
+            .collect::<Result<Vec<_>, _>>()?
+    };
+
+    // Extract and copy tiles within the area for each zoom level, committing
+    // every few ranges so the transaction doesn't grow without bound
+    output_conn.execute_batch("BEGIN")?;
+    let mut copied = 0;
+    {
+        let row = match scheme {
+            Scheme::Tms => "tile_row",
+            Scheme::Xyz => "(1 << zoom_level) - 1 - tile_row",
+        };
+        let filter = "zoom_level = ? AND tile_column BETWEEN ? AND ? AND tile_row BETWEEN ? AND ?";
+        // Normalized to normalized copies the referenced images, then the map rows
+        let mut copy_images = match schema {
+            MbtilesSchema::Flat => None,
+            MbtilesSchema::Normalized => Some(output_conn.prepare(&format!(
+                "INSERT OR IGNORE INTO images (tile_data, tile_id)
+                 SELECT i.tile_data, i.tile_id FROM input.map m JOIN input.images i ON i.tile_id = m.tile_id
+                 WHERE {}",
+                filter
+            ))?),
+        };
+        let mut insert = output_conn.prepare(&match schema {
+            MbtilesSchema::Flat => format!(
+                "INSERT INTO tiles SELECT zoom_level, tile_column, {}, tile_data FROM {} WHERE {}",
+                row, input_tiles, filter
+            ),
+            MbtilesSchema::Normalized => format!(
+                "INSERT INTO map (zoom_level, tile_column, tile_row, tile_id)
+                 SELECT zoom_level, tile_column, {}, tile_id FROM input.map WHERE {}",
+                row, filter
+            ),
+        })?;
+        let stored = stored_extents(input_path, options)?;
+        let mut work = Vec::new();
+        for zoom in zoom_levels {
+            work.extend(work_ranges(options, zoom, scheme, &stored)?);
+        }
+        let grid_ranges: Vec<TileRange> = work.iter().map(|range| range.to_scheme(scheme)).collect();
+        work.retain(|range| !done.contains(range));
+
+        let mut count = output_conn.prepare(&format!(
+            "SELECT COUNT(*) FROM {}
+             WHERE zoom_level = ? AND tile_column BETWEEN ? AND ? AND tile_row BETWEEN ? AND ?",
+            input_tiles
+        ))?;
+        let counting = Instant::now();
+        let mut total = 0;
+        for range in &work {
+            interrupt::check()?;
+            let params = rusqlite::params![range.zoom, range.x_min, range.x_max, range.y_min, range.y_max];
+            total += count.query_row(params, |row| row.get::<_, u64>(0))?;
+        }
+        tracing::debug!(tiles = total, ranges = work.len(), elapsed_ms = counting.elapsed().as_millis() as u64, "counted");
+        progress.start(total);
+
+        let mut pending = 0;
+        for ranges in work.chunk_by(|a, b| a.zoom == b.zoom) {
+            let _span = tracing::info_span!("copy_zoom", zoom = ranges[0].zoom).entered();
+            let zoom_started = Instant::now();
+            let mut zoom_tiles = 0;
+            for range in ranges {
+                interrupt::check()?;
+                let params = rusqlite::params![range.zoom, range.x_min, range.x_max, range.y_min, range.y_max];
+                if let Some(copy_images) = &mut copy_images {
+                    copy_images.execute(params)?;
+                }
+                let rows = insert.execute(params)?;
+                copied += rows;
+                zoom_tiles += rows;
+                progress.advance(rows as u64);
+                if options.resume {
+                    writer.record_range(range)?;
+                }
+
+                pending += rows;
+                if pending >= TRANSACTION_TILES {
+                    output_conn.execute_batch("COMMIT; BEGIN")?;
+                    pending = 0;
+                }
+            }
+            tracing::debug!(tiles = zoom_tiles, elapsed_ms = zoom_started.elapsed().as_millis() as u64, "zoom copied");
+        }
+
+        grids::copy_grids(output_conn, "input", &grid_ranges, scheme, false)?;
+    }
+    output_conn.execute_batch("COMMIT")?;
+    progress.finish();
+
+    output_conn.execute("DETACH DATABASE input", [])?;
+    writer.clear_progress()?;
+
+    let extents: Vec<TileRange> = writer.zoom_info()?.iter().map(|z| z.range()).collect();
+    let center = writer.connection()
+        .query_row("SELECT value FROM metadata WHERE name = 'center'", [], |row| row.get::<_, String>(0))
+        .ok();
+    for (name, value) in derived_metadata(&extents, options, center.as_deref()) {
+        writer.set_metadata(&name, &value)?;
+    }
+    let existing = read_metadata(writer.connection())?;
+    let sample: Option<Vec<u8>> = writer.connection()
+        .query_row("SELECT tile_data FROM tiles LIMIT 1", [], |row| row.get(0))
+        .optional()?;
+    for (name, value) in inferred_metadata(&existing, input_path, sample.as_deref().map(detect_format)) {
+        writer.set_metadata(&name, &value)?;
+    }
+
+    Ok(copied)
+}
+
+/// Extract by reading tiles through Rust, for formats SQLite can't handle
+/// directly or when more than one reader thread is requested
+fn extract_to_sink(
+    input_path: &str,
+    output_path: &str,
+    output_format: OutputFormat,
+    options: &ExtractOptions,
+    progress: &dyn Progress,
+) -> Result<usize> {
+    let source = open_source_with(input_path, options.immutable_input)?;
+    // Compiled once, every tile runs in a fresh instance of it
+    let plugin = options.transform_plugin.as_deref().map(TilePlugin::load).transpose()?;
+    let mut existing_metadata = None;
+    let mut done = HashSet::new();
+    let mut sink: Box<dyn TileSink> = match output_format {
+        OutputFormat::Mbtiles if options.mode == OutputMode::Append && Path::new(output_path).exists() => {
+            let mut writer = MbtilesWriter::open(output_path)?;
+            writer.ignore_existing_tiles();
+            existing_metadata = Some(read_metadata(writer.connection())?);
+            Box::new(writer)
+        }
+        OutputFormat::Mbtiles if options.resume => {
+            let schema = if options.dedupe { MbtilesSchema::Normalized } else { MbtilesSchema::Flat };
+            let (writer, completed) = create_output(output_path, schema, options)?;
+            done = completed;
+            Box::new(writer)
+        }
+        OutputFormat::Mbtiles if options.dedupe => {
+            Box::new(MbtilesWriter::create_with_schema(output_path, MbtilesSchema::Normalized)?)
+        }
+        _ => output_format.create_sink(output_path)?,
+    };
+
+    let mut metadata = source.metadata()?;
+    let scheme = source_scheme(input_path, &metadata, options);
+    metadata.retain(|(name, _)| name != "scheme");
+    for (name, value) in &mut metadata {
+        *value = options.transform.apply_metadata(name, value)?;
+    }
+
+    let min_zoom = options.min_zoom.unwrap_or(0);
+    let max_zoom = options.max_zoom.unwrap_or(i32::MAX);
+    let stored = stored_extents(input_path, options)?;
+    let mut work = VecDeque::new();
+    for zoom in source.zoom_levels()? {
+        if zoom < min_zoom || zoom > max_zoom {
+            continue;
+        }
+        work.extend(work_ranges(options, zoom, scheme, &stored)?);
+    }
+    let grid_ranges: Vec<TileRange> = work.iter().map(|range| range.to_scheme(scheme)).collect();
+    work.retain(|range| !done.contains(range));
+    let mut total = 0;
+    for range in &work {
+        total += source.count_tiles(range)?;
+    }
+    progress.start(total);
+    drop(source);
+
+    // Readers pull ranges off a shared queue and send batches of tiles to
+    // this thread, which is the only one touching the sink
+    let work = Mutex::new(work);
+    let jobs = options.jobs.max(1);
+    let (sender, receiver) = mpsc::sync_channel::<Result<Batch>>(jobs * 2);
+    let mut copied = 0;
+    let mut extents: BTreeMap<i32, TileRange> = BTreeMap::new();
+    // Tiles written and time of the first and last write per zoom level
+    let mut zoom_writes: BTreeMap<i32, (u64, Instant, Instant)> = BTreeMap::new();
+    let mut detected = None;
+
+    thread::scope(|scope| -> Result<()> {
+        for _ in 0..jobs {
+            let sender = sender.clone();
+            let (work, plugin) = (&work, plugin.as_ref());
+            scope.spawn(move || {
+                if let Err(e) = read_ranges(input_path, options, plugin, scheme, work, &sender) {
+                    // The writer may have already stopped, nothing left to tell
+                    let _ = sender.send(Err(e));
+                }
+            });
+        }
+        drop(sender);
+
+        for batch in receiver {
+            interrupt::check()?;
+            let (batch, completed) = batch?;
+            let len = batch.len() as u64;
+            for mut tile in batch {
+                tile.y = scheme.to_tms(tile.zoom, tile.y);
+                extents
+                    .entry(tile.zoom)
+                    .and_modify(|e| e.include(tile.x, tile.y))
+                    .or_insert_with(|| TileRange::single(tile.zoom, tile.x, tile.y));
+                detected.get_or_insert_with(|| detect_format(&tile.data));
+                sink.write_tile(&tile)?;
+                copied += 1;
+
+                let now = Instant::now();
+                let writes = zoom_writes.entry(tile.zoom).or_insert((0, now, now));
+                writes.0 += 1;
+                writes.2 = now;
+            }
+            if let Some(range) = completed {
+                sink.complete_range(&range)?;
+            }
+            progress.advance(len);
+        }
+        Ok(())
+    })?;
+    let extents: Vec<TileRange> = extents.into_values().collect();
+    for (zoom, (tiles, first, last)) in zoom_writes {
+        let elapsed_ms = (last - first).as_millis() as u64;
+        tracing::debug!(zoom, tiles, elapsed_ms, "zoom written");
+    }
+
+    let center = metadata.iter().find(|(name, _)| name == "center").map(|(_, value)| value.clone());
+    metadata.extend(derived_metadata(&extents, options, center.as_deref()));
+    metadata.extend(inferred_metadata(&metadata, input_path, detected));
+    if let Some(existing) = &existing_metadata {
+        metadata = appended_metadata(existing, metadata);
+    }
+    for (name, value) in &metadata {
+        sink.write_metadata(name, value)?;
+    }
+
+    sink.finish()?;
+
+    // Interaction grids only exist in MBTiles, copy them once the tiles are in
+    if output_format == OutputFormat::Mbtiles && is_mbtiles(input_path) {
+        let writer = MbtilesWriter::open(output_path)?;
+        let conn = writer.connection();
+        let input_uri = read_only_uri(input_path, options.immutable_input);
+        conn.execute("ATTACH DATABASE ? AS input", rusqlite::params![input_uri])?;
+        let tx = conn.unchecked_transaction()?;
+        grids::copy_grids(&tx, "input", &grid_ranges, scheme, false)?;
+        tx.commit()?;
+        conn.execute("DETACH DATABASE input", [])?;
+    }
+
+    // A resumed run only saw some of the tiles, so derive from the whole file
+    if options.resume && existing_metadata.is_none() {
+        let writer = MbtilesWriter::open(output_path)?;
+        writer.clear_progress()?;
+        let extents: Vec<TileRange> = writer.zoom_info()?.iter().map(|z| z.range()).collect();
+        for (name, value) in derived_metadata(&extents, options, center.as_deref()) {
+            writer.set_metadata(&name, &value)?;
+        }
+    }
+
+    progress.finish();
+    Ok(copied)
+}
+
+fn read_metadata(conn: &rusqlite::Connection) -> Result<Vec<(String, String)>> {
+    let mut stmt = conn.prepare("SELECT name, value FROM metadata")?;
+    let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    Ok(rows.collect::<Result<Vec<_>, _>>()?)
+}
+
+/// Metadata to write when appending to a file that already has `existing`:
+/// new keys are added, the zoom range and bounds widened and everything else
+/// left as it was
+fn appended_metadata(existing: &[(String, String)], new: Vec<(String, String)>) -> Vec<(String, String)> {
+    let mut result = Vec::new();
+    for (name, value) in new {
+        let Some((_, old)) = existing.iter().find(|(n, _)| *n == name) else {
+            result.push((name, value));
+            continue;
+        };
+        let zooms = (old.trim().parse::<i32>(), value.trim().parse::<i32>());
+        let merged = match (name.as_str(), zooms) {
+            ("minzoom", (Ok(a), Ok(b))) => a.min(b).to_string(),
+            ("maxzoom", (Ok(a), Ok(b))) => a.max(b).to_string(),
+            ("bounds", _) => match (BoundingBox::from_metadata(old), BoundingBox::from_metadata(&value)) {
+                (Some(a), Some(b)) => a.union(&b).to_metadata(),
+                _ => continue,
+            },
+            _ => continue,
+        };
+        result.push((name, merged));
+    }
+    result
+}
+
+/// Spec-required `name` and `format` keys missing from `metadata`: the input
+/// file name and the format detected from a copied tile
+fn inferred_metadata(
+    metadata: &[(String, String)],
+    input_path: &str,
+    detected: Option<TileFormat>,
+) -> Vec<(String, String)> {
+    let has = |key: &str| metadata.iter().any(|(name, _)| name == key);
+    let mut inferred = Vec::new();
+    if !has("name") && let Some(stem) = Path::new(input_path).file_stem() {
+        inferred.push(("name".to_string(), stem.to_string_lossy().into_owned()));
+    }
+    if !has("format") && let Some(format) = detected {
+        inferred.push(("format".to_string(), format.to_string()));
+    }
+    inferred
+}
+
+/// Tiles sent from a reader thread to the writer, with the range they
+/// belong to on the last batch of each range
+type Batch = (Vec<Tile>, Option<TileRange>);
+
+/// Tiles per batch sent from a reader thread to the writer
+const BATCH_SIZE: usize = 256;
+/// Approximate number of tiles in each unit of work handed to a reader
+const CHUNK_TILES: i32 = 4096;
+
+/// Encoder quality for lossy raster tiles masked to the region
+const MASK_QUALITY: f32 = 85.0;
+
+/// Reader thread body: copy tiles of queued ranges into batches until the
+/// queue is empty
+fn read_ranges(
+    input_path: &str,
+    options: &ExtractOptions,
+    plugin: Option<&TilePlugin>,
+    scheme: Scheme,
+    work: &Mutex<VecDeque<TileRange>>,
+    sender: &mpsc::SyncSender<Result<Batch>>,
+) -> Result<()> {
+    let source = open_source_with(input_path, options.immutable_input)?;
+    let send = |batch: Batch| sender.send(Ok(batch)).map_err(|_| anyhow!("Writer stopped"));
+
+    loop {
+        interrupt::check()?;
+        let Some(range) = work.lock().expect("work queue poisoned").pop_front() else {
+            return Ok(());
+        };
+        let _span = tracing::trace_span!("read_range", zoom = range.zoom, x_min = range.x_min, y_min = range.y_min).entered();
+        let mut batch = Vec::with_capacity(BATCH_SIZE);
+        source.for_each_tile(&range, &mut |mut tile| {
+            tile.data = options.transform.apply(tile.zoom, tile.data)?;
+            // After `transform`, so JPEG tiles converted to another format can be masked
+            if options.clip_to_region
+                && let Area::Region(region) = &options.area
+            {
+                match clip_tile(region, scheme, &tile)? {
+                    Some(Some(data)) => tile.data = data,
+                    Some(None) => return Ok(()),
+                    None => {}
+                }
+            }
+            if let Some(plugin) = plugin {
+                // Plugins see TMS rows like every other tile consumer
+                tile.y = scheme.to_tms(tile.zoom, tile.y);
+                let data = plugin.apply(&tile)?;
+                tile.y = scheme.from_tms(tile.zoom, tile.y);
+                match data {
+                    Some(data) => tile.data = data,
+                    None => return Ok(()),
+                }
+            }
+            batch.push(tile);
+            if batch.len() == BATCH_SIZE {
+                send((std::mem::replace(&mut batch, Vec::with_capacity(BATCH_SIZE)), None))?;
+            }
+            Ok(())
+        })?;
+        send((batch, Some(range)))?;
+    }
+}
+
+/// The blob of `tile` (in the input's row numbering) clipped to `region`:
+/// vector features cut to the polygon, or raster pixels outside it made
+/// transparent. `None` if the tile lies wholly inside, `Some(None)` if
+/// nothing is left of it.
+fn clip_tile(region: &Region, scheme: Scheme, tile: &Tile) -> Result<Option<Option<Vec<u8>>>> {
+    let area = region.tile_rings(tile.zoom, tile.x, scheme.to_tms(tile.zoom, tile.y));
+    let raw = decompress(&tile.data)?;
+    let format = detect_format(&raw);
+    if format == TileFormat::Pbf {
+        let mut vector = VectorTile::decode(&raw)?;
+        if !vector.clip_to_area(&area)? {
+            return Ok(None);
+        }
+        if vector.layers.is_empty() {
+            return Ok(Some(None));
+        }
+        return Ok(Some(Some(compress(&vector.encode(), detect_compression(&tile.data))?)));
+    }
+
+    if !clip::crosses_square(&area, 0.0, 1.0) {
+        return Ok(if clip::contains(&area, (0.5, 0.5)) { None } else { Some(None) });
+    }
+    if format == TileFormat::Jpg {
+        return Err(anyhow!(
+            "Can't mask JPEG tiles outside the region, they have no transparency ({}/{}/{}); convert them to png or webp",
+            tile.zoom,
+            tile.x,
+            tile.y
+        ));
+    }
+    match raster::mask(&raster::decode(&raw, format)?, &area) {
+        Some(masked) => Ok(Some(Some(raster::encode(&masked, format, MASK_QUALITY, false)?))),
+        None => Ok(Some(None)),
+    }
+}
+
+/// Split a range into bands of whole rows of roughly `CHUNK_TILES` tiles
+fn split_rows(range: &TileRange) -> Vec<TileRange> {
+    let width = range.x_max - range.x_min + 1;
+    let rows = (CHUNK_TILES / width).max(1);
+    (range.y_min..=range.y_max)
+        .step_by(rows as usize)
+        .map(|y_min| TileRange { y_min, y_max: (y_min + rows - 1).min(range.y_max), ..*range })
+        .collect()
+}
+
+/// Ranges in the input's row numbering to queue at `zoom`, split into bands.
+/// A copy of the whole world only covers the `stored` extent of the zoom.
+fn work_ranges(options: &ExtractOptions, zoom: i32, scheme: Scheme, stored: &BTreeMap<i32, TileRange>) -> Result<Vec<TileRange>> {
+    if let Some(extent) = stored.get(&zoom) {
+        return Ok(split_rows(extent));
+    }
+    Ok(area_ranges(options, zoom)?.iter().flat_map(|range| split_rows(&range.to_scheme(scheme))).collect())
+}
+
+/// Extent of the input's tiles at each zoom when copying the whole world,
+/// so high zooms don't queue work for every possible tile
+fn stored_extents(input_path: &str, options: &ExtractOptions) -> Result<BTreeMap<i32, TileRange>> {
+    if !matches!(options.area, Area::World) {
+        return Ok(BTreeMap::new());
+    }
+    let source = open_source_with(input_path, options.immutable_input)?;
+    Ok(source.zoom_info()?.iter().map(|zoom| (zoom.zoom, zoom.range())).collect())
+}
+
+/// Tile ranges to copy at `zoom`: the area plus any buffer, without overlaps
+fn area_ranges(options: &ExtractOptions, zoom: i32) -> Result<Vec<TileRange>> {
+    if options.global_below_zoom.is_some_and(|global| zoom < global) {
+        return Ok(vec![TileRange::full(zoom)?]);
+    }
+    let ranges = options.area.tile_ranges(zoom)?;
+    if options.buffer <= 0 {
+        return Ok(ranges);
+    }
+    let grown: Vec<TileRange> = ranges.iter().flat_map(|range| range.buffered(options.buffer)).collect();
+    Ok(TileRange::union(&grown))
+}
+
+/// Row numbering of the input given its metadata. PMTiles and GeoPackage
+/// rows are well defined, only MBTiles inputs can be XYZ.
+fn source_scheme(input_path: &str, metadata: &[(String, String)], options: &ExtractOptions) -> Scheme {
+    if !is_mbtiles(input_path) {
+        return Scheme::Tms;
+    }
+    let declared = metadata.iter().find(|(name, _)| name == "scheme").map(|(_, value)| value.as_str());
+    input_scheme(options, declared)
+}
+
+/// The scheme of an MBTiles input: explicit option, then metadata, then TMS
+fn input_scheme(options: &ExtractOptions, declared: Option<&str>) -> Scheme {
+    options.input_scheme
+        .or_else(|| declared.and_then(Scheme::from_metadata))
+        .unwrap_or(Scheme::Tms)
+}
+
+/// `bounds`, `center`, `minzoom` and `maxzoom` describing the tiles actually
+/// copied, given the extent of the copied tiles at each zoom level (ascending).
+///
+/// Bounds are the requested area clipped to the edges of the copied tiles at
+/// the highest zoom, or just those edges when a buffer was copied around the
+/// area. Global low zoom tiles widen the bounds to include them while the
+/// center stays on the area. The source center's zoom is kept if still in range.
+fn derived_metadata(
+    extents: &[TileRange],
+    options: &ExtractOptions,
+    source_center: Option<&str>,
+) -> Vec<(String, String)> {
+    let (Some(first), Some(last)) = (extents.first(), extents.last()) else {
+        return Vec::new();
+    };
+
+    let tile_bounds = last.bounds();
+    let area_bounds = options.area.bbox();
+    let bounds = if options.buffer > 0 {
+        tile_bounds
+    } else if area_bounds.crosses_antimeridian() {
+        // The copied extent spans the whole width, only clip latitudes
+        BoundingBox {
+            north: area_bounds.north.min(tile_bounds.north),
+            south: area_bounds.south.max(tile_bounds.south),
+            ..area_bounds
+        }
+    } else {
+        area_bounds.intersection(&tile_bounds).unwrap_or(tile_bounds)
+    };
+
+    let center_zoom = source_center
+        .and_then(|center| center.split(',').nth(2))
+        .and_then(|zoom| zoom.trim().parse::<i32>().ok())
+        .filter(|zoom| (first.zoom..=last.zoom).contains(zoom))
+        .unwrap_or(first.zoom);
+    let round = |v: f64| (v * 1e6).round() / 1e6;
+    let (lon, lat) = bounds.center();
+    let center = format!("{},{},{}", round(lon), round(lat), center_zoom);
+
+    let bounds = match options.global_below_zoom {
+        Some(global) if first.zoom < global => {
+            extents.iter().filter(|extent| extent.zoom < global).fold(bounds, |b, extent| b.union(&extent.bounds()))
+        }
+        _ => bounds,
+    };
+
+    vec![
+        ("bounds".to_string(), bounds.to_metadata()),
+        ("center".to_string(), center),
+        ("minzoom".to_string(), first.zoom.to_string()),
+        ("maxzoom".to_string(), last.zoom.to_string()),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("mbtiles-extract-{}-{}", std::process::id(), name));
+        path.to_string_lossy().into_owned()
+    }
+
+    /// Every tile of zoom levels 0 to 3, each holding its own TMS coordinates,
+    /// stored with XYZ rows and a `scheme` key when `scheme` is XYZ
+    fn fixture(name: &str, scheme: Scheme) -> String {
+        let path = temp_path(name);
+        let _ = std::fs::remove_file(&path);
+        let writer = MbtilesWriter::create(&path).unwrap();
+        writer.insert_metadata("name", "fixture").unwrap();
+        if scheme == Scheme::Xyz {
+            writer.insert_metadata("scheme", "xyz").unwrap();
+        }
+        for zoom in 0..=3 {
+            for x in 0..1 << zoom {
+                for y in 0..1 << zoom {
+                    let data = format!("{}/{}/{}", zoom, x, y).into_bytes();
+                    writer.insert_tile(&Tile { zoom, x, y: scheme.from_tms(zoom, y), data }).unwrap();
+                }
+            }
+        }
+        path
+    }
+
+    /// Extract with the SQLite copy and with reader threads, checking both
+    /// agree, and return the TMS coordinates of the tiles written
+    fn extract_tiles(input: &str, options: &mut ExtractOptions) -> BTreeSet<(i32, i32, i32)> {
+        let mut results = Vec::new();
+        for jobs in [1, 3] {
+            let output = input.replace(".mbtiles", &format!("-output-{}.mbtiles", jobs));
+            let _ = std::fs::remove_file(&output);
+            options.jobs = jobs;
+            let copied = extract(input, &output, options).unwrap();
+
+            let reader = MbtilesReader::open(&output).unwrap();
+            assert_eq!(reader.metadata_value("scheme").unwrap(), None);
+            let mut tiles = BTreeSet::new();
+            for zoom in reader.zoom_levels().unwrap() {
+                reader
+                    .for_each_tile(&TileRange::full(zoom).unwrap(), |tile| {
+                        // Rows were renumbered along with the coordinates
+                        assert_eq!(tile.data, format!("{}/{}/{}", tile.zoom, tile.x, tile.y).into_bytes());
+                        tiles.insert((tile.zoom, tile.x, tile.y));
+                        Ok(())
+                    })
+                    .unwrap();
+            }
+            assert_eq!(copied, tiles.len());
+            drop(reader);
+            std::fs::remove_file(&output).unwrap();
+            results.push(tiles);
+        }
+        assert_eq!(results[0], results[1]);
+        results.remove(0)
+    }
+
+    /// The tiles of inclusive `(zoom, x_min, x_max, y_min, y_max)` ranges
+    fn tiles(ranges: &[(i32, i32, i32, i32, i32)]) -> BTreeSet<(i32, i32, i32)> {
+        let mut tiles = BTreeSet::new();
+        for &(zoom, x_min, x_max, y_min, y_max) in ranges {
+            for x in x_min..=x_max {
+                for y in y_min..=y_max {
+                    tiles.insert((zoom, x, y));
+                }
+            }
+        }
+        tiles
+    }
+
+    fn bbox(west: f64, south: f64, east: f64, north: f64) -> BoundingBox {
+        BoundingBox { north, east, south, west }
+    }
+
+    #[test]
+    fn bbox_selects_the_tiles_it_touches() {
+        let input = fixture("bbox.mbtiles", Scheme::Tms);
+        let mut options = ExtractOptions::new(bbox(-10.0, -10.0, 10.0, 10.0));
+        let expected = tiles(&[(0, 0, 0, 0, 0), (1, 0, 1, 0, 1), (2, 1, 2, 1, 2), (3, 3, 4, 3, 4)]);
+        assert_eq!(extract_tiles(&input, &mut options), expected);
+
+        // Crossing the antimeridian takes the columns at both edges of the grid
+        let mut options = ExtractOptions::new(bbox(170.0, -10.0, -170.0, 10.0));
+        let expected = tiles(&[
+            (0, 0, 0, 0, 0),
+            (1, 0, 1, 0, 1),
+            (2, 0, 0, 1, 2),
+            (2, 3, 3, 1, 2),
+            (3, 0, 0, 3, 4),
+            (3, 7, 7, 3, 4),
+        ]);
+        assert_eq!(extract_tiles(&input, &mut options), expected);
+        std::fs::remove_file(input).unwrap();
+    }
+
+    #[test]
+    fn xyz_rows_are_flipped() {
+        // North of the equator, so TMS rows are in the upper half of the grid
+        let input = fixture("xyz.mbtiles", Scheme::Xyz);
+        let mut options = ExtractOptions::new(bbox(10.0, 10.0, 20.0, 20.0));
+        let expected = tiles(&[(0, 0, 0, 0, 0), (1, 1, 1, 1, 1), (2, 2, 2, 2, 2), (3, 4, 4, 4, 4)]);
+        assert_eq!(extract_tiles(&input, &mut options), expected);
+
+        // An explicit scheme wins over the metadata
+        options.input_scheme = Some(Scheme::Tms);
+        options.min_zoom = Some(2);
+        let output = temp_path("xyz-as-tms.mbtiles");
+        let _ = std::fs::remove_file(&output);
+        extract(&input, &output, &options).unwrap();
+        let reader = MbtilesReader::open(&output).unwrap();
+        assert_eq!(reader.tile(2, 2, 2).unwrap(), Some(b"2/2/1".to_vec()));
+        drop(reader);
+        std::fs::remove_file(output).unwrap();
+        std::fs::remove_file(input).unwrap();
+    }
+
+    #[test]
+    fn zoom_filters() {
+        let input = fixture("zooms.mbtiles", Scheme::Tms);
+        let mut options = ExtractOptions::new(Area::World);
+        options.min_zoom = Some(1);
+        options.max_zoom = Some(2);
+        let expected = tiles(&[(1, 0, 1, 0, 1), (2, 0, 3, 0, 3)]);
+        assert_eq!(extract_tiles(&input, &mut options), expected);
+
+        options.max_zoom = None;
+        options.min_zoom = Some(3);
+        assert_eq!(extract_tiles(&input, &mut options), tiles(&[(3, 0, 7, 0, 7)]));
+
+        options.min_zoom = Some(3);
+        options.max_zoom = Some(2);
+        let output = temp_path("zooms-inverted.mbtiles");
+        let error = extract(&input, &output, &options).unwrap_err();
+        assert_eq!(error.to_string(), "minzoom (3) is greater than maxzoom (2)");
+        assert!(!Path::new(&output).exists());
+        std::fs::remove_file(input).unwrap();
+    }
+}
@@ -0,0 +1,491 @@
+use anyhow::{Result, anyhow};
+
+use crate::mvt::{Feature, GeomType, Layer, Value};
+
+/// A boolean expression over a vector tile feature, selecting features to
+/// keep or drop while copying, such as
+/// `zoom >= 10 && layer == "roads" && properties.class != "service"`.
+///
+/// Names are `zoom`, `layer`, `geometry` (`"Point"`, `"LineString"` or
+/// `"Polygon"`), `id` and `properties.KEY`, or `properties["KEY"]` for keys
+/// that aren't identifiers. A missing id or property is `null`. Literals are
+/// numbers, strings in single or double quotes, `true`, `false`, `null` and
+/// lists `[a, b]` on the right of `in`. Operators are `==` (or `=`), `!=`,
+/// `<`, `<=`, `>`, `>=`, `in`, `!`, `&&`, `||` and parentheses, with `NOT`,
+/// `AND` and `OR` in any case as other spellings. `!` applies to a whole
+/// comparison, so `!properties.rank < 5` is `!(properties.rank < 5)`.
+/// Values of different types are never equal and don't order, so
+/// `properties.rank < 5` is false for features without a numeric rank. Only
+/// `true` counts as true.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    source: String,
+    expr: Expr,
+}
+
+impl Filter {
+    pub fn parse(source: &str) -> Result<Self> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser { tokens, pos: 0, end: source.len(), depth: 0 };
+        let expr = parser.or()?;
+        if let Some((at, token)) = parser.tokens.get(parser.pos) {
+            return Err(anyhow!("Invalid filter at {}: unexpected {}", at + 1, token.describe()));
+        }
+        Ok(Filter { source: source.to_string(), expr })
+    }
+
+    /// The expression as written
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    /// True if `feature` of `layer` in a tile at `zoom` satisfies the expression
+    pub fn matches(&self, zoom: i32, layer: &Layer, feature: &Feature) -> bool {
+        self.expr.eval(&Context { zoom, layer, feature }) == Scalar::Bool(true)
+    }
+}
+
+struct Context<'a> {
+    zoom: i32,
+    layer: &'a Layer,
+    feature: &'a Feature,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Zoom,
+    Layer,
+    Geometry,
+    Id,
+    Property(String),
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    /// Flat lists, so long chains don't nest
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+    Compare(Comparison, Box<Expr>, Box<Expr>),
+    In(Box<Expr>, Vec<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// An evaluated expression, borrowing strings from the expression or tile
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Scalar<'a> {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(&'a str),
+}
+
+impl Expr {
+    fn eval<'a>(&'a self, context: &Context<'a>) -> Scalar<'a> {
+        match self {
+            Expr::Null => Scalar::Null,
+            Expr::Bool(b) => Scalar::Bool(*b),
+            Expr::Number(n) => Scalar::Number(*n),
+            Expr::String(s) => Scalar::String(s),
+            Expr::Zoom => Scalar::Number(context.zoom as f64),
+            Expr::Layer => Scalar::String(&context.layer.name),
+            Expr::Geometry => match context.feature.geom_type {
+                GeomType::Point => Scalar::String("Point"),
+                GeomType::LineString => Scalar::String("LineString"),
+                GeomType::Polygon => Scalar::String("Polygon"),
+                GeomType::Unknown => Scalar::Null,
+            },
+            Expr::Id => context.feature.id.map_or(Scalar::Null, |id| Scalar::Number(id as f64)),
+            Expr::Property(key) => context
+                .layer
+                .properties(context.feature)
+                .find(|(k, _)| k == key)
+                .map_or(Scalar::Null, |(_, value)| match value {
+                    Value::String(s) => Scalar::String(s),
+                    Value::Float(f) => Scalar::Number(*f as f64),
+                    Value::Double(d) => Scalar::Number(*d),
+                    Value::Int(i) | Value::Sint(i) => Scalar::Number(*i as f64),
+                    Value::Uint(u) => Scalar::Number(*u as f64),
+                    Value::Bool(b) => Scalar::Bool(*b),
+                }),
+            Expr::Not(expr) => Scalar::Bool(expr.eval(context) != Scalar::Bool(true)),
+            Expr::Neg(expr) => match expr.eval(context) {
+                Scalar::Number(n) => Scalar::Number(-n),
+                _ => Scalar::Null,
+            },
+            Expr::And(exprs) => Scalar::Bool(exprs.iter().all(|expr| expr.eval(context) == Scalar::Bool(true))),
+            Expr::Or(exprs) => Scalar::Bool(exprs.iter().any(|expr| expr.eval(context) == Scalar::Bool(true))),
+            Expr::Compare(op, a, b) => Scalar::Bool(compare(*op, a.eval(context), b.eval(context))),
+            Expr::In(needle, list) => {
+                let needle = needle.eval(context);
+                Scalar::Bool(list.iter().any(|item| compare(Comparison::Eq, needle, item.eval(context))))
+            }
+        }
+    }
+}
+
+fn compare(op: Comparison, a: Scalar, b: Scalar) -> bool {
+    let ordering = match (a, b) {
+        (Scalar::Number(a), Scalar::Number(b)) => a.partial_cmp(&b),
+        (Scalar::String(a), Scalar::String(b)) => Some(a.cmp(b)),
+        (Scalar::Bool(a), Scalar::Bool(b)) if matches!(op, Comparison::Eq | Comparison::Ne) => Some(a.cmp(&b)),
+        (Scalar::Null, Scalar::Null) if matches!(op, Comparison::Eq | Comparison::Ne) => Some(std::cmp::Ordering::Equal),
+        _ => None,
+    };
+    match (op, ordering) {
+        (Comparison::Ne, None) => true,
+        (_, None) => false,
+        (Comparison::Eq, Some(o)) => o.is_eq(),
+        (Comparison::Ne, Some(o)) => o.is_ne(),
+        (Comparison::Lt, Some(o)) => o.is_lt(),
+        (Comparison::Le, Some(o)) => o.is_le(),
+        (Comparison::Gt, Some(o)) => o.is_gt(),
+        (Comparison::Ge, Some(o)) => o.is_ge(),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    String(String),
+    Ident(String),
+    Symbol(&'static str),
+}
+
+impl Token {
+    fn describe(&self) -> String {
+        match self {
+            Token::Number(n) => format!("number {}", n),
+            Token::String(s) => format!("string {:?}", s),
+            Token::Ident(name) => format!("\"{}\"", name),
+            Token::Symbol(symbol) => format!("\"{}\"", symbol),
+        }
+    }
+}
+
+const SYMBOLS: [&str; 17] = ["&&", "||", "==", "!=", "<=", ">=", "=", "<", ">", "!", "(", ")", "[", "]", ",", ".", "-"];
+
+/// Split `source` into tokens with their byte offsets
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>> {
+    let mut tokens = Vec::new();
+    let mut rest = source;
+    while let Some(c) = rest.chars().next() {
+        let at = source.len() - rest.len();
+        if c.is_whitespace() {
+            rest = &rest[c.len_utf8()..];
+        } else if c.is_ascii_digit() {
+            let len = rest.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(rest.len());
+            let number = rest[..len].parse().map_err(|_| anyhow!("Invalid filter at {}: bad number {}", at + 1, &rest[..len]))?;
+            tokens.push((at, Token::Number(number)));
+            rest = &rest[len..];
+        } else if c == '"' || c == '\'' {
+            let mut text = String::new();
+            let mut chars = rest[1..].char_indices();
+            let end = loop {
+                match chars.next() {
+                    Some((i, q)) if q == c => break i + 2,
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, escaped)) => text.push(escaped),
+                        None => return Err(anyhow!("Invalid filter at {}: unterminated string", at + 1)),
+                    },
+                    Some((_, other)) => text.push(other),
+                    None => return Err(anyhow!("Invalid filter at {}: unterminated string", at + 1)),
+                }
+            };
+            tokens.push((at, Token::String(text)));
+            rest = &rest[end..];
+        } else if c.is_alphabetic() || c == '_' {
+            let len = rest.find(|c: char| !c.is_alphanumeric() && c != '_').unwrap_or(rest.len());
+            let token = match rest[..len].to_ascii_uppercase().as_str() {
+                "AND" => Token::Symbol("&&"),
+                "OR" => Token::Symbol("||"),
+                "NOT" => Token::Symbol("!"),
+                _ => Token::Ident(rest[..len].to_string()),
+            };
+            tokens.push((at, token));
+            rest = &rest[len..];
+        } else if let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(**symbol)) {
+            tokens.push((at, Token::Symbol(symbol)));
+            rest = &rest[symbol.len()..];
+        } else {
+            return Err(anyhow!("Invalid filter at {}: unexpected \"{}\"", at + 1, c));
+        }
+    }
+    Ok(tokens)
+}
+
+/// How deeply parentheses, `!` and `-` may nest, keeping the recursive
+/// parser and evaluation well within the stack
+const MAX_DEPTH: usize = 100;
+
+/// Recursive descent over the tokens, loosest binding first: `||`, `&&`,
+/// `!`, comparisons, then unary minus
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    /// Length of the source, for errors at its end
+    end: usize,
+    /// Parentheses, `!` and `-` currently open
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, token)| token)
+    }
+
+    fn eat(&mut self, symbol: &str) -> bool {
+        let matches = match self.peek() {
+            Some(Token::Symbol(s)) => *s == symbol,
+            Some(Token::Ident(name)) => name == symbol,
+            _ => false,
+        };
+        if matches {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn error(&self, expected: &str) -> anyhow::Error {
+        match self.tokens.get(self.pos) {
+            Some((at, token)) => anyhow!("Invalid filter at {}: expected {}, found {}", at + 1, expected, token.describe()),
+            None => anyhow!("Invalid filter at {}: expected {}", self.end + 1, expected),
+        }
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<()> {
+        if self.eat(symbol) { Ok(()) } else { Err(self.error(&format!("\"{}\"", symbol))) }
+    }
+
+    /// `parse` one level deeper, after the token just eaten opened it
+    fn nested(&mut self, parse: fn(&mut Self) -> Result<Expr>) -> Result<Expr> {
+        if self.depth == MAX_DEPTH {
+            let at = self.tokens[self.pos - 1].0;
+            return Err(anyhow!("Invalid filter at {}: nested more than {} deep", at + 1, MAX_DEPTH));
+        }
+        self.depth += 1;
+        let expr = parse(self);
+        self.depth -= 1;
+        expr
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        let mut exprs = vec![self.and()?];
+        while self.eat("||") {
+            exprs.push(self.and()?);
+        }
+        Ok(if exprs.len() == 1 { exprs.remove(0) } else { Expr::Or(exprs) })
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut exprs = vec![self.not()?];
+        while self.eat("&&") {
+            exprs.push(self.not()?);
+        }
+        Ok(if exprs.len() == 1 { exprs.remove(0) } else { Expr::And(exprs) })
+    }
+
+    /// `!` negates a whole comparison, as `NOT` does in SQL
+    fn not(&mut self) -> Result<Expr> {
+        if self.eat("!") {
+            return Ok(Expr::Not(Box::new(self.nested(Self::not)?)));
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Expr> {
+        let left = self.unary()?;
+        if self.eat("in") {
+            self.expect("[")?;
+            let mut list = Vec::new();
+            while !self.eat("]") {
+                if !list.is_empty() {
+                    self.expect(",")?;
+                }
+                list.push(self.unary()?);
+            }
+            return Ok(Expr::In(Box::new(left), list));
+        }
+        let op = [
+            ("==", Comparison::Eq),
+            ("=", Comparison::Eq),
+            ("!=", Comparison::Ne),
+            ("<=", Comparison::Le),
+            (">=", Comparison::Ge),
+            ("<", Comparison::Lt),
+            (">", Comparison::Gt),
+        ]
+        .into_iter()
+        .find(|(symbol, _)| self.eat(symbol));
+        match op {
+            Some((_, op)) => Ok(Expr::Compare(op, Box::new(left), Box::new(self.unary()?))),
+            None => Ok(left),
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        if self.eat("-") {
+            return Ok(Expr::Neg(Box::new(self.nested(Self::unary)?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr> {
+        if self.eat("(") {
+            let expr = self.nested(Self::or)?;
+            self.expect(")")?;
+            return Ok(expr);
+        }
+        let Some(token) = self.peek().cloned() else {
+            return Err(self.error("a value"));
+        };
+        let expr = match token {
+            Token::Number(n) => Expr::Number(n),
+            Token::String(s) => Expr::String(s),
+            Token::Ident(name) => match name.as_str() {
+                "true" => Expr::Bool(true),
+                "false" => Expr::Bool(false),
+                "null" => Expr::Null,
+                "zoom" => Expr::Zoom,
+                "layer" => Expr::Layer,
+                "geometry" => Expr::Geometry,
+                "id" => Expr::Id,
+                "properties" => {
+                    self.pos += 1;
+                    return self.property();
+                }
+                _ => return Err(self.error("zoom, layer, geometry, id or properties")),
+            },
+            Token::Symbol(_) => return Err(self.error("a value")),
+        };
+        self.pos += 1;
+        Ok(expr)
+    }
+
+    /// The key after `properties`, as `.KEY` or `["KEY"]`
+    fn property(&mut self) -> Result<Expr> {
+        if self.eat(".") {
+            let Some(Token::Ident(key)) = self.peek().cloned() else {
+                return Err(self.error("a property name"));
+            };
+            self.pos += 1;
+            return Ok(Expr::Property(key));
+        }
+        self.expect("[")?;
+        let Some(Token::String(key)) = self.peek().cloned() else {
+            return Err(self.error("a quoted property name"));
+        };
+        self.pos += 1;
+        self.expect("]")?;
+        Ok(Expr::Property(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A polygon feature with id 7 in layer "roads", with properties `class`
+    /// "primary", `rank` 3, `bridge` true and `name:en` "Main"
+    fn matches(filter: &str) -> bool {
+        let layer = Layer {
+            name: "roads".to_string(),
+            version: 2,
+            extent: 4096,
+            keys: ["class", "rank", "bridge", "name:en"].map(str::to_string).to_vec(),
+            values: vec![Value::String("primary".to_string()), Value::Uint(3), Value::Bool(true), Value::String("Main".to_string())],
+            features: Vec::new(),
+        };
+        let feature = Feature { id: Some(7), tags: vec![0, 0, 1, 1, 2, 2, 3, 3], geom_type: GeomType::Polygon, geometry: Vec::new() };
+        Filter::parse(filter).unwrap().matches(12, &layer, &feature)
+    }
+
+    fn error(filter: &str) -> String {
+        Filter::parse(filter).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn precedence() {
+        // && binds tighter than ||
+        assert!(matches("zoom == 1 && id == 1 || layer == 'roads'"));
+        assert!(!matches("zoom == 1 && (id == 1 || layer == 'roads')"));
+        assert!(matches("layer == 'roads' || id == 1 && zoom == 1"));
+        // ! binds tighter than && and applies to the whole comparison
+        assert!(matches("!zoom < 10 && id == 7"));
+        assert!(!matches("!(zoom < 10 || id == 7)"));
+        assert!(matches("NOT zoom < 10 AND id = 7 OR false"));
+        assert!(matches("!!(id == 7)"));
+        assert!(matches("-zoom == -12 && -(-id) == 7"));
+    }
+
+    #[test]
+    fn lists() {
+        assert!(matches("properties.class in ['primary', 'secondary']"));
+        assert!(!matches("properties.class in ['secondary']"));
+        assert!(matches("id in [1, 7] && zoom in [-1, 12]"));
+        assert!(!matches("id in []"));
+        assert!(matches("geometry in ['Polygon']"));
+        assert!(error("id in 7").contains("expected \"[\""));
+    }
+
+    #[test]
+    fn properties() {
+        assert!(matches("properties.class == \"primary\""));
+        assert!(matches("properties[\"name:en\"] == 'Main'"));
+        assert!(matches("properties['rank'] >= 3 && properties.bridge == true"));
+        assert!(matches("properties.missing == null && properties['also missing'] == null"));
+        assert!(error("properties[name]").contains("expected a quoted property name"));
+        assert!(error("properties.'class'").contains("expected a property name"));
+    }
+
+    #[test]
+    fn mixed_types() {
+        // Different types are never equal and don't order
+        assert!(!matches("properties.rank == '3'"));
+        assert!(matches("properties.rank != '3'"));
+        assert!(!matches("properties.class < 5") && !matches("properties.class >= 5"));
+        assert!(!matches("properties.bridge == 1"));
+        assert!(!matches("properties.bridge < true"));
+        assert!(!matches("properties.missing < 1") && !matches("properties.missing >= 1"));
+        assert!(matches("properties.missing != 0"));
+        assert!(!matches("-properties.class == -0") && !matches("properties.class"));
+        assert!(matches("properties.bridge"));
+    }
+
+    #[test]
+    fn error_positions() {
+        assert_eq!(error("zoom >"), "Invalid filter at 7: expected a value");
+        assert_eq!(error("zoom > 5 )"), "Invalid filter at 10: unexpected \")\"");
+        assert_eq!(error("(zoom > 5"), "Invalid filter at 10: expected \")\"");
+        assert_eq!(error("zoom == 'x"), "Invalid filter at 9: unterminated string");
+        assert_eq!(error("zoom # 5"), "Invalid filter at 6: unexpected \"#\"");
+        assert_eq!(error("zoom > 1.2.3"), "Invalid filter at 8: bad number 1.2.3");
+        assert_eq!(error("size > 5"), "Invalid filter at 1: expected zoom, layer, geometry, id or properties, found \"size\"");
+        assert_eq!(error("zoom == ]"), "Invalid filter at 9: expected a value, found \"]\"");
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let nested = |depth: usize| format!("{}id == 7{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(matches(&nested(MAX_DEPTH)));
+        assert_eq!(error(&nested(MAX_DEPTH + 1)), format!("Invalid filter at {}: nested more than 100 deep", MAX_DEPTH + 1));
+        assert!(error(&nested(60_000)).contains("nested more than"));
+        assert!(error(&format!("{}true", "!".repeat(60_000))).contains("nested more than"));
+        assert!(error(&format!("-{}", "-".repeat(60_000))).contains("nested more than"));
+        // Long chains are flat, not nested
+        let chain = vec!["id == 1"; 60_000].join(" || ");
+        assert!(!matches(&chain));
+        assert!(matches(&format!("{} || id == 7", chain)));
+    }
+}
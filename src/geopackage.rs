@@ -0,0 +1,586 @@
+use std::collections::BTreeMap;
+use std::f64::consts::PI;
+use std::path::Path;
+
+use anyhow::{Context, Result, anyhow};
+use rusqlite::{Connection, OpenFlags, OptionalExtension, params};
+
+use crate::bbox::{BoundingBox, TileRange};
+use crate::coord::{MAX_LATITUDE, MAX_ZOOM};
+use crate::info::ZoomInfo;
+use crate::mbtiles::read_only_uri;
+use crate::raster;
+use crate::sink::TileSink;
+use crate::source::{SourceKind, TileSource};
+use crate::tile::{detect_format, Tile, TileFormat};
+
+/// `application_id` of a GeoPackage, "GPKG" in ASCII
+pub(crate) const APPLICATION_ID: i32 = 0x4750_4B47;
+
+/// `user_version` of GeoPackage 1.3
+const USER_VERSION: i32 = 10300;
+
+/// Half the side of the EPSG:3857 square, in meters
+const MERCATOR_EXTENT: f64 = 20_037_508.342_789_244;
+
+/// Radius of the sphere EPSG:3857 projects
+const EARTH_RADIUS: f64 = 6_378_137.0;
+
+/// Tile pyramid table written to new files
+const TILE_TABLE: &str = "tiles";
+
+/// Table keeping the MBTiles metadata, which GeoPackage has no place for, so
+/// a round trip loses none of it
+const METADATA_TABLE: &str = "mbtiles_metadata";
+
+/// Side in pixels of tiles whose pixels can't be counted, like vector tiles
+const DEFAULT_TILE_SIZE: u32 = 256;
+
+const WGS84_WKT: &str = "GEOGCS[\"WGS 84\",DATUM[\"WGS_1984\",SPHEROID[\"WGS 84\",6378137,298.257223563,\
+AUTHORITY[\"EPSG\",\"7030\"]],AUTHORITY[\"EPSG\",\"6326\"]],PRIMEM[\"Greenwich\",0,AUTHORITY[\"EPSG\",\"8901\"]],\
+UNIT[\"degree\",0.0174532925199433,AUTHORITY[\"EPSG\",\"9122\"]],AUTHORITY[\"EPSG\",\"4326\"]]";
+
+const WEB_MERCATOR_WKT: &str = "PROJCS[\"WGS 84 / Pseudo-Mercator\",GEOGCS[\"WGS 84\",DATUM[\"WGS_1984\",\
+SPHEROID[\"WGS 84\",6378137,298.257223563,AUTHORITY[\"EPSG\",\"7030\"]],AUTHORITY[\"EPSG\",\"6326\"]],\
+PRIMEM[\"Greenwich\",0,AUTHORITY[\"EPSG\",\"8901\"]],UNIT[\"degree\",0.0174532925199433,AUTHORITY[\"EPSG\",\"9122\"]],\
+AUTHORITY[\"EPSG\",\"4326\"]],PROJECTION[\"Mercator_1SP\"],PARAMETER[\"central_meridian\",0],\
+PARAMETER[\"scale_factor\",1],PARAMETER[\"false_easting\",0],PARAMETER[\"false_northing\",0],\
+UNIT[\"metre\",1,AUTHORITY[\"EPSG\",\"9001\"]],AXIS[\"X\",EAST],AXIS[\"Y\",NORTH],AUTHORITY[\"EPSG\",\"3857\"]]";
+
+/// Read access to the tile pyramid of an OGC GeoPackage. Only pyramids in
+/// the Web Mercator grid MBTiles uses can be read: EPSG:3857 over the whole
+/// square, with 2^z tiles a side at zoom level z.
+pub struct GeopackageReader {
+    conn: Connection,
+    /// Name of the tile pyramid table, and the same quoted for SQL
+    name: String,
+    table: String,
+}
+
+impl GeopackageReader {
+    /// Open a GeoPackage read-only. A file holding several tile pyramids is
+    /// read from the first by table name.
+    pub fn open(path: &str) -> Result<Self> {
+        if !Path::new(path).exists() {
+            return Err(anyhow!("Input file not found: {}", path));
+        }
+        let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+        let conn = Connection::open_with_flags(read_only_uri(path, false), flags)
+            .context(format!("Failed to open input file: {}", path))?;
+
+        let pyramid = conn
+            .query_row(
+                "SELECT s.table_name, r.organization, r.organization_coordsys_id, s.min_x, s.min_y, s.max_x, s.max_y
+                 FROM gpkg_contents c
+                 JOIN gpkg_tile_matrix_set s ON s.table_name = c.table_name
+                 JOIN gpkg_spatial_ref_sys r ON r.srs_id = s.srs_id
+                 WHERE c.data_type IN ('tiles', 'vector-tiles')
+                 ORDER BY s.table_name LIMIT 1",
+                [],
+                |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, i64>(2)?,
+                        [row.get::<_, f64>(3)?, row.get(4)?, row.get(5)?, row.get(6)?],
+                    ))
+                },
+            )
+            .optional()
+            .map_err(|e| anyhow!("Not a GeoPackage: {}: {}", path, e))?;
+        let Some((table, organization, code, extent)) = pyramid else {
+            return Err(anyhow!("No tile pyramid in GeoPackage {}", path));
+        };
+
+        let mercator = organization.eq_ignore_ascii_case("EPSG")
+            && code == 3857
+            && extent.iter().zip([-1.0, -1.0, 1.0, 1.0]).all(|(value, sign)| (value - sign * MERCATOR_EXTENT).abs() < 1.0);
+        let irregular: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM gpkg_tile_matrix
+             WHERE table_name = ? AND (matrix_width != 1 << zoom_level OR matrix_height != 1 << zoom_level)",
+            params![table],
+            |row| row.get(0),
+        )?;
+        if !mercator || irregular > 0 {
+            return Err(anyhow!(
+                "Only GeoPackage tiles in the Web Mercator (EPSG:3857) grid with 2^z tiles a side can be read: {}",
+                path
+            ));
+        }
+
+        let quoted = format!("\"{}\"", table.replace('"', "\"\""));
+        Ok(GeopackageReader { conn, name: table, table: quoted })
+    }
+
+    /// Metadata kept from an MBTiles file, or what the GeoPackage tables tell
+    fn stored_metadata(&self) -> Result<BTreeMap<String, String>> {
+        let mut metadata = BTreeMap::new();
+        let kept = self
+            .conn
+            .prepare("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?")?
+            .exists(params![METADATA_TABLE])?;
+        if kept {
+            let mut stmt = self.conn.prepare(&format!("SELECT name, value FROM {}", METADATA_TABLE))?;
+            let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+            for row in rows {
+                let (name, value) = row?;
+                metadata.insert(name, value);
+            }
+        }
+
+        let (identifier, description, bbox, srs) = self.conn.query_row(
+            "SELECT c.identifier, c.description, c.min_x, c.min_y, c.max_x, c.max_y, r.organization_coordsys_id
+             FROM gpkg_contents c LEFT JOIN gpkg_spatial_ref_sys r ON r.srs_id = c.srs_id
+             WHERE c.table_name = ?",
+            params![self.name],
+            |row| {
+                let bbox: [Option<f64>; 4] = [row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?];
+                let bbox = match bbox {
+                    [Some(min_x), Some(min_y), Some(max_x), Some(max_y)] => Some([min_x, min_y, max_x, max_y]),
+                    _ => None,
+                };
+                Ok((
+                    row.get::<_, Option<String>>(0)?,
+                    row.get::<_, Option<String>>(1)?,
+                    bbox,
+                    row.get::<_, Option<i64>>(6)?,
+                ))
+            },
+        )?;
+        if let Some(identifier) = identifier {
+            metadata.entry("name".to_string()).or_insert(identifier);
+        }
+        if let Some(description) = description.filter(|d| !d.is_empty()) {
+            metadata.entry("description".to_string()).or_insert(description);
+        }
+        let bounds = match (bbox, srs) {
+            (Some([min_x, min_y, max_x, max_y]), Some(4326)) => {
+                Some(BoundingBox { west: min_x, south: min_y, east: max_x, north: max_y })
+            }
+            (Some([min_x, min_y, max_x, max_y]), Some(3857)) => {
+                let (west, south) = mercator_to_lon_lat(min_x, min_y);
+                let (east, north) = mercator_to_lon_lat(max_x, max_y);
+                Some(BoundingBox { west, south, east, north })
+            }
+            _ => None,
+        };
+        if let Some(bounds) = bounds {
+            metadata.entry("bounds".to_string()).or_insert_with(|| bounds.to_metadata());
+        }
+        Ok(metadata)
+    }
+}
+
+impl TileSource for GeopackageReader {
+    fn kind(&self) -> SourceKind {
+        SourceKind::Geopackage
+    }
+
+    /// Rows are always TMS once read, so a `scheme` key kept from the
+    /// original file is dropped
+    fn metadata(&self) -> Result<Vec<(String, String)>> {
+        let mut metadata = self.stored_metadata()?;
+        metadata.remove("scheme");
+        if let Some(data) = self.sample_tile()? {
+            metadata.entry("format".to_string()).or_insert_with(|| detect_format(&data).to_string());
+        }
+        let zooms = self.zoom_levels()?;
+        if let (Some(min), Some(max)) = (zooms.first(), zooms.last()) {
+            metadata.entry("minzoom".to_string()).or_insert_with(|| min.to_string());
+            metadata.entry("maxzoom".to_string()).or_insert_with(|| max.to_string());
+        }
+        Ok(metadata.into_iter().collect())
+    }
+
+    fn zoom_levels(&self) -> Result<Vec<i32>> {
+        let mut stmt = self.conn.prepare(&format!("SELECT DISTINCT zoom_level FROM {} ORDER BY zoom_level", self.table))?;
+        let zooms = stmt.query_map([], |row| row.get(0))?.collect::<Result<Vec<_>, _>>()?;
+        Ok(zooms)
+    }
+
+    fn tile(&self, zoom: i32, x: i32, y: i32) -> Result<Option<Vec<u8>>> {
+        if !(0..=MAX_ZOOM).contains(&zoom) {
+            return Ok(None);
+        }
+        let data = self
+            .conn
+            .prepare_cached(&format!(
+                "SELECT tile_data FROM {} WHERE zoom_level = ? AND tile_column = ? AND tile_row = ?",
+                self.table
+            ))?
+            .query_row(params![zoom, x, flip(zoom, y)], |row| row.get(0))
+            .optional()?;
+        Ok(data)
+    }
+
+    fn for_each_tile(&self, range: &TileRange, f: &mut dyn FnMut(Tile) -> Result<()>) -> Result<()> {
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT tile_column, tile_row, tile_data FROM {}
+             WHERE zoom_level = ? AND tile_column BETWEEN ? AND ? AND tile_row BETWEEN ? AND ?",
+            self.table
+        ))?;
+        let zoom = range.zoom;
+        let mut rows = stmt.query(params![zoom, range.x_min, range.x_max, flip(zoom, range.y_max), flip(zoom, range.y_min)])?;
+        while let Some(row) = rows.next()? {
+            f(Tile { zoom, x: row.get(0)?, y: flip(zoom, row.get(1)?), data: row.get(2)? })?;
+        }
+        Ok(())
+    }
+
+    fn for_each_tile_size(&self, range: &TileRange, f: &mut dyn FnMut(i32, i32, u64) -> Result<()>) -> Result<()> {
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT tile_column, tile_row, LENGTH(tile_data) FROM {}
+             WHERE zoom_level = ? AND tile_column BETWEEN ? AND ? AND tile_row BETWEEN ? AND ?",
+            self.table
+        ))?;
+        let zoom = range.zoom;
+        let mut rows = stmt.query(params![zoom, range.x_min, range.x_max, flip(zoom, range.y_max), flip(zoom, range.y_min)])?;
+        while let Some(row) = rows.next()? {
+            f(row.get(0)?, flip(zoom, row.get(1)?), row.get::<_, i64>(2)? as u64)?;
+        }
+        Ok(())
+    }
+
+    fn count_tiles(&self, range: &TileRange) -> Result<u64> {
+        let zoom = range.zoom;
+        let count = self.conn.query_row(
+            &format!(
+                "SELECT COUNT(*) FROM {} WHERE zoom_level = ? AND tile_column BETWEEN ? AND ? AND tile_row BETWEEN ? AND ?",
+                self.table
+            ),
+            params![zoom, range.x_min, range.x_max, flip(zoom, range.y_max), flip(zoom, range.y_min)],
+            |row| row.get(0),
+        )?;
+        Ok(count)
+    }
+
+    fn zoom_info(&self) -> Result<Vec<ZoomInfo>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT zoom_level, COUNT(*), SUM(LENGTH(tile_data)),
+                    MIN(tile_column), MAX(tile_column), MIN(tile_row), MAX(tile_row)
+             FROM {} GROUP BY zoom_level ORDER BY zoom_level",
+            self.table
+        ))?;
+        let zooms = stmt
+            .query_map([], |row| {
+                let zoom = row.get(0)?;
+                Ok(ZoomInfo {
+                    zoom,
+                    tiles: row.get(1)?,
+                    bytes: row.get::<_, Option<u64>>(2)?.unwrap_or(0),
+                    x_min: row.get(3)?,
+                    x_max: row.get(4)?,
+                    y_min: flip(zoom, row.get(6)?),
+                    y_max: flip(zoom, row.get(5)?),
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(zooms)
+    }
+
+    fn sample_tile(&self) -> Result<Option<Vec<u8>>> {
+        let data = self
+            .conn
+            .query_row(&format!("SELECT tile_data FROM {} LIMIT 1", self.table), [], |row| row.get(0))
+            .optional()?;
+        Ok(data)
+    }
+}
+
+/// Creates a GeoPackage holding one tile pyramid in the Web Mercator grid.
+/// The tile matrices and contents entry are written by `finish`, from the
+/// zoom levels seen and the metadata.
+pub struct GeopackageWriter {
+    conn: Connection,
+    metadata: Vec<(String, String)>,
+    /// Format and pixel size of the first tile written
+    first_tile: Option<(TileFormat, u32)>,
+    /// Extent of the tiles written per zoom level, TMS
+    extents: BTreeMap<i32, TileRange>,
+}
+
+impl GeopackageWriter {
+    pub fn create(path: &str) -> Result<Self> {
+        let conn = Connection::open(path).context(format!("Failed to create output file: {}", path))?;
+        // Like a new MBTiles file, a half written one is useless anyway
+        conn.pragma_update_and_check(None, "journal_mode", "OFF", |_| Ok(()))?;
+        conn.pragma_update(None, "synchronous", "OFF")?;
+        conn.pragma_update(None, "application_id", APPLICATION_ID)?;
+        conn.pragma_update(None, "user_version", USER_VERSION)?;
+        conn.execute_batch(&format!(
+            "CREATE TABLE gpkg_spatial_ref_sys (srs_name TEXT NOT NULL, srs_id INTEGER PRIMARY KEY,
+                 organization TEXT NOT NULL, organization_coordsys_id INTEGER NOT NULL, definition TEXT NOT NULL,
+                 description TEXT);
+             CREATE TABLE gpkg_contents (table_name TEXT NOT NULL PRIMARY KEY, data_type TEXT NOT NULL,
+                 identifier TEXT UNIQUE, description TEXT DEFAULT '',
+                 last_change DATETIME NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+                 min_x DOUBLE, min_y DOUBLE, max_x DOUBLE, max_y DOUBLE,
+                 srs_id INTEGER REFERENCES gpkg_spatial_ref_sys (srs_id));
+             CREATE TABLE gpkg_tile_matrix_set (table_name TEXT NOT NULL PRIMARY KEY REFERENCES gpkg_contents (table_name),
+                 srs_id INTEGER NOT NULL REFERENCES gpkg_spatial_ref_sys (srs_id),
+                 min_x DOUBLE NOT NULL, min_y DOUBLE NOT NULL, max_x DOUBLE NOT NULL, max_y DOUBLE NOT NULL);
+             CREATE TABLE gpkg_tile_matrix (table_name TEXT NOT NULL REFERENCES gpkg_contents (table_name),
+                 zoom_level INTEGER NOT NULL, matrix_width INTEGER NOT NULL, matrix_height INTEGER NOT NULL,
+                 tile_width INTEGER NOT NULL, tile_height INTEGER NOT NULL,
+                 pixel_x_size DOUBLE NOT NULL, pixel_y_size DOUBLE NOT NULL, PRIMARY KEY (table_name, zoom_level));
+             CREATE TABLE gpkg_extensions (table_name TEXT, column_name TEXT, extension_name TEXT NOT NULL,
+                 definition TEXT NOT NULL, scope TEXT NOT NULL, UNIQUE (table_name, column_name, extension_name));
+             CREATE TABLE {tiles} (id INTEGER PRIMARY KEY AUTOINCREMENT, zoom_level INTEGER NOT NULL,
+                 tile_column INTEGER NOT NULL, tile_row INTEGER NOT NULL, tile_data BLOB NOT NULL,
+                 UNIQUE (zoom_level, tile_column, tile_row));
+             CREATE TABLE {metadata} (name TEXT, value TEXT);",
+            tiles = TILE_TABLE,
+            metadata = METADATA_TABLE
+        ))?;
+        let srs = [
+            ("Undefined cartesian SRS", -1, "NONE", -1, "undefined"),
+            ("Undefined geographic SRS", 0, "NONE", 0, "undefined"),
+            ("WGS 84 geodetic", 4326, "EPSG", 4326, WGS84_WKT),
+            ("WGS 84 / Pseudo-Mercator", 3857, "EPSG", 3857, WEB_MERCATOR_WKT),
+        ];
+        for (name, id, organization, code, definition) in srs {
+            conn.execute(
+                "INSERT INTO gpkg_spatial_ref_sys (srs_name, srs_id, organization, organization_coordsys_id, definition)
+                 VALUES (?, ?, ?, ?, ?)",
+                params![name, id, organization, code, definition],
+            )?;
+        }
+        conn.execute_batch("BEGIN")?;
+
+        Ok(GeopackageWriter { conn, metadata: Vec::new(), first_tile: None, extents: BTreeMap::new() })
+    }
+
+    fn metadata_value(&self, name: &str) -> Option<&str> {
+        self.metadata.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    fn write_tables(&self) -> Result<()> {
+        let format = self
+            .metadata_value("format")
+            .and_then(TileFormat::from_metadata)
+            .or(self.first_tile.map(|(format, _)| format));
+        let tile_size = self.first_tile.map_or(DEFAULT_TILE_SIZE, |(_, size)| size);
+
+        let bounds = self.metadata_value("bounds").and_then(BoundingBox::from_metadata).or_else(|| {
+            let max_zoom = self.extents.values().next_back()?;
+            Some(max_zoom.bounds())
+        });
+        let [min_x, min_y, max_x, max_y] = match bounds {
+            // A box across the antimeridian has no single extent in meters
+            Some(bounds) if !bounds.crosses_antimeridian() => {
+                let (min_x, min_y) = lon_lat_to_mercator(bounds.west, bounds.south);
+                let (max_x, max_y) = lon_lat_to_mercator(bounds.east, bounds.north);
+                [min_x, min_y, max_x, max_y]
+            }
+            _ => [-MERCATOR_EXTENT, -MERCATOR_EXTENT, MERCATOR_EXTENT, MERCATOR_EXTENT],
+        };
+        let data_type = if format == Some(TileFormat::Pbf) { "vector-tiles" } else { "tiles" };
+        let identifier = self.metadata_value("name").filter(|name| !name.is_empty()).unwrap_or(TILE_TABLE);
+        self.conn.execute(
+            "INSERT INTO gpkg_contents (table_name, data_type, identifier, description, min_x, min_y, max_x, max_y, srs_id)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, 3857)",
+            params![
+                TILE_TABLE,
+                data_type,
+                identifier,
+                self.metadata_value("description").unwrap_or(""),
+                min_x,
+                min_y,
+                max_x,
+                max_y
+            ],
+        )?;
+        self.conn.execute(
+            "INSERT INTO gpkg_tile_matrix_set VALUES (?, 3857, ?, ?, ?, ?)",
+            params![TILE_TABLE, -MERCATOR_EXTENT, -MERCATOR_EXTENT, MERCATOR_EXTENT, MERCATOR_EXTENT],
+        )?;
+
+        for &zoom in self.extents.keys() {
+            let side = 1_i64 << zoom;
+            let pixel_size = 2.0 * MERCATOR_EXTENT / (side as f64 * tile_size as f64);
+            self.conn.execute(
+                "INSERT INTO gpkg_tile_matrix VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+                params![TILE_TABLE, zoom, side, side, tile_size, tile_size, pixel_size, pixel_size],
+            )?;
+        }
+
+        let extension = match format {
+            Some(TileFormat::Webp) => Some(("gpkg_webp", "http://www.geopackage.org/spec/#extension_tiles_webp")),
+            Some(TileFormat::Pbf) => Some((
+                "im_vector_tiles_mapbox",
+                "https://gitlab.com/imagemattersllc/ogc-vtp2/-/blob/master/extensions/5-vector-tiles-mapbox.adoc",
+            )),
+            _ => None,
+        };
+        if let Some((name, definition)) = extension {
+            self.conn.execute(
+                "INSERT INTO gpkg_extensions VALUES (?, 'tile_data', ?, ?, 'read-write')",
+                params![TILE_TABLE, name, definition],
+            )?;
+        }
+
+        for (name, value) in &self.metadata {
+            self.conn
+                .execute(&format!("INSERT INTO {} VALUES (?, ?)", METADATA_TABLE), params![name, value])?;
+        }
+        Ok(())
+    }
+}
+
+impl TileSink for GeopackageWriter {
+    fn write_metadata(&mut self, name: &str, value: &str) -> Result<()> {
+        self.metadata.retain(|(n, _)| n != name);
+        self.metadata.push((name.to_string(), value.to_string()));
+        Ok(())
+    }
+
+    fn write_tile(&mut self, tile: &Tile) -> Result<()> {
+        if !(0..=MAX_ZOOM).contains(&tile.zoom) {
+            return Err(anyhow!("Invalid zoom level {}, expected 0 to {}", tile.zoom, MAX_ZOOM));
+        }
+        if self.first_tile.is_none() {
+            let format = detect_format(&tile.data);
+            let size = match format {
+                TileFormat::Pbf => DEFAULT_TILE_SIZE,
+                _ => raster::decode(&tile.data, format).map_or(DEFAULT_TILE_SIZE, |image| image.width()),
+            };
+            self.first_tile = Some((format, size));
+        }
+        self.conn
+            .prepare_cached(&format!(
+                "INSERT INTO {} (zoom_level, tile_column, tile_row, tile_data) VALUES (?, ?, ?, ?)",
+                TILE_TABLE
+            ))?
+            .execute(params![tile.zoom, tile.x, flip(tile.zoom, tile.y), tile.data])?;
+        self.extents
+            .entry(tile.zoom)
+            .and_modify(|extent| extent.include(tile.x, tile.y))
+            .or_insert_with(|| TileRange::single(tile.zoom, tile.x, tile.y));
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<()> {
+        self.write_tables()?;
+        self.conn.execute_batch("COMMIT")?;
+        Ok(())
+    }
+}
+
+/// Row counted from the other edge: TMS to GeoPackage rows, which count
+/// from the top like XYZ, and back
+fn flip(zoom: i32, row: i32) -> i32 {
+    (1 << zoom) - 1 - row
+}
+
+fn lon_lat_to_mercator(lon: f64, lat: f64) -> (f64, f64) {
+    let lat = lat.clamp(-MAX_LATITUDE, MAX_LATITUDE).to_radians();
+    (lon.to_radians() * EARTH_RADIUS, (PI / 4.0 + lat / 2.0).tan().ln() * EARTH_RADIUS)
+}
+
+fn mercator_to_lon_lat(x: f64, y: f64) -> (f64, f64) {
+    ((x / EARTH_RADIUS).to_degrees(), (y / EARTH_RADIUS).sinh().atan().to_degrees())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, RgbaImage};
+
+    use crate::source::is_geopackage;
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("mbtiles-gpkg-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path.to_string_lossy().into_owned()
+    }
+
+    fn png(side: u32, shade: u8) -> Vec<u8> {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(side, side, image::Rgba([shade, 0, 0, 255])));
+        raster::encode(&image, TileFormat::Png, 100.0, true).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = temp_path("round-trip.gpkg");
+        let tiles = [
+            Tile { zoom: 0, x: 0, y: 0, data: png(512, 0) },
+            Tile { zoom: 2, x: 1, y: 0, data: png(512, 1) },
+            Tile { zoom: 2, x: 3, y: 2, data: png(512, 2) },
+        ];
+        let mut writer = Box::new(GeopackageWriter::create(&path).unwrap());
+        writer.write_metadata("name", "Round trip").unwrap();
+        writer.write_metadata("attribution", "someone").unwrap();
+        writer.write_metadata("scheme", "tms").unwrap();
+        for tile in &tiles {
+            writer.write_tile(tile).unwrap();
+        }
+        writer.finish().unwrap();
+        assert!(is_geopackage(&path));
+
+        // One matrix per zoom level, in tiles of the first tile's size, and
+        // rows counted from the top
+        let conn = Connection::open(&path).unwrap();
+        let mut stmt = conn
+            .prepare("SELECT zoom_level, matrix_width, matrix_height, tile_width, tile_height, pixel_x_size FROM gpkg_tile_matrix")
+            .unwrap();
+        let matrices: Vec<(i32, i64, i64, u32, u32, f64)> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(matrices.len(), 2);
+        assert_eq!((matrices[0].0, matrices[0].1, matrices[0].2, matrices[0].3, matrices[0].4), (0, 1, 1, 512, 512));
+        assert_eq!((matrices[1].0, matrices[1].1, matrices[1].2, matrices[1].3), (2, 4, 4, 512));
+        assert!((matrices[1].5 - 2.0 * MERCATOR_EXTENT / (4.0 * 512.0)).abs() < 1e-9);
+        let rows: Vec<(i32, i32, i32)> = conn
+            .prepare("SELECT zoom_level, tile_column, tile_row FROM tiles ORDER BY zoom_level, tile_column")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(rows, [(0, 0, 0), (2, 1, 3), (2, 3, 1)]);
+        drop(stmt);
+        drop(conn);
+
+        let reader = GeopackageReader::open(&path).unwrap();
+        assert_eq!(reader.zoom_levels().unwrap(), [0, 2]);
+        for tile in &tiles {
+            assert_eq!(reader.tile(tile.zoom, tile.x, tile.y).unwrap().as_ref(), Some(&tile.data));
+        }
+        assert_eq!(reader.tile(2, 1, 3).unwrap(), None);
+        assert_eq!(reader.tile(MAX_ZOOM + 1, 0, 0).unwrap(), None);
+        let range = TileRange { zoom: 2, x_min: 0, x_max: 3, y_min: 0, y_max: 1 };
+        let mut read = Vec::new();
+        reader
+            .for_each_tile(&range, &mut |tile| {
+                read.push((tile.x, tile.y));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(read, [(1, 0)]);
+        assert_eq!(reader.count_tiles(&TileRange::full(2).unwrap()).unwrap(), 2);
+        let info = &reader.zoom_info().unwrap()[1];
+        assert_eq!((info.x_min, info.x_max, info.y_min, info.y_max), (1, 3, 0, 2));
+
+        let metadata: BTreeMap<String, String> = reader.metadata().unwrap().into_iter().collect();
+        assert_eq!(metadata["name"], "Round trip");
+        assert_eq!(metadata["attribution"], "someone");
+        assert_eq!(metadata["format"], "png");
+        assert_eq!((metadata["minzoom"].as_str(), metadata["maxzoom"].as_str()), ("0", "2"));
+        assert!(!metadata.contains_key("scheme"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn zoom_levels_past_the_grid_fail() {
+        let path = temp_path("zoom.gpkg");
+        let mut writer = GeopackageWriter::create(&path).unwrap();
+        for zoom in [-1, MAX_ZOOM + 1] {
+            let error = writer.write_tile(&Tile { zoom, x: 0, y: 0, data: png(1, 0) }).unwrap_err().to_string();
+            assert!(error.starts_with("Invalid zoom level"), "{}", error);
+        }
+        drop(writer);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,77 @@
+//! UTFGrid interaction data (`grids` and `grid_data`) of MBTiles files.
+//!
+//! Inputs may store grids as plain tables or, like TileMill, as views over
+//! `grid_key`, `keymap` and `grid_utfgrid`. Output always uses plain tables.
+
+use anyhow::Result;
+use rusqlite::{Connection, params};
+
+use crate::bbox::TileRange;
+use crate::tile::Scheme;
+
+/// True if database `db` has a `grids` table or view
+pub(crate) fn has_grids(conn: &Connection, db: &str) -> Result<bool> {
+    let count: i64 = conn.query_row(
+        &format!("SELECT COUNT(*) FROM {}.sqlite_master WHERE name = 'grids' AND type IN ('table', 'view')", db),
+        [],
+        |row| row.get(0),
+    )?;
+    Ok(count > 0)
+}
+
+/// Create empty `grids` and `grid_data` tables in the main database
+pub(crate) fn create_grid_tables(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS grids (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER, grid BLOB);
+         CREATE UNIQUE INDEX IF NOT EXISTS grid_index ON grids (zoom_level, tile_column, tile_row);
+         CREATE TABLE IF NOT EXISTS grid_data
+             (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER, key_name TEXT, key_json TEXT);
+         CREATE UNIQUE INDEX IF NOT EXISTS grid_data_index
+             ON grid_data (zoom_level, tile_column, tile_row, key_name);",
+    )?;
+    Ok(())
+}
+
+/// Copy the grids inside `ranges` (TMS) from attached database `db`, whose
+/// rows are numbered in `scheme`, into the main database. Existing grids
+/// are kept unless `replace` is set. Returns the number of grids copied.
+pub(crate) fn copy_grids(conn: &Connection, db: &str, ranges: &[TileRange], scheme: Scheme, replace: bool) -> Result<u64> {
+    if !has_grids(conn, db)? {
+        return Ok(0);
+    }
+    create_grid_tables(conn)?;
+
+    let row = match scheme {
+        Scheme::Tms => "tile_row",
+        Scheme::Xyz => "(1 << zoom_level) - 1 - tile_row",
+    };
+    let conflict = if replace { "REPLACE" } else { "IGNORE" };
+    let filter = "WHERE zoom_level = ? AND tile_column BETWEEN ? AND ? AND tile_row BETWEEN ? AND ?";
+    let mut grids = conn.prepare(&format!(
+        "INSERT OR {conflict} INTO grids SELECT zoom_level, tile_column, {row}, grid FROM {db}.grids {filter}"
+    ))?;
+    let has_data = conn.query_row(
+        &format!("SELECT COUNT(*) FROM {}.sqlite_master WHERE name = 'grid_data'", db),
+        [],
+        |r| r.get::<_, i64>(0),
+    )? > 0;
+    let mut data = if has_data {
+        Some(conn.prepare(&format!(
+            "INSERT OR {conflict} INTO grid_data
+             SELECT zoom_level, tile_column, {row}, key_name, key_json FROM {db}.grid_data {filter}"
+        ))?)
+    } else {
+        None
+    };
+
+    let mut copied = 0;
+    for range in ranges {
+        let r = range.to_scheme(scheme);
+        let values = params![r.zoom, r.x_min, r.x_max, r.y_min, r.y_max];
+        copied += grids.execute(values)? as u64;
+        if let Some(data) = &mut data {
+            data.execute(values)?;
+        }
+    }
+    Ok(copied)
+}
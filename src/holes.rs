@@ -0,0 +1,74 @@
+use std::collections::HashSet;
+
+use anyhow::Result;
+
+use crate::bbox::BoundingBox;
+use crate::coord::TileCoord;
+use crate::source::{is_mbtiles, open_source};
+use crate::tile::Scheme;
+
+/// Degrees the `bounds` are shrunk by on each side before looking for
+/// children, since bounds are rounded to 6 decimals and often lie exactly
+/// on tile edges
+const BOUNDS_TOLERANCE: f64 = 1e-6;
+
+/// Tiles missing below existing parents: every child of a tile at one zoom
+/// level that lies within the `bounds` metadata but is absent from the next
+/// zoom level, down to the highest zoom. Without bounds all four children
+/// of a tile are expected. Returned in XYZ numbering, sorted.
+pub fn holes(input_path: &str) -> Result<Vec<TileCoord>> {
+    let source = open_source(input_path)?;
+    let metadata = source.metadata()?;
+    let value = |key: &str| metadata.iter().find(|(name, _)| name == key).map(|(_, value)| value.as_str());
+    let scheme = match value("scheme").and_then(Scheme::from_metadata) {
+        Some(scheme) if is_mbtiles(input_path) => scheme,
+        _ => Scheme::Tms,
+    };
+    let bounds = value("bounds").and_then(expected_bounds);
+
+    let mut missing = Vec::new();
+    let zooms = source.zoom_info()?;
+    let Some(first) = zooms.first() else {
+        return Ok(missing);
+    };
+    // Tiles of each zoom in TMS, checked as children of the zoom above
+    let read = |zoom: i32| -> Result<HashSet<(i32, i32)>> {
+        let mut tiles = HashSet::new();
+        if let Some(info) = zooms.iter().find(|info| info.zoom == zoom) {
+            source.for_each_tile_size(&info.range(), &mut |x, y, _| {
+                tiles.insert((x, scheme.to_tms(zoom, y)));
+                Ok(())
+            })?;
+        }
+        Ok(tiles)
+    };
+
+    let last = zooms.last().map_or(first.zoom, |info| info.zoom);
+    let mut parents = read(first.zoom)?;
+    for zoom in first.zoom + 1..=last {
+        let children = read(zoom)?;
+        let expected = bounds.map(|bounds| bounds.tile_ranges(zoom));
+        for &(x, y) in &parents {
+            for (cx, cy) in [(2 * x, 2 * y), (2 * x + 1, 2 * y), (2 * x, 2 * y + 1), (2 * x + 1, 2 * y + 1)] {
+                let inside = expected.as_ref().is_none_or(|ranges| ranges.iter().any(|r| r.contains(zoom, cx, cy)));
+                if inside && !children.contains(&(cx, cy)) {
+                    missing.push(TileCoord::from_tms(zoom, cx, cy));
+                }
+            }
+        }
+        parents = children;
+    }
+    missing.sort_unstable();
+    Ok(missing)
+}
+
+/// The area of a `bounds` metadata value within which child tiles are
+/// expected, `None` if the value is malformed
+pub(crate) fn expected_bounds(value: &str) -> Option<BoundingBox> {
+    BoundingBox::from_metadata(value).map(|b| BoundingBox {
+        north: b.north - BOUNDS_TOLERANCE,
+        east: b.east - BOUNDS_TOLERANCE,
+        south: b.south + BOUNDS_TOLERANCE,
+        west: b.west + BOUNDS_TOLERANCE,
+    })
+}
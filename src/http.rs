@@ -0,0 +1,172 @@
+use std::io::Read;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow};
+
+/// Time allowed to connect, and to wait for each read or write
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Redirects followed before giving up
+const MAX_REDIRECTS: usize = 5;
+
+/// A response read in full
+#[derive(Debug, Clone)]
+pub(crate) struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    /// Value of the first header called `name`, ignoring case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(field, _)| field.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+}
+
+/// GET `url` with `headers`, following redirects. Servers of `https://`
+/// URLs must present a certificate the system trusts. The body is returned
+/// as sent, still compressed if the server applied a `Content-Encoding`.
+pub(crate) fn get(url: &str, headers: &[(String, String)]) -> Result<HttpResponse> {
+    send(agent(MAX_REDIRECTS), "GET", url, headers, None, None)
+}
+
+/// GET `length` bytes from `offset` of `url` with a range request. A server
+/// that answers with anything but the range fails before its body is read,
+/// so ignoring the range doesn't download the whole file. The range may come
+/// back shorter if the file ends before it.
+pub(crate) fn get_range(url: &str, offset: u64, length: u64) -> Result<Vec<u8>> {
+    if length == 0 {
+        return Ok(Vec::new());
+    }
+    let last = offset.checked_add(length - 1).ok_or_else(|| anyhow!("Invalid range of {}: {}+{}", url, offset, length))?;
+    let range = format!("bytes={}-{}", offset, last);
+    let response = send(agent(MAX_REDIRECTS), "GET", url, &[("Range".to_string(), range)], None, Some(206))?;
+    match response.status {
+        206 => Ok(response.body),
+        200 => Err(anyhow!("{} doesn't support range requests", url)),
+        status => Err(anyhow!("Failed to read {}: HTTP {}", url, status)),
+    }
+}
+
+/// Size in bytes of the file at `url`, from the `Content-Range` of a one
+/// byte range request
+pub(crate) fn content_length(url: &str) -> Result<u64> {
+    let response = send(agent(MAX_REDIRECTS), "GET", url, &[("Range".to_string(), "bytes=0-0".to_string())], None, Some(206))?;
+    match response.status {
+        206 => response
+            .header("Content-Range")
+            .and_then(|range| range.rsplit_once('/'))
+            .and_then(|(_, total)| total.trim().parse().ok())
+            .ok_or_else(|| anyhow!("{} sent no file size in its Content-Range", url)),
+        200 => Err(anyhow!("{} doesn't support range requests", url)),
+        status => Err(anyhow!("Failed to read {}: HTTP {}", url, status)),
+    }
+}
+
+/// Send `body` to `url` with a `method` request, without following redirects
+pub(crate) fn send_body(method: &str, url: &str, headers: &[(String, String)], body: &[u8]) -> Result<HttpResponse> {
+    send(agent(0), method, url, headers, Some(body), None)
+}
+
+/// Shared agent following up to `redirects` redirects, keeping connections
+/// alive between requests
+fn agent(redirects: usize) -> &'static ureq::Agent {
+    static FOLLOWING: OnceLock<ureq::Agent> = OnceLock::new();
+    static DIRECT: OnceLock<ureq::Agent> = OnceLock::new();
+    let build = || {
+        ureq::AgentBuilder::new()
+            .timeout_connect(TIMEOUT)
+            .timeout_read(TIMEOUT)
+            .timeout_write(TIMEOUT)
+            .redirects(redirects as u32)
+            .user_agent(&format!("mbtiles/{}", env!("CARGO_PKG_VERSION")))
+            .build()
+    };
+    if redirects == 0 { DIRECT.get_or_init(build) } else { FOLLOWING.get_or_init(build) }
+}
+
+/// Send one request, reading the body only of responses with status `only`
+/// if given. Error statuses are returned like any other response.
+fn send(
+    agent: &ureq::Agent,
+    method: &str,
+    url: &str,
+    headers: &[(String, String)],
+    body: Option<&[u8]>,
+    only: Option<u16>,
+) -> Result<HttpResponse> {
+    check_url(url)?;
+    let mut request = agent.request(method, url);
+    for (name, value) in headers {
+        request = request.set(name, value);
+    }
+    let result = match body {
+        Some(body) => request.send_bytes(body),
+        None => request.call(),
+    };
+    let response = match result {
+        Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+        Err(ureq::Error::Transport(e)) => return Err(anyhow!("Failed to connect to {}: {}", url, e)),
+    };
+
+    let status = response.status();
+    let headers = response
+        .headers_names()
+        .into_iter()
+        .flat_map(|name| {
+            let values: Vec<String> = response.all(&name).into_iter().map(str::to_string).collect();
+            values.into_iter().map(move |value| (name.clone(), value))
+        })
+        .collect();
+    let mut body = Vec::new();
+    if only.is_none_or(|only| only == status) {
+        response
+            .into_reader()
+            .read_to_end(&mut body)
+            .with_context(|| format!("Failed to read response from {}", url))?;
+    }
+    Ok(HttpResponse { status, headers, body })
+}
+
+/// True if `path` is an `http://` or `https://` URL rather than a file path
+pub(crate) fn is_url(path: &str) -> bool {
+    let lower = path.get(..8).unwrap_or(path).to_ascii_lowercase();
+    lower.starts_with("http://") || lower.starts_with("https://")
+}
+
+/// Fail unless `url` is one [`get`] can fetch
+pub(crate) fn check_url(url: &str) -> Result<()> {
+    split_url(url).map(|_| ())
+}
+
+/// Host (with any port) and path of an `http://` or `https://` URL
+pub(crate) fn split_url(url: &str) -> Result<(&str, &str)> {
+    let scheme_end = url.find("://").ok_or_else(|| anyhow!("Not a URL: {}", url))?;
+    match url[..scheme_end].to_ascii_lowercase().as_str() {
+        "http" | "https" => {}
+        other => return Err(anyhow!("Unsupported URL scheme {}: {}", other, url)),
+    }
+    let rest = &url[scheme_end + 3..];
+    Ok(match rest.find('/') {
+        Some(slash) => (&rest[..slash], &rest[slash..]),
+        None => (rest, "/"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_and_overflowing_ranges_send_no_request() {
+        // Nothing listens on the discard port, any request would fail
+        let url = "http://127.0.0.1:9/tiles.pmtiles";
+        assert_eq!(get_range(url, 0, 0).unwrap(), Vec::<u8>::new());
+        assert_eq!(get_range(url, u64::MAX, 0).unwrap(), Vec::<u8>::new());
+        let error = get_range(url, u64::MAX, 2).unwrap_err().to_string();
+        assert!(error.starts_with("Invalid range"), "{}", error);
+        assert!(get_range(url, 0, 1).is_err());
+    }
+}
@@ -0,0 +1,309 @@
+//! A read-only SQLite VFS reading database files from `http(s)://` URLs
+//! with range requests, so MBTiles files in object storage can be queried
+//! without downloading them.
+//!
+//! Files are read in blocks of [`BLOCK_SIZE`] bytes, each connection
+//! keeping the last [`CACHED_BLOCKS`] of them. The file must not change
+//! while it is open: files are opened immutable, without locks, journals
+//! or WAL.
+
+use std::collections::{HashMap, VecDeque};
+use std::ffi::{CStr, c_char, c_int, c_void};
+use std::ptr;
+use std::sync::Once;
+
+use rusqlite::ffi;
+
+use crate::http;
+
+/// Name the VFS is registered under, for `?vfs=` in SQLite URIs
+pub(crate) const VFS_NAME: &str = "mbtiles-http";
+
+/// Bytes fetched per request at least, a few SQLite pages
+const BLOCK_SIZE: u64 = 64 * 1024;
+
+/// Blocks each open file keeps, 16 MiB
+const CACHED_BLOCKS: usize = 256;
+
+/// Register the VFS with SQLite, once per process
+pub(crate) fn register() {
+    static REGISTER: Once = Once::new();
+    REGISTER.call_once(|| unsafe {
+        let default = ffi::sqlite3_vfs_find(ptr::null());
+        assert!(!default.is_null(), "SQLite has no default VFS");
+        let vfs = Box::new(ffi::sqlite3_vfs {
+            iVersion: 2,
+            szOsFile: std::mem::size_of::<RemoteFile>() as c_int,
+            mxPathname: (*default).mxPathname.max(4096),
+            pNext: ptr::null_mut(),
+            zName: c"mbtiles-http".as_ptr(),
+            pAppData: default.cast(),
+            xOpen: Some(open),
+            xDelete: Some(delete),
+            xAccess: Some(access),
+            xFullPathname: Some(full_pathname),
+            xDlOpen: None,
+            xDlError: None,
+            xDlSym: None,
+            xDlClose: None,
+            xRandomness: Some(randomness),
+            xSleep: Some(sleep),
+            xCurrentTime: Some(current_time),
+            xGetLastError: Some(last_error),
+            xCurrentTimeInt64: Some(current_time_int64),
+            xSetSystemCall: None,
+            xGetSystemCall: None,
+            xNextSystemCall: None,
+        });
+        let rc = ffi::sqlite3_vfs_register(Box::into_raw(vfs), 0);
+        assert_eq!(rc, ffi::SQLITE_OK, "Failed to register the {} VFS", VFS_NAME);
+    });
+}
+
+/// An open remote file: SQLite's file header followed by our state
+#[repr(C)]
+struct RemoteFile {
+    base: ffi::sqlite3_file,
+    remote: *mut Remote,
+}
+
+struct Remote {
+    url: String,
+    size: u64,
+    blocks: HashMap<u64, Vec<u8>>,
+    /// Cached block numbers, oldest first
+    order: VecDeque<u64>,
+}
+
+impl Remote {
+    /// Copy `buf.len()` bytes from `offset` into `buf`, fetching the blocks
+    /// not cached with one range request. Returns the bytes copied, fewer
+    /// at the end of the file.
+    fn read(&mut self, buf: &mut [u8], offset: u64) -> anyhow::Result<usize> {
+        if offset >= self.size {
+            return Ok(0);
+        }
+        let end = (offset + buf.len() as u64).min(self.size);
+        let (first, last) = (offset / BLOCK_SIZE, (end - 1) / BLOCK_SIZE);
+        let missing: Vec<u64> = (first..=last).filter(|block| !self.blocks.contains_key(block)).collect();
+        if let (Some(&from), Some(&to)) = (missing.first(), missing.last()) {
+            let start = from * BLOCK_SIZE;
+            let length = ((to + 1) * BLOCK_SIZE).min(self.size) - start;
+            let data = http::get_range(&self.url, start, length)?;
+            if (data.len() as u64) < length {
+                return Err(anyhow::anyhow!("{} ended early, it may have changed while open", self.url));
+            }
+            for (i, chunk) in data.chunks(BLOCK_SIZE as usize).enumerate() {
+                self.insert(from + i as u64, chunk.to_vec());
+            }
+        }
+
+        let mut copied = 0;
+        while offset + (copied as u64) < end {
+            let position = offset + copied as u64;
+            let block = &self.blocks[&(position / BLOCK_SIZE)];
+            let within = (position % BLOCK_SIZE) as usize;
+            let n = (block.len() - within).min((end - position) as usize);
+            buf[copied..copied + n].copy_from_slice(&block[within..within + n]);
+            copied += n;
+        }
+        Ok(copied)
+    }
+
+    fn insert(&mut self, block: u64, data: Vec<u8>) {
+        if self.blocks.insert(block, data).is_none() {
+            self.order.push_back(block);
+        }
+        while self.order.len() > CACHED_BLOCKS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.blocks.remove(&oldest);
+            }
+        }
+    }
+}
+
+static IO_METHODS: ffi::sqlite3_io_methods = ffi::sqlite3_io_methods {
+    iVersion: 1,
+    xClose: Some(close),
+    xRead: Some(read),
+    xWrite: Some(write),
+    xTruncate: Some(truncate),
+    xSync: Some(sync),
+    xFileSize: Some(file_size),
+    xLock: Some(lock),
+    xUnlock: Some(lock),
+    xCheckReservedLock: Some(check_reserved_lock),
+    xFileControl: Some(file_control),
+    xSectorSize: Some(sector_size),
+    xDeviceCharacteristics: Some(device_characteristics),
+    xShmMap: None,
+    xShmLock: None,
+    xShmBarrier: None,
+    xShmUnmap: None,
+    xFetch: None,
+    xUnfetch: None,
+};
+
+unsafe fn default_vfs(vfs: *mut ffi::sqlite3_vfs) -> *mut ffi::sqlite3_vfs {
+    unsafe { (*vfs).pAppData.cast() }
+}
+
+unsafe extern "C" fn open(
+    _vfs: *mut ffi::sqlite3_vfs,
+    name: ffi::sqlite3_filename,
+    file: *mut ffi::sqlite3_file,
+    flags: c_int,
+    out_flags: *mut c_int,
+) -> c_int {
+    unsafe {
+        let file = file.cast::<RemoteFile>();
+        (*file).base.pMethods = ptr::null();
+        // Only the database itself, read-only: immutable files have no journal
+        if name.is_null() || flags & ffi::SQLITE_OPEN_MAIN_DB == 0 || flags & ffi::SQLITE_OPEN_READWRITE != 0 {
+            return ffi::SQLITE_CANTOPEN;
+        }
+        let url = CStr::from_ptr(name).to_string_lossy().into_owned();
+        let size = match http::content_length(&url) {
+            Ok(size) => size,
+            Err(e) => {
+                tracing::debug!(url = url.as_str(), error = %e, "failed to open remote database");
+                return ffi::SQLITE_CANTOPEN;
+            }
+        };
+        let remote = Remote { url, size, blocks: HashMap::new(), order: VecDeque::new() };
+        (*file).remote = Box::into_raw(Box::new(remote));
+        (*file).base.pMethods = &IO_METHODS;
+        if !out_flags.is_null() {
+            *out_flags = ffi::SQLITE_OPEN_READONLY;
+        }
+        ffi::SQLITE_OK
+    }
+}
+
+unsafe extern "C" fn delete(_vfs: *mut ffi::sqlite3_vfs, _name: *const c_char, _sync_dir: c_int) -> c_int {
+    ffi::SQLITE_READONLY
+}
+
+unsafe extern "C" fn access(_vfs: *mut ffi::sqlite3_vfs, _name: *const c_char, _flags: c_int, out: *mut c_int) -> c_int {
+    // No journal, WAL or other side files exist next to a remote database
+    unsafe { *out = 0 };
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn full_pathname(_vfs: *mut ffi::sqlite3_vfs, name: *const c_char, size: c_int, out: *mut c_char) -> c_int {
+    unsafe {
+        let name = CStr::from_ptr(name).to_bytes_with_nul();
+        if name.len() > size as usize {
+            return ffi::SQLITE_CANTOPEN;
+        }
+        ptr::copy_nonoverlapping(name.as_ptr().cast(), out, name.len());
+    }
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn randomness(vfs: *mut ffi::sqlite3_vfs, size: c_int, out: *mut c_char) -> c_int {
+    unsafe {
+        let default = default_vfs(vfs);
+        (*default).xRandomness.map_or(0, |f| f(default, size, out))
+    }
+}
+
+unsafe extern "C" fn sleep(vfs: *mut ffi::sqlite3_vfs, microseconds: c_int) -> c_int {
+    unsafe {
+        let default = default_vfs(vfs);
+        (*default).xSleep.map_or(0, |f| f(default, microseconds))
+    }
+}
+
+unsafe extern "C" fn current_time(vfs: *mut ffi::sqlite3_vfs, out: *mut f64) -> c_int {
+    unsafe {
+        let default = default_vfs(vfs);
+        (*default).xCurrentTime.map_or(ffi::SQLITE_ERROR, |f| f(default, out))
+    }
+}
+
+unsafe extern "C" fn current_time_int64(vfs: *mut ffi::sqlite3_vfs, out: *mut ffi::sqlite3_int64) -> c_int {
+    unsafe {
+        let default = default_vfs(vfs);
+        match (*default).xCurrentTimeInt64 {
+            Some(f) if (*default).iVersion >= 2 => f(default, out),
+            _ => ffi::SQLITE_ERROR,
+        }
+    }
+}
+
+unsafe extern "C" fn last_error(vfs: *mut ffi::sqlite3_vfs, size: c_int, out: *mut c_char) -> c_int {
+    unsafe {
+        let default = default_vfs(vfs);
+        (*default).xGetLastError.map_or(0, |f| f(default, size, out))
+    }
+}
+
+unsafe extern "C" fn close(file: *mut ffi::sqlite3_file) -> c_int {
+    unsafe {
+        let file = file.cast::<RemoteFile>();
+        if !(*file).remote.is_null() {
+            drop(Box::from_raw((*file).remote));
+            (*file).remote = ptr::null_mut();
+        }
+    }
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn read(file: *mut ffi::sqlite3_file, buf: *mut c_void, amount: c_int, offset: ffi::sqlite3_int64) -> c_int {
+    unsafe {
+        let remote = &mut *(*file.cast::<RemoteFile>()).remote;
+        let buf = std::slice::from_raw_parts_mut(buf.cast::<u8>(), amount as usize);
+        match remote.read(buf, offset as u64) {
+            Ok(n) if n == buf.len() => ffi::SQLITE_OK,
+            Ok(n) => {
+                // SQLite expects the rest of a short read zeroed
+                buf[n..].fill(0);
+                ffi::SQLITE_IOERR_SHORT_READ
+            }
+            Err(e) => {
+                tracing::warn!(url = remote.url.as_str(), offset, error = %e, "remote database read failed");
+                ffi::SQLITE_IOERR_READ
+            }
+        }
+    }
+}
+
+unsafe extern "C" fn write(_file: *mut ffi::sqlite3_file, _buf: *const c_void, _amount: c_int, _offset: ffi::sqlite3_int64) -> c_int {
+    ffi::SQLITE_READONLY
+}
+
+unsafe extern "C" fn truncate(_file: *mut ffi::sqlite3_file, _size: ffi::sqlite3_int64) -> c_int {
+    ffi::SQLITE_READONLY
+}
+
+unsafe extern "C" fn sync(_file: *mut ffi::sqlite3_file, _flags: c_int) -> c_int {
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn file_size(file: *mut ffi::sqlite3_file, out: *mut ffi::sqlite3_int64) -> c_int {
+    unsafe { *out = (*(*file.cast::<RemoteFile>()).remote).size as ffi::sqlite3_int64 };
+    ffi::SQLITE_OK
+}
+
+/// Locking and unlocking: nothing else writes to an immutable file
+unsafe extern "C" fn lock(_file: *mut ffi::sqlite3_file, _level: c_int) -> c_int {
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn check_reserved_lock(_file: *mut ffi::sqlite3_file, out: *mut c_int) -> c_int {
+    unsafe { *out = 0 };
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn file_control(_file: *mut ffi::sqlite3_file, _op: c_int, _arg: *mut c_void) -> c_int {
+    ffi::SQLITE_NOTFOUND
+}
+
+unsafe extern "C" fn sector_size(_file: *mut ffi::sqlite3_file) -> c_int {
+    4096
+}
+
+unsafe extern "C" fn device_characteristics(_file: *mut ffi::sqlite3_file) -> c_int {
+    ffi::SQLITE_IOCAP_IMMUTABLE
+}
@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+
+use crate::bbox::{BoundingBox, TileRange};
+use crate::progress::{NoProgress, Progress};
+use crate::source::{open_source, SourceKind};
+use crate::tile::{detect_compression, detect_format, Compression, TileFormat};
+
+/// Summary of an MBTiles file as reported by `mbtile info`
+#[derive(Debug, Clone)]
+pub struct Info {
+    pub kind: SourceKind,
+    pub metadata: Vec<(String, String)>,
+    /// Format detected from the first tile blob, `None` if there are no tiles
+    pub format: Option<TileFormat>,
+    pub compression: Option<Compression>,
+    pub zooms: Vec<ZoomInfo>,
+    /// Bounds computed from the tile extent at the highest zoom level
+    pub bounds: Option<BoundingBox>,
+}
+
+/// Tile statistics for one zoom level
+#[derive(Debug, Clone)]
+pub struct ZoomInfo {
+    pub zoom: i32,
+    pub tiles: u64,
+    pub bytes: u64,
+    pub x_min: i32,
+    pub x_max: i32,
+    pub y_min: i32,
+    pub y_max: i32,
+}
+
+impl ZoomInfo {
+    /// Tile range spanned by this zoom's tiles
+    pub fn range(&self) -> TileRange {
+        TileRange { zoom: self.zoom, x_min: self.x_min, x_max: self.x_max, y_min: self.y_min, y_max: self.y_max }
+    }
+}
+
+impl Info {
+    pub fn total_tiles(&self) -> u64 {
+        self.zooms.iter().map(|z| z.tiles).sum()
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.zooms.iter().map(|z| z.bytes).sum()
+    }
+}
+
+/// Summarize the MBTiles or PMTiles file at `input_path`
+pub fn info(input_path: &str) -> Result<Info> {
+    let source = open_source(input_path)?;
+
+    let metadata = source.metadata()?;
+
+    let sample = source.sample_tile()?;
+    let format = sample.as_deref().map(detect_format);
+    let compression = sample.as_deref().map(detect_compression);
+
+    let zooms = source.zoom_info()?;
+
+    // Bounds are most precise at the highest zoom level
+    let bounds = zooms.last().map(|z| z.range().bounds());
+
+    Ok(Info { kind: source.kind(), metadata, format, compression, zooms, bounds })
+}
+
+/// Number of tiles stored in one format and compression
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatCount {
+    pub format: TileFormat,
+    pub compression: Compression,
+    pub tiles: u64,
+}
+
+/// Detect the format and compression of every tile of `input_path`,
+/// most common first. More than one entry means a mixed tileset.
+pub fn format_counts(input_path: &str) -> Result<Vec<FormatCount>> {
+    format_counts_with_progress(input_path, &NoProgress)
+}
+
+/// Like [`format_counts`], reporting progress per tile read
+pub fn format_counts_with_progress(input_path: &str, progress: &dyn Progress) -> Result<Vec<FormatCount>> {
+    let source = open_source(input_path)?;
+    let zooms = source.zoom_levels()?;
+    let mut total = 0;
+    for &zoom in &zooms {
+        total += source.count_tiles(&TileRange::full(zoom)?)?;
+    }
+    progress.start(total);
+
+    let mut counts: HashMap<(TileFormat, Compression), u64> = HashMap::new();
+    for zoom in zooms {
+        source.for_each_tile(&TileRange::full(zoom)?, &mut |tile| {
+            *counts.entry((detect_format(&tile.data), detect_compression(&tile.data))).or_default() += 1;
+            progress.advance(1);
+            Ok(())
+        })?;
+    }
+    progress.finish();
+
+    let mut counts: Vec<FormatCount> =
+        counts.into_iter().map(|((format, compression), tiles)| FormatCount { format, compression, tiles }).collect();
+    counts.sort_by(|a, b| b.tiles.cmp(&a.tiles).then_with(|| (a.format.as_str(), a.compression.to_string()).cmp(&(b.format.as_str(), b.compression.to_string()))));
+    Ok(counts)
+}
@@ -0,0 +1,109 @@
+//! Library for inspecting and extracting MBTiles tilesets.
+//!
+//! The `mbtile` binary is a thin command line wrapper around this crate.
+
+pub mod apply;
+pub mod bbox;
+pub mod check;
+pub mod composite;
+mod clip;
+pub mod coord;
+pub mod coverage;
+pub mod dedupe;
+pub mod diff;
+pub mod directory;
+pub mod erase;
+pub mod expire;
+pub mod extract;
+pub mod filter;
+pub mod geopackage;
+pub(crate) mod grids;
+pub mod holes;
+mod http;
+mod http_vfs;
+pub mod info;
+pub mod interrupt;
+pub mod list;
+pub mod mbtiles;
+pub mod merge;
+mod metrics;
+pub mod mvt;
+pub mod ndjson;
+pub mod optimize;
+pub mod overview;
+pub mod overzoom;
+pub mod pmtiles;
+mod plugin;
+pub mod progress;
+pub mod raster;
+pub mod region;
+pub mod route;
+pub mod seed;
+pub mod serve;
+mod sigv4;
+pub mod ssh;
+pub mod sink;
+pub mod source;
+pub mod split;
+pub mod stats;
+pub mod sync;
+pub mod tar;
+pub mod tile;
+mod tile_cache;
+pub mod tile_list;
+pub mod tilejson;
+pub mod timestamps;
+pub mod transform;
+pub mod upload;
+pub mod validate;
+pub mod vector_layers;
+
+pub use apply::{apply, apply_with_progress};
+pub use bbox::{BBoxOrder, BoundingBox, TileRange};
+pub use check::{check, check_with_progress, repair, repair_with_progress, BadTile, CheckReport, RepairReport, TileProblem};
+pub use composite::{composite, composite_with_progress};
+pub use coord::{TileCoord, MAX_LATITUDE};
+pub use coverage::{coverage, Coverage};
+pub use dedupe::{dedupe, dedupe_with_progress, DedupeReport};
+pub use diff::{diff, diff_with_progress, DiffReport, ZoomDiff};
+pub use directory::{export_dir, export_dir_with_progress, import_dir, DirectoryWriter};
+pub use erase::erase;
+pub use expire::expire;
+pub use extract::{estimate_extract, extract, extract_with_progress, Area, ExtractOptions, OutputMode, ZoomEstimate};
+pub use filter::Filter;
+pub use geopackage::{GeopackageReader, GeopackageWriter};
+pub use holes::holes;
+pub use info::{format_counts, format_counts_with_progress, info, FormatCount, Info, ZoomInfo};
+pub use list::{list_tiles, ListOptions, TileEntry};
+pub use interrupt::{interrupt, Interrupted};
+pub use merge::{merge, merge_with_progress, Conflict};
+pub use mbtiles::{tile_hash, MbtilesReader, MbtilesSchema, MbtilesWriter};
+pub use ndjson::{dump_ndjson, dump_ndjson_with_progress, load_ndjson};
+pub use optimize::{optimize, OptimizeReport};
+pub use raster::{RasterConversion, Watermark, WatermarkPosition};
+pub use overview::{build_overviews, build_overviews_with_progress};
+pub use overzoom::{overzoom, overzoom_with_progress};
+pub use region::Region;
+pub use route::Route;
+pub use pmtiles::{PmtilesReader, PmtilesWriter};
+pub use progress::{NoProgress, Progress};
+pub use seed::{seed, seed_with_progress, SeedOptions, SeedReport};
+pub use serve::{serve, serve_with, Mount, ServeOptions};
+pub use sink::{OutputFormat, TileSink};
+pub use source::{open_source, SourceKind, TileSource};
+pub use ssh::SshReader;
+pub use split::{split_by_size, split_by_size_with_progress, split_by_zoom, split_by_zoom_with_progress, SplitPart};
+pub use stats::{layer_stats, layer_stats_with_progress, stats, LargeTile, LayerStats, Stats, TileSize, ZoomLayers, ZoomStats};
+pub use sync::{sync, sync_with_progress, SyncReport};
+pub use tar::{TarReader, TarWriter};
+pub use tile::{compress, decompress, detect_compression, gzip, detect_format, tile_to_lon_lat, Compression, Scheme, Tile, TileFormat};
+pub use tile_list::TileList;
+pub use tilejson::{tilejson, tilejson_for_source};
+pub use timestamps::{disable_timestamps, enable_timestamps};
+pub use transform::{transform, transform_with_progress, LayerFilter, TileCompression, TileTransform};
+pub use upload::{upload_dir, upload_dir_with_progress, Credentials, UploadOptions, UploadReport};
+pub use validate::{validate, validate_with, ValidateOptions, ValidationReport, DEFAULT_LAYER_SAMPLE};
+pub use vector_layers::{
+    generate_vector_layers, generate_vector_layers_with_progress, scan_vector_layers, scan_vector_layers_with_progress,
+    vector_layers_json, VectorLayer,
+};
@@ -0,0 +1,106 @@
+use std::collections::HashSet;
+
+use anyhow::{Result, anyhow};
+
+use crate::bbox::TileRange;
+use crate::extract::Area;
+use crate::mbtiles::{tile_hash, MbtilesReader};
+use crate::source::{is_mbtiles, open_source};
+use crate::tile::Scheme;
+use crate::timestamps::tiles_since;
+
+/// One row of `mbtile list`
+#[derive(Debug, Clone)]
+pub struct TileEntry {
+    pub zoom: i32,
+    pub x: i32,
+    /// Row in TMS numbering
+    pub y: i32,
+    pub bytes: u64,
+    /// Hex md5 of the tile data, when requested
+    pub hash: Option<String>,
+}
+
+/// Which tiles `list_tiles` reports
+#[derive(Debug, Clone, Default)]
+pub struct ListOptions {
+    /// Only tiles intersecting this area, every tile when `None`
+    pub area: Option<Area>,
+    pub min_zoom: Option<i32>,
+    pub max_zoom: Option<i32>,
+    /// Read each tile to compute its hash; otherwise only sizes are read
+    pub hash: bool,
+    /// Only tiles written or changed at or after this many seconds since the
+    /// Unix epoch, from an MBTiles file with timestamps enabled
+    pub updated_since: Option<i64>,
+    /// Only tiles first written at or after this many seconds since the Unix
+    /// epoch, from an MBTiles file with timestamps enabled
+    pub created_since: Option<i64>,
+}
+
+/// Call `f` for every tile of `input_path` matching `options`, ordered by zoom
+pub fn list_tiles(input_path: &str, options: &ListOptions, f: &mut dyn FnMut(TileEntry) -> Result<()>) -> Result<()> {
+    let source = open_source(input_path)?;
+    let scheme = if !is_mbtiles(input_path) {
+        Scheme::Tms
+    } else {
+        source
+            .metadata()?
+            .into_iter()
+            .find(|(name, _)| name == "scheme")
+            .and_then(|(_, value)| Scheme::from_metadata(&value))
+            .unwrap_or(Scheme::Tms)
+    };
+
+    // Coordinates as stored of the tiles passing the timestamp filters
+    let recent = if options.updated_since.is_some() || options.created_since.is_some() {
+        if !is_mbtiles(input_path) {
+            return Err(anyhow!("Only MBTiles files have tile timestamps: {}", input_path));
+        }
+        let reader = MbtilesReader::open(input_path)?;
+        let conn = reader.connection();
+        let filters = [(options.updated_since, false), (options.created_since, true)];
+        let mut recent: Option<HashSet<(i32, i32, i32)>> = None;
+        for (since, created) in filters.into_iter().filter_map(|(since, created)| Some((since?, created))) {
+            let tiles = tiles_since(conn, since, created).map_err(|e| anyhow!("{}: {}", input_path, e))?;
+            recent = Some(match recent {
+                Some(recent) => recent.intersection(&tiles).copied().collect(),
+                None => tiles,
+            });
+        }
+        recent
+    } else {
+        None
+    };
+    let wanted = |zoom: i32, x: i32, y: i32| recent.as_ref().is_none_or(|recent| recent.contains(&(zoom, x, y)));
+
+    for zoom in source.zoom_levels()? {
+        if zoom < options.min_zoom.unwrap_or(0) || zoom > options.max_zoom.unwrap_or(i32::MAX) {
+            continue;
+        }
+        let ranges = match &options.area {
+            Some(area) => area.tile_ranges(zoom)?.iter().map(|range| range.to_scheme(scheme)).collect(),
+            None => vec![TileRange::full(zoom)?],
+        };
+        for range in ranges {
+            if options.hash {
+                source.for_each_tile(&range, &mut |tile| {
+                    if !wanted(zoom, tile.x, tile.y) {
+                        return Ok(());
+                    }
+                    let y = scheme.to_tms(zoom, tile.y);
+                    let hash = Some(tile_hash(&tile.data));
+                    f(TileEntry { zoom, x: tile.x, y, bytes: tile.data.len() as u64, hash })
+                })?;
+            } else {
+                source.for_each_tile_size(&range, &mut |x, y, bytes| {
+                    if !wanted(zoom, x, y) {
+                        return Ok(());
+                    }
+                    f(TileEntry { zoom, x, y: scheme.to_tms(zoom, y), bytes, hash: None })
+                })?;
+            }
+        }
+    }
+    Ok(())
+}
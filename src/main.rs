@@ -1,142 +1,2719 @@
-use clap::{Parser, Subcommand};
-use rusqlite::Connection;
-use anyhow::{Context, Result, anyhow};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
+use anyhow::{Result, anyhow};
+use indicatif::{ProgressBar, ProgressStyle};
+use mbtiles::mvt::VectorTile;
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::fmt::format::FmtSpan;
+use mbtiles::{
+    Area, BBoxOrder, BoundingBox, Compression, Conflict, ExtractOptions, Filter, Interrupted, LayerFilter, ListOptions, MbtilesWriter,
+    NoProgress, OutputFormat, OutputMode, Progress, RasterConversion, Region, Route, Scheme, SourceKind, TileCompression, TileCoord,
+    TileFormat, TileList, TileTransform, ValidateOptions, Watermark, WatermarkPosition,
+};
 
 #[derive(Parser)]
 #[command(name = "mbtile")]
 #[command(about = "MBTiles utility", long_about = None)]
 struct Cli {
+    /// Suppress progress and summary output
+    #[arg(long, short, global = true)]
+    quiet: bool,
+
+    /// How to report progress of long running commands on stderr
+    #[arg(long, value_enum, global = true, default_value_t = ProgressMode::Bar)]
+    progress: ProgressMode,
+
+    /// Print reports and summaries on stdout as JSON
+    #[arg(long, global = true)]
+    json: bool,
+
+    /// Log to stderr: -v info, -vv debug with timings, -vvv trace. RUST_LOG overrides.
+    #[arg(long, short, global = true, action = ArgAction::Count)]
+    verbose: u8,
+
+    /// Format of log lines
+    #[arg(long, value_enum, global = true, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
     #[command(subcommand)]
     command: Commands,
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ProgressMode {
+    Bar,
+    Json,
+    None,
+}
+
+/// Output settings shared by all commands
+#[derive(Clone, Copy)]
+struct Ui {
+    quiet: bool,
+    progress: ProgressMode,
+    json: bool,
+}
+
+impl Ui {
+    fn reporter(&self) -> Box<dyn Progress> {
+        match (self.quiet, self.progress) {
+            (true, _) | (_, ProgressMode::None) => Box::new(NoProgress),
+            (false, ProgressMode::Bar) => Box::new(BarProgress::new()),
+            (false, ProgressMode::Json) => Box::new(JsonProgress::new()),
+        }
+    }
+
+    /// Print the closing line of a command, or `details` in JSON mode
+    fn summary(&self, message: &str, details: serde_json::Value) {
+        match (self.quiet, self.json) {
+            (true, _) => {}
+            (false, true) => println!("{}", details),
+            (false, false) => println!("{}", message),
+        }
+    }
+
+    /// Like [`Self::summary`] on stderr, for commands writing data to stdout
+    fn summary_stderr(&self, message: &str, details: serde_json::Value) {
+        match (self.quiet, self.json) {
+            (true, _) => {}
+            (false, true) => eprintln!("{}", details),
+            (false, false) => eprintln!("{}", message),
+        }
+    }
+}
+
 #[derive(Subcommand)]
 enum Commands {
-    /// Extract tiles from an MBTiles file
-    Extract {
+    /// Extract tiles within an area from an MBTiles or PMTiles file
+    #[command(mut_arg("bbox", |arg| arg.required_unless_present_any(["region", "route", "tile_list", "bbox_file", "bbox_from"])))]
+    Extract(ExtractArgs),
+    /// Copy tiles between MBTiles, PMTiles, GeoPackage and tar files, optionally filtered by area
+    /// and zoom and converted to another tile format or schema
+    Copy(ExtractArgs),
+    /// Print metadata, tile format, per-zoom counts and bounds of a tileset
+    Info {
+        /// Input MBTiles or PMTiles file
+        input: String,
+
+        /// Read every tile and count tiles per format and compression, to spot mixed tilesets
+        #[arg(long)]
+        formats: bool,
+    },
+    /// Print the tile size distribution per zoom level and the largest tiles
+    Stats {
+        /// Input MBTiles or PMTiles file
+        input: String,
+
+        /// Number of largest tiles to list
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+
+        /// Also decode vector tiles and break down features and bytes per layer and zoom level
+        #[arg(long)]
+        layers: bool,
+
+        /// With --layers, decode at most this many tiles per zoom level, spread evenly
+        #[arg(long, value_name = "TILES", requires = "layers")]
+        sample: Option<u64>,
+    },
+    /// Write one tile blob to stdout or a file
+    Tile(TileArgs),
+    /// Print z,x,y and size of each tile as CSV or newline delimited JSON
+    List(ListArgs),
+    /// Check an MBTiles file for MBTiles 1.3 spec compliance
+    Validate {
         /// Input MBTiles file
         input: String,
 
-        /// Output MBTiles file
+        /// Vector tiles decoded per zoom level to check their layers against vector_layers, 0 to skip
+        #[arg(long, value_name = "TILES", default_value_t = mbtiles::DEFAULT_LAYER_SAMPLE)]
+        layer_sample: u64,
+
+        /// Fail on tiles larger than this, e.g. 500KB (powers of 1024)
+        #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+        max_tile_size: Option<u64>,
+
+        /// Only warn about tiles over --max-tile-size
+        #[arg(long, requires = "max_tile_size")]
+        oversize_warning: bool,
+    },
+    /// Check an MBTiles file for database corruption, a missing tile index, missing required metadata and unreadable tiles
+    Check {
+        /// Input MBTiles file
+        input: String,
+
+        /// Delete the bad tiles and extra copies of duplicated tiles found, create the tile index and fill in missing metadata
+        #[arg(long)]
+        fix: bool,
+
+        /// With --fix, rebuild deleted raster tiles by upscaling an ancestor tile
+        #[arg(long, requires = "fix")]
+        regenerate: bool,
+    },
+    /// Convert a flat MBTiles file to the normalized schema in place, storing identical tiles once
+    Dedupe {
+        /// MBTiles file to rewrite
+        input: String,
+    },
+    /// Rebuild the tile index, ANALYZE and VACUUM an MBTiles file to reclaim free pages
+    #[command(visible_alias = "vacuum")]
+    Optimize {
+        /// MBTiles file to optimize
+        input: String,
+
+        /// Write the optimized copy to this new file with VACUUM INTO, leaving the input untouched
+        #[arg(long, short)]
+        output: Option<String>,
+    },
+    /// Compare two tilesets by tile hash, optionally writing the changes as an MBTiles diff
+    Diff {
+        /// Old MBTiles or PMTiles file
+        old: String,
+
+        /// New MBTiles or PMTiles file
+        new: String,
+
+        /// Write added and changed tiles plus a list of removed ones to this MBTiles file
+        #[arg(long, short)]
+        output: Option<String>,
+    },
+    /// Apply a diff written by `diff --output` to a base tileset
+    Apply {
+        /// Base MBTiles or PMTiles file the diff was made against
+        base: String,
+
+        /// Diff MBTiles file
+        diff: String,
+
+        /// Output file for the updated tileset (.mbtiles or .pmtiles)
+        output: String,
+    },
+    /// Update an MBTiles file in place to match another tileset, writing only the tiles that differ
+    Sync {
+        /// MBTiles or PMTiles file or http(s):// URL to copy from, or ssh://[user@]host[:port]/path of an MBTiles file
+        source: String,
+
+        /// Local MBTiles file to update, created if missing
+        target: String,
+
+        /// Keep tiles and metadata the source doesn't have
+        #[arg(long)]
+        no_delete: bool,
+    },
+    /// Read or edit the metadata table
+    Metadata {
+        #[command(subcommand)]
+        command: MetadataCommand,
+    },
+    /// Record when each tile is written, for list --updated-since
+    Timestamps {
+        #[command(subcommand)]
+        command: TimestampsCommand,
+    },
+    /// Print a TileJSON 3.0 document built from the metadata
+    Tilejson {
+        /// Input MBTiles or PMTiles file
+        input: String,
+
+        /// Tile URL template, e.g. https://example.com/{z}/{x}/{y}.pbf
+        #[arg(long)]
+        url_template: String,
+
+        /// Write to this file instead of stdout
+        #[arg(long, short)]
+        output: Option<String>,
+    },
+    /// Write GeoJSON polygons outlining where a tileset has tiles at one zoom level
+    Coverage {
+        /// Input MBTiles or PMTiles file
+        input: String,
+
+        /// Zoom level to outline (default: the highest zoom)
+        #[arg(long)]
+        zoom: Option<i32>,
+
+        /// Write to this file instead of stdout
+        #[arg(long, short)]
+        output: Option<String>,
+    },
+    /// List tiles missing below existing parent tiles within the tileset's bounds
+    Holes {
+        /// Input MBTiles or PMTiles file
+        input: String,
+
+        /// Write the missing tiles to this file, one z/x/y (XYZ) per line as read by --tile-list
+        #[arg(long, short)]
+        output: Option<String>,
+    },
+    /// Re-encode every vector tile with a different compression
+    Recompress {
+        /// Input MBTiles or PMTiles file
+        input: String,
+
+        /// Output MBTiles or PMTiles file
+        output: String,
+
+        /// Target compression: none, gzip or gzip:LEVEL
+        #[arg(long, value_parser = TileCompression::parse)]
+        tile_compression: TileCompression,
+    },
+    /// Simplify vector tile lines and polygons with Douglas-Peucker
+    Simplify {
+        /// Input MBTiles or PMTiles file
+        input: String,
+
+        /// Output MBTiles or PMTiles file
+        output: String,
+
+        /// Largest distance a removed vertex may be from the simplified shape, in units of a 4096 extent
+        #[arg(long)]
+        tolerance: f64,
+    },
+    /// Transcode every raster tile to another image format
+    ConvertRaster {
+        /// Input MBTiles or PMTiles file
+        input: String,
+
+        /// Output MBTiles or PMTiles file
+        output: String,
+
+        /// Target image format
+        #[arg(long, value_enum)]
+        to: RasterFormatArg,
+
+        /// Encoder quality for JPEG and lossy WebP (0-100)
+        #[arg(long, default_value_t = 80.0)]
+        quality: f32,
+
+        /// Encode WebP losslessly
+        #[arg(long)]
+        lossless: bool,
+    },
+    /// Convert a tileset to or from OGC GeoPackage tiles, as `copy` does with --output-format
+    Convert {
+        /// Input MBTiles, PMTiles, GeoPackage or tar file
+        input: String,
+
+        /// Output file
+        output: String,
+
+        /// Format to write, by default MBTiles with --from gpkg and GeoPackage with --from mbtiles
+        #[arg(long, value_enum, required_unless_present = "from")]
+        to: Option<ConvertFormatArg>,
+
+        /// Format the input must be
+        #[arg(long, value_enum)]
+        from: Option<ConvertFormatArg>,
+    },
+    /// Add lower zoom levels to a raster MBTiles file by downsampling child tiles
+    BuildOverviews {
+        /// MBTiles file to modify
+        input: String,
+
+        /// Lowest zoom level to generate
+        #[arg(long)]
+        min_zoom: i32,
+    },
+    /// Fill missing tiles down to a zoom level in place from their nearest ancestor,
+    /// scaling up raster tiles and clipping vector tiles
+    Overzoom {
+        /// MBTiles file to modify
+        input: String,
+
+        /// Highest zoom level to fill
+        #[arg(long)]
+        max_zoom: i32,
+    },
+    /// Alpha blend the tiles of a raster overlay, such as hillshade or labels, onto a raster base
+    Composite {
+        /// Base MBTiles or PMTiles file, whose tiles, format and metadata the output keeps
+        base: String,
+
+        /// Overlay MBTiles or PMTiles file drawn on top, scaled up from parent tiles at zoom levels it lacks
+        overlay: String,
+
+        /// Output MBTiles or PMTiles file
+        output: String,
+    },
+    /// Delete tiles inside (or outside) an area from an MBTiles file in place
+    Erase(EraseArgs),
+    /// Delete the tiles of an imposm or osm2pgsql expiry list so they can be rendered again
+    Expire(ExpireArgs),
+    /// Combine several tilesets into one
+    Merge {
+        /// Input MBTiles or PMTiles files, in priority order for --conflict
+        #[arg(required = true, num_args = 2..)]
+        inputs: Vec<String>,
+
+        /// Output MBTiles or PMTiles file
+        #[arg(long, short)]
+        output: String,
+
+        /// How to resolve tiles present in more than one input
+        #[arg(long, value_enum, default_value_t = ConflictArg::Last)]
+        conflict: ConflictArg,
+    },
+    /// Write a tileset to several files, by zoom range or by maximum file size
+    Split {
+        /// Input MBTiles or PMTiles file
+        input: String,
+
+        /// Comma separated zoom ranges (MIN-MAX or Z), one output file each
+        #[arg(
+            long,
+            required_unless_present = "max_size",
+            conflicts_with = "max_size",
+            value_delimiter = ',',
+            value_parser = parse_zoom_range
+        )]
+        by_zoom: Vec<(i32, i32)>,
+
+        /// Largest size of each part, e.g. 2GB or 500M (powers of 1024)
+        #[arg(long, value_parser = parse_size)]
+        max_size: Option<u64>,
+
+        /// Directory to write the parts to (default: the input's directory)
+        #[arg(long)]
+        output_dir: Option<String>,
+
+        /// Replace parts that already exist
+        #[arg(long)]
+        overwrite: bool,
+    },
+    /// Write tiles to a {z}/{x}/{y}.{ext} directory tree
+    ExportDir(ExportDirArgs),
+    /// Write every tile to stdout as a line of JSON with its coordinates and base64 data
+    Dump {
+        /// Input MBTiles, PMTiles, GeoPackage or tar file
+        input: String,
+
+        /// Output format
+        #[arg(long, value_enum, default_value_t = DumpFormatArg::Ndjson)]
+        format: DumpFormatArg,
+
+        /// Row numbering of the written y coordinate
+        #[arg(long, value_enum, default_value_t = SchemeArg::Xyz)]
+        scheme: SchemeArg,
+
+        /// Lowest zoom level to write
+        #[arg(long)]
+        minzoom: Option<i32>,
+
+        /// Highest zoom level to write
+        #[arg(long)]
+        maxzoom: Option<i32>,
+    },
+    /// Build a tileset from `dump` lines read from stdin
+    Load {
+        /// Output MBTiles, PMTiles, GeoPackage or tar file
+        output: String,
+
+        /// Row numbering of the y coordinate read
+        #[arg(long, value_enum, default_value_t = SchemeArg::Xyz)]
+        scheme: SchemeArg,
+    },
+    /// Build a tileset from a {z}/{x}/{y}.{ext} directory tree
+    ImportDir {
+        /// Input directory
+        input: String,
+
+        /// Output MBTiles or PMTiles file
         output: String,
 
-        /// Bounding box in format: N,E,S,W
+        /// Tile format (default: detected from the first tile)
+        #[arg(long, value_enum)]
+        format: Option<TileFormatArg>,
+
+        /// Row numbering of the input tree
+        #[arg(long, value_enum, default_value_t = SchemeArg::Xyz)]
+        scheme: SchemeArg,
+    },
+    /// Serve tiles over HTTP at /{z}/{x}/{y}.{ext} (XYZ scheme)
+    Serve(ServeArgs),
+    /// Download the tiles of an area from a tile server into a new MBTiles file
+    Seed(SeedArgs),
+}
+
+#[derive(Args)]
+struct ExportDirArgs {
+    /// Input MBTiles or PMTiles file
+    input: String,
+
+    /// Output directory
+    output: String,
+
+    /// Row numbering of the written tree
+    #[arg(long, value_enum, default_value_t = SchemeArg::Xyz)]
+    scheme: SchemeArg,
+
+    /// Lowest zoom level to export
+    #[arg(long)]
+    minzoom: Option<i32>,
+
+    /// Highest zoom level to export
+    #[arg(long)]
+    maxzoom: Option<i32>,
+
+    /// Upload the exported tree to s3://bucket/prefix or gs://bucket/prefix. Credentials are read from
+    /// AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY and AWS_SESSION_TOKEN (for gs://, an HMAC key)
+    #[arg(long, value_name = "TARGET")]
+    upload: Option<String>,
+
+    /// Base URL of the store to upload to, e.g. http://localhost:9000 (default: AWS_ENDPOINT_URL, else the
+    /// HTTPS endpoint of S3 or Cloud Storage)
+    #[arg(long, requires = "upload")]
+    endpoint: Option<String>,
+
+    /// Region of the bucket (default: AWS_REGION or AWS_DEFAULT_REGION, else us-east-1)
+    #[arg(long, requires = "upload")]
+    region: Option<String>,
+
+    /// Uploads in flight at once, whole files or parts of large ones
+    #[arg(long, default_value_t = 16, requires = "upload")]
+    upload_concurrency: usize,
+
+    /// Send files larger than this as multipart uploads in parts of this size, at least 5MiB
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, default_value = "16MiB", requires = "upload")]
+    upload_part_size: u64,
+}
+
+#[derive(Args)]
+struct SeedArgs {
+    /// Tile URL with {z}, {x} and {y} (or {-y} for TMS rows), e.g. https://tiles.example.com/{z}/{x}/{y}.png
+    url: String,
+
+    /// Output MBTiles file
+    output: String,
+
+    /// Bounding box in format: W,S,E,N (see --bbox-order). May be repeated. Default: the whole world
+    #[arg(long, conflicts_with = "region", allow_hyphen_values = true)]
+    bbox: Vec<String>,
+
+    /// Order of the values in --bbox
+    #[arg(long, value_enum, default_value_t = BBoxOrderArg::Wsen)]
+    bbox_order: BBoxOrderArg,
+
+    /// GeoJSON file with a (multi)polygon
+    #[arg(long)]
+    region: Option<String>,
+
+    /// Zoom levels to download, MIN-MAX or Z
+    #[arg(long, value_parser = parse_zoom_range)]
+    zoom: (i32, i32),
+
+    /// Requests in flight at once
+    #[arg(long, default_value_t = 4)]
+    concurrency: usize,
+
+    /// Most requests per second
+    #[arg(long)]
+    rate: Option<f64>,
+
+    /// Retries of tiles failing with a network error, 429 or 5xx, with growing waits
+    #[arg(long, default_value_t = 3)]
+    retries: u32,
+
+    /// Attribution to store in the metadata, as the tile provider requires
+    #[arg(long)]
+    attribution: Option<String>,
+
+    /// Extra request header, e.g. "User-Agent: my-app/1.0". May be repeated
+    #[arg(long = "header", value_name = "NAME: VALUE", value_parser = parse_header)]
+    headers: Vec<(String, String)>,
+
+    /// Continue seeding an existing output, skipping the tiles already done
+    #[arg(long)]
+    resume: bool,
+
+    /// Request the tiles that failed in earlier runs again
+    #[arg(long)]
+    retry_failed: bool,
+
+    /// Re-request the tiles an existing output has, conditionally on their
+    /// stored ETag or Last-Modified, rewriting only those that changed
+    #[arg(long, conflicts_with_all = ["resume", "retry_failed"])]
+    refresh: bool,
+}
+
+#[derive(Args)]
+struct ServeArgs {
+    /// Input MBTiles or PMTiles files. One file is served at the root, several
+    /// at /<file stem> unless given a --mount
+    #[arg(required_unless_present = "watch")]
+    inputs: Vec<String>,
+
+    /// Serve the input with file stem NAME under a URL prefix, e.g. basemap=/base
+    #[arg(long, value_name = "NAME=PREFIX", value_parser = parse_mount)]
+    mount: Vec<(String, String)>,
+
+    /// Also serve every .mbtiles and .pmtiles file in this directory at /<file stem>,
+    /// picking up files as they are added and removed
+    #[arg(long, value_name = "DIR")]
+    watch: Option<String>,
+
+    /// Port to listen on
+    #[arg(long, default_value_t = 3000)]
+    port: u16,
+
+    /// Address to bind to
+    #[arg(long, default_value = "127.0.0.1")]
+    bind: String,
+
+    /// Seconds clients and CDNs may cache responses without revalidating.
+    /// By default they revalidate every time, using the ETag or Last-Modified
+    #[arg(long, value_name = "SECONDS")]
+    max_age: Option<u32>,
+
+    /// Let web pages from these origins fetch tiles, e.g. http://localhost:8080, or * for any
+    #[arg(long, value_name = "ORIGINS", value_delimiter = ',')]
+    cors: Vec<String>,
+
+    /// Only serve requests presenting this key as ?key= or an Authorization: Bearer header.
+    /// May be repeated
+    #[arg(long = "auth-token", value_name = "TOKEN")]
+    auth_tokens: Vec<String>,
+
+    /// File of accepted keys, one per line. Blank lines and lines starting with # are skipped
+    #[arg(long, value_name = "FILE")]
+    auth_tokens_file: Option<String>,
+
+    /// Keep up to this much recently served tile data in memory, e.g. 256M
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    cache_size: Option<u64>,
+
+    /// Fetch tiles missing from the input from this tile server URL template, e.g.
+    /// https://tiles.example.com/{z}/{x}/{y}.png, serving and storing them in the input.
+    /// Needs a single MBTiles input, created if it doesn't exist
+    #[arg(long, value_name = "URL")]
+    upstream: Option<String>,
+}
+
+#[derive(Subcommand)]
+enum MetadataCommand {
+    /// Print all metadata
+    List {
+        /// Input MBTiles or PMTiles file
+        input: String,
+    },
+    /// Print the value of one key
+    Get {
+        /// Input MBTiles or PMTiles file
+        input: String,
+
+        name: String,
+    },
+    /// Set a key, replacing any existing value
+    Set {
+        /// MBTiles file to modify
+        input: String,
+
+        name: String,
+
+        value: String,
+
+        /// Require the value to be valid JSON and store it compacted
+        #[arg(long)]
+        json_value: bool,
+    },
+    /// Remove a key
+    Delete {
+        /// MBTiles file to modify
+        input: String,
+
+        name: String,
+    },
+    /// Scan the vector tiles and write the `json` key listing their layers, zoom ranges and attributes
+    GenerateJson {
+        /// MBTiles file to modify
+        input: String,
+
+        /// Decode at most this many tiles per zoom level, spread evenly
+        #[arg(long, value_name = "TILES")]
+        sample: Option<u64>,
+
+        /// Print the generated value instead of storing it
         #[arg(long)]
-        bbox: String,
+        dry_run: bool,
     },
 }
 
-fn main() {
-    let cli = Cli::parse();
+#[derive(Subcommand)]
+enum TimestampsCommand {
+    /// Start recording when tiles are written and changed, stamping existing tiles now
+    Enable {
+        /// MBTiles file to modify
+        input: String,
+    },
+    /// Stop recording and drop the recorded timestamps
+    Disable {
+        /// MBTiles file to modify
+        input: String,
+    },
+}
 
-    match cli.command {
-        Commands::Extract { input, output, bbox } => {
-            if let Err(e) = extract_tiles(&input, &output, &bbox) {
-                eprintln!("Error: {}", e);
-                std::process::exit(1);
-            }
-        }
-    }
+#[derive(Args)]
+struct EraseArgs {
+    /// MBTiles file to modify
+    input: String,
+
+    /// Bounding box in format: W,S,E,N (see --bbox-order). May be repeated.
+    #[arg(long, required_unless_present = "region", conflicts_with = "region", allow_hyphen_values = true)]
+    bbox: Vec<String>,
+
+    /// Order of the values in --bbox
+    #[arg(long, value_enum, default_value_t = BBoxOrderArg::Wsen)]
+    bbox_order: BBoxOrderArg,
+
+    /// GeoJSON file with a (multi)polygon
+    #[arg(long)]
+    region: Option<String>,
+
+    /// Delete the tiles outside the area instead
+    #[arg(long)]
+    invert: bool,
+
+    /// Lowest zoom level to erase from
+    #[arg(long)]
+    minzoom: Option<i32>,
+
+    /// Highest zoom level to erase from
+    #[arg(long)]
+    maxzoom: Option<i32>,
 }
 
-#[derive(Debug)]
-struct BoundingBox {
-    north: f64,
-    east: f64,
-    south: f64,
-    west: f64,
+#[derive(Args)]
+struct ExpireArgs {
+    /// MBTiles file to modify
+    input: String,
+
+    /// Expired tiles, one z/x/y per line in XYZ numbering
+    list: String,
+
+    /// Also delete the tiles containing the expired ones at lower zoom levels
+    #[arg(long)]
+    ancestors: bool,
+
+    /// Also delete the tiles within the expired ones at higher zoom levels
+    #[arg(long)]
+    descendants: bool,
 }
 
-impl BoundingBox {
-    fn parse(bbox_str: &str) -> Result<Self> {
-        let parts: Vec<&str> = bbox_str.split(',').collect();
-        if parts.len() != 4 {
-            return Err(anyhow!("Bounding box must have 4 values: N,E,S,W"));
-        }
+#[derive(Args)]
+struct ListArgs {
+    /// Input MBTiles or PMTiles file
+    input: String,
 
-        Ok(BoundingBox {
-            north: parts[0].trim().parse().context("Invalid north value")?,
-            east: parts[1].trim().parse().context("Invalid east value")?,
-            south: parts[2].trim().parse().context("Invalid south value")?,
-            west: parts[3].trim().parse().context("Invalid west value")?,
-        })
-    }
+    /// Only tiles intersecting this bounding box (W,S,E,N, see --bbox-order). May be repeated.
+    #[arg(long, conflicts_with = "region", allow_hyphen_values = true)]
+    bbox: Vec<String>,
 
-    fn tile_bounds(&self, zoom: i32) -> (i32, i32, i32, i32) {
-        let n = 2_i32.pow(zoom as u32);
+    /// Order of the values in --bbox
+    #[arg(long, value_enum, default_value_t = BBoxOrderArg::Wsen)]
+    bbox_order: BBoxOrderArg,
 
-        // Convert lat/lon to tile coordinates (slippy map)
-        let x_min = ((self.west + 180.0) / 360.0 * n as f64).floor() as i32;
-        let x_max = ((self.east + 180.0) / 360.0 * n as f64).floor() as i32;
+    /// Only tiles intersecting this GeoJSON (multi)polygon
+    #[arg(long)]
+    region: Option<String>,
 
-        let lat_rad = self.north.to_radians();
-        let y_min = ((1.0 - lat_rad.tan().asinh() / std::f64::consts::PI) / 2.0 * n as f64).floor() as i32;
+    /// Lowest zoom level to list
+    #[arg(long)]
+    minzoom: Option<i32>,
 
-        let lat_rad = self.south.to_radians();
-        let y_max = ((1.0 - lat_rad.tan().asinh() / std::f64::consts::PI) / 2.0 * n as f64).floor() as i32;
+    /// Highest zoom level to list
+    #[arg(long)]
+    maxzoom: Option<i32>,
 
-        // Convert slippy map Y to TMS Y (flip)
-        let tms_y_min = n - 1 - y_max;
-        let tms_y_max = n - 1 - y_min;
+    /// Output format
+    #[arg(long, value_enum, default_value_t = ListFormatArg::Csv)]
+    format: ListFormatArg,
 
-        // Clamp to valid range
-        (
-            x_min.max(0).min(n - 1),
-            x_max.max(0).min(n - 1),
-            tms_y_min.max(0).min(n - 1),
-            tms_y_max.max(0).min(n - 1)
-        )
-    }
+    /// Row numbering of the printed y coordinate
+    #[arg(long, value_enum, default_value_t = SchemeArg::Xyz)]
+    scheme: SchemeArg,
+
+    /// Add the Bing Maps quadkey of each tile
+    #[arg(long)]
+    quadkey: bool,
+
+    /// Add the md5 hash of each tile (reads all tile data)
+    #[arg(long)]
+    hash: bool,
+
+    /// Only tiles written or changed since this time: a date such as 2024-01-01, a UTC
+    /// time such as 2024-01-01T12:00:00Z or seconds since 1970. Needs timestamps enabled
+    #[arg(long, value_name = "TIME", value_parser = parse_timestamp)]
+    updated_since: Option<i64>,
+
+    /// Only tiles first written since this time, as for --updated-since
+    #[arg(long, value_name = "TIME", value_parser = parse_timestamp)]
+    created_since: Option<i64>,
 }
 
-fn extract_tiles(input_path: &str, output_path: &str, bbox_str: &str) -> Result<()> {
-    let bbox = BoundingBox::parse(bbox_str)?;
+#[derive(Args)]
+struct TileArgs {
+    /// Input MBTiles or PMTiles file
+    input: String,
 
-    let output_conn = Connection::open(output_path)
-        .context(format!("Failed to create output file: {}", output_path))?;
+    #[arg(required_unless_present = "quadkey")]
+    zoom: Option<i32>,
 
-    // Create output schema
-    output_conn.execute_batch(
-        "CREATE TABLE metadata (name TEXT, value TEXT);
-         CREATE TABLE tiles (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER, tile_data BLOB);
-         CREATE UNIQUE INDEX tile_index ON tiles (zoom_level, tile_column, tile_row);"
-    )?;
+    #[arg(required_unless_present = "quadkey")]
+    x: Option<i32>,
 
-    // Attach input database
-    output_conn.execute(
-        "ATTACH DATABASE ? AS input",
-        rusqlite::params![input_path]
-    )?;
+    #[arg(required_unless_present = "quadkey")]
+    y: Option<i32>,
 
-    // Copy metadata
-    output_conn.execute(
-        "INSERT INTO metadata SELECT name, value FROM input.metadata",
-        []
-    )?;
+    /// Address the tile by Bing Maps quadkey instead of zoom, x and y
+    #[arg(long, conflicts_with_all = ["zoom", "x", "y", "scheme"])]
+    quadkey: Option<String>,
 
-    // Get all zoom levels present in the database
-    let zoom_levels: Vec<i32> = {
-        let mut stmt = output_conn.prepare("SELECT DISTINCT zoom_level FROM input.tiles ORDER BY zoom_level")?;
-        stmt.query_map([], |row| row.get(0))?
-            .collect::<Result<Vec<_>, _>>()?
-    };
+    /// Row numbering of the given y coordinate
+    #[arg(long, value_enum, default_value_t = SchemeArg::Xyz)]
+    scheme: SchemeArg,
 
-    // Extract and copy tiles within bounding box for each zoom level
-    let mut copied = 0;
-    for zoom in zoom_levels {
-        let (x_min, x_max, y_min, y_max) = bbox.tile_bounds(zoom);
+    /// Write to this file instead of stdout
+    #[arg(long, short)]
+    output: Option<String>,
 
-        let rows = output_conn.execute(
-            "INSERT INTO tiles SELECT zoom_level, tile_column, tile_row, tile_data FROM input.tiles
-             WHERE zoom_level = ? AND tile_column BETWEEN ? AND ? AND tile_row BETWEEN ? AND ?",
-            rusqlite::params![zoom, x_min, x_max, y_min, y_max]
-        )?;
-        copied += rows;
-    }
+    /// Remove gzip or zlib compression from the blob
+    #[arg(long)]
+    decompress: bool,
+
+    /// Decode a vector tile and print it as GeoJSON, one FeatureCollection per layer
+    #[arg(long, conflicts_with = "decompress")]
+    decode: bool,
+}
 
-    output_conn.execute("DETACH DATABASE input", [])?;
+#[derive(Args)]
+struct ExtractArgs {
+    /// Input MBTiles, PMTiles, GeoPackage or tar file, `-` for a tar archive on stdin
+    input: String,
 
-    println!("Extraction complete: {} tiles copied", copied);
+    /// Output MBTiles, PMTiles, GeoPackage (.gpkg) or tar file, `-` for a tar archive on stdout
+    output: String,
 
-    Ok(())
+    /// Bounding box in format: W,S,E,N (see --bbox-order). May be repeated to extract several areas.
+    #[arg(long, conflicts_with_all = ["region", "tile_list"], allow_hyphen_values = true)]
+    bbox: Vec<String>,
+
+    /// File with one bounding box per line, added to any --bbox
+    #[arg(long, conflicts_with_all = ["region", "tile_list"])]
+    bbox_file: Option<String>,
+
+    /// Also extract the area in the `bounds` metadata of this MBTiles or PMTiles file
+    #[arg(long, conflicts_with_all = ["region", "tile_list"])]
+    bbox_from: Option<String>,
+
+    /// Order of the values in --bbox and --bbox-file lines
+    #[arg(long, value_enum, default_value_t = BBoxOrderArg::Wsen)]
+    bbox_order: BBoxOrderArg,
+
+    /// GeoJSON file with a (multi)polygon; only tiles intersecting it are copied
+    #[arg(long, conflicts_with = "tile_list")]
+    region: Option<String>,
+
+    /// Also clip tiles on the edge of --region to the polygon, so nothing outside it is copied: vector features are cut and PNG or WebP pixels outside made transparent
+    #[arg(long, requires = "region", conflicts_with_all = ["bbox", "bbox_file", "bbox_from"])]
+    clip: bool,
+
+    /// GPX track or GeoJSON line; only tiles within --buffer-km of it are copied
+    #[arg(long, conflicts_with_all = ["bbox", "bbox_file", "bbox_from", "region", "tile_list"])]
+    route: Option<String>,
+
+    /// Distance in kilometres around --route to include
+    #[arg(long, default_value_t = 0.0, requires = "route")]
+    buffer_km: f64,
+
+    /// File listing the tiles to copy, one z/x/y or z,x,y (XYZ) per line
+    #[arg(long)]
+    tile_list: Option<String>,
+
+    /// Lowest zoom level to extract
+    #[arg(long)]
+    minzoom: Option<i32>,
+
+    /// Highest zoom level to extract
+    #[arg(long)]
+    maxzoom: Option<i32>,
+
+    /// Zoom levels to extract: Z or MIN-MAX
+    #[arg(long, value_parser = parse_zoom_range, conflicts_with_all = ["minzoom", "maxzoom"])]
+    zoom: Option<(i32, i32)>,
+
+    /// Also copy N rings of tiles around the area at every zoom level
+    #[arg(long, default_value_t = 0)]
+    buffer: i32,
+
+    /// Copy every tile at zoom levels below this one, for a world overview
+    #[arg(long)]
+    global_below_zoom: Option<i32>,
+
+    /// Output container format (default: guessed from the output extension)
+    #[arg(long, value_enum)]
+    output_format: Option<OutputFormatArg>,
+
+    /// Row numbering of the input MBTiles (default: from `scheme` metadata, else tms)
+    #[arg(long, value_enum)]
+    scheme: Option<SchemeArg>,
+
+    /// Number of reader threads
+    #[arg(long, short = 'j', default_value_t = 1)]
+    jobs: usize,
+
+    /// Store identical tiles once using the map/images schema (MBTiles output)
+    #[arg(long)]
+    dedupe: bool,
+
+    /// Schema of MBTiles output; normalized is the same as --dedupe
+    #[arg(long, value_enum, conflicts_with = "dedupe")]
+    schema: Option<SchemaArg>,
+
+    /// Transcode raster tiles to this image format
+    #[arg(long, value_enum, group = "format_target")]
+    raster_format: Option<RasterFormatArg>,
+
+    /// Make every tile of a mixed tileset this format: raster tiles in other
+    /// formats are transcoded, vector tiles all gzipped unless
+    /// --tile-compression says otherwise. Fails on tiles it can't convert.
+    #[arg(long, value_enum, group = "format_target")]
+    enforce_format: Option<TileFormatArg>,
+
+    /// Encoder quality for JPEG and lossy WebP (0-100) with --raster-format or --enforce-format
+    #[arg(long, default_value_t = 80.0, requires = "format_target")]
+    quality: f32,
+
+    /// Encode WebP losslessly with --raster-format or --enforce-format
+    #[arg(long, requires = "format_target")]
+    lossless: bool,
+
+    /// PNG, JPEG or WebP image drawn onto every raster tile
+    #[arg(long)]
+    watermark: Option<String>,
+
+    /// Corner of each tile the --watermark goes in, or its center
+    #[arg(long, value_enum, default_value_t = WatermarkPositionArg::Br, requires = "watermark")]
+    position: WatermarkPositionArg,
+
+    /// Opacity of the --watermark from 0 to 1
+    #[arg(long, default_value_t = 1.0, requires = "watermark")]
+    opacity: f32,
+
+    /// Keep only these vector tile layers (comma separated)
+    #[arg(long, value_delimiter = ',', conflicts_with = "drop_layers")]
+    keep_layers: Option<Vec<String>>,
+
+    /// Remove these vector tile layers (comma separated)
+    #[arg(long, value_delimiter = ',')]
+    drop_layers: Option<Vec<String>>,
+
+    /// Remove these vector tile feature attributes (comma separated, `*` wildcards allowed)
+    #[arg(long, value_delimiter = ',')]
+    drop_attributes: Vec<String>,
+
+    /// Keep only vector tile features matching this expression, such as
+    /// 'zoom >= 10 && layer == "roads" && properties.class != "service"'
+    #[arg(long, value_name = "EXPR", value_parser = Filter::parse)]
+    filter: Option<Filter>,
+
+    /// Remove vector tile features matching this expression, in the --filter
+    /// syntax, such as 'layer = "poi" AND properties.rank > 5'
+    #[arg(long, value_name = "EXPR", value_parser = Filter::parse)]
+    drop_features: Option<Filter>,
+
+    /// Re-encode vector tiles: none, gzip or gzip:LEVEL
+    #[arg(long, value_parser = TileCompression::parse)]
+    tile_compression: Option<TileCompression>,
+
+    /// Rename a vector tile layer, OLD=NEW (repeatable). Layer options and filters use the old names.
+    #[arg(long, value_name = "OLD=NEW", value_parser = parse_rename)]
+    rename_layer: Vec<(String, String)>,
+
+    /// Rescale vector tile geometry to this extent, e.g. 512 for low precision renderers
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    extent: Option<u32>,
+
+    /// Run this WASI module (a .wasm command) for every tile. It gets the
+    /// tile's z, x and y (XYZ row) as arguments and its blob on stdin, and
+    /// writes the new blob to stdout, or nothing to drop the tile. It runs
+    /// sandboxed, without file, network or environment access.
+    #[arg(long, value_name = "PLUGIN.wasm")]
+    transform: Option<String>,
+
+    /// Replace the output file if it already exists
+    #[arg(long, alias = "force", conflicts_with = "append")]
+    overwrite: bool,
+
+    /// Add tiles to an existing MBTiles output, keeping the tiles it already has
+    #[arg(long)]
+    append: bool,
+
+    /// Only report how many tiles would be copied and their estimated size
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Keep OUTPUT.partial if the extract fails, and continue from it when rerun with the same options
+    #[arg(long, conflicts_with = "append")]
+    resume: bool,
+
+    /// Skip SQLite locking on the input, for files on read-only mounts that nothing else writes to
+    #[arg(long)]
+    immutable: bool,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum OutputFormatArg {
+    Mbtiles,
+    Pmtiles,
+    /// OGC GeoPackage tiles
+    Gpkg,
+    /// {z}/{x}/{y}.{ext} entries of a tar archive, which `-` streams to stdout
+    Tar,
+}
+
+impl From<OutputFormatArg> for OutputFormat {
+    fn from(arg: OutputFormatArg) -> Self {
+        match arg {
+            OutputFormatArg::Mbtiles => OutputFormat::Mbtiles,
+            OutputFormatArg::Pmtiles => OutputFormat::Pmtiles,
+            OutputFormatArg::Gpkg => OutputFormat::Geopackage,
+            OutputFormatArg::Tar => OutputFormat::Tar,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum SchemeArg {
+    Xyz,
+    Tms,
+}
+
+impl From<SchemeArg> for Scheme {
+    fn from(arg: SchemeArg) -> Self {
+        match arg {
+            SchemeArg::Xyz => Scheme::Xyz,
+            SchemeArg::Tms => Scheme::Tms,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum TileFormatArg {
+    Png,
+    Jpg,
+    Webp,
+    Pbf,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum WatermarkPositionArg {
+    Tl,
+    Tr,
+    Bl,
+    Br,
+    Center,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ListFormatArg {
+    Csv,
+    Ndjson,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum DumpFormatArg {
+    /// One {"z","x","y","data"} object per line, data in base64
+    Ndjson,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ConflictArg {
+    First,
+    Last,
+    UnionLayers,
+}
+
+impl From<ConflictArg> for Conflict {
+    fn from(arg: ConflictArg) -> Self {
+        match arg {
+            ConflictArg::First => Conflict::First,
+            ConflictArg::Last => Conflict::Last,
+            ConflictArg::UnionLayers => Conflict::UnionLayers,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum BBoxOrderArg {
+    Wsen,
+    Nesw,
+}
+
+impl From<BBoxOrderArg> for BBoxOrder {
+    fn from(arg: BBoxOrderArg) -> Self {
+        match arg {
+            BBoxOrderArg::Wsen => BBoxOrder::Wsen,
+            BBoxOrderArg::Nesw => BBoxOrder::Nesw,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SchemaArg {
+    Flat,
+    Normalized,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ConvertFormatArg {
+    Mbtiles,
+    /// OGC GeoPackage tiles
+    Gpkg,
+}
+
+impl From<ConvertFormatArg> for OutputFormat {
+    fn from(arg: ConvertFormatArg) -> Self {
+        match arg {
+            ConvertFormatArg::Mbtiles => OutputFormat::Mbtiles,
+            ConvertFormatArg::Gpkg => OutputFormat::Geopackage,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum RasterFormatArg {
+    Png,
+    Jpg,
+    Webp,
+}
+
+impl From<RasterFormatArg> for TileFormat {
+    fn from(arg: RasterFormatArg) -> Self {
+        match arg {
+            RasterFormatArg::Png => TileFormat::Png,
+            RasterFormatArg::Jpg => TileFormat::Jpg,
+            RasterFormatArg::Webp => TileFormat::Webp,
+        }
+    }
+}
+
+impl From<WatermarkPositionArg> for WatermarkPosition {
+    fn from(arg: WatermarkPositionArg) -> Self {
+        match arg {
+            WatermarkPositionArg::Tl => WatermarkPosition::TopLeft,
+            WatermarkPositionArg::Tr => WatermarkPosition::TopRight,
+            WatermarkPositionArg::Bl => WatermarkPosition::BottomLeft,
+            WatermarkPositionArg::Br => WatermarkPosition::BottomRight,
+            WatermarkPositionArg::Center => WatermarkPosition::Center,
+        }
+    }
+}
+
+impl From<TileFormatArg> for TileFormat {
+    fn from(arg: TileFormatArg) -> Self {
+        match arg {
+            TileFormatArg::Png => TileFormat::Png,
+            TileFormatArg::Jpg => TileFormat::Jpg,
+            TileFormatArg::Webp => TileFormat::Webp,
+            TileFormatArg::Pbf => TileFormat::Pbf,
+        }
+    }
+}
+
+/// Terminal progress bar with ETA
+struct BarProgress(ProgressBar);
+
+impl BarProgress {
+    fn new() -> Self {
+        let bar = ProgressBar::new(0);
+        bar.set_style(
+            ProgressStyle::with_template("{bar:40} {pos}/{len} tiles ({per_sec}, ETA {eta})")
+                .expect("valid progress template"),
+        );
+        BarProgress(bar)
+    }
+}
+
+impl Progress for BarProgress {
+    fn start(&self, total: u64) {
+        self.0.set_length(total);
+    }
+
+    fn advance(&self, tiles: u64) {
+        self.0.inc(tiles);
+    }
+
+    fn finish(&self) {
+        self.0.finish_and_clear();
+    }
+}
+
+/// One JSON object per line on stderr, at most once a second, for CI logs
+struct JsonProgress {
+    started: Instant,
+    total: AtomicU64,
+    done: AtomicU64,
+    last_report: Mutex<Instant>,
+}
+
+impl JsonProgress {
+    fn new() -> Self {
+        let now = Instant::now();
+        JsonProgress {
+            started: now,
+            total: AtomicU64::new(0),
+            done: AtomicU64::new(0),
+            last_report: Mutex::new(now),
+        }
+    }
+
+    fn report(&self, event: &str) {
+        let total = self.total.load(Ordering::Relaxed);
+        let done = self.done.load(Ordering::Relaxed);
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let eta = (done > 0 && total >= done).then(|| elapsed / done as f64 * (total - done) as f64);
+        let line = serde_json::json!({
+            "event": event,
+            "done": done,
+            "total": total,
+            "elapsed_secs": (elapsed * 10.0).round() / 10.0,
+            "eta_secs": eta.map(|eta| eta.round()),
+        });
+        eprintln!("{}", line);
+    }
+}
+
+impl Progress for JsonProgress {
+    fn start(&self, total: u64) {
+        self.total.store(total, Ordering::Relaxed);
+        self.report("start");
+    }
+
+    fn advance(&self, tiles: u64) {
+        self.done.fetch_add(tiles, Ordering::Relaxed);
+        let mut last_report = self.last_report.lock().expect("progress lock poisoned");
+        if last_report.elapsed() >= Duration::from_secs(1) {
+            *last_report = Instant::now();
+            self.report("progress");
+        }
+    }
+
+    fn finish(&self) {
+        self.report("finish");
+    }
+}
+
+/// Exit status after Ctrl-C, as shells report for SIGINT
+const EXIT_INTERRUPTED: i32 = 130;
+
+fn main() {
+    let cli = Cli::parse();
+    init_logging(cli.verbose, cli.log_format);
+    let ui = Ui { quiet: cli.quiet, progress: cli.progress, json: cli.json };
+
+    let result = match cli.command {
+        Commands::Extract(args) | Commands::Copy(args) => extract_tiles(args, ui),
+        Commands::Info { input, formats } => print_info(&input, formats, ui),
+        Commands::Stats { input, top, layers, sample } => print_stats(&input, top, layers.then_some(sample), ui),
+        Commands::Tile(args) => dump_tile(args),
+        Commands::List(args) => list_tiles(args),
+        Commands::Validate { input, layer_sample, max_tile_size, oversize_warning } => {
+            let options = ValidateOptions { layer_sample, max_tile_size, oversize_warning };
+            validate_file(&input, &options, ui)
+        }
+        Commands::Check { input, fix, regenerate } => check_file(&input, fix, regenerate, ui),
+        Commands::Dedupe { input } => dedupe_file(&input, ui),
+        Commands::Optimize { input, output } => optimize_file(&input, output.as_deref(), ui),
+        Commands::Diff { old, new, output } => diff_files(&old, &new, output.as_deref(), ui),
+        Commands::Apply { base, diff, output } => apply_diff(&base, &diff, &output, ui),
+        Commands::Sync { source, target, no_delete } => sync_files(&source, &target, !no_delete, ui),
+        Commands::Metadata { command } => edit_metadata(command, ui),
+        Commands::Timestamps { command } => edit_timestamps(command, ui),
+        Commands::Tilejson { input, url_template, output } => print_tilejson(&input, &url_template, output.as_deref()),
+        Commands::Coverage { input, zoom, output } => print_coverage(&input, zoom, output.as_deref()),
+        Commands::Holes { input, output } => find_holes(&input, output.as_deref(), ui),
+        Commands::Recompress { input, output, tile_compression } => recompress(&input, &output, tile_compression, ui),
+        Commands::Simplify { input, output, tolerance } => simplify(&input, &output, tolerance, ui),
+        Commands::ConvertRaster { input, output, to, quality, lossless } => {
+            let conversion = RasterConversion { to: to.into(), quality, lossless };
+            convert_raster(&input, &output, conversion, ui)
+        }
+        Commands::Convert { input, output, to, from } => convert_tiles(&input, &output, to, from, ui),
+        Commands::BuildOverviews { input, min_zoom } => build_overviews(&input, min_zoom, ui),
+        Commands::Overzoom { input, max_zoom } => overzoom_tiles(&input, max_zoom, ui),
+        Commands::Composite { base, overlay, output } => composite_tiles(&base, &overlay, &output, ui),
+        Commands::Erase(args) => erase_tiles(args, ui),
+        Commands::Expire(args) => expire_tiles(args, ui),
+        Commands::Merge { inputs, output, conflict } => merge_files(&inputs, &output, conflict.into(), ui),
+        Commands::Split { input, by_zoom, max_size, output_dir, overwrite } => {
+            split_file(&input, &by_zoom, max_size, output_dir.as_deref(), overwrite, ui)
+        }
+        Commands::ExportDir(args) => export_dir(args, ui),
+        Commands::Dump { input, format: DumpFormatArg::Ndjson, scheme, minzoom, maxzoom } => {
+            dump_tiles(&input, scheme.into(), minzoom, maxzoom, ui)
+        }
+        Commands::Load { output, scheme } => load_tiles(&output, scheme.into(), ui),
+        Commands::ImportDir { input, output, format, scheme } => {
+            import_dir(&input, &output, format.map(TileFormat::from), scheme.into(), ui)
+        }
+        Commands::Serve(args) => serve_tiles(args),
+        Commands::Seed(args) => seed_tiles(args, ui),
+    };
+
+    if let Err(e) = result {
+        let code = if e.is::<Interrupted>() { EXIT_INTERRUPTED } else { 1 };
+        match cli.log_format {
+            LogFormat::Json => tracing::error!(error = %format!("{:#}", e), "command failed"),
+            LogFormat::Text if code == EXIT_INTERRUPTED => eprintln!("Interrupted"),
+            LogFormat::Text => {
+                tracing::debug!("{:#}", e);
+                eprintln!("Error: {}", e);
+            }
+        }
+        std::process::exit(code);
+    }
+}
+
+/// Send `tracing` events from the library to stderr
+fn init_logging(verbose: u8, format: LogFormat) {
+    let level = match verbose {
+        0 => "warn",
+        1 => "info",
+        2 => "debug",
+        _ => "trace",
+    };
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(format!("mbtiles={}", level)));
+    let spans = if verbose >= 2 { FmtSpan::CLOSE } else { FmtSpan::NONE };
+    let builder = tracing_subscriber::fmt().with_env_filter(filter).with_span_events(spans).with_writer(std::io::stderr);
+    match format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().init(),
+    }
+}
+
+/// Parse a --zoom argument: a single zoom level or an inclusive MIN-MAX range
+fn parse_zoom_range(value: &str) -> Result<(i32, i32), String> {
+    let parse = |zoom: &str| zoom.trim().parse::<i32>().map_err(|_| format!("Invalid zoom level: {}", zoom));
+    let (min, max) = match value.split_once('-') {
+        Some((min, max)) => (parse(min)?, parse(max)?),
+        None => (parse(value)?, parse(value)?),
+    };
+    if min > max {
+        return Err(format!("minzoom ({}) is greater than maxzoom ({})", min, max));
+    }
+    Ok((min, max))
+}
+
+/// Parse a --header argument: NAME: VALUE
+fn parse_header(value: &str) -> Result<(String, String), String> {
+    match value.split_once(':') {
+        Some((name, value)) if !name.trim().is_empty() => Ok((name.trim().to_string(), value.trim().to_string())),
+        _ => Err(format!("Expected NAME: VALUE, got {}", value)),
+    }
+}
+
+/// Parse a --rename-layer argument: OLD=NEW
+fn parse_rename(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((old, new)) if !old.is_empty() && !new.is_empty() => Ok((old.to_string(), new.to_string())),
+        _ => Err(format!("Expected OLD=NEW, got {}", value)),
+    }
+}
+
+/// Parse a --mount argument: NAME=PREFIX
+fn parse_mount(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((name, prefix)) if !name.is_empty() => Ok((name.to_string(), prefix.to_string())),
+        _ => Err(format!("Expected NAME=PREFIX, got {}", value)),
+    }
+}
+
+/// Parse a size such as 2GB, 1.5G, 500MiB or 4096. K, M, G and T are powers
+/// of 1024 so parts also fit limits given in binary units.
+fn parse_size(value: &str) -> Result<u64, String> {
+    let upper = value.trim().to_ascii_uppercase();
+    let digits = upper.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let unit = match &upper[digits.len()..] {
+        "" | "B" => 1_u64,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        _ => return Err(format!("Unknown size unit: {}", &value.trim()[digits.len()..])),
+    };
+    let number: f64 = digits.trim().parse().map_err(|_| format!("Invalid size: {}", value))?;
+    if number <= 0.0 {
+        return Err(format!("Size must be positive: {}", value));
+    }
+    Ok((number * unit as f64) as u64)
+}
+
+/// Parse seconds since the Unix epoch, a YYYY-MM-DD date or a
+/// YYYY-MM-DDTHH:MM[:SS][Z] time, both in UTC
+fn parse_timestamp(value: &str) -> Result<i64, String> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<i64>() {
+        return Ok(seconds);
+    }
+    let invalid = || format!("Invalid time, expected YYYY-MM-DD or YYYY-MM-DDTHH:MM:SSZ: {}", value);
+    let (date, time) = value.split_once(['T', ' ']).unwrap_or((value, ""));
+    // Digits only, `parse` would also take a sign
+    let number = |part: Option<&str>| {
+        part.filter(|part| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit()))
+            .and_then(|part| part.parse::<i64>().ok())
+            .ok_or_else(invalid)
+    };
+    let mut parts = date.splitn(3, '-');
+    let (year, month, day) = (number(parts.next())?, number(parts.next())?, number(parts.next())?);
+    let mut clock = [0_i64; 3];
+    if !time.is_empty() {
+        let parts: Vec<&str> = time.trim_end_matches('Z').split(':').collect();
+        if !(2..=3).contains(&parts.len()) {
+            return Err(invalid());
+        }
+        for (i, part) in parts.into_iter().enumerate() {
+            clock[i] = number(Some(part))?;
+        }
+    }
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let month_days = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    let in_range = |value: i64, max: i64| (0..=max).contains(&value);
+    if !(1..=12).contains(&month)
+        || !(1..=month_days).contains(&day)
+        || !in_range(clock[0], 23)
+        || !in_range(clock[1], 59)
+        || !in_range(clock[2], 60)
+    {
+        return Err(invalid());
+    }
+    // Days from 1970-01-01 in the proleptic Gregorian calendar
+    let (y, m) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let era = y.div_euclid(400);
+    let year_of_era = y - era * 400;
+    let day_of_year = (153 * m + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    Ok(days * 86_400 + clock[0] * 3600 + clock[1] * 60 + clock[2])
+}
+
+/// The area given by --bbox arguments or a --region argument
+fn parse_area(bboxes: &[String], order: BBoxOrder, region: Option<&str>) -> Result<Area> {
+    match (bboxes, region) {
+        (_, Some(path)) => Ok(Region::from_geojson_file(path)?.into()),
+        ([], None) => Err(anyhow!("Either --bbox or --region is required")),
+        (bboxes, None) => {
+            let boxes = bboxes.iter().map(|bbox| BoundingBox::parse(bbox, order)).collect::<Result<Vec<_>>>()?;
+            Ok(boxes.into())
+        }
+    }
+}
+
+/// The `bounds` metadata of another tileset
+fn tileset_bounds(path: &str) -> Result<BoundingBox> {
+    let metadata = mbtiles::open_source(path)?.metadata()?;
+    let bounds = metadata
+        .iter()
+        .find(|(name, _)| name == "bounds")
+        .ok_or_else(|| anyhow!("No bounds metadata in {}", path))?;
+    BoundingBox::from_metadata(&bounds.1).ok_or_else(|| anyhow!("Invalid bounds metadata in {}: {}", path, bounds.1))
+}
+
+fn extract_tiles(args: ExtractArgs, ui: Ui) -> Result<()> {
+    // The extract stops at the next range and cleans up; a second Ctrl-C quits at once
+    ctrlc::set_handler(|| {
+        if mbtiles::interrupt::is_interrupted() {
+            std::process::exit(EXIT_INTERRUPTED);
+        }
+        mbtiles::interrupt();
+    })?;
+
+    let area = match (&args.tile_list, &args.region, &args.route) {
+        (Some(path), ..) => TileList::from_file(path)?.into(),
+        (None, Some(path), _) => Region::from_geojson_file(path)?.into(),
+        (None, None, Some(path)) => Route::from_file(path, args.buffer_km)?.into(),
+        (None, None, None) => {
+            let mut boxes = match &args.bbox_file {
+                Some(path) => BoundingBox::list_from_file(path, args.bbox_order.into())?,
+                None => Vec::new(),
+            };
+            if let Some(path) = &args.bbox_from {
+                boxes.push(tileset_bounds(path)?);
+            }
+            for bbox in &args.bbox {
+                boxes.push(BoundingBox::parse(bbox, args.bbox_order.into())?);
+            }
+            if boxes.is_empty() { Area::World } else { boxes.into() }
+        }
+    };
+
+    let mut options = ExtractOptions::new(area);
+    (options.min_zoom, options.max_zoom) = match args.zoom {
+        Some((min, max)) => (Some(min), Some(max)),
+        None => (args.minzoom, args.maxzoom),
+    };
+    options.buffer = args.buffer;
+    options.clip_to_region = args.clip;
+    options.global_below_zoom = args.global_below_zoom;
+    options.mode = match (args.overwrite, args.append) {
+        (true, _) => OutputMode::Overwrite,
+        (_, true) => OutputMode::Append,
+        _ => OutputMode::Create,
+    };
+    options.resume = args.resume;
+    options.immutable_input = args.immutable;
+    options.output_format = args.output_format.map(OutputFormat::from);
+    options.input_scheme = args.scheme.map(Scheme::from);
+    options.jobs = args.jobs;
+    options.dedupe = args.dedupe || args.schema == Some(SchemaArg::Normalized);
+    options.transform.raster =
+        args.raster_format.map(|to| RasterConversion { to: to.into(), quality: args.quality, lossless: args.lossless });
+    options.transform.enforce_format = args.enforce_format.map(TileFormat::from);
+    options.transform.watermark =
+        args.watermark.as_deref().map(|path| Watermark::open(path, args.position.into(), args.opacity)).transpose()?;
+    options.transform.layers = match (args.keep_layers, args.drop_layers) {
+        (Some(names), _) => Some(LayerFilter::Keep(names)),
+        (None, Some(names)) => Some(LayerFilter::Drop(names)),
+        (None, None) => None,
+    };
+    options.transform.drop_attributes = args.drop_attributes;
+    options.transform.filter = args.filter;
+    options.transform.drop_features = args.drop_features;
+    options.transform.extent = args.extent;
+    options.transform.rename_layers = args.rename_layer;
+    options.transform.compression = args.tile_compression;
+    match options.transform.enforce_format {
+        Some(TileFormat::Pbf) => {
+            options.transform.compression.get_or_insert(TileCompression::Gzip(6));
+        }
+        Some(to) => options.transform.raster = Some(RasterConversion { to, quality: args.quality, lossless: args.lossless }),
+        None => {}
+    }
+    options.transform_plugin = args.transform;
+
+    if args.dry_run {
+        let estimates = mbtiles::estimate_extract(&args.input, &options)?;
+        let tiles: u64 = estimates.iter().map(|z| z.tiles).sum();
+        let bytes: u64 = estimates.iter().map(|z| z.bytes).sum();
+        if ui.json {
+            let zooms: Vec<_> = estimates
+                .iter()
+                .map(|z| serde_json::json!({ "zoom": z.zoom, "tiles": z.tiles, "estimated_bytes": z.bytes }))
+                .collect();
+            let report = serde_json::json!({ "zooms": zooms, "tiles": tiles, "estimated_bytes": bytes });
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            return Ok(());
+        }
+        for zoom in &estimates {
+            println!("  z{}: {} tiles, ~{} bytes", zoom.zoom, zoom.tiles, zoom.bytes);
+        }
+        println!("Dry run: {} tiles would be copied, ~{} bytes of tile data", tiles, bytes);
+        return Ok(());
+    }
+
+    let copied = mbtiles::extract_with_progress(&args.input, &args.output, &options, ui.reporter().as_ref())?;
+
+    let message = format!("Extraction complete: {} tiles copied", copied);
+    let details = serde_json::json!({ "tiles_copied": copied, "output": args.output });
+    if args.output == "-" {
+        ui.summary_stderr(&message, details);
+    } else {
+        ui.summary(&message, details);
+    }
+
+    Ok(())
+}
+
+fn print_info(input_path: &str, formats: bool, ui: Ui) -> Result<()> {
+    let info = mbtiles::info(input_path)?;
+    let formats = if formats { mbtiles::format_counts_with_progress(input_path, ui.reporter().as_ref())? } else { Vec::new() };
+
+    if ui.json {
+        let metadata: serde_json::Map<String, serde_json::Value> =
+            info.metadata.iter().map(|(name, value)| (name.clone(), value.as_str().into())).collect();
+        let zooms: Vec<_> = info
+            .zooms
+            .iter()
+            .map(|z| serde_json::json!({ "zoom": z.zoom, "tiles": z.tiles, "bytes": z.bytes }))
+            .collect();
+        let mut report = serde_json::json!({
+            "file": input_path,
+            "type": info.kind.to_string(),
+            "metadata": metadata,
+            "format": info.format.map(|f| f.to_string()),
+            "compression": info.compression.map(|c| c.to_string()),
+            "zooms": zooms,
+            "total_tiles": info.total_tiles(),
+            "total_bytes": info.total_bytes(),
+            "bounds": info.bounds.map(|b| [b.west, b.south, b.east, b.north]),
+        });
+        if !formats.is_empty() {
+            let formats: Vec<_> = formats
+                .iter()
+                .map(|f| serde_json::json!({ "format": f.format.to_string(), "compression": f.compression.to_string(), "tiles": f.tiles }))
+                .collect();
+            report["formats"] = formats.into();
+        }
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+
+    println!("File: {}", input_path);
+    println!("Type: {}", info.kind);
+
+    println!("Metadata:");
+    for (name, value) in &info.metadata {
+        println!("  {}: {}", name, value);
+    }
+
+    match (info.format, info.compression) {
+        (Some(format), Some(Compression::None)) | (Some(format), None) => println!("Tile format: {}", format),
+        (Some(format), Some(compression)) => println!("Tile format: {} ({})", format, compression),
+        (None, _) => println!("Tile format: unknown (no tiles)"),
+    }
+    if !formats.is_empty() {
+        println!("Tiles per format{}:", if formats.len() > 1 { " (mixed)" } else { "" });
+        for f in &formats {
+            match f.compression {
+                Compression::None => println!("  {}: {} tiles", f.format, f.tiles),
+                compression => println!("  {} ({}): {} tiles", f.format, compression, f.tiles),
+            }
+        }
+    }
+
+    println!("Zoom levels:");
+    for zoom in &info.zooms {
+        println!("  z{}: {} tiles, {} bytes", zoom.zoom, zoom.tiles, zoom.bytes);
+    }
+    println!("Total: {} tiles, {} bytes", info.total_tiles(), info.total_bytes());
+
+    if let Some(b) = info.bounds {
+        println!("Computed bounds (W,S,E,N): {:.6},{:.6},{:.6},{:.6}", b.west, b.south, b.east, b.north);
+    }
+
+    Ok(())
+}
+
+/// `layers` is `Some(sample)` to add the per-layer breakdown
+fn print_stats(input_path: &str, top: usize, layers: Option<Option<u64>>, ui: Ui) -> Result<()> {
+    let stats = mbtiles::stats(input_path, top)?;
+    let layers = match layers {
+        Some(sample) => mbtiles::layer_stats_with_progress(input_path, sample, ui.reporter().as_ref())?,
+        None => Vec::new(),
+    };
+
+    if ui.json {
+        let zooms: Vec<_> = stats
+            .zooms
+            .iter()
+            .map(|z| {
+                serde_json::json!({
+                    "zoom": z.zoom, "tiles": z.tiles, "bytes": z.bytes, "min": z.min, "max": z.max,
+                    "mean": z.mean, "p50": z.p50, "p90": z.p90, "p99": z.p99,
+                })
+            })
+            .collect();
+        let largest: Vec<_> = stats
+            .largest
+            .iter()
+            .map(|large| {
+                let t = large.size;
+                let mut tile = serde_json::json!({ "z": t.zoom, "x": t.x, "y": Scheme::Xyz.from_tms(t.zoom, t.y), "bytes": t.bytes });
+                if !large.layers.is_empty() {
+                    let layers: Vec<_> = large
+                        .layers
+                        .iter()
+                        .map(|l| serde_json::json!({ "name": l.name, "bytes": l.bytes, "features": l.features }))
+                        .collect();
+                    tile["layers"] = layers.into();
+                }
+                tile
+            })
+            .collect();
+        let mut report = serde_json::json!({ "zooms": zooms, "largest": largest });
+        if !layers.is_empty() {
+            let layers: Vec<_> = layers
+                .iter()
+                .map(|z| {
+                    let layers: Vec<_> = z
+                        .layers
+                        .iter()
+                        .map(|l| {
+                            serde_json::json!({
+                                "name": l.name, "tiles": l.tiles, "features": l.features, "bytes": l.bytes, "max": l.max,
+                            })
+                        })
+                        .collect();
+                    serde_json::json!({ "zoom": z.zoom, "tiles": z.tiles, "decoded": z.decoded, "layers": layers })
+                })
+                .collect();
+            report["layers"] = layers.into();
+        }
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+
+    println!("Zoom levels (sizes in bytes):");
+    for z in &stats.zooms {
+        println!(
+            "  z{}: {} tiles, {} bytes total, min {}, max {}, mean {:.0}, p50 {}, p90 {}, p99 {}",
+            z.zoom, z.tiles, z.bytes, z.min, z.max, z.mean, z.p50, z.p90, z.p99
+        );
+    }
+
+    if !stats.largest.is_empty() {
+        println!("Largest tiles (z/x/y, xyz):");
+        for large in &stats.largest {
+            let tile = large.size;
+            let y = Scheme::Xyz.from_tms(tile.zoom, tile.y);
+            println!("  {}/{}/{}: {} bytes", tile.zoom, tile.x, y, tile.bytes);
+            // The layers making up most of it, sizes are uncompressed
+            let total: u64 = large.layers.iter().map(|l| l.bytes).sum();
+            for l in large.layers.iter().take(3) {
+                println!(
+                    "    {}: {} bytes uncompressed ({:.1}%), {} features",
+                    l.name,
+                    l.bytes,
+                    100.0 * l.bytes as f64 / total.max(1) as f64,
+                    l.features
+                );
+            }
+            if large.layers.len() > 3 {
+                println!("    ... and {} more layers", large.layers.len() - 3);
+            }
+        }
+    }
+
+    if !layers.is_empty() {
+        println!("Layers per zoom level (uncompressed bytes):");
+        for z in &layers {
+            let sampled = if z.decoded < z.tiles { format!(", {} of {} tiles decoded", z.decoded, z.tiles) } else { String::new() };
+            println!("  z{}{}:", z.zoom, sampled);
+            let total: u64 = z.layers.iter().map(|l| l.bytes).sum();
+            for l in &z.layers {
+                println!(
+                    "    {}: {} bytes ({:.1}%), {} features in {} tiles, max {} bytes",
+                    l.name,
+                    l.bytes,
+                    100.0 * l.bytes as f64 / total.max(1) as f64,
+                    l.features,
+                    l.tiles,
+                    l.max
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn dump_tile(args: TileArgs) -> Result<()> {
+    let (zoom, x, y, scheme) = match (&args.quadkey, args.zoom, args.x, args.y) {
+        (Some(key), ..) => {
+            let tile = TileCoord::from_quadkey(key)?;
+            (tile.zoom, tile.x, tile.y, Scheme::Xyz)
+        }
+        (None, Some(zoom), Some(x), Some(y)) => (zoom, x, y, args.scheme.into()),
+        _ => return Err(anyhow!("Either zoom, x and y or --quadkey is required")),
+    };
+    let tms_y = scheme.to_tms(zoom, y);
+    let Some(data) = mbtiles::open_source(&args.input)?.tile(zoom, x, tms_y)? else {
+        return Err(anyhow!("No tile at {}/{}/{} in {}", zoom, x, y, args.input));
+    };
+
+    let out = if args.decode {
+        let tile = VectorTile::decode(&mbtiles::decompress(&data)?)?;
+        let mut json = serde_json::to_string_pretty(&tile.to_geojson(zoom, x, tms_y)?)?;
+        json.push('\n');
+        json.into_bytes()
+    } else if args.decompress {
+        mbtiles::decompress(&data)?
+    } else {
+        data
+    };
+
+    match &args.output {
+        Some(path) => std::fs::write(path, &out).map_err(|e| anyhow!("Failed to write {}: {}", path, e))?,
+        None => std::io::stdout().write_all(&out)?,
+    }
+
+    Ok(())
+}
+
+fn list_tiles(args: ListArgs) -> Result<()> {
+    let area = match (&args.bbox[..], &args.region) {
+        ([], None) => None,
+        (bbox, region) => Some(parse_area(bbox, args.bbox_order.into(), region.as_deref())?),
+    };
+    let options = &ListOptions {
+        area,
+        min_zoom: args.minzoom,
+        max_zoom: args.maxzoom,
+        hash: args.hash,
+        updated_since: args.updated_since,
+        created_since: args.created_since,
+    };
+    let (format, scheme, quadkey) = (args.format, Scheme::from(args.scheme), args.quadkey);
+
+    let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+    if format == ListFormatArg::Csv {
+        let mut header = String::from("z,x,y,size_bytes");
+        if quadkey {
+            header.push_str(",quadkey");
+        }
+        if options.hash {
+            header.push_str(",hash");
+        }
+        writeln!(out, "{}", header)?;
+    }
+
+    mbtiles::list_tiles(&args.input, options, &mut |entry| {
+        let y = scheme.from_tms(entry.zoom, entry.y);
+        let key = quadkey.then(|| TileCoord::from_tms(entry.zoom, entry.x, entry.y).quadkey());
+        match format {
+            ListFormatArg::Csv => {
+                write!(out, "{},{},{},{}", entry.zoom, entry.x, y, entry.bytes)?;
+                for value in [&key, &entry.hash].into_iter().flatten() {
+                    write!(out, ",{}", value)?;
+                }
+                writeln!(out)?;
+            }
+            ListFormatArg::Ndjson => {
+                let mut row = serde_json::json!({ "z": entry.zoom, "x": entry.x, "y": y, "size_bytes": entry.bytes });
+                if let Some(key) = key {
+                    row["quadkey"] = key.into();
+                }
+                if let Some(hash) = &entry.hash {
+                    row["hash"] = hash.as_str().into();
+                }
+                writeln!(out, "{}", row)?;
+            }
+        }
+        Ok(())
+    })?;
+
+    out.flush()?;
+    Ok(())
+}
+
+fn validate_file(input_path: &str, options: &ValidateOptions, ui: Ui) -> Result<()> {
+    let report = mbtiles::validate_with(input_path, options)?;
+
+    if ui.json {
+        let json = serde_json::json!({
+            "file": input_path,
+            "valid": report.is_valid(),
+            "errors": report.errors,
+            "warnings": report.warnings,
+        });
+        println!("{}", serde_json::to_string_pretty(&json)?);
+    } else {
+        for warning in &report.warnings {
+            println!("warning: {}", warning);
+        }
+        for error in &report.errors {
+            println!("error: {}", error);
+        }
+    }
+
+    if !report.is_valid() {
+        return Err(anyhow!("{} failed validation with {} errors", input_path, report.errors.len()));
+    }
+
+    if !ui.json {
+        println!("{} is valid", input_path);
+    }
+    Ok(())
+}
+
+fn check_file(input_path: &str, fix: bool, regenerate: bool, ui: Ui) -> Result<()> {
+    let (report, repair) = if fix {
+        let repair = mbtiles::repair_with_progress(input_path, regenerate, ui.reporter().as_ref())?;
+        (repair.check.clone(), Some(repair))
+    } else {
+        (mbtiles::check_with_progress(input_path, ui.reporter().as_ref())?, None)
+    };
+    // Bad tiles no longer count once deleted
+    let remaining = if fix { 0 } else { report.bad_tiles.len() };
+    let ok = repair.as_ref().map_or(report.is_ok(), |repair| repair.is_ok());
+    let index_missing = !report.has_tile_index && !repair.as_ref().is_some_and(|repair| repair.index_created);
+    let missing_metadata: Vec<&String> = report
+        .missing_metadata
+        .iter()
+        .filter(|name| !repair.as_ref().is_some_and(|repair| repair.metadata_added.iter().any(|(added, _)| added == *name)))
+        .collect();
+
+    if ui.json {
+        let bad_tiles: Vec<_> = report
+            .bad_tiles
+            .iter()
+            .map(|tile| serde_json::json!({ "z": tile.zoom, "x": tile.x, "y": tile.y, "problem": tile.problem.to_string() }))
+            .collect();
+        let mut json = serde_json::json!({
+            "file": input_path,
+            "ok": ok,
+            "integrity_errors": report.integrity_errors,
+            "tile_index": report.has_tile_index,
+            "format": report.format.map(|format| format.to_string()),
+            "tiles": report.tiles,
+            "bad_tiles": bad_tiles,
+            "missing_metadata": report.missing_metadata,
+        });
+        if let Some(repair) = &repair {
+            json["tiles_deleted"] = repair.deleted.into();
+            json["tiles_regenerated"] = repair.regenerated.into();
+            json["tile_index_created"] = repair.index_created.into();
+            let added: serde_json::Map<String, serde_json::Value> =
+                repair.metadata_added.iter().map(|(name, value)| (name.clone(), value.clone().into())).collect();
+            json["metadata_added"] = added.into();
+        }
+        println!("{}", serde_json::to_string_pretty(&json)?);
+    } else {
+        for error in &report.integrity_errors {
+            println!("integrity: {}", error);
+        }
+        if !report.has_tile_index {
+            println!("error: no unique index on zoom_level, tile_column, tile_row");
+        }
+        for name in &report.missing_metadata {
+            println!("error: missing metadata key {}", name);
+        }
+        if report.format.is_none() {
+            println!("warning: no known format in metadata, tile formats not checked");
+        }
+        for tile in &report.bad_tiles {
+            println!("Tile {}/{}/{} has {}", tile.zoom, tile.x, tile.y, tile.problem);
+        }
+        if let Some(repair) = &repair {
+            println!("Fixed: {} bad tiles deleted, {} regenerated", repair.deleted, repair.regenerated);
+            if repair.index_created {
+                println!("Fixed: created the unique tile index");
+            }
+            for (name, value) in &repair.metadata_added {
+                println!("Fixed: set metadata {} to {}", name, value);
+            }
+        }
+    }
+
+    if !ok {
+        return Err(anyhow!(
+            "{} failed the check: {} integrity errors, {} bad tiles, {} missing metadata keys{}",
+            input_path,
+            report.integrity_errors.len(),
+            remaining,
+            missing_metadata.len(),
+            if index_missing { ", missing tile index" } else { "" }
+        ));
+    }
+
+    if !ui.json {
+        let tiles = repair.map_or(report.tiles, |repair| report.tiles - repair.deleted + repair.regenerated);
+        println!("{}: {} tiles OK", input_path, tiles);
+    }
+    Ok(())
+}
+
+fn dedupe_file(input_path: &str, ui: Ui) -> Result<()> {
+    let report = mbtiles::dedupe_with_progress(input_path, ui.reporter().as_ref())?;
+
+    ui.summary(
+        &format!(
+            "Dedupe complete: {} tiles, {} unique, {} bytes saved ({} -> {} bytes)",
+            report.tiles,
+            report.unique,
+            report.bytes_saved(),
+            report.bytes_before,
+            report.bytes_after
+        ),
+        serde_json::json!({
+            "tiles": report.tiles,
+            "unique": report.unique,
+            "bytes_before": report.bytes_before,
+            "bytes_after": report.bytes_after,
+        }),
+    );
+
+    Ok(())
+}
+
+fn optimize_file(input_path: &str, output_path: Option<&str>, ui: Ui) -> Result<()> {
+    let report = mbtiles::optimize(input_path, output_path)?;
+
+    ui.summary(
+        &format!(
+            "Optimize complete: {} free pages reclaimed, {} bytes saved ({} -> {} bytes)",
+            report.free_pages,
+            report.bytes_saved(),
+            report.bytes_before,
+            report.bytes_after
+        ),
+        serde_json::json!({
+            "output": output_path.unwrap_or(input_path),
+            "free_pages": report.free_pages,
+            "bytes_before": report.bytes_before,
+            "bytes_after": report.bytes_after,
+        }),
+    );
+
+    Ok(())
+}
+
+fn diff_files(old_path: &str, new_path: &str, output_path: Option<&str>, ui: Ui) -> Result<()> {
+    let report = mbtiles::diff_with_progress(old_path, new_path, output_path, ui.reporter().as_ref())?;
+
+    if ui.json {
+        let zooms: Vec<_> = report
+            .zooms
+            .iter()
+            .filter(|z| z.added + z.changed + z.removed + z.unchanged > 0)
+            .map(|z| {
+                serde_json::json!({
+                    "zoom": z.zoom, "added": z.added, "changed": z.changed, "removed": z.removed, "unchanged": z.unchanged,
+                })
+            })
+            .collect();
+        let json = serde_json::json!({
+            "metadata_changed": report.metadata,
+            "zooms": zooms,
+            "added": report.added(),
+            "changed": report.changed(),
+            "removed": report.removed(),
+            "unchanged": report.unchanged(),
+            "output": output_path,
+        });
+        println!("{}", serde_json::to_string_pretty(&json)?);
+        return Ok(());
+    }
+
+    for name in &report.metadata {
+        println!("metadata changed: {}", name);
+    }
+    for zoom in report.zooms.iter().filter(|z| z.added + z.changed + z.removed + z.unchanged > 0) {
+        println!(
+            "  z{}: {} added, {} changed, {} removed, {} unchanged",
+            zoom.zoom, zoom.added, zoom.changed, zoom.removed, zoom.unchanged
+        );
+    }
+    println!(
+        "Total: {} added, {} changed, {} removed, {} unchanged",
+        report.added(),
+        report.changed(),
+        report.removed(),
+        report.unchanged()
+    );
+
+    if let Some(path) = output_path {
+        ui.summary(&format!("Diff written to {}", path), serde_json::json!({ "output": path }));
+    }
+
+    Ok(())
+}
+
+fn apply_diff(base_path: &str, diff_path: &str, output_path: &str, ui: Ui) -> Result<()> {
+    let written = mbtiles::apply_with_progress(base_path, diff_path, output_path, ui.reporter().as_ref())?;
+
+    ui.summary(
+        &format!("Apply complete: {} tiles written to {}", written, output_path),
+        serde_json::json!({ "tiles_written": written, "output": output_path }),
+    );
+
+    Ok(())
+}
+
+fn sync_files(source_path: &str, target_path: &str, delete: bool, ui: Ui) -> Result<()> {
+    let report = mbtiles::sync_with_progress(source_path, target_path, delete, ui.reporter().as_ref())?;
+
+    ui.summary(
+        &format!(
+            "Sync complete: {} added, {} changed, {} removed, {} unchanged, {} metadata values updated ({} bytes written)",
+            report.added, report.changed, report.removed, report.unchanged, report.metadata, report.bytes
+        ),
+        serde_json::json!({
+            "added": report.added,
+            "changed": report.changed,
+            "removed": report.removed,
+            "unchanged": report.unchanged,
+            "metadata": report.metadata,
+            "bytes_written": report.bytes,
+            "target": target_path,
+        }),
+    );
+
+    Ok(())
+}
+
+fn edit_metadata(command: MetadataCommand, ui: Ui) -> Result<()> {
+    match command {
+        MetadataCommand::List { input } => {
+            let metadata = mbtiles::open_source(&input)?.metadata()?;
+            if ui.json {
+                let object: serde_json::Map<String, serde_json::Value> = metadata
+                    .into_iter()
+                    .map(|(name, value)| (name, serde_json::Value::String(value)))
+                    .collect();
+                println!("{}", serde_json::to_string_pretty(&object)?);
+            } else {
+                for (name, value) in metadata {
+                    println!("{}: {}", name, value);
+                }
+            }
+        }
+        MetadataCommand::Get { input, name } => {
+            let metadata = mbtiles::open_source(&input)?.metadata()?;
+            let Some((_, value)) = metadata.into_iter().find(|(key, _)| *key == name) else {
+                return Err(anyhow!("No metadata value for {} in {}", name, input));
+            };
+            println!("{}", value);
+        }
+        MetadataCommand::Set { input, name, value, json_value } => {
+            let value = if json_value {
+                let parsed: serde_json::Value =
+                    serde_json::from_str(&value).map_err(|e| anyhow!("Invalid JSON value for {}: {}", name, e))?;
+                serde_json::to_string(&parsed)?
+            } else {
+                value
+            };
+            MbtilesWriter::open(&input)?.set_metadata(&name, &value)?;
+            ui.summary(&format!("Set {} in {}", name, input), serde_json::json!({ "set": name, "file": input }));
+        }
+        MetadataCommand::Delete { input, name } => {
+            if !MbtilesWriter::open(&input)?.delete_metadata(&name)? {
+                return Err(anyhow!("No metadata value for {} in {}", name, input));
+            }
+            ui.summary(
+                &format!("Deleted {} from {}", name, input),
+                serde_json::json!({ "deleted": name, "file": input }),
+            );
+        }
+        MetadataCommand::GenerateJson { input, sample, dry_run } => {
+            if dry_run {
+                let layers = mbtiles::scan_vector_layers_with_progress(&input, sample, ui.reporter().as_ref())?;
+                let value: serde_json::Value = serde_json::from_str(&mbtiles::vector_layers_json(&input, &layers)?)?;
+                println!("{}", serde_json::to_string_pretty(&value)?);
+                return Ok(());
+            }
+            let layers = mbtiles::generate_vector_layers_with_progress(&input, sample, ui.reporter().as_ref())?;
+            let names: Vec<&str> = layers.iter().map(|layer| layer.id.as_str()).collect();
+            ui.summary(
+                &format!("Set json in {} with {} vector layers: {}", input, layers.len(), names.join(", ")),
+                serde_json::json!({ "set": "json", "file": input, "vector_layers": names }),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn print_tilejson(input_path: &str, url_template: &str, output_path: Option<&str>) -> Result<()> {
+    let doc = serde_json::to_string_pretty(&mbtiles::tilejson(input_path, url_template)?)?;
+
+    match output_path {
+        Some(path) => std::fs::write(path, doc + "\n").map_err(|e| anyhow!("Failed to write {}: {}", path, e))?,
+        None => println!("{}", doc),
+    }
+
+    Ok(())
+}
+
+fn print_coverage(input_path: &str, zoom: Option<i32>, output_path: Option<&str>) -> Result<()> {
+    let doc = serde_json::to_string_pretty(&mbtiles::coverage(input_path, zoom)?.to_geojson())?;
+
+    match output_path {
+        Some(path) => std::fs::write(path, doc + "\n").map_err(|e| anyhow!("Failed to write {}: {}", path, e))?,
+        None => println!("{}", doc),
+    }
+
+    Ok(())
+}
+
+fn find_holes(input_path: &str, output_path: Option<&str>, ui: Ui) -> Result<()> {
+    let missing = mbtiles::holes(input_path)?;
+
+    let mut per_zoom: BTreeMap<i32, u64> = BTreeMap::new();
+    for tile in &missing {
+        *per_zoom.entry(tile.zoom).or_default() += 1;
+    }
+    if let Some(path) = output_path {
+        let lines: String = missing.iter().map(|tile| format!("{}\n", tile)).collect();
+        std::fs::write(path, lines).map_err(|e| anyhow!("Failed to write {}: {}", path, e))?;
+    } else if !ui.json {
+        for tile in &missing {
+            println!("{}", tile);
+        }
+    }
+
+    let mut details = serde_json::json!({
+        "missing": missing.len(),
+        "per_zoom": per_zoom,
+    });
+    match output_path {
+        Some(path) => details["output"] = path.into(),
+        None => details["tiles"] = missing.iter().map(|tile| tile.to_string()).collect(),
+    }
+    let zooms: Vec<String> = per_zoom.iter().map(|(zoom, count)| format!("z{}: {}", zoom, count)).collect();
+    ui.summary(
+        &if zooms.is_empty() {
+            "No holes: every expected child tile exists".to_string()
+        } else {
+            format!("Holes: {} missing tiles ({})", missing.len(), zooms.join(", "))
+        },
+        details,
+    );
+
+    Ok(())
+}
+
+fn recompress(input_path: &str, output_path: &str, compression: TileCompression, ui: Ui) -> Result<()> {
+    let transform = TileTransform { compression: Some(compression), ..Default::default() };
+    let written = mbtiles::transform_with_progress(input_path, output_path, &transform, ui.reporter().as_ref())?;
+
+    let before = std::fs::metadata(input_path)?.len();
+    let after = std::fs::metadata(output_path)?.len();
+    ui.summary(
+        &format!("Recompress complete: {} tiles, {} -> {} bytes", written, before, after),
+        serde_json::json!({ "tiles": written, "bytes_before": before, "bytes_after": after }),
+    );
+
+    Ok(())
+}
+
+fn simplify(input_path: &str, output_path: &str, tolerance: f64, ui: Ui) -> Result<()> {
+    if tolerance.is_nan() || tolerance < 0.0 {
+        return Err(anyhow!("Invalid tolerance: {}", tolerance));
+    }
+    let transform = TileTransform { simplify: Some(tolerance), ..Default::default() };
+    let written = mbtiles::transform_with_progress(input_path, output_path, &transform, ui.reporter().as_ref())?;
+
+    let before = std::fs::metadata(input_path)?.len();
+    let after = std::fs::metadata(output_path)?.len();
+    ui.summary(
+        &format!("Simplify complete: {} tiles, {} -> {} bytes", written, before, after),
+        serde_json::json!({ "tiles": written, "bytes_before": before, "bytes_after": after }),
+    );
+
+    Ok(())
+}
+
+fn convert_raster(input_path: &str, output_path: &str, conversion: RasterConversion, ui: Ui) -> Result<()> {
+    let transform = TileTransform { raster: Some(conversion), ..Default::default() };
+    let written = mbtiles::transform_with_progress(input_path, output_path, &transform, ui.reporter().as_ref())?;
+
+    let before = std::fs::metadata(input_path)?.len();
+    let after = std::fs::metadata(output_path)?.len();
+    ui.summary(
+        &format!("Conversion complete: {} tiles, {} -> {} bytes", written, before, after),
+        serde_json::json!({ "tiles": written, "bytes_before": before, "bytes_after": after }),
+    );
+
+    Ok(())
+}
+
+fn convert_tiles(
+    input_path: &str,
+    output_path: &str,
+    to: Option<ConvertFormatArg>,
+    from: Option<ConvertFormatArg>,
+    ui: Ui,
+) -> Result<()> {
+    match from {
+        Some(ConvertFormatArg::Gpkg) if !mbtiles::source::is_geopackage(input_path) => {
+            return Err(anyhow!("Not a GeoPackage: {}", input_path));
+        }
+        Some(ConvertFormatArg::Mbtiles) if !matches!(mbtiles::open_source(input_path)?.kind(), SourceKind::Mbtiles(_)) => {
+            return Err(anyhow!("Not an MBTiles file: {}", input_path));
+        }
+        _ => {}
+    }
+    let to = match (to, from) {
+        (Some(to), _) => to,
+        (None, Some(ConvertFormatArg::Gpkg)) => ConvertFormatArg::Mbtiles,
+        (None, _) => ConvertFormatArg::Gpkg,
+    };
+
+    let mut options = ExtractOptions::new(Area::World);
+    options.output_format = Some(to.into());
+    let written = mbtiles::extract_with_progress(input_path, output_path, &options, ui.reporter().as_ref())?;
+
+    ui.summary(
+        &format!("Conversion complete: {} tiles written to {}", written, output_path),
+        serde_json::json!({ "tiles": written, "output": output_path }),
+    );
+
+    Ok(())
+}
+
+fn build_overviews(input_path: &str, min_zoom: i32, ui: Ui) -> Result<()> {
+    let written = mbtiles::build_overviews_with_progress(input_path, min_zoom, ui.reporter().as_ref())?;
+
+    ui.summary(
+        &format!("Overviews complete: {} tiles written down to zoom {}", written, min_zoom),
+        serde_json::json!({ "tiles_written": written, "min_zoom": min_zoom }),
+    );
+
+    Ok(())
+}
+
+fn overzoom_tiles(input_path: &str, max_zoom: i32, ui: Ui) -> Result<()> {
+    let written = mbtiles::overzoom_with_progress(input_path, max_zoom, ui.reporter().as_ref())?;
+
+    ui.summary(
+        &format!("Overzoom complete: {} tiles filled down to zoom {}", written, max_zoom),
+        serde_json::json!({ "tiles_written": written, "max_zoom": max_zoom }),
+    );
+
+    Ok(())
+}
+
+fn composite_tiles(base_path: &str, overlay_path: &str, output_path: &str, ui: Ui) -> Result<()> {
+    let (written, blended) = mbtiles::composite_with_progress(base_path, overlay_path, output_path, ui.reporter().as_ref())?;
+
+    ui.summary(
+        &format!("Composite complete: {} tiles, {} blended with the overlay", written, blended),
+        serde_json::json!({ "tiles": written, "blended": blended }),
+    );
+
+    Ok(())
+}
+
+fn seed_tiles(args: SeedArgs, ui: Ui) -> Result<()> {
+    let area = if args.bbox.is_empty() && args.region.is_none() {
+        Area::World
+    } else {
+        parse_area(&args.bbox, args.bbox_order.into(), args.region.as_deref())?
+    };
+    let (min_zoom, max_zoom) = args.zoom;
+    let mut options = mbtiles::SeedOptions::new(area, min_zoom, max_zoom);
+    options.concurrency = args.concurrency;
+    options.rate = args.rate;
+    options.retries = args.retries;
+    options.attribution = args.attribution;
+    options.headers = args.headers;
+    options.resume = args.resume;
+    options.retry_failed = args.retry_failed;
+    options.refresh = args.refresh;
+    ctrlc::set_handler(|| {
+        if mbtiles::interrupt::is_interrupted() {
+            std::process::exit(EXIT_INTERRUPTED);
+        }
+        mbtiles::interrupt();
+    })?;
+    let report = mbtiles::seed_with_progress(&args.url, &args.output, &options, ui.reporter().as_ref())?;
+
+    let message = if args.refresh {
+        format!(
+            "Refresh complete: {} tiles ({} bytes) updated in {}, {} unchanged, {} removed, {} failed",
+            report.downloaded, report.bytes, args.output, report.unchanged, report.removed, report.failed
+        )
+    } else {
+        format!(
+            "Seed complete: {} tiles ({} bytes) written to {}, {} missing upstream, {} failed",
+            report.downloaded, report.bytes, args.output, report.missing, report.failed
+        )
+    };
+    ui.summary(
+        &message,
+        serde_json::json!({
+            "tiles_written": report.downloaded,
+            "bytes": report.bytes,
+            "missing": report.missing,
+            "failed": report.failed,
+            "failures_left": report.failures_left,
+            "unchanged": report.unchanged,
+            "removed": report.removed,
+            "output": args.output,
+        }),
+    );
+    if report.failures_left > 0 {
+        return Err(anyhow!(
+            "{} tiles could not be downloaded, run again with --retry-failed to retry them",
+            report.failures_left
+        ));
+    }
+    Ok(())
+}
+
+fn erase_tiles(args: EraseArgs, ui: Ui) -> Result<()> {
+    let input_path = &args.input;
+    let area = parse_area(&args.bbox, args.bbox_order.into(), args.region.as_deref())?;
+    let before = std::fs::metadata(input_path)?.len();
+    let deleted = mbtiles::erase(input_path, &area, args.invert, args.minzoom, args.maxzoom)?;
+    let after = std::fs::metadata(input_path)?.len();
+
+    ui.summary(
+        &format!("Erase complete: {} tiles deleted, {} -> {} bytes", deleted, before, after),
+        serde_json::json!({ "tiles_deleted": deleted, "bytes_before": before, "bytes_after": after }),
+    );
+
+    Ok(())
+}
+
+fn expire_tiles(args: ExpireArgs, ui: Ui) -> Result<()> {
+    let tiles = TileList::from_file(&args.list)?;
+    let deleted = mbtiles::expire(&args.input, &tiles, args.ancestors, args.descendants)?;
+
+    ui.summary(
+        &format!("Expire complete: {} tiles listed, {} tiles deleted from {}", tiles.len(), deleted, args.input),
+        serde_json::json!({ "tiles_listed": tiles.len(), "tiles_deleted": deleted, "input": args.input }),
+    );
+
+    Ok(())
+}
+
+fn edit_timestamps(command: TimestampsCommand, ui: Ui) -> Result<()> {
+    match command {
+        TimestampsCommand::Enable { input } => {
+            let stamped = mbtiles::enable_timestamps(&input)?;
+            let message = match stamped {
+                Some(stamped) => format!("Recording tile timestamps in {}, {} existing tiles stamped", input, stamped),
+                None => format!("Already recording tile timestamps in {}", input),
+            };
+            ui.summary(&message, serde_json::json!({ "file": input, "tiles_stamped": stamped.unwrap_or(0) }));
+        }
+        TimestampsCommand::Disable { input } => {
+            if !mbtiles::disable_timestamps(&input)? {
+                return Err(anyhow!("{} has no tile timestamps", input));
+            }
+            ui.summary(
+                &format!("Stopped recording tile timestamps in {}", input),
+                serde_json::json!({ "file": input }),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn merge_files(inputs: &[String], output_path: &str, conflict: Conflict, ui: Ui) -> Result<()> {
+    let inputs: Vec<&str> = inputs.iter().map(String::as_str).collect();
+    let written = mbtiles::merge_with_progress(&inputs, output_path, conflict, ui.reporter().as_ref())?;
+
+    ui.summary(
+        &format!("Merge complete: {} tiles written to {}", written, output_path),
+        serde_json::json!({ "tiles_written": written, "output": output_path }),
+    );
+
+    Ok(())
+}
+
+fn split_file(
+    input_path: &str,
+    ranges: &[(i32, i32)],
+    max_size: Option<u64>,
+    output_dir: Option<&str>,
+    overwrite: bool,
+    ui: Ui,
+) -> Result<()> {
+    let output_dir = match output_dir {
+        Some(dir) => dir.to_string(),
+        None => Path::new(input_path).parent().map_or(String::new(), |parent| parent.to_string_lossy().into_owned()),
+    };
+    let mode = if overwrite { OutputMode::Overwrite } else { OutputMode::Create };
+    let progress = ui.reporter();
+    let parts = match max_size {
+        Some(max_bytes) => mbtiles::split_by_size_with_progress(input_path, max_bytes, &output_dir, mode, progress.as_ref())?,
+        None => mbtiles::split_by_zoom_with_progress(input_path, ranges, &output_dir, mode, progress.as_ref())?,
+    };
+
+    let mut message = String::new();
+    for part in &parts {
+        message.push_str(&format!(
+            "  {}: z{}-{}, {} tiles, {} bytes\n",
+            part.path, part.min_zoom, part.max_zoom, part.tiles, part.bytes
+        ));
+    }
+    message.push_str(&format!("Split complete: {} parts written", parts.len()));
+    let details: Vec<_> = parts
+        .iter()
+        .map(|part| {
+            serde_json::json!({
+                "path": part.path,
+                "min_zoom": part.min_zoom,
+                "max_zoom": part.max_zoom,
+                "tiles": part.tiles,
+                "bytes": part.bytes,
+            })
+        })
+        .collect();
+    ui.summary(&message, serde_json::json!({ "parts": details }));
+
+    Ok(())
+}
+
+fn export_dir(args: ExportDirArgs, ui: Ui) -> Result<()> {
+    let progress = ui.reporter();
+    let written = mbtiles::export_dir_with_progress(
+        &args.input,
+        &args.output,
+        args.scheme.into(),
+        args.minzoom,
+        args.maxzoom,
+        progress.as_ref(),
+    )?;
+
+    let Some(target) = args.upload else {
+        ui.summary(
+            &format!("Export complete: {} tiles written to {}", written, args.output),
+            serde_json::json!({ "tiles_written": written, "output": args.output }),
+        );
+        return Ok(());
+    };
+
+    let env = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+    let (Some(access_key_id), Some(secret_access_key)) = (env("AWS_ACCESS_KEY_ID"), env("AWS_SECRET_ACCESS_KEY")) else {
+        return Err(anyhow!("--upload needs AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY set"));
+    };
+    let credentials = mbtiles::Credentials { access_key_id, secret_access_key, session_token: env("AWS_SESSION_TOKEN") };
+    let mut options = mbtiles::UploadOptions::new(credentials);
+    if let Some(region) = args.region.or_else(|| env("AWS_REGION")).or_else(|| env("AWS_DEFAULT_REGION")) {
+        options.region = region;
+    }
+    options.endpoint = args.endpoint.or_else(|| env("AWS_ENDPOINT_URL"));
+    options.concurrency = args.upload_concurrency;
+    options.part_size = args.upload_part_size;
+    let progress = ui.reporter();
+    let uploaded = mbtiles::upload_dir_with_progress(&args.output, &target, &options, progress.as_ref())?;
+
+    ui.summary(
+        &format!(
+            "Export complete: {} tiles written to {}, {} files ({} bytes) uploaded to {}",
+            written, args.output, uploaded.files, uploaded.bytes, target
+        ),
+        serde_json::json!({
+            "tiles_written": written,
+            "output": args.output,
+            "upload": target,
+            "files_uploaded": uploaded.files,
+            "bytes_uploaded": uploaded.bytes,
+        }),
+    );
+
+    Ok(())
+}
+
+fn dump_tiles(input_path: &str, scheme: Scheme, min_zoom: Option<i32>, max_zoom: Option<i32>, ui: Ui) -> Result<()> {
+    let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+    let progress = ui.reporter();
+    let written = mbtiles::dump_ndjson_with_progress(input_path, &mut out, scheme, min_zoom, max_zoom, progress.as_ref())?;
+
+    ui.summary_stderr(
+        &format!("Dump complete: {} tiles written", written),
+        serde_json::json!({ "tiles_written": written }),
+    );
+
+    Ok(())
+}
+
+fn load_tiles(output_path: &str, scheme: Scheme, ui: Ui) -> Result<()> {
+    let loaded = mbtiles::load_ndjson(&mut std::io::stdin().lock(), output_path, scheme)?;
+
+    ui.summary(
+        &format!("Load complete: {} tiles written to {}", loaded, output_path),
+        serde_json::json!({ "tiles_written": loaded, "output": output_path }),
+    );
+
+    Ok(())
+}
+
+fn import_dir(input_dir: &str, output_path: &str, format: Option<TileFormat>, scheme: Scheme, ui: Ui) -> Result<()> {
+    let imported = mbtiles::import_dir(input_dir, output_path, format, scheme)?;
+
+    ui.summary(
+        &format!("Import complete: {} tiles written to {}", imported, output_path),
+        serde_json::json!({ "tiles_written": imported, "output": output_path }),
+    );
+
+    Ok(())
+}
+
+fn serve_tiles(args: ServeArgs) -> Result<()> {
+    let stem = |path: &str| Path::new(path).file_stem().map_or(String::new(), |stem| stem.to_string_lossy().into_owned());
+    for (name, _) in &args.mount {
+        if !args.inputs.iter().any(|input| stem(input) == *name) {
+            return Err(anyhow!("--mount {}: no input file named {}", name, name));
+        }
+    }
+    let single = args.inputs.len() == 1 && args.watch.is_none();
+    let mounts: Vec<mbtiles::Mount> = args
+        .inputs
+        .iter()
+        .map(|input| {
+            let name = stem(input);
+            match args.mount.iter().find(|(mount, _)| *mount == name) {
+                Some((_, prefix)) => mbtiles::Mount::new(prefix, input),
+                None if single => mbtiles::Mount::new("", input),
+                None => mbtiles::Mount::new(&name, input),
+            }
+        })
+        .collect();
+    for (i, mount) in mounts.iter().enumerate() {
+        if mounts[..i].iter().any(|other| other.prefix == mount.prefix) {
+            return Err(anyhow!("Two tilesets would be served at {}/", mount.prefix));
+        }
+    }
+
+    let mut tokens = args.auth_tokens;
+    if let Some(file) = &args.auth_tokens_file {
+        let contents = std::fs::read_to_string(file).map_err(|e| anyhow!("Failed to read {}: {}", file, e))?;
+        let listed: Vec<String> = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(String::from)
+            .collect();
+        if listed.is_empty() {
+            return Err(anyhow!("No tokens in {}", file));
+        }
+        tokens.extend(listed);
+    }
+
+    let addr = format!("{}:{}", args.bind, args.port);
+    for mount in &mounts {
+        let base = format!("http://{}{}", addr, mount.prefix);
+        println!("Serving {} at {}/{{z}}/{{x}}/{{y}}, TileJSON at {}/tilejson.json", mount.path, base, base);
+    }
+    if let Some(dir) = &args.watch {
+        println!("Serving the tilesets in {} at http://{}/<file stem>/{{z}}/{{x}}/{{y}}", dir, addr);
+    }
+    if !tokens.is_empty() {
+        println!("Requests must present one of {} keys", tokens.len());
+    }
+    if let Some(upstream) = &args.upstream {
+        println!("Fetching missing tiles from {}", upstream);
+    }
+    let options = mbtiles::ServeOptions {
+        mounts,
+        watch_dir: args.watch,
+        max_age: args.max_age,
+        cors: args.cors,
+        tokens,
+        cache_size: args.cache_size,
+        upstream: args.upstream,
+    };
+    mbtiles::serve_with(&options, &addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("2GB"), Ok(2 << 30));
+        assert_eq!(parse_size("1.5G"), Ok(3 << 29));
+        assert_eq!(parse_size("500MiB"), Ok(500 << 20));
+        assert_eq!(parse_size("256m"), Ok(256 << 20));
+        assert_eq!(parse_size(" 8 KB "), Ok(8 << 10));
+        assert_eq!(parse_size("1T"), Ok(1 << 40));
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("4096B"), Ok(4096));
+    }
+
+    #[test]
+    fn invalid_sizes() {
+        assert_eq!(parse_size("5X"), Err("Unknown size unit: X".to_string()));
+        assert_eq!(parse_size("5MBB"), Err("Unknown size unit: MBB".to_string()));
+        assert_eq!(parse_size("5iB"), Err("Unknown size unit: iB".to_string()));
+        assert_eq!(parse_size("5PB"), Err("Unknown size unit: PB".to_string()));
+        assert_eq!(parse_size("GB"), Err("Invalid size: GB".to_string()));
+        assert_eq!(parse_size("1.2.3M"), Err("Invalid size: 1.2.3M".to_string()));
+        assert_eq!(parse_size(""), Err("Invalid size: ".to_string()));
+        assert_eq!(parse_size("0"), Err("Size must be positive: 0".to_string()));
+        assert_eq!(parse_size("-1G"), Err("Size must be positive: -1G".to_string()));
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(parse_timestamp("1700000000"), Ok(1_700_000_000));
+        assert_eq!(parse_timestamp("-5"), Ok(-5));
+        assert_eq!(parse_timestamp("1970-01-01"), Ok(0));
+        assert_eq!(parse_timestamp("2024-01-01T12:30"), Ok(1_704_112_200));
+        assert_eq!(parse_timestamp("2024-01-01T12:30:15Z"), Ok(1_704_112_215));
+        assert_eq!(parse_timestamp("2024-01-01 12:30:15"), Ok(1_704_112_215));
+    }
+
+    #[test]
+    fn leap_days_and_century_years() {
+        assert_eq!(parse_timestamp("2024-02-29"), Ok(1_709_164_800));
+        assert_eq!(parse_timestamp("2024-03-01"), Ok(1_709_164_800 + 86_400));
+        assert_eq!(parse_timestamp("2024-02-29T23:59:60Z"), Ok(1_709_251_200));
+        // 2000 is a leap year as a multiple of 400, 1900 and 2100 aren't
+        assert_eq!(parse_timestamp("2000-02-29"), Ok(951_782_400));
+        assert_eq!(parse_timestamp("2100-03-01"), Ok(4_107_542_400));
+        assert!(parse_timestamp("2023-02-29").is_err());
+        assert!(parse_timestamp("1900-02-29").is_err());
+        assert!(parse_timestamp("2100-02-29").is_err());
+    }
+
+    #[test]
+    fn dates_before_1970() {
+        assert_eq!(parse_timestamp("1969-12-31"), Ok(-86_400));
+        assert_eq!(parse_timestamp("1969-12-31T23:59:59Z"), Ok(-1));
+        assert_eq!(parse_timestamp("1900-01-01"), Ok(-2_208_988_800));
+        assert_eq!(parse_timestamp("1600-03-01"), Ok(-11_670_912_000));
+    }
+
+    #[test]
+    fn malformed_timestamps() {
+        for value in [
+            "",
+            "yesterday",
+            "2024.5",
+            "2024-01",
+            "2024-13-01",
+            "2024-00-10",
+            "2024-01-00",
+            "2024-04-31",
+            "2024-01-32",
+            "2024-1-01x",
+            "2024-01-01T12",
+            "2024-01-01T24:00",
+            "2024-01-01T12:60",
+            "2024-01-01T12:00:61",
+            "2024-01-01T12:-5",
+            "2024-01-+5",
+            "+2024-01-05",
+            "2024-01-05T+1:00",
+            "2024-01-05T12: 5",
+            "2024-01-01T12:00:00:00",
+            "2024/01/01",
+        ] {
+            let error = parse_timestamp(value).unwrap_err();
+            assert!(error.starts_with("Invalid time, expected YYYY-MM-DD"), "{}: {}", value, error);
+        }
+    }
+
+    #[test]
+    fn negative_bbox_values() {
+        let parse = |args: &[&str]| Cli::try_parse_from(["mbtiles"].iter().chain(args)).map(|cli| cli.command);
+        let bbox = "-10,-80,10,80";
+        let Ok(Commands::Extract(args)) = parse(&["extract", "--bbox", bbox, "--bbox", "-170,5,-160,10", "in.mbtiles", "out.mbtiles"])
+        else {
+            panic!("extract --bbox {} should parse", bbox)
+        };
+        assert_eq!(args.bbox, [bbox, "-170,5,-160,10"]);
+        let Ok(Commands::Seed(args)) = parse(&["seed", "--zoom", "0-2", "--bbox", bbox, "https://a/{z}/{x}/{y}.png", "out.mbtiles"])
+        else {
+            panic!("seed --bbox {} should parse", bbox)
+        };
+        assert_eq!(args.bbox, [bbox]);
+        let Ok(Commands::Erase(args)) = parse(&["erase", "in.mbtiles", "--bbox", bbox]) else { panic!("erase --bbox {} should parse", bbox) };
+        assert_eq!(args.bbox, [bbox]);
+        let Ok(Commands::List(args)) = parse(&["list", "in.mbtiles", "--bbox", bbox]) else { panic!("list --bbox {} should parse", bbox) };
+        assert_eq!(args.bbox, [bbox]);
+    }
 }
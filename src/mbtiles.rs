@@ -0,0 +1,667 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::path::Path;
+
+use anyhow::{Context, Result, anyhow};
+use rusqlite::{Connection, OpenFlags, OptionalExtension, params};
+
+use crate::bbox::TileRange;
+use crate::http;
+use crate::http_vfs;
+use crate::info::ZoomInfo;
+use crate::sink::TileSink;
+use crate::source::{SourceKind, TileSource};
+use crate::tile::Tile;
+
+/// Read access to an existing MBTiles file
+pub struct MbtilesReader {
+    conn: Connection,
+    schema: MbtilesSchema,
+}
+
+impl MbtilesReader {
+    /// Open a flat or normalized MBTiles file read-only. A normalized file
+    /// lacking the `tiles` view gets a temporary one so all queries can use it.
+    /// `path` may be an `http(s)://` URL of a server answering range requests.
+    pub fn open(path: &str) -> Result<Self> {
+        Self::open_uri(path, false)
+    }
+
+    /// Like [`Self::open`] but also tell SQLite the file can't change while
+    /// open, so no locks or `-shm` files are needed. For files on read-only
+    /// mounts that nothing else writes to.
+    pub fn open_immutable(path: &str) -> Result<Self> {
+        Self::open_uri(path, true)
+    }
+
+    fn open_uri(path: &str, immutable: bool) -> Result<Self> {
+        if http::is_url(path) {
+            // The VFS can only report that it failed, check why up front
+            http::content_length(path).context(format!("Failed to open input file: {}", path))?;
+        } else if !Path::new(path).exists() {
+            return Err(anyhow!("Input file not found: {}", path));
+        }
+        let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+        let conn = Connection::open_with_flags(read_only_uri(path, immutable), flags)
+            .context(format!("Failed to open input file: {}", path))?;
+
+        let schema = MbtilesSchema::detect(&conn, "main").context(format!("Failed to read {}", path))?;
+        if schema == MbtilesSchema::Normalized && !has_object(&conn, "main", "tiles")? {
+            conn.execute_batch(&format!("CREATE TEMP VIEW tiles AS {}", NORMALIZED_SELECT))?;
+        }
+
+        Ok(MbtilesReader { conn, schema })
+    }
+
+    pub fn schema(&self) -> MbtilesSchema {
+        self.schema
+    }
+
+    /// All metadata rows as (name, value), sorted by name
+    pub fn metadata(&self) -> Result<Vec<(String, String)>> {
+        query_metadata(&self.conn)
+    }
+
+    pub fn metadata_value(&self, name: &str) -> Result<Option<String>> {
+        let value = self.conn
+            .query_row("SELECT value FROM metadata WHERE name = ?", params![name], |row| row.get(0))
+            .optional()?;
+        Ok(value)
+    }
+
+    /// Zoom levels that contain at least one tile, ascending
+    pub fn zoom_levels(&self) -> Result<Vec<i32>> {
+        let mut stmt = self.conn.prepare("SELECT DISTINCT zoom_level FROM tiles ORDER BY zoom_level")?;
+        let zooms = stmt.query_map([], |row| row.get(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(zooms)
+    }
+
+    pub fn tile(&self, zoom: i32, x: i32, y: i32) -> Result<Option<Vec<u8>>> {
+        let data = self.conn
+            .query_row(
+                "SELECT tile_data FROM tiles WHERE zoom_level = ? AND tile_column = ? AND tile_row = ?",
+                params![zoom, x, y],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(data)
+    }
+
+    /// Call `f` for every tile inside `range`
+    pub fn for_each_tile<F>(&self, range: &TileRange, mut f: F) -> Result<()>
+    where
+        F: FnMut(Tile) -> Result<()>,
+    {
+        let mut stmt = self.conn.prepare(
+            "SELECT zoom_level, tile_column, tile_row, tile_data FROM tiles
+             WHERE zoom_level = ? AND tile_column BETWEEN ? AND ? AND tile_row BETWEEN ? AND ?"
+        )?;
+        let mut rows = stmt.query(params![range.zoom, range.x_min, range.x_max, range.y_min, range.y_max])?;
+        while let Some(row) = rows.next()? {
+            f(Tile {
+                zoom: row.get(0)?,
+                x: row.get(1)?,
+                y: row.get(2)?,
+                data: row.get(3)?,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Number of tiles inside `range`
+    pub fn count_tiles(&self, range: &TileRange) -> Result<u64> {
+        let count = self.conn.query_row(
+            "SELECT COUNT(*) FROM tiles
+             WHERE zoom_level = ? AND tile_column BETWEEN ? AND ? AND tile_row BETWEEN ? AND ?",
+            params![range.zoom, range.x_min, range.x_max, range.y_min, range.y_max],
+            |row| row.get(0),
+        )?;
+        Ok(count)
+    }
+
+    /// Tile count, size and extent per zoom level
+    pub fn zoom_info(&self) -> Result<Vec<ZoomInfo>> {
+        query_zoom_info(&self.conn)
+    }
+
+    pub fn sample_tile(&self) -> Result<Option<Vec<u8>>> {
+        let data = self.conn
+            .query_row("SELECT tile_data FROM tiles LIMIT 1", [], |row| row.get(0))
+            .optional()?;
+        Ok(data)
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+}
+
+impl TileSource for MbtilesReader {
+    fn kind(&self) -> SourceKind {
+        SourceKind::Mbtiles(self.schema)
+    }
+
+    fn metadata(&self) -> Result<Vec<(String, String)>> {
+        MbtilesReader::metadata(self)
+    }
+
+    fn zoom_levels(&self) -> Result<Vec<i32>> {
+        MbtilesReader::zoom_levels(self)
+    }
+
+    fn tile(&self, zoom: i32, x: i32, y: i32) -> Result<Option<Vec<u8>>> {
+        MbtilesReader::tile(self, zoom, x, y)
+    }
+
+    fn for_each_tile(&self, range: &TileRange, f: &mut dyn FnMut(Tile) -> Result<()>) -> Result<()> {
+        MbtilesReader::for_each_tile(self, range, f)
+    }
+
+    fn for_each_tile_size(&self, range: &TileRange, f: &mut dyn FnMut(i32, i32, u64) -> Result<()>) -> Result<()> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT tile_column, tile_row, LENGTH(tile_data) FROM tiles
+             WHERE zoom_level = ? AND tile_column BETWEEN ? AND ? AND tile_row BETWEEN ? AND ?"
+        )?;
+        let mut rows = stmt.query(params![range.zoom, range.x_min, range.x_max, range.y_min, range.y_max])?;
+        while let Some(row) = rows.next()? {
+            f(row.get(0)?, row.get(1)?, row.get::<_, i64>(2)? as u64)?;
+        }
+        Ok(())
+    }
+
+    fn for_each_tile_hash(
+        &self,
+        range: &TileRange,
+        f: &mut dyn FnMut(i32, i32, String, Option<Vec<u8>>) -> Result<()>,
+    ) -> Result<()> {
+        query_tile_hashes(&self.conn, self.schema, range, f)
+    }
+
+    fn count_tiles(&self, range: &TileRange) -> Result<u64> {
+        MbtilesReader::count_tiles(self, range)
+    }
+
+    fn zoom_info(&self) -> Result<Vec<ZoomInfo>> {
+        MbtilesReader::zoom_info(self)
+    }
+
+    fn sample_tile(&self) -> Result<Option<Vec<u8>>> {
+        MbtilesReader::sample_tile(self)
+    }
+}
+
+/// Creates a new MBTiles file and writes tiles and metadata into it
+pub struct MbtilesWriter {
+    conn: Connection,
+    schema: MbtilesSchema,
+    /// Set once `TileSink` writes have opened a transaction
+    in_transaction: bool,
+    /// `TileSink` writes in the open transaction
+    pending: usize,
+    /// Commit `TileSink` writes every this many tiles, 0 for one transaction
+    batch_tiles: usize,
+    /// Skip inserts of tiles that are already present instead of failing
+    ignore_existing: bool,
+    /// Record completed ranges in the progress table
+    track_progress: bool,
+}
+
+/// Tiles written to a new file per transaction
+pub(crate) const TRANSACTION_TILES: usize = 50_000;
+
+/// Ranges already copied into a resumable partial file
+const PROGRESS_TABLE: &str = "_progress";
+
+/// Page cache for bulk writes to a new file, in KiB
+const BULK_CACHE_KIB: i64 = 256 * 1024;
+
+/// Table layout used to store tiles
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MbtilesSchema {
+    /// A single `tiles` table holding every blob
+    Flat,
+    /// `map` rows referencing blobs in `images` by content hash, with a
+    /// `tiles` view joining the two. Identical tiles are stored once.
+    Normalized,
+}
+
+/// Body of the `tiles` view of a normalized file
+pub(crate) const NORMALIZED_SELECT: &str =
+    "SELECT map.zoom_level AS zoom_level, map.tile_column AS tile_column,
+            map.tile_row AS tile_row, images.tile_data AS tile_data
+     FROM map JOIN images ON images.tile_id = map.tile_id";
+
+impl MbtilesSchema {
+    /// Detect the layout of database `db` (`main` or an attached name)
+    pub fn detect(conn: &Connection, db: &str) -> Result<Self> {
+        let tiles_type: Option<String> = conn
+            .query_row(
+                &format!("SELECT type FROM {}.sqlite_master WHERE name = 'tiles'", db),
+                [],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let normalized = has_object(conn, db, "map")? && has_object(conn, db, "images")?;
+
+        match tiles_type.as_deref() {
+            Some("table") => Ok(MbtilesSchema::Flat),
+            _ if normalized => Ok(MbtilesSchema::Normalized),
+            Some(_) => Err(anyhow!("tiles is a view over tables other than map and images")),
+            None => Err(anyhow!("No tiles table or map/images tables found")),
+        }
+    }
+
+    /// A table expression yielding the tiles of database `db`, usable even
+    /// when a normalized file has no `tiles` view
+    pub fn tiles_source(&self, db: &str) -> String {
+        match self {
+            MbtilesSchema::Flat => format!("{}.tiles", db),
+            MbtilesSchema::Normalized => format!(
+                "(SELECT m.zoom_level AS zoom_level, m.tile_column AS tile_column,
+                         m.tile_row AS tile_row, i.tile_data AS tile_data
+                  FROM {db}.map m JOIN {db}.images i ON i.tile_id = m.tile_id)",
+                db = db
+            ),
+        }
+    }
+}
+
+impl fmt::Display for MbtilesSchema {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            MbtilesSchema::Flat => "flat",
+            MbtilesSchema::Normalized => "normalized (map/images)",
+        })
+    }
+}
+
+/// Create the `map` and `images` tables and the `tiles` view over them
+pub(crate) fn create_normalized_tables(conn: &Connection) -> Result<()> {
+    conn.execute_batch(&format!(
+        "CREATE TABLE map (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER, tile_id TEXT);
+         CREATE UNIQUE INDEX map_index ON map (zoom_level, tile_column, tile_row);
+         CREATE TABLE images (tile_data BLOB, tile_id TEXT);
+         CREATE UNIQUE INDEX images_id ON images (tile_id);
+         CREATE VIEW tiles AS {};",
+        NORMALIZED_SELECT
+    ))?;
+    Ok(())
+}
+
+/// SQLite URI opening `path` read-only, for `open` or `ATTACH`. `http(s)://`
+/// URLs are read with range requests through [`crate::http_vfs`], always
+/// immutable.
+pub(crate) fn read_only_uri(path: &str, immutable: bool) -> String {
+    let mut uri = String::from("file:");
+    for c in path.chars() {
+        match c {
+            '%' => uri.push_str("%25"),
+            '?' => uri.push_str("%3f"),
+            '#' => uri.push_str("%23"),
+            c => uri.push(c),
+        }
+    }
+    if http::is_url(path) {
+        http_vfs::register();
+        uri.push_str(&format!("?vfs={}&mode=ro&immutable=1", http_vfs::VFS_NAME));
+        return uri;
+    }
+    uri.push_str(if immutable { "?mode=ro&immutable=1" } else { "?mode=ro" });
+    uri
+}
+
+/// True if `name` is a table or view in database `db`
+fn has_object(conn: &Connection, db: &str, name: &str) -> Result<bool> {
+    let count: i64 = conn.query_row(
+        &format!("SELECT COUNT(*) FROM {}.sqlite_master WHERE name = ? AND type IN ('table', 'view')", db),
+        params![name],
+        |row| row.get(0),
+    )?;
+    Ok(count > 0)
+}
+
+impl MbtilesWriter {
+    pub fn create(path: &str) -> Result<Self> {
+        Self::create_with_schema(path, MbtilesSchema::Flat)
+    }
+
+    pub fn create_with_schema(path: &str, schema: MbtilesSchema) -> Result<Self> {
+        let conn = Connection::open(path)
+            .context(format!("Failed to create output file: {}", path))?;
+        // A half written new file is useless either way, so skip the
+        // journal and fsyncs while it's filled
+        conn.pragma_update_and_check(None, "journal_mode", "OFF", |_| Ok(()))?;
+        conn.pragma_update(None, "synchronous", "OFF")?;
+        conn.pragma_update(None, "cache_size", -BULK_CACHE_KIB)?;
+
+        match schema {
+            MbtilesSchema::Flat => conn.execute_batch(
+                "CREATE TABLE metadata (name TEXT, value TEXT);
+                 CREATE TABLE tiles (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER, tile_data BLOB);
+                 CREATE UNIQUE INDEX tile_index ON tiles (zoom_level, tile_column, tile_row);"
+            )?,
+            MbtilesSchema::Normalized => {
+                conn.execute_batch("CREATE TABLE metadata (name TEXT, value TEXT);")?;
+                create_normalized_tables(&conn)?;
+            }
+        }
+
+        Ok(MbtilesWriter {
+            conn,
+            schema,
+            in_transaction: false,
+            pending: 0,
+            batch_tiles: TRANSACTION_TILES,
+            ignore_existing: false,
+            track_progress: false,
+        })
+    }
+
+    /// Open an existing MBTiles file for modification. `TileSink` writes
+    /// stay in one transaction until `finish`, so a failure leaves the file
+    /// unchanged.
+    pub fn open(path: &str) -> Result<Self> {
+        if !Path::new(path).exists() {
+            return Err(anyhow!("File not found: {}", path));
+        }
+        let conn = Connection::open(path).context(format!("Failed to open {}", path))?;
+        let schema = MbtilesSchema::detect(&conn, "main").context(format!("Failed to read {}", path))?;
+        Ok(MbtilesWriter {
+            conn,
+            schema,
+            in_transaction: false,
+            pending: 0,
+            batch_tiles: 0,
+            ignore_existing: false,
+            track_progress: false,
+        })
+    }
+
+    pub fn insert_metadata(&self, name: &str, value: &str) -> Result<()> {
+        self.conn.execute("INSERT INTO metadata (name, value) VALUES (?, ?)", params![name, value])?;
+        Ok(())
+    }
+
+    /// Insert or replace the metadata value for `name`
+    pub fn set_metadata(&self, name: &str, value: &str) -> Result<()> {
+        self.conn.execute("DELETE FROM metadata WHERE name = ?", params![name])?;
+        self.insert_metadata(name, value)
+    }
+
+    /// Remove `name` from the metadata, returning whether it was present
+    pub fn delete_metadata(&self, name: &str) -> Result<bool> {
+        Ok(self.conn.execute("DELETE FROM metadata WHERE name = ?", params![name])? > 0)
+    }
+
+    /// All metadata rows as (name, value), sorted by name
+    pub fn metadata(&self) -> Result<Vec<(String, String)>> {
+        query_metadata(&self.conn)
+    }
+
+    /// Tile count, size and extent per zoom level of the tiles written so far
+    pub fn zoom_info(&self) -> Result<Vec<ZoomInfo>> {
+        query_zoom_info(&self.conn)
+    }
+
+    /// Like [`TileSource::for_each_tile_hash`], reading through this
+    /// writer's connection, which stays readable inside its own transaction
+    pub fn for_each_tile_hash(
+        &self,
+        range: &TileRange,
+        f: &mut dyn FnMut(i32, i32, String, Option<Vec<u8>>) -> Result<()>,
+    ) -> Result<()> {
+        query_tile_hashes(&self.conn, self.schema, range, f)
+    }
+
+    /// Make later inserts leave tiles already in the file untouched rather
+    /// than fail on the duplicate
+    pub fn ignore_existing_tiles(&mut self) {
+        self.ignore_existing = true;
+    }
+
+    /// Keep a table of the ranges passed to `TileSink::complete_range` so a
+    /// copy into this file can be resumed. `TileSink` writes are committed in
+    /// batches from then on, with the journal enabled so an interrupted batch
+    /// rolls back cleanly. Returns the ranges recorded by earlier runs.
+    pub(crate) fn track_progress(&mut self) -> Result<HashSet<TileRange>> {
+        self.conn.pragma_update_and_check(None, "journal_mode", "DELETE", |_| Ok(()))?;
+        self.conn.execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS {} (zoom_level INTEGER, x_min INTEGER, x_max INTEGER, y_min INTEGER, y_max INTEGER)",
+            PROGRESS_TABLE
+        ))?;
+        self.track_progress = true;
+        self.batch_tiles = TRANSACTION_TILES;
+
+        let mut stmt = self.conn.prepare(&format!("SELECT * FROM {}", PROGRESS_TABLE))?;
+        let ranges = stmt.query_map([], |row| {
+            Ok(TileRange { zoom: row.get(0)?, x_min: row.get(1)?, x_max: row.get(2)?, y_min: row.get(3)?, y_max: row.get(4)? })
+        })?;
+        Ok(ranges.collect::<Result<HashSet<_>, _>>()?)
+    }
+
+    /// Commit `TileSink` writes every `tiles` tiles, with the journal
+    /// enabled so an interrupted write keeps every committed batch
+    pub(crate) fn commit_every(&mut self, tiles: usize) -> Result<()> {
+        self.conn.pragma_update_and_check(None, "journal_mode", "DELETE", |_| Ok(()))?;
+        self.batch_tiles = tiles;
+        Ok(())
+    }
+
+    /// Add `range` to the progress table
+    pub(crate) fn record_range(&self, range: &TileRange) -> Result<()> {
+        self.conn
+            .prepare_cached(&format!("INSERT INTO {} VALUES (?, ?, ?, ?, ?)", PROGRESS_TABLE))?
+            .execute(params![range.zoom, range.x_min, range.x_max, range.y_min, range.y_max])?;
+        Ok(())
+    }
+
+    /// Drop the progress table once the copy is complete
+    pub(crate) fn clear_progress(&self) -> Result<()> {
+        self.conn.execute_batch(&format!("DROP TABLE IF EXISTS {}", PROGRESS_TABLE))?;
+        Ok(())
+    }
+
+    pub fn insert_tile(&self, tile: &Tile) -> Result<()> {
+        self.write_row(tile, if self.ignore_existing { "INSERT OR IGNORE" } else { "INSERT" })
+    }
+
+    /// Insert the tile or overwrite the one at its coordinates. Normalized
+    /// blobs no longer referenced are left for [`Self::prune_images`].
+    pub fn replace_tile(&self, tile: &Tile) -> Result<()> {
+        self.write_row(tile, "INSERT OR REPLACE")
+    }
+
+    fn write_row(&self, tile: &Tile, insert: &str) -> Result<()> {
+        match self.schema {
+            MbtilesSchema::Flat => {
+                self.conn
+                    .prepare_cached(&format!(
+                        "{} INTO tiles (zoom_level, tile_column, tile_row, tile_data) VALUES (?, ?, ?, ?)",
+                        insert
+                    ))?
+                    .execute(params![tile.zoom, tile.x, tile.y, tile.data])?;
+            }
+            MbtilesSchema::Normalized => {
+                let tile_id = tile_hash(&tile.data);
+                self.conn
+                    .prepare_cached("INSERT OR IGNORE INTO images (tile_data, tile_id) VALUES (?, ?)")?
+                    .execute(params![tile.data, tile_id])?;
+                self.conn
+                    .prepare_cached(&format!(
+                        "{} INTO map (zoom_level, tile_column, tile_row, tile_id) VALUES (?, ?, ?, ?)",
+                        insert
+                    ))?
+                    .execute(params![tile.zoom, tile.x, tile.y, tile_id])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the tile at `zoom`/`x`/`y` (TMS), returning whether it existed.
+    /// Normalized blobs no longer referenced are left for [`Self::prune_images`].
+    pub fn delete_tile(&self, zoom: i32, x: i32, y: i32) -> Result<bool> {
+        let table = match self.schema {
+            MbtilesSchema::Flat => "tiles",
+            MbtilesSchema::Normalized => "map",
+        };
+        let deleted = self
+            .conn
+            .prepare_cached(&format!(
+                "DELETE FROM {} WHERE zoom_level = ? AND tile_column = ? AND tile_row = ?",
+                table
+            ))?
+            .execute(params![zoom, x, y])?;
+        Ok(deleted > 0)
+    }
+
+    /// Delete blobs in `images` that no `map` row references, returning how many
+    pub fn prune_images(&self) -> Result<usize> {
+        if self.schema != MbtilesSchema::Normalized {
+            return Ok(0);
+        }
+        Ok(self.conn.execute("DELETE FROM images WHERE tile_id NOT IN (SELECT tile_id FROM map)", [])?)
+    }
+
+    pub fn schema(&self) -> MbtilesSchema {
+        self.schema
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+}
+
+impl TileSink for MbtilesWriter {
+    fn write_metadata(&mut self, name: &str, value: &str) -> Result<()> {
+        self.set_metadata(name, value)
+    }
+
+    fn write_tile(&mut self, tile: &Tile) -> Result<()> {
+        if !self.in_transaction {
+            self.conn.execute_batch("BEGIN")?;
+            self.in_transaction = true;
+        }
+        self.insert_tile(tile)?;
+
+        self.pending += 1;
+        if self.batch_tiles > 0 && self.pending >= self.batch_tiles {
+            self.conn.execute_batch("COMMIT")?;
+            self.in_transaction = false;
+            self.pending = 0;
+        }
+        Ok(())
+    }
+
+    fn complete_range(&mut self, range: &TileRange) -> Result<()> {
+        if self.track_progress {
+            self.record_range(range)?;
+        }
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<()> {
+        if self.in_transaction {
+            self.conn.execute_batch("COMMIT")?;
+        }
+        Ok(())
+    }
+}
+
+fn query_metadata(conn: &Connection) -> Result<Vec<(String, String)>> {
+    let mut stmt = conn.prepare("SELECT name, value FROM metadata ORDER BY name")?;
+    let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(rows)
+}
+
+/// Hashes of the tiles inside `range`. Normalized `tile_id`s that look like
+/// an md5 are used as they are, so only the `map` table is read, once a
+/// sample of them proves to be the md5 of their tiles; other tiles are
+/// hashed from their data.
+fn query_tile_hashes(
+    conn: &Connection,
+    schema: MbtilesSchema,
+    range: &TileRange,
+    f: &mut dyn FnMut(i32, i32, String, Option<Vec<u8>>) -> Result<()>,
+) -> Result<()> {
+    let (sql, trusted) = match schema {
+        MbtilesSchema::Flat => (
+            "SELECT tile_column, tile_row, NULL, tile_data FROM tiles
+             WHERE zoom_level = ?1 AND tile_column BETWEEN ?2 AND ?3 AND tile_row BETWEEN ?4 AND ?5",
+            None,
+        ),
+        MbtilesSchema::Normalized => (
+            "SELECT tile_column, tile_row, tile_id,
+                    CASE WHEN ?6 AND length(tile_id) = 32 AND tile_id NOT GLOB '*[^0-9A-Fa-f]*' THEN NULL
+                         ELSE (SELECT tile_data FROM images WHERE images.tile_id = map.tile_id) END
+             FROM map
+             WHERE zoom_level = ?1 AND tile_column BETWEEN ?2 AND ?3 AND tile_row BETWEEN ?4 AND ?5",
+            Some(tile_ids_are_hashes(conn, range.zoom)?),
+        ),
+    };
+    let mut stmt = conn.prepare_cached(sql)?;
+    let within = params![range.zoom, range.x_min, range.x_max, range.y_min, range.y_max];
+    let mut rows = match trusted {
+        None => stmt.query(within)?,
+        Some(trusted) => stmt.query(params![range.zoom, range.x_min, range.x_max, range.y_min, range.y_max, trusted])?,
+    };
+    while let Some(row) = rows.next()? {
+        let data: Option<Vec<u8>> = row.get(3)?;
+        let hash = match &data {
+            Some(data) => tile_hash(data),
+            None => row.get::<_, String>(2)?.to_ascii_lowercase(),
+        };
+        f(row.get(0)?, row.get(1)?, hash, data)?;
+    }
+    Ok(())
+}
+
+/// Normalized tiles per zoom level whose md5 is checked against their
+/// md5-like `tile_id`
+pub(crate) const HASH_SAMPLE_TILES: i64 = 8;
+
+/// True unless a few tiles of `zoom` picked at random have a `tile_id` that
+/// looks like an md5 but isn't theirs, as with opaque ids of other tools or
+/// ids left stale by in-place edits
+fn tile_ids_are_hashes(conn: &Connection, zoom: i32) -> Result<bool> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT map.tile_id, images.tile_data FROM map JOIN images ON images.tile_id = map.tile_id
+         WHERE map.zoom_level = ? AND length(map.tile_id) = 32 AND map.tile_id NOT GLOB '*[^0-9A-Fa-f]*'
+         ORDER BY random() LIMIT ?"
+    )?;
+    let mut rows = stmt.query(params![zoom, HASH_SAMPLE_TILES])?;
+    while let Some(row) = rows.next()? {
+        let (id, data): (String, Vec<u8>) = (row.get(0)?, row.get(1)?);
+        if !id.eq_ignore_ascii_case(&tile_hash(&data)) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn query_zoom_info(conn: &Connection) -> Result<Vec<ZoomInfo>> {
+    let mut stmt = conn.prepare(
+        "SELECT zoom_level, COUNT(*), SUM(LENGTH(tile_data)),
+                MIN(tile_column), MAX(tile_column), MIN(tile_row), MAX(tile_row)
+         FROM tiles GROUP BY zoom_level ORDER BY zoom_level"
+    )?;
+    let zooms = stmt.query_map([], |row| {
+        Ok(ZoomInfo {
+            zoom: row.get(0)?,
+            tiles: row.get(1)?,
+            bytes: row.get::<_, Option<u64>>(2)?.unwrap_or(0),
+            x_min: row.get(3)?,
+            x_max: row.get(4)?,
+            y_min: row.get(5)?,
+            y_max: row.get(6)?,
+        })
+    })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(zooms)
+}
+
+/// Hex md5 of a tile blob, the `tile_id` convention of normalized MBTiles
+pub fn tile_hash(data: &[u8]) -> String {
+    format!("{:x}", md5::compute(data))
+}
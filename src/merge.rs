@@ -0,0 +1,381 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use anyhow::{Result, anyhow};
+use serde_json::Value;
+
+use crate::bbox::{BoundingBox, TileRange};
+use crate::grids;
+use crate::mbtiles::{MbtilesWriter, read_only_uri};
+use crate::mvt::VectorTile;
+use crate::progress::{NoProgress, Progress};
+use crate::sink::OutputFormat;
+use crate::source::{is_mbtiles, open_source, TileSource};
+use crate::tile::{compress, decompress, detect_compression, Scheme, Tile};
+
+/// What to do when several inputs have a tile at the same z/x/y
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Conflict {
+    /// Keep the tile of the earliest input
+    First,
+    /// Keep the tile of the latest input
+    Last,
+    /// Decode the vector tiles and combine their layers into one tile.
+    /// Layers with the same name have their features concatenated.
+    UnionLayers,
+}
+
+/// Merge `inputs` into a new tileset at `output_path`. Returns the number of
+/// tiles written.
+///
+/// Metadata comes from the first input, with `bounds`, `minzoom` and
+/// `maxzoom` widened to cover all inputs. With [`Conflict::UnionLayers`]
+/// the `vector_layers` of all inputs are combined too.
+pub fn merge(inputs: &[&str], output_path: &str, conflict: Conflict) -> Result<u64> {
+    merge_with_progress(inputs, output_path, conflict, &NoProgress)
+}
+
+/// Like [`merge`], reporting each input tile read to `progress`
+pub fn merge_with_progress(inputs: &[&str], output_path: &str, conflict: Conflict, progress: &dyn Progress) -> Result<u64> {
+    if inputs.is_empty() {
+        return Err(anyhow!("No inputs to merge"));
+    }
+
+    let mut sources: Vec<(Box<dyn TileSource>, Scheme)> = Vec::new();
+    for &path in inputs {
+        let source = open_source(path)?;
+        let declared = source.metadata()?.into_iter().find(|(name, _)| name == "scheme").map(|(_, value)| value);
+        let scheme = match declared.as_deref().and_then(Scheme::from_metadata) {
+            Some(scheme) if is_mbtiles(path) => scheme,
+            _ => Scheme::Tms,
+        };
+        sources.push((source, scheme));
+    }
+
+    let metadata: Vec<BTreeMap<String, String>> = sources
+        .iter()
+        .map(|(source, _)| source.metadata().map(|m| m.into_iter().collect()))
+        .collect::<Result<_>>()?;
+    let formats: BTreeSet<&str> = metadata.iter().filter_map(|m| m.get("format").map(String::as_str)).collect();
+    if formats.len() > 1 {
+        return Err(anyhow!("Inputs have different tile formats: {}", formats.into_iter().collect::<Vec<_>>().join(", ")));
+    }
+
+    let zooms: BTreeSet<i32> =
+        sources.iter().map(|(source, _)| source.zoom_levels()).collect::<Result<Vec<_>>>()?.into_iter().flatten().collect();
+    let mut total = 0;
+    for (source, _) in &sources {
+        for &zoom in &zooms {
+            total += source.count_tiles(&TileRange::full(zoom)?)?;
+        }
+    }
+    progress.start(total);
+
+    let mut sink = OutputFormat::from_path(output_path).create_sink(output_path)?;
+    let mut written = 0;
+    for &zoom in &zooms {
+        let range = TileRange::full(zoom)?;
+
+        // Inputs holding each tile (TMS), in input order
+        let mut owners: HashMap<(i32, i32), Vec<usize>> = HashMap::new();
+        for (index, (source, scheme)) in sources.iter().enumerate() {
+            source.for_each_tile_size(&range, &mut |x, y, _| {
+                owners.entry((x, scheme.to_tms(zoom, y))).or_default().push(index);
+                Ok(())
+            })?;
+        }
+
+        for (index, (source, scheme)) in sources.iter().enumerate() {
+            source.for_each_tile(&range, &mut |mut tile| {
+                progress.advance(1);
+                tile.y = scheme.to_tms(zoom, tile.y);
+                let owned = &owners[&(tile.x, tile.y)];
+                let keep = match conflict {
+                    Conflict::First => owned[0] == index,
+                    Conflict::Last => owned[owned.len() - 1] == index,
+                    Conflict::UnionLayers => owned[0] == index,
+                };
+                if !keep {
+                    return Ok(());
+                }
+                if conflict == Conflict::UnionLayers && owned.len() > 1 {
+                    let mut blobs = vec![tile.data];
+                    for &other in &owned[1..] {
+                        let (other_source, other_scheme) = &sources[other];
+                        let y = other_scheme.from_tms(zoom, tile.y);
+                        blobs.extend(other_source.tile(zoom, tile.x, y)?);
+                    }
+                    tile = Tile { data: union_layers(&blobs)?, ..tile };
+                }
+                written += 1;
+                sink.write_tile(&tile)
+            })?;
+        }
+    }
+
+    for (name, value) in merged_metadata(&metadata, conflict)? {
+        sink.write_metadata(&name, &value)?;
+    }
+    sink.finish()?;
+
+    if OutputFormat::from_path(output_path) == OutputFormat::Mbtiles {
+        let writer = MbtilesWriter::open(output_path)?;
+        let conn = writer.connection();
+        for (&path, (_, scheme)) in inputs.iter().zip(&sources) {
+            if !is_mbtiles(path) {
+                continue;
+            }
+            conn.execute("ATTACH DATABASE ? AS input", rusqlite::params![read_only_uri(path, false)])?;
+            let ranges = zooms.iter().map(|&zoom| TileRange::full(zoom)).collect::<Result<Vec<_>>>()?;
+            grids::copy_grids(conn, "input", &ranges, *scheme, conflict == Conflict::Last)?;
+            conn.execute("DETACH DATABASE input", [])?;
+        }
+    }
+
+    progress.finish();
+    Ok(written)
+}
+
+/// Combine the layers of several vector tile blobs, compressed like the first
+fn union_layers(blobs: &[Vec<u8>]) -> Result<Vec<u8>> {
+    let compression = detect_compression(&blobs[0]);
+    let mut merged = VectorTile::default();
+    for blob in blobs {
+        let tile = VectorTile::decode(&decompress(blob)?)?;
+        for layer in tile.layers {
+            match merged.layers.iter_mut().find(|existing| existing.name == layer.name) {
+                Some(existing) => existing.append(layer)?,
+                None => merged.layers.push(layer),
+            }
+        }
+    }
+    compress(&merged.encode(), compression)
+}
+
+/// Metadata of the first input, widened to cover every input
+fn merged_metadata(metadata: &[BTreeMap<String, String>], conflict: Conflict) -> Result<BTreeMap<String, String>> {
+    let mut merged = metadata[0].clone();
+    merged.remove("scheme");
+
+    let zoom = |key: &'static str| metadata.iter().filter_map(move |m| m.get(key)?.trim().parse::<i32>().ok());
+    if let Some(min) = zoom("minzoom").min() {
+        merged.insert("minzoom".to_string(), min.to_string());
+    }
+    if let Some(max) = zoom("maxzoom").max() {
+        merged.insert("maxzoom".to_string(), max.to_string());
+    }
+
+    let bounds = metadata.iter().filter_map(|m| BoundingBox::from_metadata(m.get("bounds")?));
+    if let Some(union) = bounds.reduce(|a, b| BoundingBox {
+        north: a.north.max(b.north),
+        east: a.east.max(b.east),
+        south: a.south.min(b.south),
+        west: a.west.min(b.west),
+    }) {
+        merged.insert("bounds".to_string(), union.to_metadata());
+    }
+
+    if conflict == Conflict::UnionLayers {
+        let mut layers: Vec<Value> = Vec::new();
+        let mut json: Option<Value> = None;
+        for m in metadata {
+            let Some(value) = m.get("json") else { continue };
+            let parsed: Value = serde_json::from_str(value).map_err(|e| anyhow!("Invalid json metadata: {}", e))?;
+            for layer in parsed.get("vector_layers").and_then(Value::as_array).into_iter().flatten() {
+                let id = layer.get("id");
+                match layers.iter_mut().find(|existing| existing.get("id") == id) {
+                    Some(existing) => merge_fields(existing, layer),
+                    None => layers.push(layer.clone()),
+                }
+            }
+            json.get_or_insert(parsed);
+        }
+        if let Some(mut json) = json {
+            json["vector_layers"] = Value::Array(layers);
+            merged.insert("json".to_string(), json.to_string());
+        }
+    }
+
+    Ok(merged)
+}
+
+/// Add the fields of `layer` missing from `existing` and widen its zooms
+pub(crate) fn merge_fields(existing: &mut Value, layer: &Value) {
+    if let (Some(Value::Object(fields)), Some(Value::Object(more))) = (existing.get_mut("fields"), layer.get("fields")) {
+        for (name, kind) in more {
+            fields.entry(name.clone()).or_insert_with(|| kind.clone());
+        }
+    }
+    for (key, wider) in [("minzoom", std::cmp::min as fn(i64, i64) -> i64), ("maxzoom", std::cmp::max)] {
+        if let (Some(a), Some(b)) = (existing.get(key).and_then(Value::as_i64), layer.get(key).and_then(Value::as_i64)) {
+            existing[key] = wider(a, b).into();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mbtiles::MbtilesReader;
+    use crate::mvt::{Feature, GeomType, Layer};
+    use crate::tile::Compression;
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("mbtiles-merge-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path.to_string_lossy().into_owned()
+    }
+
+    /// An MBTiles file with `metadata` and `tiles` given with TMS rows,
+    /// stored with XYZ rows when the metadata says so
+    fn input(name: &str, metadata: &[(&str, &str)], tiles: &[Tile]) -> String {
+        let path = temp_path(name);
+        let writer = MbtilesWriter::create(&path).unwrap();
+        for (name, value) in metadata {
+            writer.insert_metadata(name, value).unwrap();
+        }
+        let scheme = if metadata.contains(&("scheme", "xyz")) { Scheme::Xyz } else { Scheme::Tms };
+        for tile in tiles {
+            writer.insert_tile(&Tile { y: scheme.from_tms(tile.zoom, tile.y), ..tile.clone() }).unwrap();
+        }
+        path
+    }
+
+    fn tile(zoom: i32, x: i32, y: i32, data: Vec<u8>) -> Tile {
+        Tile { zoom, x, y, data }
+    }
+
+    /// A layer with one point feature per value of its `name` property
+    fn layer(name: &str, names: &[&str]) -> Layer {
+        let features = (0..names.len() as u32)
+            .map(|i| Feature { id: None, tags: vec![0, i], geom_type: GeomType::Point, geometry: vec![9, 2, 2] })
+            .collect();
+        Layer {
+            name: name.to_string(),
+            version: 2,
+            extent: 4096,
+            keys: vec!["name".to_string()],
+            values: names.iter().map(|name| crate::mvt::Value::String(name.to_string())).collect(),
+            features,
+        }
+    }
+
+    fn vector(layers: Vec<Layer>) -> Vec<u8> {
+        compress(&VectorTile { layers }.encode(), Compression::Gzip).unwrap()
+    }
+
+    /// Name and feature `name`s of every layer of a vector tile blob
+    fn layer_names(blob: &[u8]) -> Vec<(String, Vec<String>)> {
+        let tile = VectorTile::decode(&decompress(blob).unwrap()).unwrap();
+        tile.layers
+            .iter()
+            .map(|layer| {
+                let names = layer.features.iter().map(|feature| {
+                    let (_, value) = layer.properties(feature).next().unwrap();
+                    match value {
+                        crate::mvt::Value::String(name) => name.clone(),
+                        other => panic!("unexpected value {:?}", other),
+                    }
+                });
+                (layer.name.clone(), names.collect())
+            })
+            .collect()
+    }
+
+    fn metadata(reader: &MbtilesReader) -> BTreeMap<String, String> {
+        reader.metadata().unwrap().into_iter().collect()
+    }
+
+    #[test]
+    fn first_and_last_input_own_shared_tiles() {
+        let a = input(
+            "owners-a.mbtiles",
+            &[("name", "a"), ("format", "png"), ("minzoom", "1"), ("maxzoom", "1"), ("bounds", "-180,-85,0,0")],
+            &[tile(1, 0, 0, b"a".to_vec()), tile(1, 1, 0, b"a".to_vec())],
+        );
+        let b = input(
+            "owners-b.mbtiles",
+            &[
+                ("name", "b"),
+                ("format", "png"),
+                ("scheme", "xyz"),
+                ("minzoom", "1"),
+                ("maxzoom", "2"),
+                ("bounds", "0,0,180,85"),
+            ],
+            &[tile(1, 1, 0, b"b".to_vec()), tile(2, 0, 0, b"b".to_vec())],
+        );
+
+        for (conflict, shared) in [(Conflict::First, b"a"), (Conflict::Last, b"b")] {
+            let output = temp_path("owners.mbtiles");
+            assert_eq!(merge(&[&a, &b], &output, conflict).unwrap(), 3);
+
+            // Rows of the XYZ input are matched up by their TMS row
+            let reader = MbtilesReader::open(&output).unwrap();
+            assert_eq!(reader.tile(1, 0, 0).unwrap(), Some(b"a".to_vec()));
+            assert_eq!(reader.tile(1, 1, 0).unwrap(), Some(shared.to_vec()));
+            assert_eq!(reader.tile(1, 1, 1).unwrap(), None);
+            assert_eq!(reader.tile(2, 0, 0).unwrap(), Some(b"b".to_vec()));
+
+            // Metadata of the first input, covering both
+            let metadata = metadata(&reader);
+            assert_eq!(metadata["name"], "a");
+            assert_eq!((metadata["minzoom"].as_str(), metadata["maxzoom"].as_str()), ("1", "2"));
+            assert_eq!(metadata["bounds"], "-180,-85,180,85");
+            assert!(!metadata.contains_key("scheme"));
+            drop(reader);
+            std::fs::remove_file(output).unwrap();
+        }
+        std::fs::remove_file(a).unwrap();
+        std::fs::remove_file(b).unwrap();
+    }
+
+    #[test]
+    fn union_layers_combines_tiles_and_vector_layers() {
+        let a = input(
+            "union-a.mbtiles",
+            &[
+                ("format", "pbf"),
+                ("json", r#"{"vector_layers":[{"id":"roads","fields":{"class":"String"},"minzoom":0,"maxzoom":2},{"id":"water","fields":{}}]}"#),
+            ],
+            &[
+                tile(0, 0, 0, vector(vec![layer("roads", &["a1"]), layer("water", &["lake"])])),
+                tile(1, 0, 0, vector(vec![layer("water", &["sea"])])),
+            ],
+        );
+        let b = input(
+            "union-b.mbtiles",
+            &[
+                ("format", "pbf"),
+                ("json", r#"{"vector_layers":[{"id":"roads","fields":{"name":"String"},"minzoom":1,"maxzoom":5},{"id":"places","fields":{}}]}"#),
+            ],
+            &[tile(0, 0, 0, vector(vec![layer("places", &["town"]), layer("roads", &["b1", "b2"])]))],
+        );
+
+        let output = temp_path("union.mbtiles");
+        assert_eq!(merge(&[&a, &b], &output, Conflict::UnionLayers).unwrap(), 2);
+        let reader = MbtilesReader::open(&output).unwrap();
+        let shared = reader.tile(0, 0, 0).unwrap().unwrap();
+        assert_eq!(detect_compression(&shared), Compression::Gzip);
+        let names = |layer: &str, names: &[&str]| (layer.to_string(), names.iter().map(|n| n.to_string()).collect());
+        assert_eq!(
+            layer_names(&shared),
+            [names("roads", &["a1", "b1", "b2"]), names("water", &["lake"]), names("places", &["town"])]
+        );
+        // A tile only one input has is copied unchanged
+        assert_eq!(layer_names(&reader.tile(1, 0, 0).unwrap().unwrap()), [names("water", &["sea"])]);
+
+        let json: Value = serde_json::from_str(&metadata(&reader)["json"]).unwrap();
+        assert_eq!(
+            json["vector_layers"],
+            serde_json::json!([
+                {"id": "roads", "fields": {"class": "String", "name": "String"}, "minzoom": 0, "maxzoom": 5},
+                {"id": "water", "fields": {}},
+                {"id": "places", "fields": {}},
+            ])
+        );
+        drop(reader);
+        for path in [output, a, b] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
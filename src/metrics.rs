@@ -0,0 +1,104 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::tile_cache::TileCache;
+
+/// Upper bounds in seconds of the request latency histogram buckets
+const LATENCY_BUCKETS: [f64; 12] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+
+/// Request counters of the tile server, rendered in the Prometheus text
+/// format. Tilesets are labelled by mount prefix, `/` for the root and
+/// empty for requests not answered by a tileset.
+#[derive(Default)]
+pub(crate) struct Metrics {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    requests: BTreeMap<(String, u16), u64>,
+    latency: BTreeMap<String, Histogram>,
+    bytes: BTreeMap<String, u64>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations per bucket, not cumulative, the last one above every bound
+    counts: [u64; LATENCY_BUCKETS.len() + 1],
+    sum: f64,
+}
+
+impl Metrics {
+    pub(crate) fn record(&self, tileset: &str, status: u16, bytes: u64, elapsed: Duration) {
+        let mut inner = self.inner.lock().expect("metrics lock");
+        *inner.requests.entry((tileset.to_string(), status)).or_default() += 1;
+        *inner.bytes.entry(tileset.to_string()).or_default() += bytes;
+
+        let seconds = elapsed.as_secs_f64();
+        let histogram = inner.latency.entry(tileset.to_string()).or_default();
+        let bucket = LATENCY_BUCKETS.iter().position(|&bound| seconds <= bound).unwrap_or(LATENCY_BUCKETS.len());
+        histogram.counts[bucket] += 1;
+        histogram.sum += seconds;
+    }
+
+    /// The counters, and those of the tile cache if there is one
+    pub(crate) fn render(&self, cache: Option<&TileCache>) -> String {
+        let inner = self.inner.lock().expect("metrics lock");
+        let mut out = String::new();
+
+        out.push_str("# HELP mbtiles_requests_total Requests answered, by tileset and status code.\n");
+        out.push_str("# TYPE mbtiles_requests_total counter\n");
+        for ((tileset, status), count) in &inner.requests {
+            let _ = writeln!(out, "mbtiles_requests_total{{tileset=\"{}\",status=\"{}\"}} {}", escape(tileset), status, count);
+        }
+
+        out.push_str("# HELP mbtiles_request_duration_seconds Time to answer a request, by tileset.\n");
+        out.push_str("# TYPE mbtiles_request_duration_seconds histogram\n");
+        for (tileset, histogram) in &inner.latency {
+            let tileset = escape(tileset);
+            let mut cumulative = 0;
+            for (i, count) in histogram.counts.iter().enumerate() {
+                cumulative += count;
+                let bound = LATENCY_BUCKETS.get(i).map_or("+Inf".to_string(), |bound| bound.to_string());
+                let _ = writeln!(
+                    out,
+                    "mbtiles_request_duration_seconds_bucket{{tileset=\"{}\",le=\"{}\"}} {}",
+                    tileset, bound, cumulative
+                );
+            }
+            let _ = writeln!(out, "mbtiles_request_duration_seconds_sum{{tileset=\"{}\"}} {}", tileset, histogram.sum);
+            let _ = writeln!(out, "mbtiles_request_duration_seconds_count{{tileset=\"{}\"}} {}", tileset, cumulative);
+        }
+
+        out.push_str("# HELP mbtiles_response_bytes_total Response body bytes sent, by tileset.\n");
+        out.push_str("# TYPE mbtiles_response_bytes_total counter\n");
+        for (tileset, bytes) in &inner.bytes {
+            let _ = writeln!(out, "mbtiles_response_bytes_total{{tileset=\"{}\"}} {}", escape(tileset), bytes);
+        }
+
+        if let Some(cache) = cache {
+            let stats = cache.stats();
+            let series = [
+                ("hits_total", "counter", "Tile reads answered from the cache.", stats.hits),
+                ("misses_total", "counter", "Tile reads that went to the file.", stats.misses),
+                ("evictions_total", "counter", "Tiles dropped to stay within the budget.", stats.evictions),
+                ("entries", "gauge", "Tiles in the cache.", stats.entries),
+                ("bytes", "gauge", "Bytes used by the cache.", stats.bytes),
+                ("budget_bytes", "gauge", "Bytes the cache may use.", cache.budget()),
+            ];
+            for (name, kind, help, value) in series {
+                let _ = writeln!(out, "# HELP mbtiles_tile_cache_{} {}", name, help);
+                let _ = writeln!(out, "# TYPE mbtiles_tile_cache_{} {}", name, kind);
+                let _ = writeln!(out, "mbtiles_tile_cache_{} {}", name, value);
+            }
+        }
+        out
+    }
+}
+
+/// Escape a label value for the text format
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
@@ -0,0 +1,1311 @@
+//! Minimal Mapbox Vector Tile (MVT 2.1) protobuf codec.
+//!
+//! Geometry is kept as the raw command stream so tiles can be re-encoded
+//! without loss; [`Feature::parts`] decodes it into coordinates.
+
+use anyhow::{Result, anyhow};
+use serde_json::{Map, Value as Json, json};
+
+use crate::clip::{self, Ring};
+
+const WIRE_VARINT: u64 = 0;
+const WIRE_64BIT: u64 = 1;
+const WIRE_LEN: u64 = 2;
+const WIRE_32BIT: u64 = 5;
+
+const CMD_MOVE_TO: u32 = 1;
+const CMD_LINE_TO: u32 = 2;
+const CMD_CLOSE_PATH: u32 = 7;
+
+/// Share of the extent kept around a tile when clipping overzoomed
+/// geometry, so lines and polygon edges run on just past the tile edge
+const CLIP_BUFFER: f64 = 1.0 / 64.0;
+
+/// A decoded vector tile
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VectorTile {
+    pub layers: Vec<Layer>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layer {
+    pub name: String,
+    pub version: u32,
+    pub extent: u32,
+    pub keys: Vec<String>,
+    pub values: Vec<Value>,
+    pub features: Vec<Feature>,
+}
+
+/// A property value from a layer's value table
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Float(f32),
+    Double(f64),
+    Int(i64),
+    Uint(u64),
+    Sint(i64),
+    Bool(bool),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GeomType {
+    Unknown,
+    Point,
+    LineString,
+    Polygon,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Feature {
+    pub id: Option<u64>,
+    /// Pairs of indexes into the layer's `keys` and `values`
+    pub tags: Vec<u32>,
+    pub geom_type: GeomType,
+    /// Encoded geometry commands
+    pub geometry: Vec<u32>,
+}
+
+impl VectorTile {
+    /// Decode an uncompressed MVT protobuf
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        let mut tile = VectorTile::default();
+        let mut reader = Reader::new(data);
+        while let Some((field, wire)) = reader.key()? {
+            match (field, wire) {
+                (3, WIRE_LEN) => tile.layers.push(Layer::decode(reader.bytes()?)?),
+                _ => reader.skip(wire)?,
+            }
+        }
+        Ok(tile)
+    }
+
+    /// Decode the layers of an uncompressed MVT protobuf, each with the
+    /// number of bytes it takes up in `data`
+    pub fn decode_layer_sizes(data: &[u8]) -> Result<Vec<(Layer, usize)>> {
+        let mut layers = Vec::new();
+        let mut reader = Reader::new(data);
+        while let Some((field, wire)) = reader.key()? {
+            match (field, wire) {
+                (3, WIRE_LEN) => {
+                    let bytes = reader.bytes()?;
+                    layers.push((Layer::decode(bytes)?, bytes.len()));
+                }
+                _ => reader.skip(wire)?,
+            }
+        }
+        Ok(layers)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for layer in &self.layers {
+            write_message(&mut out, 3, &layer.encode());
+        }
+        out
+    }
+
+    /// The part of this tile covering one of its descendants `levels` zooms
+    /// down, at column `dx` and row `dy` counted from the top within this
+    /// tile: geometry is scaled up and clipped to the descendant plus a
+    /// small buffer. Features left without geometry are dropped, then
+    /// layers left without features.
+    pub fn overzoom(&self, levels: u32, dx: u32, dy: u32) -> Result<VectorTile> {
+        let mut layers = Vec::new();
+        for layer in &self.layers {
+            let extent = layer.extent as i64;
+            let buffer = (layer.extent as f64 * CLIP_BUFFER).round();
+            let (min, max) = (-buffer, extent as f64 + buffer);
+            let (origin_x, origin_y) = (dx as i64 * extent, dy as i64 * extent);
+
+            let mut features = Vec::new();
+            for feature in &layer.features {
+                let parts = feature.parts()?;
+                let scaled: Vec<Vec<(f64, f64)>> = parts
+                    .iter()
+                    .map(|part| {
+                        part.iter()
+                            .map(|&(x, y)| (((x << levels) - origin_x) as f64, ((y << levels) - origin_y) as f64))
+                            .collect()
+                    })
+                    .collect();
+                let clipped: Vec<Vec<(i64, i64)>> = match feature.geom_type {
+                    GeomType::Point => scaled
+                        .iter()
+                        .flatten()
+                        .filter(|&&(x, y)| (min..max).contains(&x) && (min..max).contains(&y))
+                        .map(|&(x, y)| vec![(x as i64, y as i64)])
+                        .collect(),
+                    GeomType::LineString => scaled.iter().flat_map(|line| clip_line(line, min, max)).collect(),
+                    GeomType::Polygon => {
+                        // Holes go with their exterior, the ring before them with positive area
+                        let mut rings = Vec::new();
+                        let mut exterior_kept = false;
+                        for (ring, points) in parts.iter().zip(&scaled) {
+                            let exterior = signed_area(ring) > 0;
+                            if !exterior && !exterior_kept {
+                                continue;
+                            }
+                            let clipped = clip_ring(points, min, max);
+                            if exterior {
+                                exterior_kept = clipped.is_some();
+                            }
+                            rings.extend(clipped);
+                        }
+                        rings
+                    }
+                    GeomType::Unknown => continue,
+                };
+                if !clipped.is_empty() {
+                    features.push(Feature { geometry: encode_geometry(&clipped, feature.geom_type), ..feature.clone() });
+                }
+            }
+
+            if !features.is_empty() {
+                layers.push(Layer {
+                    name: layer.name.clone(),
+                    version: layer.version,
+                    extent: layer.extent,
+                    keys: layer.keys.clone(),
+                    values: layer.values.clone(),
+                    features,
+                });
+            }
+        }
+        Ok(VectorTile { layers })
+    }
+
+    /// Simplify lines and polygon rings with Douglas-Peucker, dropping
+    /// vertices closer than `tolerance` to the simplified shape. The
+    /// tolerance is in units of a 4096 extent and scaled to each layer's
+    /// extent. Rings that collapse are removed, an exterior with its holes,
+    /// then features left without geometry and layers left without features.
+    pub fn simplify(&mut self, tolerance: f64) -> Result<()> {
+        for layer in &mut self.layers {
+            let tolerance = tolerance * layer.extent as f64 / 4096.0;
+            layer.rebuild_geometry(
+                |point| Some(point.to_vec()),
+                |line| Some(douglas_peucker(line, tolerance)).filter(|line| line.len() >= 2),
+                |ring| simplify_ring(ring, tolerance),
+            )?;
+        }
+        self.layers.retain(|layer| !layer.features.is_empty());
+        Ok(())
+    }
+
+    /// Rescale geometry to layers of `extent` units per tile side, rounding
+    /// to the new grid. Vertices that land on the same spot merge, and lines
+    /// and rings left without length or area, or rings whose orientation
+    /// flips, are removed like collapsed rings in [`VectorTile::simplify`].
+    pub fn rescale(&mut self, extent: u32) -> Result<()> {
+        for layer in &mut self.layers {
+            if layer.extent == extent {
+                continue;
+            }
+            let factor = extent as f64 / layer.extent as f64;
+            let scale = |points: &[(i64, i64)]| -> Vec<(i64, i64)> {
+                let mut scaled: Vec<(i64, i64)> =
+                    points.iter().map(|&(x, y)| ((x as f64 * factor).round() as i64, (y as f64 * factor).round() as i64)).collect();
+                scaled.dedup();
+                scaled
+            };
+            layer.rebuild_geometry(
+                |point| Some(scale(point)),
+                |line| Some(scale(line)).filter(|line| line.len() >= 2),
+                |ring| {
+                    let scaled = scale(ring);
+                    let area = signed_area(&scaled);
+                    (scaled.len() >= 4 && area != 0 && area.signum() == signed_area(ring).signum()).then_some(scaled)
+                },
+            )?;
+            layer.extent = extent;
+        }
+        self.layers.retain(|layer| !layer.features.is_empty());
+        Ok(())
+    }
+
+    /// Clip geometry to `area`, rings in units of the tile's side with the
+    /// origin at its top left, like the tile extent scaled to 1. Points
+    /// outside are removed, lines cut into the pieces inside and polygons
+    /// intersected with it, then emptied features and layers removed.
+    /// Returns false, leaving layers as they were, if all geometry already
+    /// was inside.
+    pub(crate) fn clip_to_area(&mut self, area: &[Ring]) -> Result<bool> {
+        let mut changed = false;
+        for layer in &mut self.layers {
+            let extent = layer.extent as f64;
+            let parts = layer.features.iter().map(Feature::parts).collect::<Result<Vec<_>>>()?;
+            let Some((min, max)) = parts.iter().flatten().flatten().fold(None, |bounds: Option<(i64, i64)>, &(x, y)| {
+                let (min, max) = bounds.unwrap_or((x, x));
+                Some((min.min(x).min(y), max.max(x).max(y)))
+            }) else {
+                continue;
+            };
+            // A square just around the geometry, off the integer grid so no
+            // vertex lies on its sides
+            let (min, max) = (min as f64 - 1.5, max as f64 + 1.5);
+            let scaled: Vec<Ring> =
+                area.iter().map(|ring| ring.iter().map(|&(x, y)| (x * extent, y * extent)).collect()).collect();
+            if !clip::crosses_square(&scaled, min, max) {
+                if clip::contains(&scaled, (min, min)) {
+                    continue;
+                }
+                layer.features.clear();
+                changed = true;
+                continue;
+            }
+            changed = true;
+            // Only what's near the geometry takes part in the crossing tests
+            let area = clip::clip_to_square(&scaled, min, max);
+            let float = |part: &[(i64, i64)]| -> Vec<(f64, f64)> { part.iter().map(|&(x, y)| (x as f64, y as f64)).collect() };
+            let round = |part: &[(f64, f64)]| -> Vec<(i64, i64)> {
+                let mut rounded: Vec<(i64, i64)> = part.iter().map(|&(x, y)| (x.round() as i64, y.round() as i64)).collect();
+                rounded.dedup();
+                rounded
+            };
+
+            let mut features = Vec::with_capacity(layer.features.len());
+            for (mut feature, parts) in std::mem::take(&mut layer.features).into_iter().zip(parts) {
+                let clipped: Vec<Vec<(i64, i64)>> = match feature.geom_type {
+                    GeomType::Point => parts
+                        .into_iter()
+                        .flatten()
+                        .filter(|&(x, y)| clip::contains(&area, (x as f64, y as f64)))
+                        .map(|point| vec![point])
+                        .collect(),
+                    GeomType::LineString => parts
+                        .iter()
+                        .flat_map(|part| clip::clip_line(&float(part), &area))
+                        .map(|line| round(&line))
+                        .filter(|line| line.len() >= 2)
+                        .collect(),
+                    GeomType::Polygon => {
+                        let rings: Vec<Ring> = parts.iter().map(|part| float(&part[..part.len() - 1])).filter(|ring| ring.len() >= 3).collect();
+                        let mut kept = Vec::new();
+                        let mut exterior_kept = false;
+                        for ring in clip::nest(clip::clip_polygon(&rings, &area)) {
+                            let mut rounded = round(&ring);
+                            if rounded.len() > 1 && rounded.first() == rounded.last() {
+                                rounded.pop();
+                            }
+                            let exterior = clip::signed_area(&ring) > 0.0;
+                            if !exterior && !exterior_kept {
+                                continue;
+                            }
+                            rounded.extend(rounded.first().copied());
+                            let rounded_area = signed_area(&rounded);
+                            // Rounding can flatten or flip small rings
+                            let valid = rounded.len() >= 4 && rounded_area != 0 && (rounded_area > 0) == exterior;
+                            if exterior {
+                                exterior_kept = valid;
+                            }
+                            if valid {
+                                kept.push(rounded);
+                            }
+                        }
+                        kept
+                    }
+                    GeomType::Unknown => {
+                        features.push(feature);
+                        continue;
+                    }
+                };
+                if !clipped.is_empty() {
+                    feature.geometry = encode_geometry(&clipped, feature.geom_type);
+                    features.push(feature);
+                }
+            }
+            layer.features = features;
+        }
+        self.layers.retain(|layer| !layer.features.is_empty());
+        Ok(changed)
+    }
+
+    pub fn layer(&self, name: &str) -> Option<&Layer> {
+        self.layers.iter().find(|layer| layer.name == name)
+    }
+
+    /// GeoJSON for the tile at `zoom`/`x`/`tms_y`: an object mapping each
+    /// layer name to a FeatureCollection in WGS84 coordinates
+    pub fn to_geojson(&self, zoom: i32, x: i32, tms_y: i32) -> Result<Json> {
+        let mut layers = Map::new();
+        for layer in &self.layers {
+            let features = layer
+                .features
+                .iter()
+                .map(|feature| layer.feature_to_geojson(feature, zoom, x, tms_y))
+                .collect::<Result<Vec<_>>>()?;
+            layers.insert(layer.name.clone(), json!({ "type": "FeatureCollection", "features": features }));
+        }
+        Ok(Json::Object(layers))
+    }
+}
+
+impl Layer {
+    fn decode(data: &[u8]) -> Result<Self> {
+        let mut layer = Layer {
+            name: String::new(),
+            version: 1,
+            extent: 4096,
+            keys: Vec::new(),
+            values: Vec::new(),
+            features: Vec::new(),
+        };
+        let mut reader = Reader::new(data);
+        while let Some((field, wire)) = reader.key()? {
+            match (field, wire) {
+                (15, WIRE_VARINT) => layer.version = reader.varint()? as u32,
+                (1, WIRE_LEN) => layer.name = reader.string()?,
+                (2, WIRE_LEN) => layer.features.push(Feature::decode(reader.bytes()?)?),
+                (3, WIRE_LEN) => layer.keys.push(reader.string()?),
+                (4, WIRE_LEN) => layer.values.push(Value::decode(reader.bytes()?)?),
+                (5, WIRE_VARINT) => layer.extent = reader.varint()? as u32,
+                _ => reader.skip(wire)?,
+            }
+        }
+        Ok(layer)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_varint_field(&mut out, 15, self.version as u64);
+        write_message(&mut out, 1, self.name.as_bytes());
+        for feature in &self.features {
+            write_message(&mut out, 2, &feature.encode());
+        }
+        for key in &self.keys {
+            write_message(&mut out, 3, key.as_bytes());
+        }
+        for value in &self.values {
+            write_message(&mut out, 4, &value.encode());
+        }
+        write_varint_field(&mut out, 5, self.extent as u64);
+        out
+    }
+
+    /// Move the features of `other`, a layer of the same name, into this
+    /// one, extending the key and value tables as needed
+    pub fn append(&mut self, other: Layer) -> Result<()> {
+        if other.extent != self.extent {
+            return Err(anyhow!(
+                "Cannot merge layer {} with extents {} and {}",
+                self.name, self.extent, other.extent
+            ));
+        }
+        let key_index: Vec<u32> = other.keys.into_iter().map(|key| self.key_index(key)).collect();
+        let value_index: Vec<u32> = other.values.into_iter().map(|value| self.value_index(value)).collect();
+        for mut feature in other.features {
+            feature.tags = feature
+                .tags
+                .chunks_exact(2)
+                .filter_map(|pair| Some([*key_index.get(pair[0] as usize)?, *value_index.get(pair[1] as usize)?]))
+                .flatten()
+                .collect();
+            self.features.push(feature);
+        }
+        Ok(())
+    }
+
+    fn key_index(&mut self, key: String) -> u32 {
+        match self.keys.iter().position(|k| *k == key) {
+            Some(index) => index as u32,
+            None => {
+                self.keys.push(key);
+                self.keys.len() as u32 - 1
+            }
+        }
+    }
+
+    fn value_index(&mut self, value: Value) -> u32 {
+        match self.values.iter().position(|v| *v == value) {
+            Some(index) => index as u32,
+            None => {
+                self.values.push(value);
+                self.values.len() as u32 - 1
+            }
+        }
+    }
+
+    /// Replace the geometry of every feature part by part: `point` maps a
+    /// feature's points together, `line` each line and `ring` each polygon
+    /// ring. Returning `None` removes the part; holes go with their exterior,
+    /// the ring before them with positive area. Features left without parts
+    /// are removed.
+    fn rebuild_geometry(
+        &mut self,
+        point: impl Fn(&[(i64, i64)]) -> Option<Vec<(i64, i64)>>,
+        line: impl Fn(&[(i64, i64)]) -> Option<Vec<(i64, i64)>>,
+        ring: impl Fn(&[(i64, i64)]) -> Option<Vec<(i64, i64)>>,
+    ) -> Result<()> {
+        let mut features = Vec::with_capacity(self.features.len());
+        for mut feature in std::mem::take(&mut self.features) {
+            let parts = feature.parts()?;
+            let rebuilt: Vec<Vec<(i64, i64)>> = match feature.geom_type {
+                GeomType::Point => {
+                    let points: Vec<(i64, i64)> = parts.into_iter().flatten().collect();
+                    point(&points).unwrap_or_default().into_iter().map(|p| vec![p]).collect()
+                }
+                GeomType::LineString => parts.iter().filter_map(|part| line(part)).collect(),
+                GeomType::Polygon => {
+                    let mut rings = Vec::new();
+                    let mut exterior_kept = false;
+                    for part in &parts {
+                        let area = signed_area(part);
+                        if area < 0 && !exterior_kept {
+                            continue;
+                        }
+                        let rebuilt = ring(part);
+                        if area > 0 {
+                            exterior_kept = rebuilt.is_some();
+                        }
+                        rings.extend(rebuilt);
+                    }
+                    rings
+                }
+                GeomType::Unknown => {
+                    features.push(feature);
+                    continue;
+                }
+            };
+            if !rebuilt.is_empty() {
+                feature.geometry = encode_geometry(&rebuilt, feature.geom_type);
+                features.push(feature);
+            }
+        }
+        self.features = features;
+        Ok(())
+    }
+
+    /// (key, value) properties of `feature`, skipping out of range tags
+    pub fn properties<'a>(&'a self, feature: &'a Feature) -> impl Iterator<Item = (&'a str, &'a Value)> + 'a {
+        feature.tags.chunks_exact(2).filter_map(|pair| {
+            let key = self.keys.get(pair[0] as usize)?;
+            let value = self.values.get(pair[1] as usize)?;
+            Some((key.as_str(), value))
+        })
+    }
+
+    fn feature_to_geojson(&self, feature: &Feature, zoom: i32, x: i32, tms_y: i32) -> Result<Json> {
+        // Tile coordinates to lon/lat via the tile's origin in world units of `extent` per tile
+        let extent = self.extent as f64;
+        let world = extent * 2_f64.powi(zoom);
+        let left = x as f64 * extent;
+        let top = ((1 << zoom) - 1 - tms_y) as f64 * extent;
+        let project = |&(px, py): &(i64, i64)| -> Json {
+            let lon = (left + px as f64) / world * 360.0 - 180.0;
+            let lat = (std::f64::consts::PI * (1.0 - 2.0 * (top + py as f64) / world)).sinh().atan().to_degrees();
+            json!([round7(lon), round7(lat)])
+        };
+        let line = |part: &Vec<(i64, i64)>| Json::Array(part.iter().map(project).collect());
+
+        let parts = feature.parts()?;
+        let geometry = match feature.geom_type {
+            GeomType::Point => match parts.len() {
+                1 => json!({ "type": "Point", "coordinates": project(&parts[0][0]) }),
+                _ => json!({ "type": "MultiPoint", "coordinates": parts.iter().map(|p| project(&p[0])).collect::<Vec<_>>() }),
+            },
+            GeomType::LineString => match parts.len() {
+                1 => json!({ "type": "LineString", "coordinates": line(&parts[0]) }),
+                _ => json!({ "type": "MultiLineString", "coordinates": parts.iter().map(line).collect::<Vec<_>>() }),
+            },
+            GeomType::Polygon => {
+                // A ring with positive area starts a new polygon; the rest are its holes
+                let mut polygons: Vec<Vec<Json>> = Vec::new();
+                for ring in &parts {
+                    let area = signed_area(ring);
+                    if area > 0 || polygons.is_empty() && area != 0 {
+                        polygons.push(vec![line(ring)]);
+                    } else if area < 0 && let Some(polygon) = polygons.last_mut() {
+                        polygon.push(line(ring));
+                    }
+                }
+                match polygons.len() {
+                    1 => json!({ "type": "Polygon", "coordinates": polygons.pop() }),
+                    _ => json!({ "type": "MultiPolygon", "coordinates": polygons }),
+                }
+            }
+            GeomType::Unknown => Json::Null,
+        };
+
+        let properties: Map<String, Json> =
+            self.properties(feature).map(|(key, value)| (key.to_string(), value.to_json())).collect();
+        let mut out = json!({ "type": "Feature", "geometry": geometry, "properties": properties });
+        if let Some(id) = feature.id {
+            out["id"] = json!(id);
+        }
+        Ok(out)
+    }
+}
+
+impl Value {
+    fn decode(data: &[u8]) -> Result<Self> {
+        let mut reader = Reader::new(data);
+        let mut value = None;
+        while let Some((field, wire)) = reader.key()? {
+            value = Some(match (field, wire) {
+                (1, WIRE_LEN) => Value::String(reader.string()?),
+                (2, WIRE_32BIT) => Value::Float(f32::from_bits(reader.fixed32()?)),
+                (3, WIRE_64BIT) => Value::Double(f64::from_bits(reader.fixed64()?)),
+                (4, WIRE_VARINT) => Value::Int(reader.varint()? as i64),
+                (5, WIRE_VARINT) => Value::Uint(reader.varint()?),
+                (6, WIRE_VARINT) => Value::Sint(zigzag_decode(reader.varint()?)),
+                (7, WIRE_VARINT) => Value::Bool(reader.varint()? != 0),
+                _ => {
+                    reader.skip(wire)?;
+                    continue;
+                }
+            });
+        }
+        value.ok_or_else(|| anyhow!("Empty value in vector tile"))
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        match self {
+            Value::String(s) => write_message(&mut out, 1, s.as_bytes()),
+            Value::Float(f) => {
+                write_key(&mut out, 2, WIRE_32BIT);
+                out.extend_from_slice(&f.to_bits().to_le_bytes());
+            }
+            Value::Double(d) => {
+                write_key(&mut out, 3, WIRE_64BIT);
+                out.extend_from_slice(&d.to_bits().to_le_bytes());
+            }
+            Value::Int(i) => write_varint_field(&mut out, 4, *i as u64),
+            Value::Uint(u) => write_varint_field(&mut out, 5, *u),
+            Value::Sint(i) => write_varint_field(&mut out, 6, zigzag_encode(*i)),
+            Value::Bool(b) => write_varint_field(&mut out, 7, *b as u64),
+        }
+        out
+    }
+
+    pub fn to_json(&self) -> Json {
+        match self {
+            Value::String(s) => json!(s),
+            Value::Float(f) => json!(f),
+            Value::Double(d) => json!(d),
+            Value::Int(i) | Value::Sint(i) => json!(i),
+            Value::Uint(u) => json!(u),
+            Value::Bool(b) => json!(b),
+        }
+    }
+}
+
+impl Feature {
+    fn decode(data: &[u8]) -> Result<Self> {
+        let mut feature = Feature { id: None, tags: Vec::new(), geom_type: GeomType::Unknown, geometry: Vec::new() };
+        let mut reader = Reader::new(data);
+        while let Some((field, wire)) = reader.key()? {
+            match (field, wire) {
+                (1, WIRE_VARINT) => feature.id = Some(reader.varint()?),
+                (2, WIRE_LEN) => feature.tags = reader.packed()?,
+                (3, WIRE_VARINT) => {
+                    feature.geom_type = match reader.varint()? {
+                        1 => GeomType::Point,
+                        2 => GeomType::LineString,
+                        3 => GeomType::Polygon,
+                        _ => GeomType::Unknown,
+                    }
+                }
+                (4, WIRE_LEN) => feature.geometry = reader.packed()?,
+                _ => reader.skip(wire)?,
+            }
+        }
+        Ok(feature)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        if let Some(id) = self.id {
+            write_varint_field(&mut out, 1, id);
+        }
+        if !self.tags.is_empty() {
+            write_packed(&mut out, 2, &self.tags);
+        }
+        let geom_type = match self.geom_type {
+            GeomType::Unknown => 0,
+            GeomType::Point => 1,
+            GeomType::LineString => 2,
+            GeomType::Polygon => 3,
+        };
+        write_varint_field(&mut out, 3, geom_type);
+        write_packed(&mut out, 4, &self.geometry);
+        out
+    }
+
+    /// Decode the geometry into parts in tile coordinates: one per point,
+    /// line or ring. Rings are closed by repeating their first vertex.
+    pub fn parts(&self) -> Result<Vec<Vec<(i64, i64)>>> {
+        let mut parts: Vec<Vec<(i64, i64)>> = Vec::new();
+        let (mut x, mut y) = (0i64, 0i64);
+        let mut i = 0;
+        while i < self.geometry.len() {
+            let command = self.geometry[i] & 0x7;
+            let count = (self.geometry[i] >> 3) as usize;
+            i += 1;
+            match command {
+                CMD_MOVE_TO | CMD_LINE_TO => {
+                    if i + 2 * count > self.geometry.len() {
+                        return Err(anyhow!("Truncated geometry in vector tile"));
+                    }
+                    for _ in 0..count {
+                        x += zigzag_decode(self.geometry[i] as u64);
+                        y += zigzag_decode(self.geometry[i + 1] as u64);
+                        i += 2;
+                        match parts.last_mut() {
+                            Some(part) if command == CMD_LINE_TO => part.push((x, y)),
+                            None if command == CMD_LINE_TO => return Err(anyhow!("LineTo before MoveTo in vector tile")),
+                            _ => parts.push(vec![(x, y)]),
+                        }
+                    }
+                }
+                CMD_CLOSE_PATH => {
+                    if let Some(part) = parts.last_mut()
+                        && let Some(&first) = part.first()
+                    {
+                        part.push(first);
+                    }
+                }
+                _ => return Err(anyhow!("Unknown geometry command {} in vector tile", command)),
+            }
+        }
+        Ok(parts)
+    }
+}
+
+/// Commands for `parts` as returned by [`Feature::parts`]: points become
+/// one MoveTo, lines a MoveTo and LineTo each and closed rings also a
+/// ClosePath instead of their repeated first vertex
+fn encode_geometry(parts: &[Vec<(i64, i64)>], geom_type: GeomType) -> Vec<u32> {
+    let command = |id: u32, count: usize| id | (count as u32) << 3;
+    let mut out = Vec::new();
+    let mut cursor = (0, 0);
+    let mut push = |out: &mut Vec<u32>, (x, y): (i64, i64)| {
+        out.push(zigzag_encode(x - cursor.0) as u32);
+        out.push(zigzag_encode(y - cursor.1) as u32);
+        cursor = (x, y);
+    };
+
+    if geom_type == GeomType::Point {
+        out.push(command(CMD_MOVE_TO, parts.len()));
+        for &point in parts.iter().flatten() {
+            push(&mut out, point);
+        }
+        return out;
+    }
+    for part in parts {
+        let closed = geom_type == GeomType::Polygon;
+        let points = if closed { &part[..part.len() - 1] } else { &part[..] };
+        out.push(command(CMD_MOVE_TO, 1));
+        push(&mut out, points[0]);
+        out.push(command(CMD_LINE_TO, points.len() - 1));
+        for &point in &points[1..] {
+            push(&mut out, point);
+        }
+        if closed {
+            out.push(command(CMD_CLOSE_PATH, 1));
+        }
+    }
+    out
+}
+
+/// The pieces of a line inside the square from `min` to `max`
+fn clip_line(line: &[(f64, f64)], min: f64, max: f64) -> Vec<Vec<(i64, i64)>> {
+    let round = |(x, y): (f64, f64)| (x.round() as i64, y.round() as i64);
+    let mut pieces = Vec::new();
+    let mut current: Vec<(i64, i64)> = Vec::new();
+    for pair in line.windows(2) {
+        let (a, b) = (pair[0], pair[1]);
+        let Some((t0, t1)) = clip_segment(a, b, min, max) else {
+            pieces.push(std::mem::take(&mut current));
+            continue;
+        };
+        let at = |t: f64| round((a.0 + t * (b.0 - a.0), a.1 + t * (b.1 - a.1)));
+        let (start, end) = (at(t0), at(t1));
+        if current.last() != Some(&start) {
+            pieces.push(std::mem::take(&mut current));
+            current.push(start);
+        }
+        if end != start {
+            current.push(end);
+        }
+        if t1 < 1.0 {
+            pieces.push(std::mem::take(&mut current));
+        }
+    }
+    pieces.push(current);
+    pieces.retain(|piece| piece.len() >= 2);
+    pieces
+}
+
+/// Liang-Barsky: the parameter range of segment `a`-`b` inside the square
+/// from `min` to `max`, `None` if it misses the square
+fn clip_segment(a: (f64, f64), b: (f64, f64), min: f64, max: f64) -> Option<(f64, f64)> {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let (mut t0, mut t1) = (0.0_f64, 1.0_f64);
+    for (p, q) in [(-dx, a.0 - min), (dx, max - a.0), (-dy, a.1 - min), (dy, max - a.1)] {
+        if p == 0.0 {
+            if q < 0.0 {
+                return None;
+            }
+        } else if p < 0.0 {
+            t0 = t0.max(q / p);
+        } else {
+            t1 = t1.min(q / p);
+        }
+    }
+    (t0 <= t1).then_some((t0, t1))
+}
+
+/// Sutherland-Hodgman: the closed ring cut to the square from `min` to
+/// `max`, `None` if nothing with an area is left
+fn clip_ring(ring: &[(f64, f64)], min: f64, max: f64) -> Option<Vec<(i64, i64)>> {
+    let mut points: Vec<(f64, f64)> = ring[..ring.len().saturating_sub(1)].to_vec();
+    // Each side of the square as the axis it bounds, its position and
+    // whether the inside is above it
+    for (axis, bound, above) in [(0, min, true), (0, max, false), (1, min, true), (1, max, false)] {
+        let coordinate = |p: (f64, f64)| if axis == 0 { p.0 } else { p.1 };
+        let inside = |p: (f64, f64)| if above { coordinate(p) >= bound } else { coordinate(p) <= bound };
+        let input = std::mem::take(&mut points);
+        for (i, &current) in input.iter().enumerate() {
+            let previous = input[(i + input.len() - 1) % input.len()];
+            let crossing = || {
+                let t = (bound - coordinate(previous)) / (coordinate(current) - coordinate(previous));
+                (previous.0 + t * (current.0 - previous.0), previous.1 + t * (current.1 - previous.1))
+            };
+            match (inside(previous), inside(current)) {
+                (true, true) => points.push(current),
+                (true, false) => points.push(crossing()),
+                (false, true) => {
+                    points.push(crossing());
+                    points.push(current);
+                }
+                (false, false) => {}
+            }
+        }
+    }
+
+    let mut rounded: Vec<(i64, i64)> = points.iter().map(|&(x, y)| (x.round() as i64, y.round() as i64)).collect();
+    rounded.dedup();
+    while rounded.len() > 1 && rounded.first() == rounded.last() {
+        rounded.pop();
+    }
+    if rounded.len() < 3 {
+        return None;
+    }
+    rounded.push(rounded[0]);
+    (signed_area(&rounded) != 0).then_some(rounded)
+}
+
+/// `line` without the vertices within `tolerance` of the line through its
+/// remaining neighbours, keeping both ends
+fn douglas_peucker(line: &[(i64, i64)], tolerance: f64) -> Vec<(i64, i64)> {
+    if line.len() <= 2 {
+        let mut line = line.to_vec();
+        line.dedup();
+        return line;
+    }
+    let mut keep = vec![false; line.len()];
+    keep[0] = true;
+    keep[line.len() - 1] = true;
+    let mut spans = vec![(0, line.len() - 1)];
+    while let Some((first, last)) = spans.pop() {
+        let farthest = (first + 1..last)
+            .map(|i| (i, segment_distance(line[i], line[first], line[last])))
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+        if let Some((i, distance)) = farthest
+            && distance > tolerance
+        {
+            keep[i] = true;
+            spans.push((first, i));
+            spans.push((i, last));
+        }
+    }
+    let mut simplified: Vec<(i64, i64)> = line.iter().zip(keep).filter(|(_, keep)| *keep).map(|(&p, _)| p).collect();
+    simplified.dedup();
+    simplified
+}
+
+/// A closed ring simplified in two halves split at the vertex farthest from
+/// its start, `None` if it loses its area or its orientation flips
+fn simplify_ring(ring: &[(i64, i64)], tolerance: f64) -> Option<Vec<(i64, i64)>> {
+    if ring.len() < 4 {
+        return None;
+    }
+    let start = ring[0];
+    let split = (1..ring.len() - 1).max_by(|&a, &b| {
+        segment_distance(ring[a], start, start).total_cmp(&segment_distance(ring[b], start, start))
+    })?;
+    let mut simplified = douglas_peucker(&ring[..=split], tolerance);
+    simplified.extend(&douglas_peucker(&ring[split..], tolerance)[1..]);
+    let area = signed_area(&simplified);
+    (simplified.len() >= 4 && area.signum() == signed_area(ring).signum() && area != 0).then_some(simplified)
+}
+
+/// Distance from `p` to the segment `a`-`b`
+fn segment_distance(p: (i64, i64), a: (i64, i64), b: (i64, i64)) -> f64 {
+    let (px, py, ax, ay, bx, by) = (p.0 as f64, p.1 as f64, a.0 as f64, a.1 as f64, b.0 as f64, b.1 as f64);
+    let (dx, dy) = (bx - ax, by - ay);
+    let length = dx * dx + dy * dy;
+    let t = if length == 0.0 { 0.0 } else { (((px - ax) * dx + (py - ay) * dy) / length).clamp(0.0, 1.0) };
+    ((px - ax - t * dx).powi(2) + (py - ay - t * dy).powi(2)).sqrt()
+}
+
+/// Twice the surveyor's formula area of `ring`; positive for exterior rings
+fn signed_area(ring: &[(i64, i64)]) -> i64 {
+    ring.windows(2).map(|w| w[0].0 * w[1].1 - w[1].0 * w[0].1).sum()
+}
+
+fn round7(value: f64) -> f64 {
+    (value * 1e7).round() / 1e7
+}
+
+fn zigzag_decode(value: u64) -> i64 {
+    ((value >> 1) as i64) ^ -((value & 1) as i64)
+}
+
+fn zigzag_encode(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn write_key(out: &mut Vec<u8>, field: u64, wire: u64) {
+    write_varint(out, field << 3 | wire);
+}
+
+fn write_varint_field(out: &mut Vec<u8>, field: u64, value: u64) {
+    write_key(out, field, WIRE_VARINT);
+    write_varint(out, value);
+}
+
+fn write_message(out: &mut Vec<u8>, field: u64, data: &[u8]) {
+    write_key(out, field, WIRE_LEN);
+    write_varint(out, data.len() as u64);
+    out.extend_from_slice(data);
+}
+
+fn write_packed(out: &mut Vec<u8>, field: u64, values: &[u32]) {
+    let mut data = Vec::new();
+    for &value in values {
+        write_varint(&mut data, value as u64);
+    }
+    write_message(out, field, &data);
+}
+
+/// Cursor over protobuf wire format data
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data, pos: 0 }
+    }
+
+    /// Next (field number, wire type), or `None` at the end
+    fn key(&mut self) -> Result<Option<(u64, u64)>> {
+        if self.pos >= self.data.len() {
+            return Ok(None);
+        }
+        let key = self.varint()?;
+        Ok(Some((key >> 3, key & 0x7)))
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = *self.data.get(self.pos).ok_or_else(|| anyhow!("Truncated varint in vector tile"))?;
+            self.pos += 1;
+            value |= ((byte & 0x7F) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(anyhow!("Varint too long in vector tile"))
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self.pos.checked_add(len).filter(|&end| end <= self.data.len());
+        let end = end.ok_or_else(|| anyhow!("Truncated field in vector tile"))?;
+        let slice = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.varint()? as usize;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        Ok(String::from_utf8(self.bytes()?.to_vec())?)
+    }
+
+    fn fixed32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn fixed64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn packed(&mut self) -> Result<Vec<u32>> {
+        let mut inner = Reader::new(self.bytes()?);
+        let mut values = Vec::new();
+        while inner.pos < inner.data.len() {
+            values.push(inner.varint()? as u32);
+        }
+        Ok(values)
+    }
+
+    fn skip(&mut self, wire: u64) -> Result<()> {
+        match wire {
+            WIRE_VARINT => {
+                self.varint()?;
+            }
+            WIRE_64BIT => {
+                self.take(8)?;
+            }
+            WIRE_LEN => {
+                self.bytes()?;
+            }
+            WIRE_32BIT => {
+                self.take(4)?;
+            }
+            _ => return Err(anyhow!("Unsupported wire type {} in vector tile", wire)),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feature(id: Option<u64>, geom_type: GeomType, parts: &[Vec<(i64, i64)>]) -> Feature {
+        Feature { id, tags: Vec::new(), geom_type, geometry: encode_geometry(parts, geom_type) }
+    }
+
+    fn layer(name: &str, features: Vec<Feature>) -> Layer {
+        Layer { name: name.to_string(), version: 2, extent: 4096, keys: Vec::new(), values: Vec::new(), features }
+    }
+
+    /// A layer "places" with a point feature tagged with every value type
+    fn sample() -> VectorTile {
+        let mut places = layer("places", vec![feature(Some(1), GeomType::Point, &[vec![(25, 17)], vec![(-3, 4000)]])]);
+        places.keys = ["name", "float", "double", "int", "uint", "sint", "bool"].map(str::to_string).to_vec();
+        places.values = vec![
+            Value::String("Zürich".to_string()),
+            Value::Float(1.5),
+            Value::Double(-2.25),
+            Value::Int(-7),
+            Value::Uint(u64::MAX),
+            Value::Sint(-300),
+            Value::Bool(true),
+        ];
+        places.features[0].tags = (0..7).flat_map(|i| [i, i]).collect();
+        let roads = layer(
+            "roads",
+            vec![
+                feature(None, GeomType::LineString, &[vec![(0, 0), (100, 50), (-20, 300)]]),
+                feature(Some(3), GeomType::Polygon, &[vec![(0, 0), (0, 10), (10, 10), (10, 0), (0, 0)]]),
+            ],
+        );
+        VectorTile { layers: vec![places, roads] }
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let tile = sample();
+        let data = tile.encode();
+        let decoded = VectorTile::decode(&data).unwrap();
+        assert_eq!(decoded, tile);
+        assert_eq!(decoded.encode(), data);
+        assert_eq!(tile.layers[0].properties(&decoded.layers[0].features[0]).count(), 7);
+        assert_eq!(decoded.layers[1].features[1].parts().unwrap(), vec![vec![(0, 0), (0, 10), (10, 10), (10, 0), (0, 0)]]);
+
+        let sizes = VectorTile::decode_layer_sizes(&data).unwrap();
+        assert_eq!(sizes.iter().map(|(layer, _)| layer.clone()).collect::<Vec<_>>(), tile.layers);
+        assert!(sizes.iter().all(|(layer, size)| layer.encode().len() == *size));
+    }
+
+    #[test]
+    fn decode_encode_round_trip() {
+        // Layer fields in the order `encode` writes them: version, name,
+        // a feature (id 1, tags [0, 0], a point at (25, 17)), key, value, extent
+        let data = [
+            0x1a, 0x1e, 0x78, 0x02, 0x0a, 0x01, b'p', 0x12, 0x0d, 0x08, 0x01, 0x12, 0x02, 0x00, 0x00, 0x18, 0x01, 0x22, 0x03,
+            0x09, 0x32, 0x22, 0x1a, 0x01, b'k', 0x22, 0x02, 0x28, 0x05, 0x28, 0x80, 0x20,
+        ];
+        let tile = VectorTile::decode(&data).unwrap();
+        let p = &tile.layers[0];
+        assert_eq!((p.name.as_str(), p.version, p.extent), ("p", 2, 4096));
+        assert_eq!(p.properties(&p.features[0]).collect::<Vec<_>>(), vec![("k", &Value::Uint(5))]);
+        assert_eq!(p.features[0].parts().unwrap(), vec![vec![(25, 17)]]);
+        assert_eq!(tile.encode(), data);
+
+        // Unknown fields are skipped, and fields missing get their defaults
+        let data = [0x1a, 0x0c, 0x0a, 0x01, b'p', 0x30, 0x07, 0x3d, 0x00, 0x00, 0x80, 0x3f, 0x10, 0x00];
+        let tile = VectorTile::decode(&data).unwrap();
+        assert_eq!(tile.layers, vec![Layer { version: 1, ..layer("p", Vec::new()) }]);
+    }
+
+    #[test]
+    fn zigzag() {
+        for (value, encoded) in [(0, 0), (-1, 1), (1, 2), (-2, 3), (2147483647, 4294967294), (-2147483648, 4294967295)] {
+            assert_eq!(zigzag_encode(value), encoded);
+            assert_eq!(zigzag_decode(encoded), value);
+        }
+        for value in [i64::MIN, i64::MAX, -300, 300] {
+            assert_eq!(zigzag_decode(zigzag_encode(value)), value);
+        }
+        // Sint values go through zigzag, Int values as two's complement
+        assert_eq!(Value::Sint(-1).encode(), [0x30, 0x01]);
+        assert_eq!(Value::Int(-1).encode().len(), 11);
+        assert_eq!(Value::decode(&Value::Int(-1).encode()).unwrap(), Value::Int(-1));
+    }
+
+    #[test]
+    fn packed_fields() {
+        let mut out = Vec::new();
+        write_packed(&mut out, 4, &[9, 300, 0, u32::MAX]);
+        assert_eq!(out, [0x22, 0x09, 0x09, 0xac, 0x02, 0x00, 0xff, 0xff, 0xff, 0xff, 0x0f]);
+        assert_eq!(Reader::new(&out[1..]).packed().unwrap(), [9, 300, 0, u32::MAX]);
+        assert_eq!(Reader::new(&[0x00]).packed().unwrap(), Vec::<u32>::new());
+
+        // A MoveTo of two points and the deltas between them, as packed geometry
+        let feature = Feature::decode(&[0x18, 0x01, 0x22, 0x05, 0x11, 0x04, 0x06, 0x03, 0x05]).unwrap();
+        assert_eq!(feature.geometry, [17, 4, 6, 3, 5]);
+        assert_eq!(feature.parts().unwrap(), vec![vec![(2, 3)], vec![(0, 0)]]);
+        // A truncated varint inside the packed bytes
+        assert!(Feature::decode(&[0x22, 0x02, 0x09, 0xac]).is_err());
+    }
+
+    #[test]
+    fn truncated_input_fails() {
+        // One layer, so no shorter prefix ends between two
+        let data = VectorTile { layers: sample().layers[..1].to_vec() }.encode();
+        for len in 1..data.len() {
+            assert!(VectorTile::decode(&data[..len]).is_err(), "{} of {} bytes decoded", len, data.len());
+        }
+        assert_eq!(VectorTile::decode(&[]).unwrap(), VectorTile::default());
+        assert_eq!(VectorTile::decode(&[0x1a, 0x05, 0x0a]).unwrap_err().to_string(), "Truncated field in vector tile");
+        assert_eq!(VectorTile::decode(&[0x1a]).unwrap_err().to_string(), "Truncated varint in vector tile");
+        let mut data = vec![0x08];
+        data.extend([0xff; 10]);
+        assert_eq!(VectorTile::decode(&data).unwrap_err().to_string(), "Varint too long in vector tile");
+        assert!(Value::decode(&[0x15, 0x00, 0x00]).is_err());
+        assert!(Value::decode(&[]).is_err());
+        // Strings must be UTF-8
+        assert!(VectorTile::decode(&[0x1a, 0x03, 0x0a, 0x01, 0xff]).is_err());
+    }
+
+    #[test]
+    fn invalid_wire_types_fail() {
+        // Groups (3, 4) and the unassigned types 6 and 7, as unknown fields
+        for wire in [3, 4, 6, 7] {
+            let error = VectorTile::decode(&[0x20 | wire]).unwrap_err().to_string();
+            assert_eq!(error, format!("Unsupported wire type {} in vector tile", wire));
+            let error = VectorTile::decode(&[0x1a, 0x01, 0x30 | wire]).unwrap_err().to_string();
+            assert_eq!(error, format!("Unsupported wire type {} in vector tile", wire));
+        }
+        // A known field with another wire type is skipped like an unknown one
+        assert_eq!(VectorTile::decode(&[0x18, 0x05]).unwrap(), VectorTile::default());
+    }
+
+    #[test]
+    fn invalid_geometry_fails() {
+        let parts = |geometry: Vec<u32>| Feature { geometry, ..feature(None, GeomType::LineString, &[]) }.parts();
+        assert_eq!(parts(vec![9, 50]).unwrap_err().to_string(), "Truncated geometry in vector tile");
+        assert_eq!(parts(vec![10, 2, 2]).unwrap_err().to_string(), "LineTo before MoveTo in vector tile");
+        assert_eq!(parts(vec![12, 2, 2]).unwrap_err().to_string(), "Unknown geometry command 4 in vector tile");
+        assert_eq!(parts(vec![9, 2, 2, 10, 2, 2, 15]).unwrap(), vec![vec![(1, 1), (2, 2), (1, 1)]]);
+    }
+
+    type Parts = Vec<Vec<(i64, i64)>>;
+
+    fn square(x0: i64, y0: i64, x1: i64, y1: i64) -> Vec<(i64, i64)> {
+        vec![(x0, y0), (x1, y0), (x1, y1), (x0, y1), (x0, y0)]
+    }
+
+    /// `parts` of one feature clipped to `area`, in units of the tile side,
+    /// with whether anything changed. `None` if the feature was removed.
+    fn clip(geom_type: GeomType, parts: &[Vec<(i64, i64)>], area: &[clip::Ring]) -> (bool, Option<Parts>) {
+        let mut tile = VectorTile { layers: vec![layer("l", vec![feature(None, geom_type, parts)])] };
+        let changed = tile.clip_to_area(area).unwrap();
+        (changed, tile.layers.first().map(|layer| layer.features[0].parts().unwrap()))
+    }
+
+    fn area_of(parts: &[Vec<(i64, i64)>]) -> i64 {
+        parts.iter().map(|part| signed_area(part)).sum::<i64>() / 2
+    }
+
+    fn tile_rect(x0: f64, y0: f64, x1: f64, y1: f64) -> clip::Ring {
+        vec![(x0, y0), (x1, y0), (x1, y1), (x0, y1)]
+    }
+
+    #[test]
+    fn clip_rings_crossing_the_edge() {
+        // The left half of the tile, cutting x at 2048
+        let left = [tile_rect(-0.1, -0.1, 0.5, 1.1)];
+        let (changed, parts) = clip(GeomType::Polygon, &[square(1000, 1000, 3000, 3000)], &left);
+        let parts = parts.unwrap();
+        assert!(changed);
+        assert_eq!(parts.len(), 1);
+        assert_eq!(area_of(&parts), 1048 * 2000);
+        assert!(parts[0].iter().all(|&(x, _)| (1000..=2048).contains(&x)));
+
+        let (_, lines) = clip(GeomType::LineString, &[vec![(1000, 1000), (3000, 1000), (3000, 3000)]], &left);
+        assert_eq!(lines.unwrap(), vec![vec![(1000, 1000), (2048, 1000)]]);
+        let (_, points) = clip(GeomType::Point, &[vec![(1000, 1000)], vec![(3000, 1000)]], &left);
+        assert_eq!(points.unwrap(), vec![vec![(1000, 1000)]]);
+    }
+
+    #[test]
+    fn clip_holes() {
+        let mut hole = square(1500, 1500, 2500, 2500);
+        hole.reverse();
+        let polygon = [square(1000, 1000, 3000, 3000), hole];
+        let (_, parts) = clip(GeomType::Polygon, &polygon, &[tile_rect(-0.1, -0.1, 0.5, 1.1)]);
+        // The hole, cut open by the edge, becomes a notch in the exterior
+        let parts = parts.unwrap();
+        assert_eq!(parts.len(), 1);
+        assert!(parts[0].contains(&(1500, 1500)) && parts[0].contains(&(2048, 2500)));
+        assert_eq!(area_of(&parts), 1048 * 2000 - 548 * 1000);
+        // Away from the edge the hole stays a hole after its exterior
+        let (_, parts) = clip(GeomType::Polygon, &polygon, &[tile_rect(-0.1, -0.1, 0.7, 1.1)]);
+        let parts = parts.unwrap();
+        assert_eq!(parts.len(), 2);
+        assert!(signed_area(&parts[0]) > 0 && signed_area(&parts[1]) < 0);
+        assert_eq!(parts[1], polygon[1]);
+
+        // Wholly inside the hole, the feature goes
+        let (changed, parts) = clip(GeomType::Polygon, &polygon, &[tile_rect(0.45, 0.45, 0.55, 0.55)]);
+        assert!(changed && parts.is_none());
+        // An area with a hole over the polygon's exterior edge leaves a frame
+        let frame = [tile_rect(0.0, 0.0, 1.0, 1.0), tile_rect(0.3, 0.3, 0.7, 0.7)];
+        let (_, parts) = clip(GeomType::Polygon, &[square(1000, 1000, 3000, 3000)], &frame);
+        let parts = parts.unwrap();
+        let inner = 2867 - 1229;
+        assert_eq!(parts.len(), 2);
+        assert_eq!(area_of(&parts), 2000 * 2000 - inner * inner);
+    }
+
+    #[test]
+    fn clip_concave_regions() {
+        // A U open at the top, its gap from x 0.33 to 0.67 down to y 0.33
+        let u = [vec![(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.67, 1.0), (0.67, 0.33), (0.33, 0.33), (0.33, 1.0), (0.0, 1.0)]];
+        let (_, parts) = clip(GeomType::Polygon, &[square(100, 2000, 4000, 2500)], &u);
+        let parts = parts.unwrap();
+        assert_eq!(parts.len(), 2);
+        assert!(parts.iter().all(|part| signed_area(part) > 0));
+        assert_eq!(area_of(&parts), (1352 - 100) * 500 + (4000 - 2744) * 500);
+
+        let (_, lines) = clip(GeomType::LineString, &[vec![(100, 2000), (4000, 2000)]], &u);
+        assert_eq!(lines.unwrap(), vec![vec![(100, 2000), (1352, 2000)], vec![(2744, 2000), (4000, 2000)]]);
+    }
+
+    #[test]
+    fn clip_features_wholly_inside_or_outside() {
+        let polygon = [square(1000, 1000, 3000, 3000)];
+        let (changed, parts) = clip(GeomType::Polygon, &polygon, &[tile_rect(0.1, 0.1, 0.9, 0.9)]);
+        assert!(!changed);
+        assert_eq!(parts.unwrap(), polygon);
+        let (changed, parts) = clip(GeomType::Polygon, &polygon, &[tile_rect(0.8, 0.8, 0.9, 0.9)]);
+        assert!(changed && parts.is_none());
+
+        // A feature outside goes while one inside stays as it was
+        let inside = feature(Some(1), GeomType::Polygon, &polygon);
+        let outside = feature(Some(2), GeomType::LineString, &[vec![(3800, 3800), (4000, 3900)]]);
+        let mut tile = VectorTile { layers: vec![layer("l", vec![inside.clone(), outside]), layer("empty", Vec::new())] };
+        assert!(tile.clip_to_area(&[tile_rect(0.1, 0.1, 0.9, 0.9)]).unwrap());
+        assert_eq!(tile.layers, vec![layer("l", vec![inside])]);
+    }
+
+    #[test]
+    fn simplify_tolerance() {
+        // The middle vertex is 3 units off the line between the ends
+        let line = [(0, 0), (5, 3), (10, 0)];
+        assert_eq!(douglas_peucker(&line, 2.0), line);
+        assert_eq!(douglas_peucker(&line, 3.0), [(0, 0), (10, 0)]);
+        assert_eq!(douglas_peucker(&line, 4.0), [(0, 0), (10, 0)]);
+        // Collinear vertices go even without tolerance, ends and repeats never stay doubled
+        assert_eq!(douglas_peucker(&[(0, 0), (1, 1), (2, 2), (2, 2), (3, 3)], 0.0), [(0, 0), (3, 3)]);
+        assert_eq!(douglas_peucker(&[(4, 4), (4, 4)], 1.0), [(4, 4)]);
+        // Only what is within tolerance of the remaining shape goes
+        let zigzag = [(0, 0), (10, 1), (20, 0), (30, 8), (40, 0)];
+        assert_eq!(douglas_peucker(&zigzag, 2.0), [(0, 0), (20, 0), (30, 8), (40, 0)]);
+
+        // The tolerance is in units of a 4096 extent, so 8 times smaller at 512
+        let tile = |extent: u32| {
+            let mut layer = layer("l", vec![feature(None, GeomType::LineString, &[line.to_vec()])]);
+            layer.extent = extent;
+            VectorTile { layers: vec![layer] }
+        };
+        let simplified = |extent: u32, tolerance: f64| {
+            let mut tile = tile(extent);
+            tile.simplify(tolerance).unwrap();
+            tile.layers[0].features[0].parts().unwrap()
+        };
+        assert_eq!(simplified(4096, 20.0), vec![vec![(0, 0), (10, 0)]]);
+        assert_eq!(simplified(512, 20.0), vec![line.to_vec()]);
+        assert_eq!(simplified(512, 32.0), vec![vec![(0, 0), (10, 0)]]);
+    }
+
+    #[test]
+    fn simplify_drops_collapsed_and_flipped_rings() {
+        let ring = square(0, 0, 100, 100);
+        assert_eq!(simplify_ring(&ring, 10.0), Some(ring.clone()));
+        // A sliver of area collapses to a line
+        assert_eq!(simplify_ring(&[(0, 0), (100, 1), (200, 0), (100, 2), (0, 0)], 5.0), None);
+        assert_eq!(simplify_ring(&[(0, 0), (1, 0), (0, 0)], 0.0), None);
+        // A self-crossing ring with barely positive area: dropping (4, 2)
+        // leaves none, then dropping (3, 4) leaves it wound the other way
+        let flipping = [(0, 0), (10, 9), (3, 4), (3, 2), (4, 2), (8, 3), (0, 0)];
+        assert_eq!(signed_area(&flipping), 1);
+        assert_eq!(simplify_ring(&flipping, 0.0), Some(flipping.to_vec()));
+        assert_eq!(simplify_ring(&flipping, 0.5), None);
+        assert_eq!(signed_area(&[(0, 0), (10, 9), (3, 2), (8, 3), (0, 0)]), -14);
+        assert_eq!(simplify_ring(&flipping, 1.5), None);
+    }
+
+    #[test]
+    fn simplify_keeps_holes_with_their_exterior() {
+        let hole = |x0, y0, x1, y1| {
+            let mut ring = square(x0, y0, x1, y1);
+            ring.reverse();
+            ring
+        };
+        let sliver = [(0, 0), (100, 1), (200, 0), (100, 2), (0, 0)];
+        let parts = vec![
+            // Kept, with its large hole; its sliver of a hole collapses
+            square(0, 0, 1000, 1000),
+            hole(100, 100, 500, 500),
+            sliver.iter().rev().map(|&(x, y)| (x + 600, y + 600)).collect(),
+            // A collapsing exterior takes its holes with it, however large
+            sliver.iter().map(|&(x, y)| (x + 2000, y)).collect(),
+            hole(2000, 0, 3000, 1000),
+            // Then the next exterior keeps its holes again
+            square(4000, 0, 5000, 1000),
+            hole(4100, 100, 4900, 900),
+        ];
+        let mut tile = VectorTile { layers: vec![layer("l", vec![feature(None, GeomType::Polygon, &parts)])] };
+        tile.simplify(5.0).unwrap();
+        let simplified = tile.layers[0].features[0].parts().unwrap();
+        assert_eq!(simplified, vec![parts[0].clone(), parts[1].clone(), parts[5].clone(), parts[6].clone()]);
+
+        // Features and then layers left without rings go
+        let mut tile = VectorTile { layers: vec![layer("l", vec![feature(None, GeomType::Polygon, &parts[3..5])])] };
+        tile.simplify(5.0).unwrap();
+        assert!(tile.layers.is_empty());
+    }
+}
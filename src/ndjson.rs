@@ -0,0 +1,170 @@
+use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result, anyhow};
+use serde_json::Value;
+
+use crate::bbox::TileRange;
+use crate::directory::InferredMetadata;
+use crate::progress::{NoProgress, Progress};
+use crate::sink::OutputFormat;
+use crate::source::open_source;
+use crate::tile::{Scheme, Tile};
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Write each tile of `input_path` within the zoom range to `out` as a line
+/// of JSON, `{"z":..,"x":..,"y":..,"data":"<base64>"}` with `y` numbered in
+/// `scheme`, ordered by zoom. Returns the number of tiles written.
+pub fn dump_ndjson(
+    input_path: &str,
+    out: &mut dyn Write,
+    scheme: Scheme,
+    min_zoom: Option<i32>,
+    max_zoom: Option<i32>,
+) -> Result<u64> {
+    dump_ndjson_with_progress(input_path, out, scheme, min_zoom, max_zoom, &NoProgress)
+}
+
+/// Like [`dump_ndjson`], reporting progress per tile written
+pub fn dump_ndjson_with_progress(
+    input_path: &str,
+    out: &mut dyn Write,
+    scheme: Scheme,
+    min_zoom: Option<i32>,
+    max_zoom: Option<i32>,
+    progress: &dyn Progress,
+) -> Result<u64> {
+    let source = open_source(input_path)?;
+    let zooms: Vec<i32> = source
+        .zoom_levels()?
+        .into_iter()
+        .filter(|&zoom| zoom >= min_zoom.unwrap_or(0) && zoom <= max_zoom.unwrap_or(i32::MAX))
+        .collect();
+    let mut total = 0;
+    for &zoom in &zooms {
+        total += source.count_tiles(&TileRange::full(zoom)?)?;
+    }
+    progress.start(total);
+
+    let mut written = 0;
+    for zoom in zooms {
+        source.for_each_tile(&TileRange::full(zoom)?, &mut |tile| {
+            let y = scheme.from_tms(tile.zoom, tile.y);
+            writeln!(out, r#"{{"z":{},"x":{},"y":{},"data":"{}"}}"#, tile.zoom, tile.x, y, base64_encode(&tile.data))?;
+            written += 1;
+            progress.advance(1);
+            Ok(())
+        })?;
+    }
+    out.flush()?;
+    progress.finish();
+    Ok(written)
+}
+
+/// Build a tileset at `output_path` from lines written by [`dump_ndjson`],
+/// with `y` numbered in `scheme`. Blank lines are skipped, other members of
+/// a line ignored. Like an imported directory, the metadata is inferred from
+/// the tiles. Returns the number of tiles written.
+pub fn load_ndjson(input: &mut dyn BufRead, output_path: &str, scheme: Scheme) -> Result<u64> {
+    if Path::new(output_path).exists() {
+        return Err(anyhow!("Output file already exists: {}", output_path));
+    }
+    // Written next to the output and moved in place once complete, so a bad
+    // line leaves no partial tileset behind
+    let temp_path = format!("{}.{}.tmp", output_path, std::process::id());
+    let result = load_into(input, &temp_path, OutputFormat::from_path(output_path), output_path, scheme);
+    match result {
+        Ok(loaded) => {
+            std::fs::rename(&temp_path, output_path).context(format!("Failed to move {} to {}", temp_path, output_path))?;
+            Ok(loaded)
+        }
+        Err(e) => {
+            let _ = std::fs::remove_file(&temp_path);
+            Err(e)
+        }
+    }
+}
+
+fn load_into(input: &mut dyn BufRead, path: &str, format: OutputFormat, output_path: &str, scheme: Scheme) -> Result<u64> {
+    let mut sink = format.create_sink(path)?;
+    let mut inferred = InferredMetadata::default();
+    let mut loaded = 0;
+
+    for (number, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let tile = parse_line(&line, scheme).map_err(|e| anyhow!("Invalid tile on line {}: {}", number + 1, e))?;
+        inferred.add(&tile);
+        sink.write_tile(&tile)?;
+        loaded += 1;
+    }
+
+    let mut metadata = BTreeMap::new();
+    let name = Path::new(output_path).file_stem().map_or("tiles".into(), |stem| stem.to_string_lossy().into_owned());
+    metadata.insert("name".to_string(), name);
+    inferred.fill(&mut metadata, None);
+    for (name, value) in &metadata {
+        sink.write_metadata(name, value)?;
+    }
+    sink.finish()?;
+    Ok(loaded)
+}
+
+fn parse_line(line: &str, scheme: Scheme) -> Result<Tile> {
+    let json: Value = serde_json::from_str(line)?;
+    let coordinate = |key: &str| -> Result<i32> {
+        json.get(key)
+            .and_then(Value::as_i64)
+            .and_then(|value| i32::try_from(value).ok())
+            .ok_or_else(|| anyhow!("missing or invalid \"{}\"", key))
+    };
+    let (zoom, x, y) = (coordinate("z")?, coordinate("x")?, coordinate("y")?);
+    if !(0..=30).contains(&zoom) || !(0..1 << zoom).contains(&x) || !(0..1 << zoom).contains(&y) {
+        return Err(anyhow!("no tile {}/{}/{}", zoom, x, y));
+    }
+    let data = json.get("data").and_then(Value::as_str).ok_or_else(|| anyhow!("missing \"data\""))?;
+    Ok(Tile { zoom, x, y: scheme.to_tms(zoom, y), data: base64_decode(data)? })
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let group = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(group >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn base64_decode(text: &str) -> Result<Vec<u8>> {
+    let text = text.trim_end_matches('=');
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    let (mut group, mut bits) = (0u32, 0);
+    for c in text.bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            _ => return Err(anyhow!("invalid base64 in \"data\"")),
+        };
+        group = group << 6 | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((group >> bits) as u8);
+        }
+    }
+    Ok(out)
+}
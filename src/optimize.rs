@@ -0,0 +1,91 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result, anyhow};
+use rusqlite::Connection;
+
+use crate::mbtiles::MbtilesSchema;
+
+/// Outcome of optimizing a file
+#[derive(Debug, Clone, Copy)]
+pub struct OptimizeReport {
+    /// Unused pages in the input before optimizing
+    pub free_pages: u64,
+    pub bytes_before: u64,
+    /// Size of the optimized file, the input itself or the `VACUUM INTO` copy
+    pub bytes_after: u64,
+}
+
+impl OptimizeReport {
+    pub fn bytes_saved(&self) -> i64 {
+        self.bytes_before as i64 - self.bytes_after as i64
+    }
+}
+
+/// Compact the MBTiles file at `path`: rebuild the tile index (creating it
+/// if missing), refresh the query planner statistics with `ANALYZE` and
+/// `VACUUM` away free pages.
+///
+/// With `output`, the input is left untouched and the compacted database is
+/// written there with `VACUUM INTO` instead, then indexed and analyzed.
+pub fn optimize(path: &str, output: Option<&str>) -> Result<OptimizeReport> {
+    if !Path::new(path).exists() {
+        return Err(anyhow!("Input file not found: {}", path));
+    }
+    if let Some(output) = output
+        && Path::new(output).exists()
+    {
+        return Err(anyhow!("Output file already exists: {}", output));
+    }
+    let bytes_before = fs::metadata(path)?.len();
+    let conn = Connection::open(path).context(format!("Failed to open {}", path))?;
+    let free_pages: i64 = conn.query_row("PRAGMA freelist_count", [], |row| row.get(0))?;
+
+    let target = match output {
+        Some(output) => {
+            conn.execute("VACUUM INTO ?", [output]).context(format!("Failed to write {}", output))?;
+            drop(conn);
+            let conn = Connection::open(output).context(format!("Failed to open {}", output))?;
+            rebuild_indexes(&conn, output)?;
+            conn.execute_batch("ANALYZE")?;
+            output
+        }
+        None => {
+            rebuild_indexes(&conn, path)?;
+            conn.execute_batch("ANALYZE; VACUUM")?;
+            path
+        }
+    };
+
+    Ok(OptimizeReport {
+        free_pages: free_pages as u64,
+        bytes_before,
+        bytes_after: fs::metadata(target)?.len(),
+    })
+}
+
+/// Make sure the tile table has its unique (zoom, column, row) index and
+/// rebuild every index of the tile tables
+fn rebuild_indexes(conn: &Connection, path: &str) -> Result<()> {
+    let (table, index) = match MbtilesSchema::detect(conn, "main").context(format!("Failed to read {}", path))? {
+        MbtilesSchema::Flat => ("tiles", "tile_index"),
+        MbtilesSchema::Normalized => ("map", "map_index"),
+    };
+    let unique: i64 = conn.query_row(
+        &format!("SELECT COUNT(*) FROM pragma_index_list('{}') WHERE \"unique\"", table),
+        [],
+        |row| row.get(0),
+    )?;
+    if unique == 0 {
+        conn.execute_batch(&format!(
+            "CREATE UNIQUE INDEX {} ON {} (zoom_level, tile_column, tile_row)",
+            index, table
+        ))
+        .map_err(|e| anyhow!("Failed to index the tiles of {}, are some stored twice? {}", path, e))?;
+    }
+    conn.execute_batch(&format!("REINDEX {}", table))?;
+    if table == "map" {
+        conn.execute_batch("REINDEX images")?;
+    }
+    Ok(())
+}
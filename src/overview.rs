@@ -0,0 +1,109 @@
+use anyhow::{Result, anyhow};
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, RgbaImage};
+use rusqlite::{OptionalExtension, params};
+
+use crate::mbtiles::MbtilesWriter;
+use crate::progress::{NoProgress, Progress};
+use crate::raster;
+use crate::tile::{detect_format, Tile, TileFormat};
+
+/// Quality used when overviews are JPEG or WebP
+const OVERVIEW_QUALITY: f32 = 85.0;
+
+/// Add zoom levels below the lowest zoom of the raster MBTiles at `path`,
+/// down to `min_zoom`, by downsampling each 2×2 block of child tiles into
+/// its parent. Existing tiles at those zooms are replaced. Returns the
+/// number of tiles written.
+pub fn build_overviews(path: &str, min_zoom: i32) -> Result<u64> {
+    build_overviews_with_progress(path, min_zoom, &NoProgress)
+}
+
+/// Like [`build_overviews`], reporting each parent tile written to `progress`
+pub fn build_overviews_with_progress(path: &str, min_zoom: i32, progress: &dyn Progress) -> Result<u64> {
+    let writer = MbtilesWriter::open(path)?;
+    let conn = writer.connection();
+
+    let base_zoom: Option<i32> = conn.query_row("SELECT MAX(zoom_level) FROM tiles", [], |row| row.get(0))?;
+    let Some(base_zoom) = base_zoom else {
+        return Err(anyhow!("{} has no tiles", path));
+    };
+    if min_zoom < 0 || min_zoom >= base_zoom {
+        return Err(anyhow!("--min-zoom must be between 0 and {} for {}", base_zoom - 1, path));
+    }
+
+    let declared: Option<String> = conn
+        .query_row("SELECT value FROM metadata WHERE name = 'format'", [], |row| row.get(0))
+        .optional()?;
+    let sample: Vec<u8> = conn.query_row("SELECT tile_data FROM tiles LIMIT 1", [], |row| row.get(0))?;
+    let format = declared.as_deref().and_then(TileFormat::from_metadata).unwrap_or_else(|| detect_format(&sample));
+    if format == TileFormat::Pbf {
+        return Err(anyhow!("Overviews can only be built for raster tiles"));
+    }
+
+    // Each zoom gets one parent per distinct block of base tiles
+    let mut total = 0;
+    for zoom in min_zoom..base_zoom {
+        let shift = base_zoom - zoom;
+        total += conn.query_row(
+            "SELECT COUNT(*) FROM (SELECT DISTINCT tile_column >> ?1, tile_row >> ?1 FROM tiles WHERE zoom_level = ?2)",
+            params![shift, base_zoom],
+            |row| row.get::<_, u64>(0),
+        )?;
+    }
+    progress.start(total);
+
+    let mut written = 0;
+    for zoom in (min_zoom..base_zoom).rev() {
+        let parents: Vec<(i32, i32)> = {
+            let mut stmt = conn.prepare(
+                "SELECT DISTINCT tile_column >> 1, tile_row >> 1 FROM tiles WHERE zoom_level = ? ORDER BY 1, 2",
+            )?;
+            stmt.query_map(params![zoom + 1], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<Result<_, _>>()?
+        };
+
+        let tx = conn.unchecked_transaction()?;
+        for (x, y) in parents {
+            let data = downsample(&writer, zoom + 1, x, y, format)?;
+            writer.delete_tile(zoom, x, y)?;
+            writer.insert_tile(&Tile { zoom, x, y, data })?;
+            written += 1;
+            progress.advance(1);
+        }
+        tx.commit()?;
+    }
+
+    writer.prune_images()?;
+    writer.set_metadata("minzoom", &min_zoom.to_string())?;
+    progress.finish();
+    Ok(written)
+}
+
+/// Composite the up to four children at `child_zoom` of the parent at
+/// `x`/`y` (TMS) and scale the result down to one tile
+fn downsample(writer: &MbtilesWriter, child_zoom: i32, x: i32, y: i32, format: TileFormat) -> Result<Vec<u8>> {
+    let mut stmt = writer.connection().prepare_cached(
+        "SELECT tile_column, tile_row, tile_data FROM tiles
+         WHERE zoom_level = ? AND tile_column BETWEEN ? AND ? AND tile_row BETWEEN ? AND ?",
+    )?;
+    let mut rows = stmt.query(params![child_zoom, 2 * x, 2 * x + 1, 2 * y, 2 * y + 1])?;
+
+    let mut children = Vec::new();
+    let mut size = 0;
+    while let Some(row) = rows.next()? {
+        let (cx, cy, data): (i32, i32, Vec<u8>) = (row.get(0)?, row.get(1)?, row.get(2)?);
+        let image = raster::decode(&data, detect_format(&data))?.to_rgba8();
+        size = size.max(image.width());
+        children.push((cx - 2 * x, cy - 2 * y, image));
+    }
+
+    // TMS rows grow northwards, so the child with the higher row is on top
+    let mut canvas = RgbaImage::new(2 * size, 2 * size);
+    for (dx, dy, image) in children {
+        let image = if image.width() == size { image } else { imageops::resize(&image, size, size, FilterType::Triangle) };
+        imageops::replace(&mut canvas, &image, (dx as u32 * size) as i64, ((1 - dy) as u32 * size) as i64);
+    }
+    let parent = imageops::resize(&canvas, size, size, FilterType::Triangle);
+    raster::encode(&DynamicImage::ImageRgba8(parent), format, OVERVIEW_QUALITY, false)
+}
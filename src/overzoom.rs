@@ -0,0 +1,249 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{Result, anyhow};
+use image::DynamicImage;
+use rusqlite::{OptionalExtension, params};
+
+use crate::coord::MAX_ZOOM;
+use crate::holes::expected_bounds;
+use crate::mbtiles::MbtilesWriter;
+use crate::mvt::VectorTile;
+use crate::progress::{NoProgress, Progress};
+use crate::raster;
+use crate::tile::{compress, decompress, detect_compression, detect_format, Compression, Scheme, Tile, TileFormat};
+
+/// Quality used when filled tiles are JPEG or WebP
+const FILL_QUALITY: f32 = 85.0;
+
+/// A tile as (zoom, column, TMS row)
+type TileKey = (i32, i32, i32);
+
+/// Fill the MBTiles file at `path` with every tile missing below an
+/// existing one down to `max_zoom`, within the `bounds` metadata if set, so
+/// clients that can't overzoom see a complete pyramid.
+///
+/// Each filled tile is made from its nearest original ancestor: raster
+/// tiles by scaling up the ancestor's matching part, vector tiles by
+/// scaling up and clipping its geometry. Vector tiles left empty by the clip
+/// are not written. `maxzoom` is raised to `max_zoom`. Returns the number of
+/// tiles written.
+pub fn overzoom(path: &str, max_zoom: i32) -> Result<u64> {
+    overzoom_with_progress(path, max_zoom, &NoProgress)
+}
+
+/// Like [`overzoom`], reporting each filled tile to `progress`
+pub fn overzoom_with_progress(path: &str, max_zoom: i32, progress: &dyn Progress) -> Result<u64> {
+    let writer = MbtilesWriter::open(path)?;
+    let conn = writer.connection();
+    let metadata = |name: &str| -> Result<Option<String>> {
+        Ok(conn.query_row("SELECT value FROM metadata WHERE name = ?", params![name], |row| row.get(0)).optional()?)
+    };
+
+    let zooms = writer.zoom_info()?;
+    let Some(first) = zooms.first().map(|info| info.zoom) else {
+        return Err(anyhow!("{} has no tiles", path));
+    };
+    if max_zoom <= first || max_zoom > MAX_ZOOM {
+        return Err(anyhow!("--max-zoom must be between {} and {} for {}", first + 1, MAX_ZOOM, path));
+    }
+    let scheme = metadata("scheme")?.as_deref().and_then(Scheme::from_metadata).unwrap_or(Scheme::Tms);
+    let bounds = metadata("bounds")?.as_deref().and_then(expected_bounds);
+    let sample: Vec<u8> = conn.query_row("SELECT tile_data FROM tiles LIMIT 1", [], |row| row.get(0))?;
+    let format = metadata("format")?.as_deref().and_then(TileFormat::from_metadata).unwrap_or_else(|| detect_format(&sample));
+
+    // Plan the fill zoom by zoom, tracking the original ancestor of each tile
+    let mut plan: Vec<(TileKey, TileKey)> = Vec::new();
+    let mut origins: HashMap<(i32, i32), TileKey> =
+        stored_tiles(&writer, first, scheme)?.into_iter().map(|(x, y)| ((x, y), (first, x, y))).collect();
+    for zoom in first + 1..=max_zoom {
+        let existing = stored_tiles(&writer, zoom, scheme)?;
+        let expected = bounds.map(|bounds| bounds.tile_ranges(zoom));
+        let mut next: HashMap<(i32, i32), TileKey> = existing.iter().map(|&(x, y)| ((x, y), (zoom, x, y))).collect();
+        for (&(x, y), &origin) in &origins {
+            for (cx, cy) in [(2 * x, 2 * y), (2 * x + 1, 2 * y), (2 * x, 2 * y + 1), (2 * x + 1, 2 * y + 1)] {
+                let inside = expected.as_ref().is_none_or(|ranges| ranges.iter().any(|r| r.contains(zoom, cx, cy)));
+                if inside && !existing.contains(&(cx, cy)) {
+                    plan.push(((zoom, cx, cy), origin));
+                    next.insert((cx, cy), origin);
+                }
+            }
+        }
+        origins = next;
+    }
+    // Tiles sharing an ancestor are made one after another from one decode
+    plan.sort_unstable_by_key(|&(tile, origin)| (origin, tile));
+    progress.start(plan.len() as u64);
+
+    let mut ancestor: Option<(TileKey, Ancestor)> = None;
+    let mut written = 0;
+    let tx = conn.unchecked_transaction()?;
+    for ((zoom, x, y), origin) in plan {
+        if ancestor.as_ref().is_none_or(|(loaded, _)| *loaded != origin) {
+            let (z, ox, oy) = origin;
+            let data: Vec<u8> = conn.query_row(
+                "SELECT tile_data FROM tiles WHERE zoom_level = ? AND tile_column = ? AND tile_row = ?",
+                params![z, ox, scheme.from_tms(z, oy)],
+                |row| row.get(0),
+            )?;
+            ancestor = Some((origin, Ancestor::decode(&data, format)?));
+        }
+        let (_, source) = ancestor.as_ref().expect("ancestor loaded above");
+
+        let levels = zoom - origin.0;
+        let mask = (1 << levels) - 1;
+        // TMS rows grow northwards, image and vector tile rows southwards
+        let (dx, dy) = ((x & mask) as u32, (mask - (y & mask)) as u32);
+        let data = match source {
+            Ancestor::Raster(image) => match raster::upscale_part(image, levels, dx, dy) {
+                Some(scaled) => Some(raster::encode(&scaled, format, FILL_QUALITY, false)?),
+                None => None,
+            },
+            Ancestor::Vector(tile, compression) => {
+                let clipped = tile.overzoom(levels as u32, dx, dy)?;
+                if clipped.layers.is_empty() { None } else { Some(compress(&clipped.encode(), *compression)?) }
+            }
+        };
+        if let Some(data) = data {
+            writer.insert_tile(&Tile { zoom, x, y: scheme.from_tms(zoom, y), data })?;
+            written += 1;
+        }
+        progress.advance(1);
+    }
+
+    let declared_max = metadata("maxzoom")?.and_then(|value| value.trim().parse::<i32>().ok());
+    if declared_max.is_none_or(|declared| declared < max_zoom) {
+        writer.set_metadata("maxzoom", &max_zoom.to_string())?;
+    }
+    tx.commit()?;
+    progress.finish();
+    Ok(written)
+}
+
+/// A decoded tile the missing tiles below it are cut from
+enum Ancestor {
+    Raster(DynamicImage),
+    Vector(VectorTile, Compression),
+}
+
+impl Ancestor {
+    fn decode(data: &[u8], format: TileFormat) -> Result<Self> {
+        Ok(match format {
+            TileFormat::Pbf => Ancestor::Vector(VectorTile::decode(&decompress(data)?)?, detect_compression(data)),
+            _ => Ancestor::Raster(raster::decode(data, detect_format(data))?),
+        })
+    }
+}
+
+/// Column and TMS row of every tile at `zoom`
+fn stored_tiles(writer: &MbtilesWriter, zoom: i32, scheme: Scheme) -> Result<HashSet<(i32, i32)>> {
+    let mut stmt = writer.connection().prepare("SELECT tile_column, tile_row FROM tiles WHERE zoom_level = ?")?;
+    let tiles = stmt
+        .query_map(params![zoom], |row| Ok((row.get(0)?, scheme.to_tms(zoom, row.get(1)?))))?
+        .collect::<Result<_, _>>()?;
+    Ok(tiles)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bbox::TileRange;
+    use crate::mbtiles::MbtilesReader;
+    use crate::mvt::{Feature, GeomType, Layer};
+    use image::{Rgba, RgbaImage};
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("mbtiles-overzoom-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path.to_string_lossy().into_owned()
+    }
+
+    const NORTH_WEST: [u8; 4] = [255, 0, 0, 255];
+    const NORTH_EAST: [u8; 4] = [0, 255, 0, 255];
+    const SOUTH_WEST: [u8; 4] = [0, 0, 255, 255];
+    const SOUTH_EAST: [u8; 4] = [255, 255, 255, 255];
+
+    fn png(image: RgbaImage) -> Vec<u8> {
+        raster::encode(&DynamicImage::ImageRgba8(image), TileFormat::Png, 100.0, true).unwrap()
+    }
+
+    /// Color at the middle of a PNG tile
+    fn center(data: &[u8]) -> [u8; 4] {
+        raster::decode(data, TileFormat::Png).unwrap().to_rgba8().get_pixel(128, 128).0
+    }
+
+    #[test]
+    fn raster_children_show_their_part_of_the_ancestor() {
+        let path = temp_path("raster.mbtiles");
+        let writer = MbtilesWriter::create(&path).unwrap();
+        writer.insert_metadata("format", "png").unwrap();
+        writer.insert_metadata("maxzoom", "1").unwrap();
+        let quadrants = RgbaImage::from_fn(256, 256, |x, y| match (x < 128, y < 128) {
+            (true, true) => Rgba(NORTH_WEST),
+            (false, true) => Rgba(NORTH_EAST),
+            (true, false) => Rgba(SOUTH_WEST),
+            (false, false) => Rgba(SOUTH_EAST),
+        });
+        writer.insert_tile(&Tile { zoom: 0, x: 0, y: 0, data: png(quadrants) }).unwrap();
+        // The north-east child exists already, black
+        let black = png(RgbaImage::from_pixel(256, 256, Rgba([0, 0, 0, 255])));
+        writer.insert_tile(&Tile { zoom: 1, x: 1, y: 1, data: black.clone() }).unwrap();
+        drop(writer);
+
+        // Three children at zoom 1, and all 16 tiles at zoom 2 but the existing one
+        assert_eq!(overzoom(&path, 2).unwrap(), 3 + 16);
+
+        let reader = MbtilesReader::open(&path).unwrap();
+        let tile = |zoom, x, y| reader.tile(zoom, x, y).unwrap().unwrap();
+        assert_eq!(center(&tile(1, 0, 1)), NORTH_WEST);
+        assert_eq!(center(&tile(1, 0, 0)), SOUTH_WEST);
+        assert_eq!(center(&tile(1, 1, 0)), SOUTH_EAST);
+        assert_eq!(tile(1, 1, 1), black);
+        // Zoom 2 is filled from the nearest original, the existing black tile in the north-east
+        assert_eq!(center(&tile(2, 0, 3)), NORTH_WEST);
+        assert_eq!(center(&tile(2, 1, 2)), NORTH_WEST);
+        assert_eq!(center(&tile(2, 0, 0)), SOUTH_WEST);
+        assert_eq!(center(&tile(2, 3, 0)), SOUTH_EAST);
+        assert_eq!(center(&tile(2, 3, 3)), [0, 0, 0, 255]);
+        assert_eq!(reader.count_tiles(&TileRange::full(2).unwrap()).unwrap(), 16);
+        assert_eq!(reader.metadata_value("maxzoom").unwrap().as_deref(), Some("2"));
+        drop(reader);
+
+        // Nothing is missing any more
+        assert_eq!(overzoom(&path, 2).unwrap(), 0);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn vector_children_keep_the_geometry_inside_them() {
+        let path = temp_path("vector.mbtiles");
+        let writer = MbtilesWriter::create(&path).unwrap();
+        writer.insert_metadata("format", "pbf").unwrap();
+        writer.insert_metadata("scheme", "xyz").unwrap();
+        // A point in the north-west quarter, y counted down from the top
+        let point = Feature { id: Some(1), tags: Vec::new(), geom_type: GeomType::Point, geometry: vec![9, 2000, 1200] };
+        let layer = Layer {
+            name: "places".to_string(),
+            version: 2,
+            extent: 4096,
+            keys: Vec::new(),
+            values: Vec::new(),
+            features: vec![point],
+        };
+        let data = compress(&VectorTile { layers: vec![layer] }.encode(), Compression::Gzip).unwrap();
+        writer.insert_tile(&Tile { zoom: 0, x: 0, y: 0, data }).unwrap();
+        drop(writer);
+
+        // Children the point isn't in are left empty and not written
+        assert_eq!(overzoom(&path, 1).unwrap(), 1);
+        let reader = MbtilesReader::open(&path).unwrap();
+        assert_eq!(reader.count_tiles(&TileRange::full(1).unwrap()).unwrap(), 1);
+        // Stored with the file's XYZ rows
+        let data = reader.tile(1, 0, 0).unwrap().unwrap();
+        assert_eq!(detect_compression(&data), Compression::Gzip);
+        let child = VectorTile::decode(&decompress(&data).unwrap()).unwrap();
+        assert_eq!(child.layers.len(), 1);
+        assert_eq!(child.layers[0].features[0].parts().unwrap(), [vec![(2000, 1200)]]);
+        drop(reader);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,203 @@
+use std::io::Cursor;
+
+use anyhow::{Context, Result, anyhow};
+use wasmi::{Config, Engine, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+use wasmi_wasi::WasiCtx;
+use wasmi_wasi::wasi_common::pipe::{ReadPipe, WritePipe};
+
+use crate::tile::{Scheme, Tile};
+
+/// Linear memory a plugin instance may grow to
+const MEMORY_LIMIT: usize = 256 * 1024 * 1024;
+
+/// Instructions, roughly, a plugin may run per tile before it's stopped
+const FUEL_PER_TILE: u64 = 1_000_000_000;
+
+/// A WASI (preview 1) module transforming tiles, compiled once and run in a
+/// fresh instance for every tile. Its `_start` gets the tile's zoom, column
+/// and XYZ row as arguments and its blob on stdin; whatever it writes to
+/// stdout replaces the tile, and writing nothing drops it. Exiting with a
+/// nonzero status fails the copy.
+///
+/// The module is sandboxed: it gets no directories, sockets or environment
+/// variables, only stderr is passed through, and its memory and run time
+/// per tile are capped.
+pub(crate) struct TilePlugin {
+    path: String,
+    module: Module,
+    linker: Linker<PluginState>,
+    fuel: u64,
+}
+
+/// Store data of one plugin instance
+struct PluginState {
+    wasi: WasiCtx,
+    limits: StoreLimits,
+}
+
+impl TilePlugin {
+    pub(crate) fn load(path: &str) -> Result<Self> {
+        let wasm = std::fs::read(path).context(format!("Failed to read transform plugin: {}", path))?;
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, &wasm).map_err(|e| anyhow!("Invalid transform plugin {}: {}", path, e))?;
+        if module.get_export("_start").is_none() {
+            return Err(anyhow!("Transform plugin {} has no _start function, build it as a WASI command", path));
+        }
+        let mut linker = Linker::new(&engine);
+        wasmi_wasi::add_to_linker(&mut linker, |state: &mut PluginState| &mut state.wasi)
+            .map_err(|e| anyhow!("Failed to set up WASI for {}: {}", path, e))?;
+        Ok(TilePlugin { path: path.to_string(), module, linker, fuel: FUEL_PER_TILE })
+    }
+
+    /// The new blob of `tile` (TMS), `None` if the plugin drops it
+    pub(crate) fn apply(&self, tile: &Tile) -> Result<Option<Vec<u8>>> {
+        let tile_id = format!("{}/{}/{}", tile.zoom, tile.x, Scheme::Xyz.from_tms(tile.zoom, tile.y));
+        let args: Vec<String> = tile_id.split('/').map(str::to_string).collect();
+        let stdout = WritePipe::new_in_memory();
+        let wasi = wasmi_wasi::WasiCtxBuilder::new()
+            .arg(&self.path)
+            .and_then(|builder| builder.args(&args))
+            .map_err(|e| anyhow!("Failed to pass tile {} to {}: {}", tile_id, self.path, e))?
+            .stdin(Box::new(ReadPipe::from(tile.data.as_slice())))
+            .stdout(Box::new(stdout.clone()))
+            .inherit_stderr()
+            .build();
+
+        let limits = StoreLimitsBuilder::new().memory_size(MEMORY_LIMIT).build();
+        let mut store = Store::new(self.module.engine(), PluginState { wasi, limits });
+        store.limiter(|state| &mut state.limits);
+        store.set_fuel(self.fuel)?;
+        let failed = |e: wasmi::Error| anyhow!("Transform plugin {} failed on tile {}: {}", self.path, tile_id, e);
+        let instance = self.linker.instantiate_and_start(&mut store, &self.module).map_err(failed)?;
+        let start = instance.get_typed_func::<(), ()>(&store, "_start").map_err(failed)?;
+        match start.call(&mut store, ()) {
+            Ok(()) => {}
+            Err(e) if e.i32_exit_status() == Some(0) => {}
+            Err(e) => return Err(failed(e)),
+        }
+        drop(store);
+
+        let data = stdout.try_into_inner().map(Cursor::into_inner).expect("store dropped, pipe unshared");
+        Ok(if data.is_empty() { None } else { Some(data) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plugin(name: &str, wat: &str) -> TilePlugin {
+        let path = std::env::temp_dir().join(format!("mbtiles-plugin-{}-{}.wat", std::process::id(), name));
+        std::fs::write(&path, wat).unwrap();
+        let plugin = TilePlugin::load(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(path).unwrap();
+        plugin
+    }
+
+    fn tile(data: &[u8]) -> Tile {
+        Tile { zoom: 2, x: 1, y: 1, data: data.to_vec() }
+    }
+
+    /// Copies at most 64 KiB from stdin to stdout, reversed if the row
+    /// argument is "3"
+    const ECHO: &str = r#"(module
+        (import "wasi_snapshot_preview1" "fd_read" (func $read (param i32 i32 i32 i32) (result i32)))
+        (import "wasi_snapshot_preview1" "fd_write" (func $write (param i32 i32 i32 i32) (result i32)))
+        (import "wasi_snapshot_preview1" "args_sizes_get" (func $args_sizes (param i32 i32) (result i32)))
+        (import "wasi_snapshot_preview1" "args_get" (func $args (param i32 i32) (result i32)))
+        (memory (export "memory") 2)
+        (func (export "_start") (local $n i32) (local $i i32) (local $t i32)
+            (drop (call $args_sizes (i32.const 0) (i32.const 4)))
+            (drop (call $args (i32.const 16) (i32.const 256)))
+            (i32.store (i32.const 8) (i32.const 65536))
+            (i32.store (i32.const 12) (i32.const 65536))
+            (drop (call $read (i32.const 0) (i32.const 8) (i32.const 1) (i32.const 4)))
+            (local.set $n (i32.load (i32.const 4)))
+            ;; argv[3] is the XYZ row
+            (if (i32.eq (i32.load8_u (i32.load (i32.const 28))) (i32.const 51))
+                (then
+                    (local.set $i (i32.const 0))
+                    (block $done (loop $swap
+                        (br_if $done (i32.ge_s (local.get $i) (i32.div_u (local.get $n) (i32.const 2))))
+                        (local.set $t (i32.load8_u (i32.add (i32.const 65536) (local.get $i))))
+                        (i32.store8 (i32.add (i32.const 65536) (local.get $i))
+                            (i32.load8_u (i32.sub (i32.add (i32.const 65535) (local.get $n)) (local.get $i))))
+                        (i32.store8 (i32.sub (i32.add (i32.const 65535) (local.get $n)) (local.get $i)) (local.get $t))
+                        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                        (br $swap)))))
+            (i32.store (i32.const 12) (local.get $n))
+            (drop (call $write (i32.const 1) (i32.const 8) (i32.const 1) (i32.const 4)))))"#;
+
+    #[test]
+    fn output_replaces_the_tile() {
+        let echo = plugin("echo", ECHO);
+        assert_eq!(echo.apply(&tile(b"abc")).unwrap(), Some(b"abc".to_vec()));
+        // TMS row 0 is XYZ row 3 at zoom 2
+        assert_eq!(echo.apply(&Tile { y: 0, ..tile(b"abcd") }).unwrap(), Some(b"dcba".to_vec()));
+        assert_eq!(echo.apply(&Tile { y: 1, ..tile(b"abcd") }).unwrap(), Some(b"abcd".to_vec()));
+    }
+
+    #[test]
+    fn no_output_drops_the_tile() {
+        let echo = plugin("drop", ECHO);
+        assert_eq!(echo.apply(&tile(b"")).unwrap(), None);
+        let silent = plugin("silent", r#"(module (func (export "_start")))"#);
+        assert_eq!(silent.apply(&tile(b"abc")).unwrap(), None);
+    }
+
+    #[test]
+    fn exit_status_and_traps_fail() {
+        let exit = |status: i32| {
+            format!(
+                r#"(module
+                    (import "wasi_snapshot_preview1" "proc_exit" (func $exit (param i32)))
+                    (memory (export "memory") 1)
+                    (func (export "_start") (call $exit (i32.const {}))))"#,
+                status
+            )
+        };
+        assert_eq!(plugin("exit0", &exit(0)).apply(&tile(b"abc")).unwrap(), None);
+        let error = plugin("exit1", &exit(1)).apply(&tile(b"abc")).unwrap_err().to_string();
+        assert!(error.contains("failed on tile 2/1/2"), "{}", error);
+        assert!(plugin("trap", r#"(module (func (export "_start") unreachable))"#).apply(&tile(b"abc")).is_err());
+    }
+
+    #[test]
+    fn sandbox_limits() {
+        let mut spin = plugin("spin", r#"(module (func (export "_start") (loop $l (br $l))))"#);
+        spin.fuel = 1_000_000;
+        assert!(spin.apply(&tile(b"abc")).is_err());
+        let grow = plugin(
+            "grow",
+            r#"(module (memory 1) (func (export "_start") (if (i32.eq (memory.grow (i32.const 8192)) (i32.const -1)) (then unreachable))))"#,
+        );
+        assert!(grow.apply(&tile(b"abc")).is_err());
+        let open = plugin(
+            "open",
+            r#"(module
+                (import "wasi_snapshot_preview1" "path_open"
+                    (func $open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "/etc/passwd")
+                (func (export "_start")
+                    (if (i32.eqz (call $open (i32.const 3) (i32.const 0) (i32.const 0) (i32.const 11)
+                            (i32.const 0) (i64.const 2) (i64.const 0) (i32.const 0) (i32.const 64)))
+                        (then unreachable))))"#,
+        );
+        assert!(open.apply(&tile(b"abc")).unwrap().is_none());
+    }
+
+    #[test]
+    fn load_checks_the_module() {
+        let path = std::env::temp_dir().join(format!("mbtiles-plugin-{}-bad.wasm", std::process::id()));
+        std::fs::write(&path, b"\0asm junk").unwrap();
+        assert!(TilePlugin::load(path.to_str().unwrap()).is_err());
+        std::fs::write(&path, r#"(module (func (export "main")))"#).unwrap();
+        let error = TilePlugin::load(path.to_str().unwrap()).err().unwrap().to_string();
+        assert!(error.contains("_start"), "{}", error);
+        std::fs::remove_file(path).unwrap();
+        assert!(TilePlugin::load("/nonexistent/plugin.wasm").is_err());
+    }
+}
@@ -0,0 +1,875 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::rc::Rc;
+
+use anyhow::{Context, Result, anyhow};
+use flate2::Compression as GzLevel;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde_json::{Map, Value};
+
+use crate::bbox::TileRange;
+use crate::coord::MAX_ZOOM;
+use crate::http;
+use crate::info::ZoomInfo;
+use crate::sink::TileSink;
+use crate::source::{SourceKind, TileSource};
+use crate::tile::{detect_compression, Compression, Tile, TileFormat};
+
+const HEADER_LEN: usize = 127;
+/// The spec requires the header and root directory to fit in the first 16 KiB
+const ROOT_MAX_LEN: usize = 16384 - HEADER_LEN;
+
+const COMPRESSION_NONE: u8 = 1;
+const COMPRESSION_GZIP: u8 = 2;
+
+/// PMTiles tile id: tiles of lower zooms come first, then the position of
+/// (x, y) along a Hilbert curve at zoom `z`. `y` uses XYZ numbering.
+pub fn tile_id(z: u8, x: u32, y: u32) -> u64 {
+    let mut acc: u64 = ((1u64 << (2 * z as u64)) - 1) / 3;
+    let n: u64 = 1 << z;
+    let (mut x, mut y) = (x as u64, y as u64);
+    let mut s = n / 2;
+    while s > 0 {
+        let rx = u64::from(x & s > 0);
+        let ry = u64::from(y & s > 0);
+        acc += s * s * ((3 * rx) ^ ry);
+        if ry == 0 {
+            if rx == 1 {
+                x = n - 1 - x;
+                y = n - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    acc
+}
+
+/// Inverse of [`tile_id`], returning (z, x, y) with XYZ rows
+pub fn tile_id_to_zxy(id: u64) -> (u8, u32, u32) {
+    let mut acc = 0u64;
+    let mut z = 0u8;
+    while acc + (1u64 << (2 * z as u64)) <= id {
+        acc += 1u64 << (2 * z as u64);
+        z += 1;
+    }
+
+    let n: u64 = 1 << z;
+    let mut t = id - acc;
+    let (mut x, mut y) = (0u64, 0u64);
+    let mut s = 1;
+    while s < n {
+        let rx = 1 & (t / 2);
+        let ry = 1 & (t ^ rx);
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - x;
+                y = s - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        x += s * rx;
+        y += s * ry;
+        t /= 4;
+        s *= 2;
+    }
+    (z, x as u32, y as u32)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    tile_id: u64,
+    offset: u64,
+    length: u32,
+    run_length: u32,
+}
+
+/// Writes a PMTiles v3 archive.
+///
+/// Tile data is spooled to a temporary file next to the output while tiles
+/// arrive in any order; `finish` sorts them by tile id, writes a clustered
+/// archive with identical tiles stored once, and removes the spool file.
+pub struct PmtilesWriter {
+    path: String,
+    spool_path: String,
+    spool: BufWriter<File>,
+    spool_len: u64,
+    /// md5 of tile contents -> (offset, length) in the spool file
+    contents: HashMap<[u8; 16], (u64, u32)>,
+    entries: Vec<Entry>,
+    metadata: Vec<(String, String)>,
+    tile_compression: Option<Compression>,
+    min_zoom: u8,
+    max_zoom: u8,
+}
+
+impl PmtilesWriter {
+    pub fn create(path: &str) -> Result<Self> {
+        let spool_path = format!("{}.tiles.tmp", path);
+        let spool = File::create(&spool_path)
+            .context(format!("Failed to create temporary file: {}", spool_path))?;
+        // Create the output now so an unwritable path fails before any work is done
+        File::create(path).context(format!("Failed to create output file: {}", path))?;
+
+        Ok(PmtilesWriter {
+            path: path.to_string(),
+            spool_path,
+            spool: BufWriter::new(spool),
+            spool_len: 0,
+            contents: HashMap::new(),
+            entries: Vec::new(),
+            metadata: Vec::new(),
+            tile_compression: None,
+            min_zoom: u8::MAX,
+            max_zoom: 0,
+        })
+    }
+
+    fn metadata_value(&self, name: &str) -> Option<&str> {
+        self.metadata.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    /// Metadata keys become JSON members; the MBTiles `json` key is merged
+    /// into the top level so `vector_layers` ends up where readers expect it
+    fn metadata_json(&self) -> Value {
+        let mut object = Map::new();
+        for (name, value) in &self.metadata {
+            if name == "json" {
+                if let Ok(Value::Object(members)) = serde_json::from_str(value) {
+                    object.extend(members);
+                }
+            } else {
+                object.insert(name.clone(), Value::String(value.clone()));
+            }
+        }
+        Value::Object(object)
+    }
+
+    fn write_archive(&mut self) -> Result<()> {
+        self.spool.flush()?;
+        self.entries.sort_by_key(|e| e.tile_id);
+
+        // Lay out tile data in tile id order, each distinct blob once, and
+        // collapse consecutive ids sharing a blob into runs
+        let mut spool = File::open(&self.spool_path)?;
+        let data_path = format!("{}.data.tmp", self.path);
+        let mut data = BufWriter::new(File::create(&data_path)?);
+        let mut placed: HashMap<u64, u64> = HashMap::new();
+        let mut data_len = 0u64;
+        let mut entries: Vec<Entry> = Vec::with_capacity(self.entries.len());
+        let mut buf = Vec::new();
+        for entry in &self.entries {
+            let offset = match placed.get(&entry.offset) {
+                Some(&offset) => offset,
+                None => {
+                    buf.resize(entry.length as usize, 0);
+                    spool.seek(SeekFrom::Start(entry.offset))?;
+                    spool.read_exact(&mut buf)?;
+                    data.write_all(&buf)?;
+                    placed.insert(entry.offset, data_len);
+                    data_len += entry.length as u64;
+                    data_len - entry.length as u64
+                }
+            };
+            match entries.last_mut() {
+                Some(last) if last.offset == offset && last.tile_id + last.run_length as u64 == entry.tile_id => {
+                    last.run_length += 1;
+                }
+                _ => entries.push(Entry { tile_id: entry.tile_id, offset, length: entry.length, run_length: 1 }),
+            }
+        }
+        data.flush()?;
+        drop(data);
+
+        let (root, leaves) = build_directories(&entries)?;
+        let metadata = gzip(serde_json::to_vec(&self.metadata_json())?.as_slice())?;
+
+        let root_offset = HEADER_LEN as u64;
+        let metadata_offset = root_offset + root.len() as u64;
+        let leaves_offset = metadata_offset + metadata.len() as u64;
+        let data_offset = leaves_offset + leaves.len() as u64;
+
+        let format = self.metadata_value("format").and_then(TileFormat::from_metadata);
+        let tile_type = match format {
+            Some(TileFormat::Pbf) => 1,
+            Some(TileFormat::Png) => 2,
+            Some(TileFormat::Jpg) => 3,
+            Some(TileFormat::Webp) => 4,
+            None => 0,
+        };
+        let tile_compression = match (format, self.tile_compression) {
+            (Some(TileFormat::Pbf), Some(Compression::Gzip)) => COMPRESSION_GZIP,
+            (Some(TileFormat::Pbf), Some(Compression::Zlib)) => 0,
+            _ => COMPRESSION_NONE,
+        };
+
+        let (west, south, east, north) = self.metadata_value("bounds")
+            .and_then(parse_floats::<4>)
+            .map(|b| (b[0], b[1], b[2], b[3]))
+            .unwrap_or((-180.0, -85.051_128_779_806_59, 180.0, 85.051_128_779_806_59));
+        let (center_lon, center_lat, center_zoom) = self.metadata_value("center")
+            .and_then(parse_floats::<3>)
+            .map(|c| (c[0], c[1], c[2] as u8))
+            .unwrap_or(((west + east) / 2.0, (south + north) / 2.0, self.min_zoom));
+
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(b"PMTiles");
+        header.push(3);
+        for value in [
+            root_offset,
+            root.len() as u64,
+            metadata_offset,
+            metadata.len() as u64,
+            leaves_offset,
+            leaves.len() as u64,
+            data_offset,
+            data_len,
+            self.entries.len() as u64,
+            entries.len() as u64,
+            placed.len() as u64,
+        ] {
+            header.extend_from_slice(&value.to_le_bytes());
+        }
+        header.extend_from_slice(&[1, COMPRESSION_GZIP, tile_compression, tile_type, self.min_zoom, self.max_zoom]);
+        for degrees in [west, south, east, north] {
+            header.extend_from_slice(&e7(degrees).to_le_bytes());
+        }
+        header.push(center_zoom);
+        header.extend_from_slice(&e7(center_lon).to_le_bytes());
+        header.extend_from_slice(&e7(center_lat).to_le_bytes());
+
+        let mut out = BufWriter::new(
+            File::create(&self.path).context(format!("Failed to create output file: {}", self.path))?,
+        );
+        out.write_all(&header)?;
+        out.write_all(&root)?;
+        out.write_all(&metadata)?;
+        out.write_all(&leaves)?;
+        std::io::copy(&mut File::open(&data_path)?, &mut out)?;
+        out.flush()?;
+
+        fs::remove_file(&data_path)?;
+        Ok(())
+    }
+}
+
+impl TileSink for PmtilesWriter {
+    fn write_metadata(&mut self, name: &str, value: &str) -> Result<()> {
+        self.metadata.retain(|(n, _)| n != name);
+        self.metadata.push((name.to_string(), value.to_string()));
+        Ok(())
+    }
+
+    fn write_tile(&mut self, tile: &Tile) -> Result<()> {
+        if !(0..=MAX_ZOOM).contains(&tile.zoom) {
+            return Err(anyhow!("Invalid zoom level {}, expected 0 to {}", tile.zoom, MAX_ZOOM));
+        }
+        let z = tile.zoom as u8;
+        // PMTiles addresses tiles with XYZ rows
+        let y = (1u32 << z) - 1 - tile.y as u32;
+        let tile_id = tile_id(z, tile.x as u32, y);
+
+        let hash = md5::compute(&tile.data).0;
+        let (offset, length) = match self.contents.get(&hash) {
+            Some(&location) => location,
+            None => {
+                let location = (self.spool_len, tile.data.len() as u32);
+                self.spool.write_all(&tile.data)?;
+                self.spool_len += tile.data.len() as u64;
+                self.contents.insert(hash, location);
+                location
+            }
+        };
+
+        self.tile_compression.get_or_insert_with(|| detect_compression(&tile.data));
+        self.min_zoom = self.min_zoom.min(z);
+        self.max_zoom = self.max_zoom.max(z);
+        self.entries.push(Entry { tile_id, offset, length, run_length: 1 });
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        if self.entries.is_empty() {
+            self.min_zoom = 0;
+        }
+        let result = self.write_archive();
+        fs::remove_file(&self.spool_path)?;
+        result
+    }
+}
+
+/// Build the root directory and, if it doesn't fit in the header's 16 KiB,
+/// a set of leaf directories. Both are returned gzip compressed.
+fn build_directories(entries: &[Entry]) -> Result<(Vec<u8>, Vec<u8>)> {
+    let root = gzip(&serialize_directory(entries))?;
+    if root.len() <= ROOT_MAX_LEN {
+        return Ok((root, Vec::new()));
+    }
+
+    let mut leaf_size = 4096;
+    loop {
+        let mut leaves = Vec::new();
+        let mut root_entries = Vec::new();
+        for chunk in entries.chunks(leaf_size) {
+            let leaf = gzip(&serialize_directory(chunk))?;
+            root_entries.push(Entry {
+                tile_id: chunk[0].tile_id,
+                offset: leaves.len() as u64,
+                length: leaf.len() as u32,
+                run_length: 0,
+            });
+            leaves.extend_from_slice(&leaf);
+        }
+        let root = gzip(&serialize_directory(&root_entries))?;
+        if root.len() <= ROOT_MAX_LEN {
+            return Ok((root, leaves));
+        }
+        leaf_size *= 2;
+    }
+}
+
+fn serialize_directory(entries: &[Entry]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    write_varint(&mut buf, entries.len() as u64);
+
+    let mut last_id = 0;
+    for entry in entries {
+        write_varint(&mut buf, entry.tile_id - last_id);
+        last_id = entry.tile_id;
+    }
+    for entry in entries {
+        write_varint(&mut buf, entry.run_length as u64);
+    }
+    for entry in entries {
+        write_varint(&mut buf, entry.length as u64);
+    }
+    for (i, entry) in entries.iter().enumerate() {
+        // 0 means "directly after the previous entry"
+        if i > 0 && entry.offset == entries[i - 1].offset + entries[i - 1].length as u64 {
+            write_varint(&mut buf, 0);
+        } else {
+            write_varint(&mut buf, entry.offset + 1);
+        }
+    }
+    buf
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn gzip(data: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), GzLevel::default());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+fn e7(degrees: f64) -> i32 {
+    (degrees * 10_000_000.0).round() as i32
+}
+
+fn parse_floats<const N: usize>(value: &str) -> Option<[f64; N]> {
+    let values = value.split(',').map(|v| v.trim().parse().ok()).collect::<Option<Vec<f64>>>()?;
+    values.try_into().ok()
+}
+
+/// Number of decoded leaf directories kept in memory by [`PmtilesReader`]
+const LEAF_CACHE_SIZE: usize = 64;
+/// Ranges with more tiles than this are read by scanning directories
+/// instead of looking up every tile individually
+const DIRECT_LOOKUP_LIMIT: u64 = 65536;
+
+#[derive(Debug, Clone)]
+struct Header {
+    root_offset: u64,
+    root_length: u64,
+    metadata_offset: u64,
+    metadata_length: u64,
+    leaves_offset: u64,
+    data_offset: u64,
+    internal_compression: u8,
+    tile_type: u8,
+    min_zoom: u8,
+    max_zoom: u8,
+    bounds: [f64; 4],
+    center_zoom: u8,
+    center: [f64; 2],
+}
+
+impl Header {
+    fn parse(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < HEADER_LEN || &bytes[0..7] != b"PMTiles" {
+            return Err(anyhow!("Not a PMTiles archive"));
+        }
+        if bytes[7] != 3 {
+            return Err(anyhow!("Unsupported PMTiles version {}", bytes[7]));
+        }
+        let u64_at = |i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+        let e7_at = |i: usize| i32::from_le_bytes(bytes[i..i + 4].try_into().unwrap()) as f64 / 10_000_000.0;
+        Ok(Header {
+            root_offset: u64_at(8),
+            root_length: u64_at(16),
+            metadata_offset: u64_at(24),
+            metadata_length: u64_at(32),
+            leaves_offset: u64_at(40),
+            data_offset: u64_at(56),
+            internal_compression: bytes[97],
+            tile_type: bytes[99],
+            min_zoom: bytes[100],
+            max_zoom: bytes[101],
+            bounds: [e7_at(102), e7_at(106), e7_at(110), e7_at(114)],
+            center_zoom: bytes[118],
+            center: [e7_at(119), e7_at(123)],
+        })
+    }
+}
+
+/// Reads tiles from a PMTiles v3 archive.
+///
+/// The root directory is loaded on open; leaf directories are read on
+/// demand and a handful of them are cached.
+pub struct PmtilesReader {
+    storage: Storage,
+    header: Header,
+    root: Rc<Vec<Entry>>,
+    leaves: RefCell<HashMap<u64, Rc<Vec<Entry>>>>,
+}
+
+/// Where the bytes of an archive come from
+enum Storage {
+    /// A local file and its size
+    File(RefCell<File>, u64),
+    /// An `http(s)://` URL read with range requests, and the start of the
+    /// archive fetched when opening it
+    Http { url: String, prefix: Vec<u8> },
+}
+
+/// Gap between two tiles up to which [`PmtilesReader::for_each_tile`]
+/// reads both at once, spending the bytes between them to save a request
+const MAX_READ_GAP: u64 = 64 * 1024;
+
+/// Longest single read of tile data
+const MAX_READ_LEN: u64 = 8 * 1024 * 1024;
+
+impl PmtilesReader {
+    /// Open the archive at `path`, or at an `http(s)://` URL read with range
+    /// requests
+    pub fn open(path: &str) -> Result<Self> {
+        let (storage, bytes) = if http::is_url(path) {
+            // One request for the header and, as the spec requires, the root
+            // directory
+            let prefix = http_range(path, 0, (HEADER_LEN + ROOT_MAX_LEN) as u64, true)?;
+            let bytes = prefix.get(..HEADER_LEN).ok_or_else(|| anyhow!("Not a PMTiles archive: {}", path))?.to_vec();
+            (Storage::Http { url: path.to_string(), prefix }, bytes)
+        } else {
+            let mut file = File::open(path).context(format!("Failed to open input file: {}", path))?;
+            let len = file.metadata().context(format!("Failed to open input file: {}", path))?.len();
+            let mut bytes = vec![0u8; HEADER_LEN];
+            file.read_exact(&mut bytes).context(format!("Not a PMTiles archive: {}", path))?;
+            (Storage::File(RefCell::new(file), len), bytes)
+        };
+        let header = Header::parse(&bytes).context(format!("Failed to read {}", path))?;
+
+        let reader = PmtilesReader {
+            storage,
+            root: Rc::new(Vec::new()),
+            leaves: RefCell::new(HashMap::new()),
+            header,
+        };
+        let root = reader.read_directory(reader.header.root_offset, reader.header.root_length)?;
+        Ok(PmtilesReader { root: Rc::new(root), ..reader })
+    }
+
+    /// `length` bytes from `offset`, both from the archive's own directories
+    /// and header, so they're checked against the file before allocating
+    fn read_at(&self, offset: u64, length: u64) -> Result<Vec<u8>> {
+        let end = offset.checked_add(length).ok_or_else(|| anyhow!("Invalid PMTiles offset {} and length {}", offset, length))?;
+        if length == 0 {
+            return Ok(Vec::new());
+        }
+        match &self.storage {
+            Storage::File(file, len) => {
+                if end > *len {
+                    return Err(anyhow!("PMTiles archive is truncated: {} bytes at {} are past its end at {}", length, offset, len));
+                }
+                let mut file = file.borrow_mut();
+                file.seek(SeekFrom::Start(offset))?;
+                let mut buf = vec![0u8; length as usize];
+                file.read_exact(&mut buf)?;
+                Ok(buf)
+            }
+            // `http_range` checks the response has exactly `length` bytes
+            Storage::Http { url, prefix } => match usize::try_from(end).ok().and_then(|end| prefix.get(offset as usize..end)) {
+                Some(bytes) => Ok(bytes.to_vec()),
+                None => http_range(url, offset, length, false),
+            },
+        }
+    }
+
+    fn decompress(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+        match self.header.internal_compression {
+            COMPRESSION_GZIP => {
+                let mut out = Vec::new();
+                GzDecoder::new(data.as_slice()).read_to_end(&mut out)?;
+                Ok(out)
+            }
+            0 | COMPRESSION_NONE => Ok(data),
+            other => Err(anyhow!("Unsupported PMTiles internal compression {}", other)),
+        }
+    }
+
+    fn read_directory(&self, offset: u64, length: u64) -> Result<Vec<Entry>> {
+        let data = self.decompress(self.read_at(offset, length)?)?;
+        deserialize_directory(&data)
+    }
+
+    fn leaf(&self, entry: &Entry) -> Result<Rc<Vec<Entry>>> {
+        if let Some(leaf) = self.leaves.borrow().get(&entry.offset) {
+            return Ok(Rc::clone(leaf));
+        }
+        let leaf = Rc::new(self.read_directory(self.header.leaves_offset.saturating_add(entry.offset), entry.length as u64)?);
+        let mut cache = self.leaves.borrow_mut();
+        if cache.len() >= LEAF_CACHE_SIZE {
+            cache.clear();
+        }
+        cache.insert(entry.offset, Rc::clone(&leaf));
+        Ok(leaf)
+    }
+
+    /// Find the (offset, length) of a tile's data by id
+    fn find(&self, tile_id: u64) -> Result<Option<(u64, u32)>> {
+        let mut directory = Rc::clone(&self.root);
+        // Directories nest at most a few levels deep; bound the walk anyway
+        for _ in 0..4 {
+            let index = match directory.binary_search_by_key(&tile_id, |e| e.tile_id) {
+                Ok(i) => i,
+                Err(0) => return Ok(None),
+                Err(i) => i - 1,
+            };
+            let entry = directory[index];
+            if entry.run_length == 0 {
+                directory = self.leaf(&entry)?;
+            } else if tile_id < entry.tile_id.saturating_add(entry.run_length as u64) {
+                return Ok(Some((self.header.data_offset.saturating_add(entry.offset), entry.length)));
+            } else {
+                return Ok(None);
+            }
+        }
+        Err(anyhow!("PMTiles directory nesting is too deep"))
+    }
+
+    /// Call `f` with every tile entry (id, data offset, length) whose id
+    /// is in `ids`, walking only the leaves that can contain such ids
+    fn for_each_entry(
+        &self,
+        directory: &[Entry],
+        ids: &std::ops::Range<u64>,
+        f: &mut dyn FnMut(u64, u64, u32) -> Result<()>,
+    ) -> Result<()> {
+        for (i, entry) in directory.iter().enumerate() {
+            let end = directory.get(i + 1).map_or(u64::MAX, |next| next.tile_id);
+            if end <= ids.start || entry.tile_id >= ids.end {
+                continue;
+            }
+            if entry.run_length == 0 {
+                let leaf = self.leaf(entry)?;
+                self.for_each_entry(&leaf, ids, f)?;
+            } else {
+                let run = entry.tile_id..entry.tile_id.saturating_add(entry.run_length as u64);
+                for id in run.start.max(ids.start)..run.end.min(ids.end) {
+                    f(id, self.header.data_offset.saturating_add(entry.offset), entry.length)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// (x, y, data offset, length) of every tile in `range`
+    fn locate_range(&self, range: &TileRange) -> Result<Vec<(i32, i32, u64, u32)>> {
+        let zoom = range.zoom;
+        let mut found = Vec::new();
+
+        let area = (range.x_max - range.x_min + 1) as u64 * (range.y_max - range.y_min + 1) as u64;
+        if area > DIRECT_LOOKUP_LIMIT {
+            // Scanning the zoom's directory entries beats millions of lookups
+            let ids = tile_id(zoom as u8, 0, 0)..tile_id(zoom as u8 + 1, 0, 0);
+            self.for_each_entry(&self.root, &ids, &mut |id, offset, length| {
+                let (_, x, xyz_y) = tile_id_to_zxy(id);
+                let y = (1 << zoom) - 1 - xyz_y as i32;
+                if range.contains(zoom, x as i32, y) {
+                    found.push((x as i32, y, offset, length));
+                }
+                Ok(())
+            })?;
+            return Ok(found);
+        }
+
+        for y in range.y_min..=range.y_max {
+            for x in range.x_min..=range.x_max {
+                if !(0..=30).contains(&zoom) || x < 0 || y < 0 || x >= 1 << zoom || y >= 1 << zoom {
+                    continue;
+                }
+                let xyz_y = (1 << zoom) - 1 - y;
+                if let Some((offset, length)) = self.find(tile_id(zoom as u8, x as u32, xyz_y as u32))? {
+                    found.push((x, y, offset, length));
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    fn format(&self) -> Option<&'static str> {
+        match self.header.tile_type {
+            1 => Some("pbf"),
+            2 => Some("png"),
+            3 => Some("jpg"),
+            4 => Some("webp"),
+            _ => None,
+        }
+    }
+}
+
+impl TileSource for PmtilesReader {
+    fn kind(&self) -> SourceKind {
+        SourceKind::Pmtiles
+    }
+
+    /// Top level JSON members become metadata values. `vector_layers` and
+    /// `tilestats` are packed into the MBTiles `json` key, and header fields
+    /// fill in `format`, `bounds`, `center`, `minzoom` and `maxzoom`.
+    fn metadata(&self) -> Result<Vec<(String, String)>> {
+        let data = self.decompress(self.read_at(self.header.metadata_offset, self.header.metadata_length)?)?;
+        let json: Value = if data.is_empty() { Value::Object(Map::new()) } else { serde_json::from_slice(&data)? };
+
+        let mut metadata = BTreeMap::new();
+        let mut packed = Map::new();
+        if let Value::Object(members) = json {
+            for (name, value) in members {
+                match (name.as_str(), value) {
+                    ("vector_layers" | "tilestats", value) => {
+                        packed.insert(name, value);
+                    }
+                    (_, Value::String(s)) => {
+                        metadata.insert(name, s);
+                    }
+                    (_, value) => {
+                        metadata.insert(name, value.to_string());
+                    }
+                }
+            }
+        }
+        if !packed.is_empty() {
+            metadata.insert("json".to_string(), Value::Object(packed).to_string());
+        }
+
+        let h = &self.header;
+        if let Some(format) = self.format() {
+            metadata.entry("format".to_string()).or_insert_with(|| format.to_string());
+        }
+        metadata.entry("minzoom".to_string()).or_insert_with(|| h.min_zoom.to_string());
+        metadata.entry("maxzoom".to_string()).or_insert_with(|| h.max_zoom.to_string());
+        metadata.entry("bounds".to_string()).or_insert_with(|| {
+            format!("{},{},{},{}", h.bounds[0], h.bounds[1], h.bounds[2], h.bounds[3])
+        });
+        metadata.entry("center".to_string()).or_insert_with(|| {
+            format!("{},{},{}", h.center[0], h.center[1], h.center_zoom)
+        });
+
+        Ok(metadata.into_iter().collect())
+    }
+
+    fn zoom_levels(&self) -> Result<Vec<i32>> {
+        Ok((self.header.min_zoom as i32..=self.header.max_zoom as i32).collect())
+    }
+
+    fn tile(&self, zoom: i32, x: i32, y: i32) -> Result<Option<Vec<u8>>> {
+        if !(0..=30).contains(&zoom) || x < 0 || y < 0 || x >= 1 << zoom || y >= 1 << zoom {
+            return Ok(None);
+        }
+        let xyz_y = (1 << zoom) - 1 - y;
+        match self.find(tile_id(zoom as u8, x as u32, xyz_y as u32))? {
+            Some((offset, length)) => Ok(Some(self.read_at(offset, length as u64)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Tiles come in the order of their data, neighbours read together
+    fn for_each_tile(&self, range: &TileRange, f: &mut dyn FnMut(Tile) -> Result<()>) -> Result<()> {
+        let mut tiles = self.locate_range(range)?;
+        tiles.sort_by_key(|&(_, _, offset, _)| offset);
+        let mut rest = tiles.as_slice();
+        while let Some(&(_, _, start, length)) = rest.first() {
+            let mut end = start.saturating_add(length as u64);
+            let mut count = 1;
+            for &(_, _, offset, length) in &rest[1..] {
+                let tile_end = end.max(offset.saturating_add(length as u64));
+                if offset > end.saturating_add(MAX_READ_GAP) || tile_end - start > MAX_READ_LEN {
+                    break;
+                }
+                end = tile_end;
+                count += 1;
+            }
+            let data = self.read_at(start, end - start)?;
+            for &(x, y, offset, length) in &rest[..count] {
+                let at = (offset - start) as usize;
+                f(Tile { zoom: range.zoom, x, y, data: data[at..at + length as usize].to_vec() })?;
+            }
+            rest = &rest[count..];
+        }
+        Ok(())
+    }
+
+    fn for_each_tile_size(&self, range: &TileRange, f: &mut dyn FnMut(i32, i32, u64) -> Result<()>) -> Result<()> {
+        for (x, y, _, length) in self.locate_range(range)? {
+            f(x, y, length as u64)?;
+        }
+        Ok(())
+    }
+
+    fn count_tiles(&self, range: &TileRange) -> Result<u64> {
+        Ok(self.locate_range(range)?.len() as u64)
+    }
+
+    fn zoom_info(&self) -> Result<Vec<ZoomInfo>> {
+        let mut zooms: BTreeMap<i32, ZoomInfo> = BTreeMap::new();
+        self.for_each_entry(&self.root, &(0..u64::MAX), &mut |id, _, length| {
+            let (z, x, xyz_y) = tile_id_to_zxy(id);
+            let (z, x) = (z as i32, x as i32);
+            let y = (1 << z) - 1 - xyz_y as i32;
+            let info = zooms.entry(z).or_insert(ZoomInfo {
+                zoom: z,
+                tiles: 0,
+                bytes: 0,
+                x_min: x,
+                x_max: x,
+                y_min: y,
+                y_max: y,
+            });
+            info.tiles += 1;
+            info.bytes += length as u64;
+            info.x_min = info.x_min.min(x);
+            info.x_max = info.x_max.max(x);
+            info.y_min = info.y_min.min(y);
+            info.y_max = info.y_max.max(y);
+            Ok(())
+        })?;
+        Ok(zooms.into_values().collect())
+    }
+
+    fn sample_tile(&self) -> Result<Option<Vec<u8>>> {
+        let mut first = None;
+        let mut directory = Rc::clone(&self.root);
+        while let Some(entry) = directory.first().copied() {
+            if entry.run_length == 0 {
+                directory = self.leaf(&entry)?;
+            } else {
+                first = Some(self.read_at(self.header.data_offset.saturating_add(entry.offset), entry.length as u64)?);
+                break;
+            }
+        }
+        Ok(first)
+    }
+}
+
+/// Read `length` bytes from `offset` of the archive at `url`. With
+/// `partial` a shorter answer is fine, for reading the start of an archive
+/// smaller than asked.
+fn http_range(url: &str, offset: u64, length: u64, partial: bool) -> Result<Vec<u8>> {
+    let body = http::get_range(url, offset, length)?;
+    if body.len() as u64 != length && !(partial && (body.len() as u64) < length) {
+        return Err(anyhow!("Failed to read {}: expected {} bytes from {}, got {}", url, length, offset, body.len()));
+    }
+    Ok(body)
+}
+
+fn deserialize_directory(data: &[u8]) -> Result<Vec<Entry>> {
+    let mut pos = 0;
+    let mut next = || read_varint(data, &mut pos);
+
+    // Every entry takes at least a byte, so a larger count can't be real
+    let count = next()?;
+    if count > data.len() as u64 {
+        return Err(anyhow!("Invalid PMTiles directory of {} entries in {} bytes", count, data.len()));
+    }
+    let count = count as usize;
+    let mut entries = vec![Entry { tile_id: 0, offset: 0, length: 0, run_length: 0 }; count];
+
+    let mut last_id = 0;
+    for entry in entries.iter_mut() {
+        last_id = next()?.checked_add(last_id).ok_or_else(|| anyhow!("Invalid PMTiles directory tile id"))?;
+        entry.tile_id = last_id;
+    }
+    for entry in entries.iter_mut() {
+        entry.run_length = next()? as u32;
+    }
+    for entry in entries.iter_mut() {
+        entry.length = next()? as u32;
+    }
+    for i in 0..count {
+        let value = next()?;
+        entries[i].offset = if value == 0 && i > 0 {
+            entries[i - 1].offset.checked_add(entries[i - 1].length as u64).ok_or_else(|| anyhow!("Invalid PMTiles directory offset"))?
+        } else {
+            value.checked_sub(1).ok_or_else(|| anyhow!("Invalid PMTiles directory offset"))?
+        };
+    }
+    Ok(entries)
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> Result<u64> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = *data.get(*pos).ok_or_else(|| anyhow!("Truncated PMTiles directory"))?;
+        *pos += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte < 0x80 {
+            return Ok(value);
+        }
+        shift += 7;
+        if shift >= 64 {
+            return Err(anyhow!("Invalid varint in PMTiles directory"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn directories_are_checked_before_allocating() {
+        let mut data = Vec::new();
+        write_varint(&mut data, 1 << 40);
+        assert!(deserialize_directory(&data).unwrap_err().to_string().contains("entries in"));
+
+        // One entry right after another that ends at u64::MAX
+        let mut data = Vec::new();
+        for value in [2, 0, 1, 1, 1, 0xffff_ffff, 0xffff_ffff, u64::MAX, 0] {
+            write_varint(&mut data, value);
+        }
+        assert!(deserialize_directory(&data).is_err());
+        assert!(deserialize_directory(&[2, 1]).unwrap_err().to_string().contains("Truncated"));
+    }
+
+    #[test]
+    fn reads_are_checked_against_the_file() {
+        let path = std::env::temp_dir().join(format!("mbtiles-pmtiles-{}-truncated.pmtiles", std::process::id()));
+        let mut header = vec![0u8; HEADER_LEN];
+        header[..8].copy_from_slice(b"PMTiles\x03");
+        header[8..16].copy_from_slice(&(HEADER_LEN as u64).to_le_bytes());
+        for root_length in [1 << 40, u64::MAX] {
+            header[16..24].copy_from_slice(&root_length.to_le_bytes());
+            std::fs::write(&path, &header).unwrap();
+            assert!(PmtilesReader::open(path.to_str().unwrap()).is_err());
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+}
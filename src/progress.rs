@@ -0,0 +1,19 @@
+/// Receives updates from long running operations.
+///
+/// Implementations must be `Sync` because updates may come from several
+/// threads. All methods default to doing nothing.
+pub trait Progress: Sync {
+    /// Called once before work starts with the number of tiles expected
+    fn start(&self, _total: u64) {}
+
+    /// Called as tiles are processed with the number just completed
+    fn advance(&self, _tiles: u64) {}
+
+    /// Called once all work is done
+    fn finish(&self) {}
+}
+
+/// Ignores all progress updates
+pub struct NoProgress;
+
+impl Progress for NoProgress {}
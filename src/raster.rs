@@ -0,0 +1,226 @@
+use std::fmt;
+use std::io::Cursor;
+
+use anyhow::{Result, anyhow};
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, ImageFormat, RgbaImage};
+
+use crate::clip::{self, Ring};
+use crate::tile::{detect_format, TileFormat};
+
+/// Target of a raster tile conversion
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RasterConversion {
+    pub to: TileFormat,
+    /// Encoder quality from 0 to 100 for lossy formats (JPEG, WebP)
+    pub quality: f32,
+    /// Encode WebP losslessly, ignoring `quality`
+    pub lossless: bool,
+}
+
+impl RasterConversion {
+    pub fn new(to: TileFormat) -> Self {
+        RasterConversion { to, quality: 80.0, lossless: false }
+    }
+
+    /// Re-encode a PNG, JPEG or WebP tile. Tiles already in the target
+    /// format are returned unchanged.
+    pub fn apply(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+        let from = detect_format(&data);
+        if from == self.to {
+            return Ok(data);
+        }
+        let image = decode(&data, from)?;
+        encode(&image, self.to, self.quality, self.lossless)
+    }
+}
+
+/// Quality used when watermarked tiles are JPEG or WebP and no conversion
+/// says otherwise
+const WATERMARK_QUALITY: f32 = 85.0;
+
+/// Where on a tile a [`Watermark`] is drawn
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatermarkPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    Center,
+}
+
+/// An image drawn onto every raster tile, a margin of 1/32 of the tile's
+/// side away from the edges. Images too big for a tile are scaled down to
+/// fit.
+#[derive(Clone)]
+pub struct Watermark {
+    image: RgbaImage,
+    pub position: WatermarkPosition,
+    /// Multiplies the image's own alpha, from 0 to 1
+    pub opacity: f32,
+}
+
+impl fmt::Debug for Watermark {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Watermark")
+            .field("size", &self.image.dimensions())
+            .field("position", &self.position)
+            .field("opacity", &self.opacity)
+            .finish()
+    }
+}
+
+impl Watermark {
+    /// Load the PNG, JPEG or WebP image at `path`
+    pub fn open(path: &str, position: WatermarkPosition, opacity: f32) -> Result<Self> {
+        let data = std::fs::read(path).map_err(|e| anyhow!("Failed to read watermark {}: {}", path, e))?;
+        let format = detect_format(&data);
+        if format == TileFormat::Pbf {
+            return Err(anyhow!("Watermark {} is not a PNG, JPEG or WebP image", path));
+        }
+        let image = decode(&data, format).map_err(|e| anyhow!("Invalid watermark image {}: {}", path, e))?;
+        Self::new(image, position, opacity)
+    }
+
+    pub fn new(image: DynamicImage, position: WatermarkPosition, opacity: f32) -> Result<Self> {
+        if !(0.0..=1.0).contains(&opacity) {
+            return Err(anyhow!("Invalid watermark opacity: {} (expected 0 to 1)", opacity));
+        }
+        Ok(Watermark { image: image.to_rgba8(), position, opacity })
+    }
+
+    /// Draw the watermark onto a raster tile, encoding the result in the
+    /// format of `conversion` if given, else the tile's own
+    pub(crate) fn apply(&self, data: &[u8], conversion: Option<&RasterConversion>) -> Result<Vec<u8>> {
+        let format = detect_format(data);
+        if format == TileFormat::Pbf {
+            return Err(anyhow!("Watermarks require raster tiles"));
+        }
+        let mut tile = decode(data, format)?.to_rgba8();
+        let (width, height) = tile.dimensions();
+        let margin = width / 32;
+        let (room_x, room_y) = (width.saturating_sub(2 * margin).max(1), height.saturating_sub(2 * margin).max(1));
+        let (logo_width, logo_height) = self.image.dimensions();
+        let scaled;
+        let logo = if logo_width > room_x || logo_height > room_y {
+            let scale = (room_x as f64 / logo_width as f64).min(room_y as f64 / logo_height as f64);
+            let size = |side: u32| ((side as f64 * scale).round() as u32).max(1);
+            scaled = imageops::resize(&self.image, size(logo_width), size(logo_height), FilterType::Triangle);
+            &scaled
+        } else {
+            &self.image
+        };
+
+        let (right, bottom) = (width.saturating_sub(margin + logo.width()), height.saturating_sub(margin + logo.height()));
+        let (x, y) = match self.position {
+            WatermarkPosition::TopLeft => (margin, margin),
+            WatermarkPosition::TopRight => (right, margin),
+            WatermarkPosition::BottomLeft => (margin, bottom),
+            WatermarkPosition::BottomRight => (right, bottom),
+            WatermarkPosition::Center => ((width - logo.width()) / 2, (height - logo.height()) / 2),
+        };
+        blend(&mut tile, logo, x, y, self.opacity);
+
+        let tile = DynamicImage::ImageRgba8(tile);
+        match conversion {
+            Some(conversion) => encode(&tile, conversion.to, conversion.quality, conversion.lossless),
+            None => encode(&tile, format, WATERMARK_QUALITY, false),
+        }
+    }
+}
+
+/// Draw `top` over `image` with its top left corner at `x`, `y` ("source
+/// over" compositing), its alpha scaled by `opacity`. Opaque pixels stay
+/// opaque.
+pub(crate) fn blend(image: &mut RgbaImage, top: &RgbaImage, x: u32, y: u32, opacity: f32) {
+    for (top_x, top_y, top) in top.enumerate_pixels() {
+        let Some(bottom) = image.get_pixel_mut_checked(x + top_x, y + top_y) else { continue };
+        let top_alpha = top[3] as f32 / 255.0 * opacity;
+        if top_alpha == 0.0 {
+            continue;
+        }
+        let bottom_alpha = bottom[3] as f32 / 255.0 * (1.0 - top_alpha);
+        let alpha = top_alpha + bottom_alpha;
+        for channel in 0..3 {
+            let value = (top[channel] as f32 * top_alpha + bottom[channel] as f32 * bottom_alpha) / alpha;
+            bottom[channel] = value.round() as u8;
+        }
+        bottom[3] = (alpha * 255.0).round() as u8;
+    }
+}
+
+/// Decode a raster tile in `format`
+pub(crate) fn decode(data: &[u8], format: TileFormat) -> Result<DynamicImage> {
+    match format {
+        TileFormat::Png => Ok(image::load_from_memory_with_format(data, ImageFormat::Png)?),
+        TileFormat::Jpg => Ok(image::load_from_memory_with_format(data, ImageFormat::Jpeg)?),
+        TileFormat::Webp => {
+            let decoded = webp::Decoder::new(data).decode().ok_or_else(|| anyhow!("Failed to decode WebP tile"))?;
+            let (width, height, pixels) = (decoded.width(), decoded.height(), decoded.to_vec());
+            let image = if decoded.is_alpha() {
+                image::RgbaImage::from_raw(width, height, pixels).map(DynamicImage::ImageRgba8)
+            } else {
+                image::RgbImage::from_raw(width, height, pixels).map(DynamicImage::ImageRgb8)
+            };
+            image.ok_or_else(|| anyhow!("Failed to decode WebP tile"))
+        }
+        TileFormat::Pbf => Err(anyhow!("Raster conversion requires raster tiles")),
+    }
+}
+
+/// Encode `image` as a `format` tile
+pub(crate) fn encode(image: &DynamicImage, format: TileFormat, quality: f32, lossless: bool) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    match format {
+        TileFormat::Png => image.write_to(&mut Cursor::new(&mut out), ImageFormat::Png)?,
+        TileFormat::Jpg => {
+            let encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut out, quality.clamp(1.0, 100.0) as u8);
+            // JPEG has no alpha channel
+            image.to_rgb8().write_with_encoder(encoder)?;
+        }
+        TileFormat::Webp => {
+            let rgba = image.to_rgba8();
+            let encoder = webp::Encoder::from_rgba(&rgba, rgba.width(), rgba.height());
+            let encoded = if lossless { encoder.encode_lossless() } else { encoder.encode(quality.clamp(0.0, 100.0)) };
+            out.extend_from_slice(&encoded);
+        }
+        TileFormat::Pbf => return Err(anyhow!("Cannot convert raster tiles to pbf")),
+    }
+    Ok(out)
+}
+
+/// The part of `image` covering one of its descendants `levels` zooms
+/// down, at column `dx` and row `dy` counted from the top within the
+/// image, scaled up to the image's size. `None` once that part would be
+/// smaller than a pixel.
+pub(crate) fn upscale_part(image: &DynamicImage, levels: i32, dx: u32, dy: u32) -> Option<DynamicImage> {
+    let size = image.width().min(image.height());
+    let part = size.checked_shr(levels as u32).unwrap_or(0);
+    if part == 0 {
+        return None;
+    }
+    let cropped = imageops::crop_imm(image, dx * part, dy * part, part, part).to_image();
+    Some(DynamicImage::ImageRgba8(imageops::resize(&cropped, size, size, FilterType::Triangle)))
+}
+
+/// `image` with the pixels whose centres lie outside `area` made
+/// transparent, rings in units of the image's side with the origin at its
+/// top left. `None` if no pixel is left.
+pub(crate) fn mask(image: &DynamicImage, area: &[Ring]) -> Option<DynamicImage> {
+    let mut rgba = image.to_rgba8();
+    let (width, height) = rgba.dimensions();
+    let mut visible = false;
+    for row in 0..height {
+        let crossings = clip::row_crossings(area, (row as f64 + 0.5) / height as f64);
+        let spans: Vec<&[f64]> = crossings.chunks_exact(2).collect();
+        for column in 0..width {
+            let x = (column as f64 + 0.5) / width as f64;
+            if spans.iter().any(|span| span[0] <= x && x < span[1]) {
+                visible = true;
+            } else {
+                rgba.put_pixel(column, row, image::Rgba([0, 0, 0, 0]));
+            }
+        }
+    }
+    visible.then_some(DynamicImage::ImageRgba8(rgba))
+}
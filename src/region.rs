@@ -0,0 +1,230 @@
+use std::f64::consts::PI;
+
+use anyhow::{Context, Result, anyhow};
+use serde_json::Value;
+
+use crate::bbox::{BoundingBox, TileRange};
+use crate::clip::Ring;
+use crate::coord::MAX_LATITUDE;
+
+/// A (multi)polygon extraction region loaded from GeoJSON.
+///
+/// Rings are stored in normalized Web Mercator coordinates where both axes
+/// run from 0 to 1 and y grows southwards, so a tile at zoom `z` is a
+/// square of side `1 / 2^z`.
+#[derive(Debug, Clone)]
+pub struct Region {
+    rings: Vec<Vec<(f64, f64)>>,
+    bbox: BoundingBox,
+}
+
+impl Region {
+    pub fn from_geojson_file(path: &str) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .context(format!("Failed to read region file: {}", path))?;
+        let value: Value = serde_json::from_str(&text)
+            .context(format!("Invalid GeoJSON in region file: {}", path))?;
+        Self::from_geojson(&value)
+    }
+
+    /// Build a region from a Polygon, MultiPolygon, Feature or FeatureCollection
+    pub fn from_geojson(value: &Value) -> Result<Self> {
+        let mut lon_lat_rings = Vec::new();
+        collect_rings(value, &mut lon_lat_rings)?;
+        if lon_lat_rings.is_empty() {
+            return Err(anyhow!("Region contains no polygons"));
+        }
+
+        let mut bbox = BoundingBox {
+            north: f64::MIN,
+            east: f64::MIN,
+            south: f64::MAX,
+            west: f64::MAX,
+        };
+        for &(lon, lat) in lon_lat_rings.iter().flatten() {
+            bbox.west = bbox.west.min(lon);
+            bbox.east = bbox.east.max(lon);
+            bbox.south = bbox.south.min(lat);
+            bbox.north = bbox.north.max(lat);
+        }
+
+        let rings = lon_lat_rings
+            .into_iter()
+            .map(|ring| ring.into_iter().map(|(lon, lat)| project(lon, lat)).collect())
+            .collect();
+
+        Ok(Region { rings, bbox })
+    }
+
+    /// Bounding box of all polygon vertices
+    pub fn bbox(&self) -> BoundingBox {
+        self.bbox
+    }
+
+    /// Tile ranges (one or more per tile row) covering every tile that
+    /// intersects the region at `zoom`
+    pub fn tile_ranges(&self, zoom: i32) -> Vec<TileRange> {
+        let n = 2_i32.pow(zoom as u32);
+        let bounds = self.bbox.tile_bounds(zoom);
+
+        let mut ranges = Vec::new();
+        for tms_y in bounds.y_min..=bounds.y_max {
+            let row = n - 1 - tms_y;
+            let top = row as f64 / n as f64;
+            let bottom = (row + 1) as f64 / n as f64;
+
+            ranges.extend(row_ranges(zoom, tms_y, self.row_spans(top, bottom)));
+        }
+        ranges
+    }
+
+    /// The region's rings in the coordinates of the tile `zoom`/`x`/`tms_y`,
+    /// which spans 0 to 1 with y growing southwards, left open for
+    /// [`crate::clip`]
+    pub(crate) fn tile_rings(&self, zoom: i32, x: i32, tms_y: i32) -> Vec<Ring> {
+        let n = 2_i32.pow(zoom as u32) as f64;
+        let row = n - 1.0 - tms_y as f64;
+        self.rings
+            .iter()
+            .map(|ring| {
+                let mut ring: Ring = ring.iter().map(|&(mx, my)| (mx * n - x as f64, my * n - row)).collect();
+                if ring.len() > 1 && ring.first() == ring.last() {
+                    ring.pop();
+                }
+                ring
+            })
+            .filter(|ring| ring.len() >= 3)
+            .collect()
+    }
+
+    /// Horizontal extents of the region within the band `top..bottom`.
+    ///
+    /// Any point of the region inside the band is either directly above or
+    /// below a boundary point inside the band, or lies above or below the
+    /// interior on the band's middle line, so these two sets together cover
+    /// the region's projection onto the x axis.
+    fn row_spans(&self, top: f64, bottom: f64) -> Vec<(f64, f64)> {
+        let mut spans = Vec::new();
+        let middle = (top + bottom) / 2.0;
+        let mut crossings = Vec::new();
+
+        for ring in &self.rings {
+            for edge in ring.windows(2) {
+                let (a, b) = (edge[0], edge[1]);
+
+                if let Some(span) = clip_to_band(a, b, top, bottom) {
+                    spans.push(span);
+                }
+
+                if (a.1 > middle) != (b.1 > middle) {
+                    crossings.push(a.0 + (middle - a.1) / (b.1 - a.1) * (b.0 - a.0));
+                }
+            }
+        }
+
+        crossings.sort_by(|a, b| a.total_cmp(b));
+        spans.extend(crossings.chunks_exact(2).map(|pair| (pair[0], pair[1])));
+        spans
+    }
+}
+
+/// Ranges of the tiles in row `tms_y` touched by x `spans` in normalized
+/// Web Mercator, one per run of adjacent tiles
+pub(crate) fn row_ranges(zoom: i32, tms_y: i32, mut spans: Vec<(f64, f64)>) -> Vec<TileRange> {
+    let n = 2_i32.pow(zoom as u32);
+    spans.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let mut columns: Vec<(i32, i32)> = Vec::new();
+    for (start, end) in spans {
+        let x_min = ((start * n as f64).floor() as i32).clamp(0, n - 1);
+        // A span ending exactly on a tile edge doesn't reach into the next tile
+        let x_max = ((end * n as f64).ceil() as i32 - 1).clamp(x_min, n - 1);
+        match columns.last_mut() {
+            Some(last) if x_min <= last.1 + 1 => last.1 = last.1.max(x_max),
+            _ => columns.push((x_min, x_max)),
+        }
+    }
+
+    columns
+        .into_iter()
+        .map(|(x_min, x_max)| TileRange { zoom, x_min, x_max, y_min: tms_y, y_max: tms_y })
+        .collect()
+}
+
+/// Project lon/lat to normalized Web Mercator
+pub(crate) fn project(lon: f64, lat: f64) -> (f64, f64) {
+    let lat = lat.clamp(-MAX_LATITUDE, MAX_LATITUDE).to_radians();
+    let x = (lon + 180.0) / 360.0;
+    let y = (1.0 - lat.tan().asinh() / PI) / 2.0;
+    (x, y)
+}
+
+/// X extent of the part of segment `a`-`b` lying strictly within `top..bottom`
+pub(crate) fn clip_to_band(a: (f64, f64), b: (f64, f64), top: f64, bottom: f64) -> Option<(f64, f64)> {
+    let (y_lo, y_hi) = (a.1.min(b.1), a.1.max(b.1));
+    // Segments merely touching the band edge belong to the neighbouring row
+    if y_hi <= top || y_lo >= bottom {
+        return None;
+    }
+    if a.1 == b.1 {
+        return Some((a.0.min(b.0), a.0.max(b.0)));
+    }
+    let x_at = |y: f64| a.0 + (y - a.1) / (b.1 - a.1) * (b.0 - a.0);
+    let x0 = x_at(y_lo.max(top));
+    let x1 = x_at(y_hi.min(bottom));
+    Some((x0.min(x1), x0.max(x1)))
+}
+
+fn collect_rings(value: &Value, rings: &mut Vec<Vec<(f64, f64)>>) -> Result<()> {
+    let kind = value["type"].as_str().ok_or_else(|| anyhow!("GeoJSON object has no type"))?;
+    match kind {
+        "FeatureCollection" => {
+            let features = value["features"].as_array()
+                .ok_or_else(|| anyhow!("FeatureCollection has no features"))?;
+            for feature in features {
+                collect_rings(feature, rings)?;
+            }
+        }
+        "Feature" => collect_rings(&value["geometry"], rings)?,
+        "GeometryCollection" => {
+            let geometries = value["geometries"].as_array()
+                .ok_or_else(|| anyhow!("GeometryCollection has no geometries"))?;
+            for geometry in geometries {
+                collect_rings(geometry, rings)?;
+            }
+        }
+        "Polygon" => parse_polygon(&value["coordinates"], rings)?,
+        "MultiPolygon" => {
+            let polygons = value["coordinates"].as_array()
+                .ok_or_else(|| anyhow!("MultiPolygon has no coordinates"))?;
+            for polygon in polygons {
+                parse_polygon(polygon, rings)?;
+            }
+        }
+        other => return Err(anyhow!("Unsupported GeoJSON type for region: {}", other)),
+    }
+    Ok(())
+}
+
+fn parse_polygon(coordinates: &Value, rings: &mut Vec<Vec<(f64, f64)>>) -> Result<()> {
+    let polygon = coordinates.as_array().ok_or_else(|| anyhow!("Polygon has no coordinates"))?;
+    for ring in polygon {
+        let positions = ring.as_array().ok_or_else(|| anyhow!("Invalid polygon ring"))?;
+        let mut points = positions
+            .iter()
+            .map(|p| match (p[0].as_f64(), p[1].as_f64()) {
+                (Some(lon), Some(lat)) => Ok((lon, lat)),
+                _ => Err(anyhow!("Invalid position in polygon ring")),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if points.len() < 3 {
+            return Err(anyhow!("Polygon ring needs at least 3 positions"));
+        }
+        // Close the ring so every edge is a window of two points
+        if points.first() != points.last() {
+            points.push(points[0]);
+        }
+        rings.push(points);
+    }
+    Ok(())
+}
@@ -0,0 +1,215 @@
+use std::f64::consts::PI;
+
+use anyhow::{Context, Result, anyhow};
+use serde_json::Value;
+
+use crate::bbox::{BoundingBox, TileRange};
+use crate::coord::MAX_LATITUDE;
+use crate::region::{clip_to_band, project, row_ranges};
+
+/// Equatorial radius used by Web Mercator, in kilometres
+const EARTH_RADIUS_KM: f64 = 6378.137;
+/// Kilometres per degree of latitude
+const KM_PER_DEGREE: f64 = 2.0 * PI * EARTH_RADIUS_KM / 360.0;
+
+/// A corridor of tiles within a distance of a GPX track or GeoJSON line.
+///
+/// Segments are stored in normalized Web Mercator like [`crate::Region`]
+/// rings, each with the buffer distance converted to Mercator units at the
+/// segment's most poleward latitude, where the projection stretches most.
+#[derive(Debug, Clone)]
+pub struct Route {
+    segments: Vec<Segment>,
+    bbox: BoundingBox,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    a: (f64, f64),
+    b: (f64, f64),
+    radius: f64,
+}
+
+impl Route {
+    /// Load a `.gpx` track or route, or a GeoJSON (multi)line string
+    pub fn from_file(path: &str, buffer_km: f64) -> Result<Self> {
+        let text = std::fs::read_to_string(path).context(format!("Failed to read route file: {}", path))?;
+        let lines = if path.to_ascii_lowercase().ends_with(".gpx") || text.trim_start().starts_with('<') {
+            parse_gpx(&text).map_err(|e| anyhow!("Invalid GPX in route file {}: {}", path, e))?
+        } else {
+            let value: Value = serde_json::from_str(&text).context(format!("Invalid GeoJSON in route file: {}", path))?;
+            let mut lines = Vec::new();
+            collect_lines(&value, &mut lines)?;
+            lines
+        };
+        Self::new(lines, buffer_km)
+    }
+
+    /// A corridor `buffer_km` wide on each side of lines of lon/lat points
+    pub fn new(lines: Vec<Vec<(f64, f64)>>, buffer_km: f64) -> Result<Self> {
+        if buffer_km.is_nan() || buffer_km < 0.0 {
+            return Err(anyhow!("Route buffer must not be negative: {}", buffer_km));
+        }
+        if lines.iter().all(Vec::is_empty) {
+            return Err(anyhow!("Route contains no points"));
+        }
+
+        let buffer_lat = buffer_km / KM_PER_DEGREE;
+        let mut bbox = BoundingBox { north: f64::MIN, east: f64::MIN, south: f64::MAX, west: f64::MAX };
+        let mut segments = Vec::new();
+        for line in lines.iter().filter(|line| !line.is_empty()) {
+            // A single point still gets a buffer around it
+            let pairs: Vec<((f64, f64), (f64, f64))> = match line.len() {
+                1 => vec![(line[0], line[0])],
+                _ => line.windows(2).map(|pair| (pair[0], pair[1])).collect(),
+            };
+            for (a, b) in pairs {
+                let poleward = (a.1.abs().max(b.1.abs()) + buffer_lat).min(MAX_LATITUDE);
+                let cos = poleward.to_radians().cos();
+                let radius = buffer_km / (2.0 * PI * EARTH_RADIUS_KM * cos);
+                segments.push(Segment { a: project(a.0, a.1), b: project(b.0, b.1), radius });
+
+                let buffer_lon = (buffer_lat / cos).min(360.0);
+                for (lon, lat) in [a, b] {
+                    bbox.west = bbox.west.min(lon - buffer_lon);
+                    bbox.east = bbox.east.max(lon + buffer_lon);
+                    bbox.south = bbox.south.min(lat - buffer_lat);
+                    bbox.north = bbox.north.max(lat + buffer_lat);
+                }
+            }
+        }
+        let bbox = BoundingBox {
+            north: bbox.north.min(90.0),
+            east: bbox.east.min(180.0),
+            south: bbox.south.max(-90.0),
+            west: bbox.west.max(-180.0),
+        };
+
+        Ok(Route { segments, bbox })
+    }
+
+    /// Bounding box of the corridor
+    pub fn bbox(&self) -> BoundingBox {
+        self.bbox
+    }
+
+    /// Tile ranges (one or more per tile row) covering every tile within
+    /// the buffer distance of the route at `zoom`. In each row a segment
+    /// covers its own extent widened by the buffer, so a few tiles at the
+    /// corners of the corridor may be included too.
+    pub fn tile_ranges(&self, zoom: i32) -> Vec<TileRange> {
+        let n = 2_i32.pow(zoom as u32);
+        let bounds = self.bbox.tile_bounds(zoom);
+
+        let mut ranges = Vec::new();
+        for tms_y in bounds.y_min..=bounds.y_max {
+            let row = n - 1 - tms_y;
+            let top = row as f64 / n as f64;
+            let bottom = (row + 1) as f64 / n as f64;
+
+            let spans: Vec<(f64, f64)> = self
+                .segments
+                .iter()
+                .filter_map(|s| {
+                    let (x0, x1) = clip_to_band(s.a, s.b, top - s.radius, bottom + s.radius)?;
+                    Some((x0 - s.radius, x1 + s.radius))
+                })
+                .collect();
+            ranges.extend(row_ranges(zoom, tms_y, spans));
+        }
+        ranges
+    }
+}
+
+/// Points of each `<trkseg>` and `<rte>` of a GPX document
+fn parse_gpx(text: &str) -> Result<Vec<Vec<(f64, f64)>>> {
+    let mut lines: Vec<Vec<(f64, f64)>> = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        let end = rest[start..].find('>').ok_or_else(|| anyhow!("Unterminated tag"))? + start;
+        let tag = &rest[start + 1..end];
+        rest = &rest[end + 1..];
+
+        let name = tag.split(|c: char| c.is_whitespace() || c == '/').next().unwrap_or("");
+        match name {
+            "trkseg" | "rte" => lines.push(Vec::new()),
+            "trkpt" | "rtept" => {
+                let lat = attribute(tag, "lat").ok_or_else(|| anyhow!("<{}> without lat", name))?;
+                let lon = attribute(tag, "lon").ok_or_else(|| anyhow!("<{}> without lon", name))?;
+                let point = (
+                    lon.parse().map_err(|_| anyhow!("Invalid lon {:?}", lon))?,
+                    lat.parse().map_err(|_| anyhow!("Invalid lat {:?}", lat))?,
+                );
+                match lines.last_mut() {
+                    Some(line) => line.push(point),
+                    None => lines.push(vec![point]),
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(lines)
+}
+
+/// Value of attribute `name` in the inside of an XML start tag
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = tag;
+    while let Some(position) = rest.find(name) {
+        let before = rest[..position].chars().next_back();
+        let after = rest[position + name.len()..].trim_start();
+        rest = &rest[position + name.len()..];
+        if !before.is_some_and(char::is_whitespace) {
+            continue;
+        }
+        let Some(value) = after.strip_prefix('=') else {
+            continue;
+        };
+        let value = value.trim_start();
+        let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+        let value = &value[1..];
+        return value.find(quote).map(|end| &value[..end]);
+    }
+    None
+}
+
+fn collect_lines(value: &Value, lines: &mut Vec<Vec<(f64, f64)>>) -> Result<()> {
+    let kind = value["type"].as_str().ok_or_else(|| anyhow!("GeoJSON object has no type"))?;
+    match kind {
+        "FeatureCollection" => {
+            let features = value["features"].as_array()
+                .ok_or_else(|| anyhow!("FeatureCollection has no features"))?;
+            for feature in features {
+                collect_lines(feature, lines)?;
+            }
+        }
+        "Feature" => collect_lines(&value["geometry"], lines)?,
+        "GeometryCollection" => {
+            let geometries = value["geometries"].as_array()
+                .ok_or_else(|| anyhow!("GeometryCollection has no geometries"))?;
+            for geometry in geometries {
+                collect_lines(geometry, lines)?;
+            }
+        }
+        "LineString" => lines.push(parse_positions(&value["coordinates"])?),
+        "MultiLineString" => {
+            let strings = value["coordinates"].as_array()
+                .ok_or_else(|| anyhow!("MultiLineString has no coordinates"))?;
+            for string in strings {
+                lines.push(parse_positions(string)?);
+            }
+        }
+        other => return Err(anyhow!("Unsupported GeoJSON type for route: {}", other)),
+    }
+    Ok(())
+}
+
+fn parse_positions(coordinates: &Value) -> Result<Vec<(f64, f64)>> {
+    let positions = coordinates.as_array().ok_or_else(|| anyhow!("LineString has no coordinates"))?;
+    positions
+        .iter()
+        .map(|p| match (p[0].as_f64(), p[1].as_f64()) {
+            (Some(lon), Some(lat)) => Ok((lon, lat)),
+            _ => Err(anyhow!("Invalid position in line string")),
+        })
+        .collect()
+}
@@ -0,0 +1,660 @@
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::mpsc::{self, TrySendError};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, anyhow};
+use rusqlite::{Connection, OptionalExtension, params};
+
+use crate::bbox::{BoundingBox, TileRange};
+use crate::coord::MAX_ZOOM;
+use crate::extract::Area;
+use crate::http::{self, HttpResponse};
+use crate::interrupt;
+use crate::mbtiles::MbtilesWriter;
+use crate::progress::{NoProgress, Progress};
+use crate::sink::TileSink;
+use crate::tile::{decompress, detect_compression, detect_format, Compression, Tile, TileFormat};
+
+/// Wait before the first retry of a tile, doubled for every later one
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Tiles written per transaction, the most an interrupted run can lose
+const SEED_BATCH_TILES: usize = 500;
+
+/// Longest `Retry-After` honoured, so one answer can't stall the job
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(120);
+
+/// Tiles of the area planned at a time, bounding what a large area holds in
+/// memory
+const PLAN_CHUNK_TILES: i32 = 4096;
+
+/// What to download when seeding
+#[derive(Debug, Clone)]
+pub struct SeedOptions {
+    pub area: Area,
+    /// Lowest zoom level to download (inclusive)
+    pub min_zoom: i32,
+    /// Highest zoom level to download (inclusive)
+    pub max_zoom: i32,
+    /// Requests in flight at once
+    pub concurrency: usize,
+    /// Most requests started per second, `None` for no limit
+    pub rate: Option<f64>,
+    /// Attempts after the first for tiles that fail with a network error, a
+    /// 429 or a 5xx status, waiting longer each time
+    pub retries: u32,
+    /// `attribution` metadata, most tile providers require one
+    pub attribution: Option<String>,
+    /// Extra request headers, e.g. the User-Agent a provider asks for
+    pub headers: Vec<(String, String)>,
+    /// Continue an existing output, requesting only the tiles it doesn't
+    /// have yet and that weren't missing or failing before
+    pub resume: bool,
+    /// Request the tiles recorded as failed in an existing output again.
+    /// Alone, only those are requested; with `resume`, they are requested
+    /// along with every tile not done yet.
+    pub retry_failed: bool,
+    /// Request the tiles an existing output already has again, sending the
+    /// `ETag` or `Last-Modified` they were downloaded with, and rewrite only
+    /// those that changed. Tiles now missing upstream are deleted.
+    pub refresh: bool,
+}
+
+impl SeedOptions {
+    pub fn new(area: impl Into<Area>, min_zoom: i32, max_zoom: i32) -> Self {
+        SeedOptions {
+            area: area.into(),
+            min_zoom,
+            max_zoom,
+            concurrency: 4,
+            rate: None,
+            retries: 3,
+            attribution: None,
+            headers: Vec::new(),
+            resume: false,
+            retry_failed: false,
+            refresh: false,
+        }
+    }
+}
+
+/// Outcome of seeding
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SeedReport {
+    /// Tiles downloaded and stored
+    pub downloaded: u64,
+    /// Bytes of tile data stored
+    pub bytes: u64,
+    /// Tiles the server has no data for (404, 204 or an empty body)
+    pub missing: u64,
+    /// Tiles still failing after every retry, or refused by the server
+    pub failed: u64,
+    /// Failed tiles recorded in the output after this run, to retry later
+    pub failures_left: u64,
+    /// Refreshed tiles the server reported or sent unchanged
+    pub unchanged: u64,
+    /// Refreshed tiles deleted because the server no longer has them
+    pub removed: u64,
+}
+
+/// Conditional request headers of a stored tile
+#[derive(Debug, Clone, Default)]
+struct Validators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+/// A tile to request (TMS) and the validators to send with it
+type Request = ((i32, i32, i32), Validators);
+
+/// Result of requesting one tile
+enum Fetched {
+    Tile(Vec<u8>, Validators),
+    NotModified,
+    Missing,
+    Failed(String),
+}
+
+/// Download every tile of `options.area` between its zoom levels from
+/// `url_template` into the MBTiles file at `output_path`.
+///
+/// The template takes `{z}`, `{x}` and `{y}` in XYZ numbering, or `{-y}`
+/// for servers using TMS rows, in an `http://` or `https://` URL. Metadata
+/// describes the area and zoom levels, the format of the first tile and the
+/// given attribution; the URL is not stored as it often carries an API key.
+///
+/// Tiles are committed in small batches, and tiles the server has no data
+/// for or that failed are recorded in the `_seed_missing` and
+/// `_seed_failures` tables, so an interrupted or partly failed job can be
+/// continued with [`SeedOptions::resume`] and
+/// [`SeedOptions::retry_failed`]. The tables are dropped once a run ends
+/// without failures. The `ETag` and `Last-Modified` of every tile are kept
+/// in `_tile_validators` for [`SeedOptions::refresh`].
+pub fn seed(url_template: &str, output_path: &str, options: &SeedOptions) -> Result<SeedReport> {
+    seed_with_progress(url_template, output_path, options, &NoProgress)
+}
+
+/// Like [`seed`], reporting the number of tiles to request up front and
+/// then each tile as it is done
+pub fn seed_with_progress(
+    url_template: &str,
+    output_path: &str,
+    options: &SeedOptions,
+    progress: &dyn Progress,
+) -> Result<SeedReport> {
+    let has = |placeholder: &str| url_template.contains(placeholder);
+    if !has("{z}") || !has("{x}") || !(has("{y}") || has("{-y}")) {
+        return Err(anyhow!("URL template needs {{z}}, {{x}} and {{y}} or {{-y}}: {}", url_template));
+    }
+    http::check_url(url_template)?;
+    if options.min_zoom < 0 || options.min_zoom > options.max_zoom || options.max_zoom > MAX_ZOOM {
+        return Err(anyhow!("Invalid zoom range {}-{}", options.min_zoom, options.max_zoom));
+    }
+
+    let exists = Path::new(output_path).exists();
+    let mut writer = match (exists, options.resume, options.retry_failed) {
+        (true, false, false) if !options.refresh => {
+            return Err(anyhow!("Output file already exists: {} (use --resume to continue seeding it)", output_path));
+        }
+        (false, _, true) => return Err(anyhow!("Output file not found: {}", output_path)),
+        (false, ..) if options.refresh => return Err(anyhow!("Output file not found: {}", output_path)),
+        (true, ..) if options.refresh => MbtilesWriter::open(output_path)?,
+        (true, ..) => {
+            let mut writer = MbtilesWriter::open(output_path)?;
+            writer.ignore_existing_tiles();
+            writer
+        }
+        (false, ..) => MbtilesWriter::create(output_path)?,
+    };
+    writer.commit_every(SEED_BATCH_TILES)?;
+    create_tables(&writer)?;
+
+    let mut plan = Plan::new(options)?;
+    progress.start(plan.count(writer.connection())?);
+    let limiter = options.rate.filter(|rate| *rate > 0.0).map(RateLimit::new);
+    let concurrency = options.concurrency.max(1);
+    let mut report = SeedReport::default();
+    let mut format = None;
+
+    // Requests are planned on this thread as workers take them, results come
+    // back unbounded so a worker never blocks while this thread feeds them
+    let (requests, queue) = mpsc::sync_channel::<Request>(concurrency * 4);
+    let queue = Mutex::new(queue);
+    thread::scope(|scope| -> Result<()> {
+        let (sender, receiver) = mpsc::channel();
+        for _ in 0..concurrency {
+            let sender = sender.clone();
+            let (queue, limiter) = (&queue, limiter.as_ref());
+            scope.spawn(move || {
+                while !interrupt::is_interrupted() {
+                    let Ok(((zoom, x, y), validators)) = queue.lock().expect("queue lock").recv() else { break };
+                    let url = tile_url(url_template, zoom, x, y);
+                    let fetched = fetch(&url, options, &validators, limiter);
+                    if sender.send(((zoom, x, y), fetched)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(sender);
+
+        let mut requests = Some(requests);
+        let mut next = plan.next(writer.connection())?;
+        loop {
+            // Queue requests until the workers have enough to do
+            if let Some(sender) = &requests {
+                while let Some(request) = next.take() {
+                    match sender.try_send(request) {
+                        Ok(()) => next = plan.next(writer.connection())?,
+                        Err(TrySendError::Full(request)) => {
+                            next = Some(request);
+                            break;
+                        }
+                        Err(TrySendError::Disconnected(_)) => break,
+                    }
+                }
+                if next.is_none() || interrupt::is_interrupted() {
+                    requests = None;
+                }
+            }
+            let Ok(((zoom, x, y), fetched)) = receiver.recv() else { break };
+            let failure = match fetched {
+                Fetched::Tile(data, validators) => {
+                    format.get_or_insert_with(|| detect_format(&data));
+                    let stored = if options.refresh { stored_tile(&writer, zoom, x, y)? } else { None };
+                    if stored.as_ref() == Some(&data) {
+                        report.unchanged += 1;
+                    } else {
+                        if stored.is_some() {
+                            writer.delete_tile(zoom, x, y)?;
+                        }
+                        report.downloaded += 1;
+                        report.bytes += data.len() as u64;
+                        writer.write_tile(&Tile { zoom, x, y, data })?;
+                    }
+                    writer
+                        .connection()
+                        .prepare_cached("INSERT OR REPLACE INTO _tile_validators VALUES (?, ?, ?, ?, ?)")?
+                        .execute(params![zoom, x, y, validators.etag, validators.last_modified])?;
+                    None
+                }
+                Fetched::NotModified => {
+                    report.unchanged += 1;
+                    None
+                }
+                Fetched::Missing => {
+                    report.missing += 1;
+                    let conn = writer.connection();
+                    conn.prepare_cached("INSERT OR IGNORE INTO _seed_missing VALUES (?, ?, ?)")?.execute(params![zoom, x, y])?;
+                    if options.refresh && writer.delete_tile(zoom, x, y)? {
+                        report.removed += 1;
+                        conn.prepare_cached(
+                            "DELETE FROM _tile_validators WHERE zoom_level = ? AND tile_column = ? AND tile_row = ?",
+                        )?
+                        .execute(params![zoom, x, y])?;
+                    }
+                    None
+                }
+                Fetched::Failed(error) => {
+                    tracing::warn!(zoom, x, y, error = error.as_str(), "tile download failed");
+                    report.failed += 1;
+                    Some(error)
+                }
+            };
+            let conn = writer.connection();
+            match failure {
+                Some(error) => conn
+                    .prepare_cached("INSERT OR REPLACE INTO _seed_failures VALUES (?, ?, ?, ?)")?
+                    .execute(params![zoom, x, y, error])?,
+                None => conn
+                    .prepare_cached("DELETE FROM _seed_failures WHERE zoom_level = ? AND tile_column = ? AND tile_row = ?")?
+                    .execute(params![zoom, x, y])?,
+            };
+            progress.advance(1);
+        }
+        Ok(())
+    })?;
+    progress.finish();
+
+    writer.prune_images()?;
+    write_metadata(&writer, output_path, options, format)?;
+    let conn = writer.connection();
+    report.failures_left = conn.query_row("SELECT COUNT(*) FROM _seed_failures", [], |row| row.get(0))?;
+    if report.failures_left == 0 && !interrupt::is_interrupted() {
+        conn.execute_batch("DROP TABLE _seed_missing; DROP TABLE _seed_failures;")?;
+    }
+    // Commit what was downloaded before reporting an interruption
+    Box::new(writer).finish()?;
+    interrupt::check()?;
+    Ok(report)
+}
+
+/// Tables recording what earlier runs got, see [`seed`]
+fn create_tables(writer: &MbtilesWriter) -> Result<()> {
+    writer.connection().execute_batch(
+        "CREATE TABLE IF NOT EXISTS _seed_missing (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER,
+             PRIMARY KEY (zoom_level, tile_column, tile_row));
+         CREATE TABLE IF NOT EXISTS _seed_failures (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER, error TEXT,
+             PRIMARY KEY (zoom_level, tile_column, tile_row));
+         CREATE TABLE IF NOT EXISTS _tile_validators (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER,
+             etag TEXT, last_modified TEXT, PRIMARY KEY (zoom_level, tile_column, tile_row));",
+    )?;
+    Ok(())
+}
+
+/// Tiles to request (TMS), found a few columns of the area at a time: those
+/// of the area not yet stored nor known to be missing upstream when
+/// resuming, plus earlier failures when retrying them, or the stored tiles
+/// with the validators they were downloaded with when refreshing
+struct Plan {
+    mode: PlanMode,
+    /// Ranges of the area left, the next at the end
+    ranges: Vec<TileRange>,
+    /// First column of the next range not planned yet
+    column: i32,
+    chunk: std::vec::IntoIter<Request>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PlanMode {
+    /// Tiles not done yet, failed ones included if `retry_failed`
+    Pending { retry_failed: bool },
+    /// Tiles recorded as failed
+    Failed,
+    /// Stored tiles
+    Stored,
+}
+
+impl Plan {
+    fn new(options: &SeedOptions) -> Result<Self> {
+        let mode = match (options.refresh, options.resume, options.retry_failed) {
+            (true, ..) => PlanMode::Stored,
+            (false, false, true) => PlanMode::Failed,
+            (false, _, retry_failed) => PlanMode::Pending { retry_failed },
+        };
+        let mut ranges = Vec::new();
+        for zoom in options.min_zoom..=options.max_zoom {
+            ranges.extend(options.area.tile_ranges(zoom)?);
+        }
+        ranges.reverse();
+        let column = ranges.last().map_or(0, |range| range.x_min);
+        Ok(Plan { mode, ranges, column, chunk: Vec::new().into_iter() })
+    }
+
+    /// Tiles of the area `mode` selects in columns `x_min` to `x_max` and
+    /// rows `y_min` to `y_max` of `zoom`, passed as parameters 1 to 5: the
+    /// ones done when `Pending`, the ones to request otherwise
+    fn query(&self) -> String {
+        let within = |table: &str| {
+            format!(
+                "{0}.zoom_level = ?1 AND {0}.tile_column BETWEEN ?2 AND ?3 AND {0}.tile_row BETWEEN ?4 AND ?5",
+                table
+            )
+        };
+        let done = |table: &str| format!("SELECT tile_column, tile_row, NULL, NULL FROM {0} WHERE {1}", table, within(table));
+        match self.mode {
+            PlanMode::Pending { retry_failed } => {
+                let mut query = format!("{} UNION {}", done("tiles"), done("_seed_missing"));
+                if !retry_failed {
+                    query += &format!(" UNION {}", done("_seed_failures"));
+                }
+                query
+            }
+            PlanMode::Failed => format!("{} ORDER BY tile_column, tile_row", done("_seed_failures")),
+            PlanMode::Stored => format!(
+                "SELECT t.tile_column, t.tile_row, v.etag, v.last_modified
+                 FROM tiles t LEFT JOIN _tile_validators v
+                   ON v.zoom_level = t.zoom_level AND v.tile_column = t.tile_column AND v.tile_row = t.tile_row
+                 WHERE {} ORDER BY t.tile_column, t.tile_row",
+                within("t")
+            ),
+        }
+    }
+
+    /// Number of tiles to request, counted range by range
+    fn count(&self, conn: &Connection) -> Result<u64> {
+        let mut stmt = conn.prepare(&format!("SELECT COUNT(*) FROM ({})", self.query()))?;
+        let mut total = 0;
+        for r in &self.ranges {
+            let selected: u64 = stmt.query_row(params![r.zoom, r.x_min, r.x_max, r.y_min, r.y_max], |row| row.get(0))?;
+            total += match self.mode {
+                PlanMode::Pending { .. } => {
+                    (r.x_max - r.x_min + 1) as u64 * (r.y_max - r.y_min + 1) as u64 - selected
+                }
+                _ => selected,
+            };
+        }
+        Ok(total)
+    }
+
+    /// The next tile to request, reading what's done so far through `conn`
+    fn next(&mut self, conn: &Connection) -> Result<Option<Request>> {
+        loop {
+            if let Some(request) = self.chunk.next() {
+                return Ok(Some(request));
+            }
+            let Some(&r) = self.ranges.last() else { return Ok(None) };
+            let columns = (PLAN_CHUNK_TILES / (r.y_max - r.y_min + 1)).max(1);
+            let (x_min, x_max) = (self.column, self.column.saturating_add(columns - 1).min(r.x_max));
+            if x_max == r.x_max {
+                self.ranges.pop();
+                self.column = self.ranges.last().map_or(0, |range| range.x_min);
+            } else {
+                self.column = x_max + 1;
+            }
+
+            let mut stmt = conn.prepare_cached(&self.query())?;
+            let rows = stmt.query_map(params![r.zoom, x_min, x_max, r.y_min, r.y_max], |row| {
+                Ok(((r.zoom, row.get(0)?, row.get(1)?), Validators { etag: row.get(2)?, last_modified: row.get(3)? }))
+            })?;
+            let selected = rows.collect::<Result<Vec<Request>, _>>()?;
+            let chunk = if let PlanMode::Pending { .. } = self.mode {
+                let done: HashSet<(i32, i32, i32)> = selected.into_iter().map(|(tile, _)| tile).collect();
+                let mut pending = Vec::new();
+                for x in x_min..=x_max {
+                    let tiles = (r.y_min..=r.y_max).map(|y| (r.zoom, x, y)).filter(|tile| !done.contains(tile));
+                    pending.extend(tiles.map(|tile| (tile, Validators::default())));
+                }
+                pending
+            } else {
+                selected
+            };
+            self.chunk = chunk.into_iter();
+        }
+    }
+}
+
+/// Data of the stored tile `zoom`/`x`/`y` (TMS)
+fn stored_tile(writer: &MbtilesWriter, zoom: i32, x: i32, y: i32) -> Result<Option<Vec<u8>>> {
+    Ok(writer
+        .connection()
+        .prepare_cached("SELECT tile_data FROM tiles WHERE zoom_level = ? AND tile_column = ? AND tile_row = ?")?
+        .query_row(params![zoom, x, y], |row| row.get(0))
+        .optional()?)
+}
+
+/// Describe the tiles of the output, keeping the name and format of a
+/// resumed file and widening its bounds
+fn write_metadata(writer: &MbtilesWriter, output_path: &str, options: &SeedOptions, format: Option<TileFormat>) -> Result<()> {
+    let existing = |name: &str| -> Result<Option<String>> {
+        Ok(writer
+            .connection()
+            .query_row("SELECT value FROM metadata WHERE name = ?", params![name], |row| row.get(0))
+            .optional()?)
+    };
+    let name = match existing("name")? {
+        Some(name) => name,
+        None => Path::new(output_path).file_stem().map_or(String::new(), |stem| stem.to_string_lossy().into_owned()),
+    };
+    let area = options.area.bbox();
+    let bounds = match existing("bounds")?.as_deref().and_then(BoundingBox::from_metadata) {
+        Some(old) => old.union(&area),
+        None => area,
+    };
+    let zooms = writer.zoom_info()?;
+    let min_zoom = zooms.first().map_or(options.min_zoom, |info| info.zoom);
+    let max_zoom = zooms.last().map_or(options.max_zoom, |info| info.zoom);
+    let (lon, lat) = bounds.center();
+    let round = |v: f64| (v * 1e6).round() / 1e6;
+    let mut metadata = vec![
+        ("name", name),
+        ("bounds", bounds.to_metadata()),
+        ("center", format!("{},{},{}", round(lon), round(lat), min_zoom)),
+        ("minzoom", min_zoom.to_string()),
+        ("maxzoom", max_zoom.to_string()),
+    ];
+    if existing("format")?.is_none()
+        && let Some(format) = format
+    {
+        metadata.push(("format", format.to_string()));
+    }
+    if let Some(attribution) = &options.attribution {
+        metadata.push(("attribution", attribution.clone()));
+    }
+    for (name, value) in metadata {
+        writer.set_metadata(name, &value)?;
+    }
+    Ok(())
+}
+
+/// Request one tile, conditionally if it has `validators`, retrying
+/// transient failures
+fn fetch(url: &str, options: &SeedOptions, validators: &Validators, limiter: Option<&RateLimit>) -> Fetched {
+    let mut headers = options.headers.clone();
+    if let Some(etag) = &validators.etag {
+        headers.push(("If-None-Match".to_string(), etag.clone()));
+    }
+    if let Some(modified) = &validators.last_modified {
+        headers.push(("If-Modified-Since".to_string(), modified.clone()));
+    }
+
+    let mut delay = RETRY_DELAY;
+    let mut error = String::new();
+    for attempt in 0..=options.retries {
+        if let Some(limiter) = limiter {
+            limiter.wait();
+        }
+        let mut retry_after = None;
+        match http::get(url, &headers) {
+            Ok(response) if response.status == 200 => {
+                let validators = Validators {
+                    etag: response.header("ETag").map(String::from),
+                    last_modified: response.header("Last-Modified").map(String::from),
+                };
+                return match tile_body(response) {
+                    Ok(body) if body.is_empty() => Fetched::Missing,
+                    Ok(body) => Fetched::Tile(body, validators),
+                    Err(e) => Fetched::Failed(format!("{}: {}", url, e)),
+                };
+            }
+            Ok(response) if response.status == 304 => return Fetched::NotModified,
+            Ok(response) if matches!(response.status, 204 | 404) => return Fetched::Missing,
+            Ok(response) if response.status == 429 || response.status >= 500 => {
+                error = format!("{}: HTTP {}", url, response.status);
+                retry_after = response
+                    .header("Retry-After")
+                    .and_then(|value| value.trim().parse::<u64>().ok())
+                    .map(|seconds| Duration::from_secs(seconds).min(MAX_RETRY_AFTER));
+            }
+            Ok(response) => return Fetched::Failed(format!("{}: HTTP {}", url, response.status)),
+            Err(e) => error = format!("{:#}", e),
+        }
+        if attempt < options.retries {
+            thread::sleep(retry_after.unwrap_or(delay));
+            delay *= 2;
+        }
+    }
+    Fetched::Failed(error)
+}
+
+/// The tile in a response body. Vector tiles keep a gzip transfer encoding,
+/// the usual way to store them; images some server compressed are unpacked.
+pub(crate) fn tile_body(response: HttpResponse) -> Result<Vec<u8>> {
+    let encoded = response
+        .header("Content-Encoding")
+        .is_some_and(|coding| matches!(coding.trim().to_ascii_lowercase().as_str(), "gzip" | "deflate"));
+    if !encoded || detect_compression(&response.body) == Compression::None {
+        return Ok(response.body);
+    }
+    let decoded = decompress(&response.body)?;
+    Ok(if detect_format(&decoded) == TileFormat::Pbf { response.body } else { decoded })
+}
+
+/// Fill a URL template for the TMS tile `zoom`/`x`/`y`
+pub(crate) fn tile_url(template: &str, zoom: i32, x: i32, y: i32) -> String {
+    let xyz_y = (1 << zoom) - 1 - y;
+    template
+        .replace("{z}", &zoom.to_string())
+        .replace("{x}", &x.to_string())
+        .replace("{-y}", &y.to_string())
+        .replace("{y}", &xyz_y.to_string())
+}
+
+/// Spaces request starts evenly across all workers
+struct RateLimit {
+    interval: Duration,
+    next: Mutex<Instant>,
+}
+
+impl RateLimit {
+    fn new(per_second: f64) -> Self {
+        RateLimit { interval: Duration::from_secs_f64(1.0 / per_second), next: Mutex::new(Instant::now()) }
+    }
+
+    /// Block until this caller's turn
+    fn wait(&self) {
+        let wait = {
+            let mut next = self.next.lock().expect("rate limit lock");
+            let now = Instant::now();
+            let slot = (*next).max(now);
+            *next = slot + self.interval;
+            slot - now
+        };
+        thread::sleep(wait);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("mbtiles-seed-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path.to_str().unwrap().to_string()
+    }
+
+    fn planned(writer: &MbtilesWriter, options: &SeedOptions) -> (u64, Vec<Request>) {
+        let mut plan = Plan::new(options).unwrap();
+        let count = plan.count(writer.connection()).unwrap();
+        let mut requests = Vec::new();
+        while let Some(request) = plan.next(writer.connection()).unwrap() {
+            requests.push(request);
+        }
+        (count, requests)
+    }
+
+    #[test]
+    fn invalid_zoom_leaves_no_output() {
+        let path = temp_path("zoom.mbtiles");
+        let area = Area::Ranges(vec![TileRange::single(0, 0, 0)]);
+        for (min_zoom, max_zoom) in [(0, MAX_ZOOM + 1), (-1, 2), (3, 2)] {
+            let options = SeedOptions::new(area.clone(), min_zoom, max_zoom);
+            assert!(seed("http://127.0.0.1:9/{z}/{x}/{y}.png", &path, &options).is_err());
+            assert!(!Path::new(&path).exists());
+        }
+    }
+
+    #[test]
+    fn plan_walks_the_area_in_chunks() {
+        let path = temp_path("plan.mbtiles");
+        let mut writer = MbtilesWriter::create(&path).unwrap();
+        create_tables(&writer).unwrap();
+        // 100 columns of 100 rows, planned 40 columns at a time
+        let area = TileRange { zoom: 12, x_min: 1000, x_max: 1099, y_min: 2000, y_max: 2099 };
+        writer.write_tile(&Tile { zoom: 12, x: 1005, y: 2005, data: b"tile".to_vec() }).unwrap();
+        let conn = writer.connection();
+        conn.execute("INSERT INTO _seed_missing VALUES (12, 1050, 2007)", []).unwrap();
+        conn.execute("INSERT INTO _seed_failures VALUES (12, 1099, 2099, 'HTTP 500')", []).unwrap();
+        conn.execute("INSERT INTO _tile_validators VALUES (12, 1005, 2005, '\"abc\"', NULL)", []).unwrap();
+
+        let mut options = SeedOptions::new(Area::Ranges(vec![area]), 12, 12);
+        options.resume = true;
+        let (count, requests) = planned(&writer, &options);
+        assert_eq!((count, requests.len()), (9997, 9997));
+        let tiles: HashSet<(i32, i32, i32)> = requests.iter().map(|(tile, _)| *tile).collect();
+        assert_eq!(tiles.len(), 9997);
+        assert!(tiles.iter().all(|&(zoom, x, y)| area.contains(zoom, x, y)));
+        for done in [(12, 1005, 2005), (12, 1050, 2007), (12, 1099, 2099)] {
+            assert!(!tiles.contains(&done));
+        }
+
+        options.retry_failed = true;
+        let (count, requests) = planned(&writer, &options);
+        assert_eq!((count, requests.len()), (9998, 9998));
+        assert!(requests.iter().any(|(tile, _)| *tile == (12, 1099, 2099)));
+
+        options.resume = false;
+        let (count, requests) = planned(&writer, &options);
+        assert_eq!(count, 1);
+        assert_eq!(requests.iter().map(|(tile, _)| *tile).collect::<Vec<_>>(), [(12, 1099, 2099)]);
+
+        options.refresh = true;
+        let (count, requests) = planned(&writer, &options);
+        assert_eq!(count, 1);
+        assert_eq!(requests.len(), 1);
+        let (tile, validators) = &requests[0];
+        assert_eq!(*tile, (12, 1005, 2005));
+        assert_eq!((validators.etag.as_deref(), validators.last_modified.as_deref()), (Some("\"abc\""), None));
+
+        // Nothing outside the zoom levels
+        let options = SeedOptions::new(Area::Ranges(vec![area]), 13, 13);
+        let (count, requests) = planned(&writer, &options);
+        assert_eq!((count, requests.len()), (0, 0));
+        drop(writer);
+        std::fs::remove_file(path).unwrap();
+    }
+}
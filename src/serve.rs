@@ -0,0 +1,679 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Result, anyhow};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::http;
+use crate::mbtiles::{tile_hash, MbtilesWriter};
+use crate::metrics::Metrics;
+use crate::seed::{tile_body, tile_url};
+use crate::source::{is_mbtiles, open_source, TileSource};
+use crate::tile_cache::TileCache;
+use crate::tilejson::tilejson_for_source;
+use crate::tile::{decompress, detect_compression, detect_format, Compression, Tile, TileFormat};
+
+/// How often a watched directory is listed for added or removed tilesets
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// A tileset served under a URL path prefix
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mount {
+    /// Prefix such as `/base`, or empty to serve at the root
+    pub prefix: String,
+    pub path: String,
+}
+
+impl Mount {
+    /// Serve `path` under `prefix`, normalized to one leading and no trailing `/`
+    pub fn new(prefix: &str, path: &str) -> Self {
+        let trimmed = prefix.trim_matches('/');
+        let prefix = if trimmed.is_empty() { String::new() } else { format!("/{}", trimmed) };
+        Mount { prefix, path: path.to_string() }
+    }
+}
+
+/// The tilesets to serve
+#[derive(Debug, Clone, Default)]
+pub struct ServeOptions {
+    pub mounts: Vec<Mount>,
+    /// Directory of `.mbtiles` and `.pmtiles` files, each served at
+    /// `/<file stem>` from when it appears until it is removed
+    pub watch_dir: Option<String>,
+    /// Seconds clients and caches may reuse a response before revalidating
+    /// it. Without it every reuse must be revalidated (`no-cache`).
+    pub max_age: Option<u32>,
+    /// Origins of other sites whose pages may fetch from the server, `*` for
+    /// any. Empty sends no CORS headers.
+    pub cors: Vec<String>,
+    /// Keys of which requests must present one, as a `key` query parameter
+    /// or an `Authorization: Bearer` header. Empty serves everyone.
+    pub tokens: Vec<String>,
+    /// Bytes of tile data kept in memory across requests, the most recently
+    /// used tiles first. None reads every tile from its file.
+    pub cache_size: Option<u64>,
+    /// URL template with `{z}`, `{x}` and `{y}` (or `{-y}` for TMS rows) of
+    /// a tile server to fetch tiles the tileset lacks from. Fetched tiles are
+    /// sent on and stored in the file, which is created if missing. Needs a
+    /// single mounted MBTiles file.
+    pub upstream: Option<String>,
+}
+
+/// Mounts shared by the workers and the background thread
+#[derive(Default)]
+struct Registry {
+    mounts: RwLock<Vec<Mount>>,
+    /// Last seen stamp and version of each mounted file, the version bumped
+    /// whenever the file is replaced or modified
+    files: Mutex<HashMap<String, (Option<FileStamp>, u64)>>,
+    /// Bumped on every change so workers can drop sources no longer mounted
+    /// or since replaced
+    generation: AtomicU64,
+}
+
+impl Registry {
+    fn version(&self, path: &str) -> u64 {
+        self.files.lock().expect("registry lock").get(path).map_or(0, |&(_, version)| version)
+    }
+}
+
+/// State of the server the workers share
+struct Shared {
+    options: ServeOptions,
+    addr: String,
+    registry: Registry,
+    metrics: Metrics,
+    tiles: Option<TileCache>,
+    mirror: Option<Mirror>,
+}
+
+/// The mounted file filled in from [`ServeOptions::upstream`]
+struct Mirror {
+    path: String,
+    upstream: String,
+    writer: Mutex<MbtilesWriter>,
+}
+
+/// A source opened by one worker
+struct Opened {
+    source: Box<dyn TileSource>,
+    extension: &'static str,
+    /// Version of the file when opened
+    version: u64,
+    /// File modification time, sent as `Last-Modified`
+    modified: Option<SystemTime>,
+}
+
+/// What identifies one build of a tileset file: a file renamed over it has
+/// another inode, one rewritten in place another modification time or size
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileStamp {
+    modified: Option<SystemTime>,
+    len: u64,
+    inode: u64,
+}
+
+impl FileStamp {
+    fn of(path: &str) -> Option<Self> {
+        let metadata = std::fs::metadata(path).ok()?;
+        #[cfg(unix)]
+        let inode = std::os::unix::fs::MetadataExt::ino(&metadata);
+        #[cfg(not(unix))]
+        let inode = 0;
+        Some(FileStamp { modified: metadata.modified().ok(), len: metadata.len(), inode })
+    }
+}
+
+/// Serve the tiles of the MBTiles or PMTiles file `path` at
+/// `http://{addr}/{z}/{x}/{y}.{ext}` using XYZ row numbering, with its
+/// TileJSON at `/tilejson.json` and its metadata as a JSON object at
+/// `/metadata`. Responses carry an `ETag` and `Last-Modified` and
+/// conditional requests are answered with 304 Not Modified. Gzipped vector
+/// tiles are sent as stored to clients that accept gzip and decompressed for
+/// the others. Prometheus metrics are served at `/metrics`.
+///
+/// A file replaced by a new build, or modified, is reopened once the
+/// requests being answered from the old one are done. Blocks forever.
+pub fn serve(path: &str, addr: &str) -> Result<()> {
+    serve_with(&ServeOptions { mounts: vec![Mount::new("", path)], ..Default::default() }, addr)
+}
+
+/// Like [`serve`] for several tilesets, each answering the same paths below
+/// its mount prefix. The longest matching prefix wins.
+pub fn serve_with(options: &ServeOptions, addr: &str) -> Result<()> {
+    let mirror = options.upstream.as_ref().map(|upstream| open_mirror(options, upstream)).transpose()?;
+    // Fail early on a bad path instead of in every worker
+    for mount in &options.mounts {
+        open_source(&mount.path)?;
+    }
+    if let Some(dir) = &options.watch_dir
+        && !Path::new(dir).is_dir()
+    {
+        return Err(anyhow!("Not a directory: {}", dir));
+    }
+    let shared = Arc::new(Shared {
+        options: options.clone(),
+        addr: addr.to_string(),
+        registry: Registry::default(),
+        metrics: Metrics::default(),
+        tiles: options.cache_size.map(TileCache::new),
+        mirror,
+    });
+    *shared.registry.mounts.write().expect("registry lock") = options.mounts.clone();
+    poll_files(&shared);
+    {
+        let shared = Arc::clone(&shared);
+        thread::spawn(move || loop {
+            thread::sleep(WATCH_INTERVAL);
+            poll_files(&shared);
+        });
+    }
+
+    let server = Arc::new(Server::http(addr).map_err(|e| anyhow!("Failed to bind {}: {}", addr, e))?);
+    let workers = thread::available_parallelism().map_or(4, |n| n.get());
+
+    let handles = (0..workers)
+        .map(|_| {
+            let server = Arc::clone(&server);
+            let shared = Arc::clone(&shared);
+            thread::spawn(move || {
+                let registry = &shared.registry;
+                let mut opened: HashMap<String, Opened> = HashMap::new();
+                let mut generation = registry.generation.load(Ordering::Acquire);
+                for request in server.incoming_requests() {
+                    let current = registry.generation.load(Ordering::Acquire);
+                    if current != generation {
+                        let mounts = registry.mounts.read().expect("registry lock");
+                        opened.retain(|path, source| {
+                            mounts.iter().any(|mount| mount.path == *path) && source.version == registry.version(path)
+                        });
+                        generation = current;
+                    }
+                    handle(&shared, &mut opened, request);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for handle in handles {
+        handle.join().map_err(|_| anyhow!("Server worker panicked"))?;
+    }
+    Ok(())
+}
+
+/// Open the single mounted MBTiles for storing tiles fetched from
+/// `upstream`, creating it with the format the URL's extension names
+fn open_mirror(options: &ServeOptions, upstream: &str) -> Result<Mirror> {
+    let [mount] = options.mounts.as_slice() else {
+        return Err(anyhow!("An upstream needs exactly one tileset to store tiles in"));
+    };
+    let path = &mount.path;
+    if !is_mbtiles(path) {
+        return Err(anyhow!("Can only store upstream tiles in an MBTiles file: {}", path));
+    }
+    http::check_url(upstream)?;
+    if !Path::new(path).exists() {
+        let extension = upstream.rsplit_once('.').map(|(_, extension)| extension.split(['?', '/']).next().unwrap_or(""));
+        let format = extension.and_then(TileFormat::from_metadata).ok_or_else(|| {
+            anyhow!("Can't tell the tile format from {}, create {} with a format first", upstream, path)
+        })?;
+        let writer = MbtilesWriter::create(path)?;
+        let name = Path::new(path).file_stem().map_or(String::new(), |stem| stem.to_string_lossy().into_owned());
+        writer.insert_metadata("name", &name)?;
+        writer.insert_metadata("format", format.as_str())?;
+    }
+    let mut writer = MbtilesWriter::open(path)?;
+    // Two requests for the same missing tile may both fetch it
+    writer.ignore_existing_tiles();
+    Ok(Mirror { path: path.clone(), upstream: upstream.to_string(), writer: Mutex::new(writer) })
+}
+
+/// Pick up tilesets added to or removed from the watched directory and
+/// mounted files that were replaced, dropping cached tiles of old versions
+fn poll_files(shared: &Shared) {
+    let registry = &shared.registry;
+    if let Some(dir) = &shared.options.watch_dir {
+        update_watched(registry, &shared.options.mounts, dir);
+    }
+
+    let mounts = registry.mounts.read().expect("registry lock").clone();
+    let mut files = registry.files.lock().expect("registry lock");
+    let known = files.len();
+    files.retain(|path, _| mounts.iter().any(|mount| mount.path == *path));
+    let mut changed = files.len() < known;
+    for mount in &mounts {
+        let stamp = FileStamp::of(&mount.path);
+        match files.get_mut(&mount.path) {
+            Some((seen, version)) if *seen != stamp => {
+                // A file still being written is picked up on a later poll
+                if stamp.is_some() {
+                    tracing::info!(path = mount.path.as_str(), "tileset changed, reopening");
+                }
+                *seen = stamp;
+                *version += 1;
+                changed = true;
+            }
+            Some(_) => {}
+            None => {
+                files.insert(mount.path.clone(), (stamp, 0));
+            }
+        }
+    }
+    if let Some(tiles) = shared.tiles.as_ref().filter(|_| changed) {
+        tiles.retain(|path, version| files.get(path).is_some_and(|&(_, current)| current == version));
+    }
+    drop(files);
+    if changed {
+        registry.generation.fetch_add(1, Ordering::AcqRel);
+    }
+}
+
+/// Replace the watched part of the mounts with the tilesets now in `dir`.
+/// Fixed mounts keep their prefixes when a watched file has the same stem.
+fn update_watched(registry: &Registry, fixed: &[Mount], dir: &str) {
+    let mut mounts = fixed.to_vec();
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            tracing::error!(dir, error = %e, "failed to list watched directory");
+            return;
+        }
+    };
+    let mut found: Vec<Mount> = entries
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+            if extension != "mbtiles" && extension != "pmtiles" {
+                return None;
+            }
+            Some(Mount::new(path.file_stem()?.to_str()?, path.to_str()?))
+        })
+        .collect();
+    found.sort_by(|a, b| a.prefix.cmp(&b.prefix));
+    for mount in found {
+        if !mounts.iter().any(|m| m.prefix == mount.prefix) {
+            mounts.push(mount);
+        }
+    }
+
+    let mut current = registry.mounts.write().expect("registry lock");
+    if *current != mounts {
+        *current = mounts;
+        registry.generation.fetch_add(1, Ordering::AcqRel);
+    }
+}
+
+fn handle(shared: &Shared, opened: &mut HashMap<String, Opened>, request: Request) {
+    let options = &shared.options;
+    let started = Instant::now();
+    let (tileset, mut response) = if *request.method() == Method::Options {
+        // CORS preflight, answered for any path
+        let mut response = Response::from_data(Vec::new()).with_status_code(204);
+        response.add_header(header("Allow", "GET, HEAD, OPTIONS"));
+        if !options.cors.is_empty() {
+            response.add_header(header("Access-Control-Allow-Methods", "GET, HEAD, OPTIONS"));
+            if let Some(requested) = request_header(&request, "Access-Control-Request-Headers") {
+                response.add_header(header("Access-Control-Allow-Headers", requested));
+            }
+            response.add_header(header("Access-Control-Max-Age", "86400"));
+        }
+        (String::new(), response)
+    } else {
+        route(shared, opened, &request)
+    };
+
+    if options.cors.iter().any(|origin| origin == "*") {
+        response.add_header(header("Access-Control-Allow-Origin", "*"));
+    } else if !options.cors.is_empty() {
+        // Caches must not hand one origin's response to another
+        response.add_header(header("Vary", "Origin"));
+        if let Some(origin) = request_header(&request, "Origin").filter(|origin| options.cors.iter().any(|o| o == origin)) {
+            response.add_header(header("Access-Control-Allow-Origin", origin));
+        }
+    }
+    let bytes = if *request.method() == Method::Head { 0 } else { response.data_length().unwrap_or(0) as u64 };
+    shared.metrics.record(&tileset, response.status_code().0, bytes, started.elapsed());
+    let url = request.url().to_string();
+    if let Err(e) = request.respond(response) {
+        tracing::error!(url = url.as_str(), error = %e, "failed to send response");
+    }
+}
+
+/// The response to a GET or HEAD request, with the prefix of the tileset
+/// that answered it (`/` for the root) for the metrics
+fn route(shared: &Shared, opened: &mut HashMap<String, Opened>, request: &Request) -> (String, Response<std::io::Cursor<Vec<u8>>>) {
+    let options = &shared.options;
+    let (path, query) = request.url().split_once('?').unwrap_or((request.url(), ""));
+    let path = path.to_string();
+    let query_key = query.split('&').find_map(|pair| pair.strip_prefix("key="));
+    if !options.tokens.is_empty() {
+        let bearer = request_header(request, "Authorization").and_then(|value| value.strip_prefix("Bearer "));
+        let presented = [query_key.map(percent_decode), bearer.map(|key| key.trim().to_string())];
+        if !presented.into_iter().flatten().any(|key| options.tokens.iter().any(|token| same_token(token, &key))) {
+            let mut response = Response::from_string("Unauthorized").with_status_code(401);
+            response.add_header(header("WWW-Authenticate", "Bearer"));
+            return (String::new(), response);
+        }
+    }
+    if path == "/metrics" {
+        let response = Response::from_string(shared.metrics.render(shared.tiles.as_ref()))
+            .with_header(header("Content-Type", "text/plain; version=0.0.4"));
+        return (String::new(), response);
+    }
+
+    let mount = {
+        let mounts = shared.registry.mounts.read().expect("registry lock");
+        mounts
+            .iter()
+            .filter(|mount| path.strip_prefix(&mount.prefix).is_some_and(|rest| rest.starts_with('/')))
+            .max_by_key(|mount| mount.prefix.len())
+            .cloned()
+    };
+    let Some(mount) = mount else {
+        return (String::new(), Response::from_string("Not found").with_status_code(404));
+    };
+    let tileset = if mount.prefix.is_empty() { "/".to_string() } else { mount.prefix.clone() };
+
+    if !opened.contains_key(&mount.path) {
+        // Read before opening, so a replacement racing the open bumps it again
+        let version = shared.registry.version(&mount.path);
+        let source = open_source(&mount.path).and_then(|source| {
+            let extension = tile_extension(source.as_ref())?;
+            let modified = std::fs::metadata(&mount.path).and_then(|m| m.modified()).ok();
+            Ok(Opened { source, extension, version, modified })
+        });
+        match source {
+            Ok(source) => {
+                opened.insert(mount.path.clone(), source);
+            }
+            Err(e) => {
+                tracing::error!(path = mount.path.as_str(), error = %e, "failed to open tileset");
+                return (tileset, Response::from_string("Internal server error").with_status_code(500));
+            }
+        }
+    }
+    let Opened { source, extension, version, modified } = &opened[&mount.path];
+    let reader = source.as_ref();
+    let cache = Caching { request, modified: *modified, max_age: options.max_age };
+
+    let response = match &path[mount.prefix.len()..] {
+        "/tilejson.json" => {
+            // Tile URLs point back at whatever host the client reached us by
+            let host = request_header(request, "Host").unwrap_or(&shared.addr);
+            let mut url_template = format!("http://{}{}/{{z}}/{{x}}/{{y}}.{}", host, mount.prefix, extension);
+            // Clients given the key in the URL need it in the tile URLs too
+            if let Some(key) = query_key.filter(|_| !options.tokens.is_empty()) {
+                url_template = format!("{}?key={}", url_template, key);
+            }
+            json_response(tilejson_for_source(reader, &url_template), &cache)
+        }
+        "/metadata" => json_response(
+            reader.metadata().map(|pairs| {
+                Value::Object(pairs.into_iter().map(|(name, value)| (name, Value::String(value))).collect())
+            }),
+            &cache,
+        ),
+        rest => match parse_tile_path(rest, extension) {
+            Some((z, x, y)) => {
+                let tiles = Tiles {
+                    reader,
+                    path: &mount.path,
+                    version: *version,
+                    cache: shared.tiles.as_ref(),
+                    mirror: shared.mirror.as_ref().filter(|mirror| mirror.path == mount.path).map(|m| (m, &shared.registry)),
+                };
+                tile_response(&tiles, z, x, y, &cache)
+            }
+            None => Response::from_string("Not found").with_status_code(404),
+        },
+    };
+    (tileset, response)
+}
+
+/// Compare digests of both, without returning early, so response times
+/// reveal neither how much of a token was guessed right nor its length
+fn same_token(token: &str, presented: &str) -> bool {
+    let (token, presented) = (Sha256::digest(token.as_bytes()), Sha256::digest(presented.as_bytes()));
+    token.iter().zip(presented.iter()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Decode the `%XX` escapes of a query value. `+` stays as it is, so keys
+/// with it work pasted in raw; malformed escapes are kept literally.
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| value.get(i + 1..i + 3))
+            .flatten()
+            .filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()))
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                out.push(byte);
+                i += 3;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn request_header<'a>(request: &'a Request, name: &'static str) -> Option<&'a str> {
+    request.headers().iter().find(|h| h.field.equiv(name)).map(|h| h.value.as_str())
+}
+
+/// The tiles of one mounted file, read through the shared cache if there is
+/// one and fetched from the upstream if it's the mirror
+struct Tiles<'a> {
+    reader: &'a dyn TileSource,
+    path: &'a str,
+    version: u64,
+    cache: Option<&'a TileCache>,
+    mirror: Option<(&'a Mirror, &'a Registry)>,
+}
+
+impl Tiles<'_> {
+    fn get(&self, z: i32, x: i32, tms_y: i32) -> Result<Option<Vec<u8>>> {
+        if let Some(data) = self.cache.and_then(|cache| cache.get(self.path, self.version, z, x, tms_y)) {
+            return Ok(Some(data.as_ref().clone()));
+        }
+        let mut data = self.reader.tile(z, x, tms_y)?;
+        if data.is_none()
+            && let Some((mirror, registry)) = self.mirror
+        {
+            data = fetch_upstream(mirror, registry, z, x, tms_y)?;
+        }
+        if let (Some(cache), Some(data)) = (self.cache, &data) {
+            cache.insert(self.path, self.version, z, x, tms_y, Arc::new(data.clone()));
+        }
+        Ok(data)
+    }
+}
+
+/// Request a tile missing from the mirror upstream and store it. None if the
+/// upstream doesn't have it either.
+fn fetch_upstream(mirror: &Mirror, registry: &Registry, z: i32, x: i32, tms_y: i32) -> Result<Option<Vec<u8>>> {
+    let url = tile_url(&mirror.upstream, z, x, tms_y);
+    let response = http::get(&url, &[])?;
+    match response.status {
+        200 => {}
+        204 | 404 => return Ok(None),
+        status => return Err(anyhow!("{}: HTTP {}", url, status)),
+    }
+    let data = tile_body(response).map_err(|e| anyhow!("{}: {}", url, e))?;
+    if data.is_empty() {
+        return Ok(None);
+    }
+
+    // Restamp while holding the registry lock, so the poll doesn't take our
+    // own write for a replaced file and drop every cached tile
+    let tile = Tile { zoom: z, x, y: tms_y, data };
+    let mut files = registry.files.lock().expect("registry lock");
+    mirror.writer.lock().expect("mirror lock").insert_tile(&tile)?;
+    if let Some((seen, _)) = files.get_mut(&mirror.path) {
+        *seen = FileStamp::of(&mirror.path);
+    }
+    Ok(Some(tile.data))
+}
+
+fn tile_response(tiles: &Tiles, z: i32, x: i32, y: i32, cache: &Caching) -> Response<std::io::Cursor<Vec<u8>>> {
+    if !(0..=30).contains(&z) || x < 0 || y < 0 || x >= 1 << z || y >= 1 << z {
+        return Response::from_string("Tile out of range").with_status_code(404);
+    }
+
+    // MBTiles stores rows in TMS order, requests use XYZ
+    let tms_y = (1 << z) - 1 - y;
+    match tiles.get(z, x, tms_y) {
+        Ok(Some(data)) => {
+            let format = detect_format(&data);
+            let mut headers = vec![header("Content-Type", format.content_type())];
+            // Only vector tiles are stored compressed, images never get an encoding
+            let coding = match (format, detect_compression(&data)) {
+                (TileFormat::Pbf, Compression::Gzip) => Some("gzip"),
+                (TileFormat::Pbf, Compression::Zlib) => Some("deflate"),
+                _ => None,
+            };
+            let data = match coding {
+                Some(coding) => {
+                    headers.push(header("Vary", "Accept-Encoding"));
+                    if accepts_encoding(cache.request, coding) {
+                        headers.push(header("Content-Encoding", coding));
+                        data
+                    } else {
+                        match decompress(&data) {
+                            Ok(data) => data,
+                            Err(e) => {
+                                tracing::error!(path = tiles.path, z, x, y, error = %e, "failed to decompress tile");
+                                return Response::from_string("Internal server error").with_status_code(500);
+                            }
+                        }
+                    }
+                }
+                None => data,
+            };
+            cache.response(data, headers)
+        }
+        Ok(None) => Response::from_string("Tile not found").with_status_code(404),
+        Err(e) => {
+            tracing::error!(path = tiles.path, z, x, y, error = %e, "failed to read tile");
+            Response::from_string("Internal server error").with_status_code(500)
+        }
+    }
+}
+
+fn json_response(doc: Result<Value>, cache: &Caching) -> Response<std::io::Cursor<Vec<u8>>> {
+    match doc.and_then(|doc| Ok(serde_json::to_vec_pretty(&doc)?)) {
+        Ok(body) => cache.response(body, vec![header("Content-Type", "application/json")]),
+        Err(e) => {
+            tracing::error!(error = %e, "failed to build JSON response");
+            Response::from_string("Internal server error").with_status_code(500)
+        }
+    }
+}
+
+/// Whether the request's `Accept-Encoding` allows `coding`. Without the
+/// header only the identity encoding is assumed, as clients that can decode
+/// gzip say so.
+fn accepts_encoding(request: &Request, coding: &str) -> bool {
+    let Some(accepted) = request_header(request, "Accept-Encoding") else {
+        return false;
+    };
+    // An explicit entry for the coding overrides the `*` wildcard
+    let mut named = None;
+    let mut wildcard = None;
+    for item in accepted.split(',') {
+        let mut parts = item.split(';').map(str::trim);
+        let name = parts.next().unwrap_or("");
+        let weight = parts.find_map(|param| param.strip_prefix("q=")).map_or(1.0, |q| q.parse::<f32>().unwrap_or(0.0));
+        if name.eq_ignore_ascii_case(coding) {
+            named = Some(weight);
+        } else if name == "*" {
+            wildcard = Some(weight);
+        }
+    }
+    named.or(wildcard).is_some_and(|weight| weight > 0.0)
+}
+
+/// Validators and freshness for the successful response to one request
+struct Caching<'a> {
+    request: &'a Request,
+    modified: Option<SystemTime>,
+    max_age: Option<u32>,
+}
+
+impl Caching<'_> {
+    /// `body` with `headers` and the caching headers, or an empty 304 when
+    /// the client's copy is still current. The strong ETag is the MD5 of
+    /// the body, as in the normalized schema's tile ids.
+    fn response(&self, body: Vec<u8>, headers: Vec<Header>) -> Response<std::io::Cursor<Vec<u8>>> {
+        let etag = format!("\"{}\"", tile_hash(&body));
+        let mut caching = vec![
+            header("ETag", &etag),
+            header("Cache-Control", &self.max_age.map_or("no-cache".to_string(), |age| format!("public, max-age={}", age))),
+        ];
+        if let Some(modified) = self.modified {
+            caching.push(header("Last-Modified", &httpdate::fmt_http_date(modified)));
+        }
+
+        let (body, status) = if self.not_modified(&etag) { (Vec::new(), 304) } else { (body, 200) };
+        let mut response = Response::from_data(body).with_status_code(status);
+        // A 304 repeats only the headers caches key and validate on
+        for header in caching.into_iter().chain(headers.into_iter().filter(|h| status == 200 || h.field.equiv("Vary"))) {
+            response.add_header(header);
+        }
+        response
+    }
+
+    /// If-None-Match takes precedence over If-Modified-Since, which only has
+    /// second precision
+    fn not_modified(&self, etag: &str) -> bool {
+        let value = |name| request_header(self.request, name);
+        if let Some(tags) = value("If-None-Match") {
+            return tags.split(',').map(str::trim).any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag);
+        }
+        let seconds = |time: SystemTime| time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        match (value("If-Modified-Since").and_then(|since| httpdate::parse_http_date(since).ok()), self.modified) {
+            (Some(since), Some(modified)) => seconds(modified) <= seconds(since),
+            _ => false,
+        }
+    }
+}
+
+/// File extension for tile URLs, from the `format` metadata or a sample tile
+fn tile_extension(reader: &dyn TileSource) -> Result<&'static str> {
+    let declared = reader.metadata()?.into_iter().find(|(name, _)| name == "format").map(|(_, value)| value);
+    let format = match declared.as_deref().and_then(TileFormat::from_metadata) {
+        Some(format) => format,
+        None => reader.sample_tile()?.map_or(TileFormat::Png, |data| detect_format(&data)),
+    };
+    Ok(format.as_str())
+}
+
+/// Parse `/{z}/{x}/{y}`, with `extension` or none on `y`
+fn parse_tile_path(path: &str, extension: &str) -> Option<(i32, i32, i32)> {
+    let parts: Vec<&str> = path.trim_matches('/').split('/').collect();
+    if parts.len() != 3 {
+        return None;
+    }
+    let y = match parts[2].split_once('.') {
+        Some((y, ext)) if ext.eq_ignore_ascii_case(extension) => y,
+        Some(_) => return None,
+        None => parts[2],
+    };
+    Some((parts[0].parse().ok()?, parts[1].parse().ok()?, y.parse().ok()?))
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("valid header")
+}
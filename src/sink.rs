@@ -0,0 +1,62 @@
+use anyhow::Result;
+
+use crate::bbox::TileRange;
+use crate::geopackage::GeopackageWriter;
+use crate::mbtiles::MbtilesWriter;
+use crate::pmtiles::PmtilesWriter;
+use crate::tar::TarWriter;
+use crate::tile::Tile;
+
+/// Destination for the tiles and metadata produced by a copy.
+///
+/// `finish` must be called once all tiles are written; formats that need
+/// an index (like PMTiles) only produce a readable file at that point.
+pub trait TileSink {
+    fn write_metadata(&mut self, name: &str, value: &str) -> Result<()>;
+
+    fn write_tile(&mut self, tile: &Tile) -> Result<()>;
+
+    /// Called once every tile of `range` has been written, for sinks that
+    /// record progress so an interrupted copy can be resumed
+    fn complete_range(&mut self, _range: &TileRange) -> Result<()> {
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<()>;
+}
+
+/// Container format written by `extract`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Mbtiles,
+    Pmtiles,
+    Geopackage,
+    /// `{z}/{x}/{y}.{ext}` entries of a tar archive, streamable to stdout
+    Tar,
+}
+
+impl OutputFormat {
+    /// Guess the format from the file extension, defaulting to MBTiles. The
+    /// path `-`, stdout, can only take a tar stream.
+    pub fn from_path(path: &str) -> Self {
+        let lower = path.to_ascii_lowercase();
+        if lower.ends_with(".pmtiles") {
+            OutputFormat::Pmtiles
+        } else if lower.ends_with(".gpkg") {
+            OutputFormat::Geopackage
+        } else if path == "-" || lower.ends_with(".tar") {
+            OutputFormat::Tar
+        } else {
+            OutputFormat::Mbtiles
+        }
+    }
+
+    pub fn create_sink(&self, path: &str) -> Result<Box<dyn TileSink>> {
+        Ok(match self {
+            OutputFormat::Mbtiles => Box::new(MbtilesWriter::create(path)?),
+            OutputFormat::Pmtiles => Box::new(PmtilesWriter::create(path)?),
+            OutputFormat::Geopackage => Box::new(GeopackageWriter::create(path)?),
+            OutputFormat::Tar => Box::new(TarWriter::create(path)?),
+        })
+    }
+}
@@ -0,0 +1,152 @@
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+
+use anyhow::Result;
+
+use crate::bbox::TileRange;
+use crate::geopackage::{self, GeopackageReader};
+use crate::http;
+use crate::info::ZoomInfo;
+use crate::mbtiles::{MbtilesReader, MbtilesSchema, tile_hash};
+use crate::pmtiles::PmtilesReader;
+use crate::ssh::{self, SshReader};
+use crate::tar::TarReader;
+use crate::tile::Tile;
+
+/// Container format and layout of an opened source
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceKind {
+    Mbtiles(MbtilesSchema),
+    Pmtiles,
+    Geopackage,
+    Tar,
+}
+
+impl fmt::Display for SourceKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SourceKind::Mbtiles(schema) => write!(f, "MBTiles, {} schema", schema),
+            SourceKind::Pmtiles => f.write_str("PMTiles v3"),
+            SourceKind::Geopackage => f.write_str("GeoPackage tiles"),
+            SourceKind::Tar => f.write_str("tar archive"),
+        }
+    }
+}
+
+/// Read access to a tileset, independent of its container format.
+///
+/// Coordinates are TMS like MBTiles; sources storing XYZ rows convert.
+pub trait TileSource {
+    fn kind(&self) -> SourceKind;
+
+    /// Metadata as MBTiles style (name, value) pairs, sorted by name
+    fn metadata(&self) -> Result<Vec<(String, String)>>;
+
+    /// Zoom levels that contain tiles, ascending
+    fn zoom_levels(&self) -> Result<Vec<i32>>;
+
+    fn tile(&self, zoom: i32, x: i32, y: i32) -> Result<Option<Vec<u8>>>;
+
+    /// Call `f` for every tile inside `range`
+    fn for_each_tile(&self, range: &TileRange, f: &mut dyn FnMut(Tile) -> Result<()>) -> Result<()>;
+
+    /// Call `f` with the column, row and byte size of every tile inside
+    /// `range`, without reading the tile data
+    fn for_each_tile_size(&self, range: &TileRange, f: &mut dyn FnMut(i32, i32, u64) -> Result<()>) -> Result<()>;
+
+    /// Call `f` with the column, row and [`tile_hash`] of every tile inside
+    /// `range`, plus the tile data where it had to be read for the hash.
+    /// Sources that store content hashes override this to skip the data.
+    fn for_each_tile_hash(
+        &self,
+        range: &TileRange,
+        f: &mut dyn FnMut(i32, i32, String, Option<Vec<u8>>) -> Result<()>,
+    ) -> Result<()> {
+        self.for_each_tile(range, &mut |tile| f(tile.x, tile.y, tile_hash(&tile.data), Some(tile.data)))
+    }
+
+    /// Number of tiles inside `range`
+    fn count_tiles(&self, range: &TileRange) -> Result<u64>;
+
+    /// Tile count, size and extent per zoom level
+    fn zoom_info(&self) -> Result<Vec<ZoomInfo>>;
+
+    /// Any one tile, used to detect the tile format
+    fn sample_tile(&self) -> Result<Option<Vec<u8>>>;
+}
+
+/// True if `path` is a PMTiles archive, judged by extension or magic bytes.
+/// URLs are judged by the extension of their path.
+pub fn is_pmtiles(path: &str) -> bool {
+    if http::is_url(path) {
+        let path = path.split(['?', '#']).next().unwrap_or(path);
+        return path.to_ascii_lowercase().ends_with(".pmtiles");
+    }
+    if path.to_ascii_lowercase().ends_with(".pmtiles") {
+        return true;
+    }
+    let mut magic = [0u8; 7];
+    File::open(path)
+        .and_then(|mut f| f.read_exact(&mut magic))
+        .is_ok_and(|_| &magic == b"PMTiles")
+}
+
+/// True if `path` is an OGC GeoPackage, judged by extension or the
+/// `application_id` in its SQLite header
+pub fn is_geopackage(path: &str) -> bool {
+    if http::is_url(path) {
+        return false;
+    }
+    if path.to_ascii_lowercase().ends_with(".gpkg") {
+        return true;
+    }
+    let mut header = [0u8; 72];
+    File::open(path)
+        .and_then(|mut f| f.read_exact(&mut header))
+        .is_ok_and(|_| header[68..72] == geopackage::APPLICATION_ID.to_be_bytes())
+}
+
+/// True if `path` is `-` (stdin), ends in `.tar` or has the ustar magic of
+/// a tar archive
+pub fn is_tar(path: &str) -> bool {
+    if path == "-" || path.to_ascii_lowercase().ends_with(".tar") {
+        return true;
+    }
+    let mut header = [0u8; 512];
+    File::open(path)
+        .and_then(|mut f| f.read_exact(&mut header))
+        .is_ok_and(|_| &header[257..262] == b"ustar")
+}
+
+/// True if `path` is an MBTiles file, the only format with SQL fast paths,
+/// a `scheme` of its own and in place updates
+pub(crate) fn is_mbtiles(path: &str) -> bool {
+    !is_pmtiles(path) && !is_geopackage(path) && !is_tar(path)
+}
+
+/// Open an MBTiles, PMTiles, GeoPackage or tar file for reading. MBTiles and
+/// PMTiles files can also be `http://` or `https://` URLs, read with range
+/// requests, and MBTiles files `ssh://` URLs read through `sqlite3` on the
+/// remote host, see [`SshReader`].
+pub fn open_source(path: &str) -> Result<Box<dyn TileSource>> {
+    open_source_with(path, false)
+}
+
+/// Like [`open_source`], opening MBTiles with [`MbtilesReader::open_immutable`]
+/// if `immutable` is set
+pub(crate) fn open_source_with(path: &str, immutable: bool) -> Result<Box<dyn TileSource>> {
+    if ssh::is_url(path) {
+        Ok(Box::new(SshReader::open(path)?))
+    } else if is_pmtiles(path) {
+        Ok(Box::new(PmtilesReader::open(path)?))
+    } else if is_geopackage(path) {
+        Ok(Box::new(GeopackageReader::open(path)?))
+    } else if is_tar(path) {
+        Ok(Box::new(TarReader::open(path)?))
+    } else if immutable {
+        Ok(Box::new(MbtilesReader::open_immutable(path)?))
+    } else {
+        Ok(Box::new(MbtilesReader::open(path)?))
+    }
+}
@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{Result, anyhow};
+
+use crate::bbox::TileRange;
+use crate::extract::{extract_with_progress, Area, ExtractOptions, OutputMode};
+use crate::progress::{NoProgress, Progress};
+use crate::source::{is_mbtiles, open_source};
+use crate::tile::Scheme;
+
+/// Share of a part's size limit filled with tile data, leaving room for
+/// the database pages, indexes and metadata around it
+const SIZE_MARGIN: f64 = 0.9;
+/// Estimated bytes a tile adds to a part besides its data: its row, index
+/// entry and the slack of partly filled pages
+const TILE_OVERHEAD: u64 = 64;
+/// Deepest zoom level at which tiles are grouped into blocks for splitting by size
+const MAX_BLOCK_ZOOM: i32 = 10;
+
+/// One file written by a split
+#[derive(Debug, Clone)]
+pub struct SplitPart {
+    pub path: String,
+    pub min_zoom: i32,
+    pub max_zoom: i32,
+    pub tiles: usize,
+    /// Size of the written file
+    pub bytes: u64,
+}
+
+/// Copy each inclusive zoom range of `input_path` into its own file in
+/// `output_dir`, named `<stem>.z<min>-<max>` (or `<stem>.z<zoom>`) with the
+/// input's extension.
+/// Ranges must not overlap. Each part gets the input's metadata with
+/// `minzoom`, `maxzoom`, `bounds` and `center` describing its own tiles.
+pub fn split_by_zoom(input_path: &str, ranges: &[(i32, i32)], output_dir: &str, mode: OutputMode) -> Result<Vec<SplitPart>> {
+    split_by_zoom_with_progress(input_path, ranges, output_dir, mode, &NoProgress)
+}
+
+/// Like [`split_by_zoom`], reporting the copy of each part to `progress`
+pub fn split_by_zoom_with_progress(
+    input_path: &str,
+    ranges: &[(i32, i32)],
+    output_dir: &str,
+    mode: OutputMode,
+    progress: &dyn Progress,
+) -> Result<Vec<SplitPart>> {
+    if ranges.is_empty() {
+        return Err(anyhow!("No zoom ranges to split by"));
+    }
+    let mut sorted = ranges.to_vec();
+    sorted.sort_unstable();
+    for pair in sorted.windows(2) {
+        if pair[1].0 <= pair[0].1 {
+            return Err(anyhow!(
+                "Zoom ranges {}-{} and {}-{} overlap",
+                pair[0].0, pair[0].1, pair[1].0, pair[1].1
+            ));
+        }
+    }
+
+    let mut parts = Vec::new();
+    for &(min_zoom, max_zoom) in ranges {
+        let zooms = if min_zoom == max_zoom { min_zoom.to_string() } else { format!("{}-{}", min_zoom, max_zoom) };
+        let mut options = ExtractOptions::new(Area::World);
+        options.min_zoom = Some(min_zoom);
+        options.max_zoom = Some(max_zoom);
+        options.mode = mode;
+        parts.push(write_part(input_path, output_dir, &format!("z{}", zooms), &options, progress)?);
+    }
+    Ok(parts)
+}
+
+/// Split `input_path` into files of at most `max_bytes` each in
+/// `output_dir`, named `<stem>.part<N>` with the input's extension.
+///
+/// Tiles are grouped by their ancestor tile at the lowest zoom level where
+/// every group fits, and groups are packed into parts in Z-order, so the
+/// same input always splits the same way and each part covers a compact
+/// region. The zoom levels above the grouping zoom go into the first part.
+pub fn split_by_size(input_path: &str, max_bytes: u64, output_dir: &str, mode: OutputMode) -> Result<Vec<SplitPart>> {
+    split_by_size_with_progress(input_path, max_bytes, output_dir, mode, &NoProgress)
+}
+
+/// Like [`split_by_size`], reporting the copy of each part to `progress`
+pub fn split_by_size_with_progress(
+    input_path: &str,
+    max_bytes: u64,
+    output_dir: &str,
+    mode: OutputMode,
+    progress: &dyn Progress,
+) -> Result<Vec<SplitPart>> {
+    let budget = (max_bytes as f64 * SIZE_MARGIN) as u64;
+    let source = open_source(input_path)?;
+    let declared = source.metadata()?.into_iter().find(|(name, _)| name == "scheme").map(|(_, value)| value);
+    let scheme = match declared.as_deref().and_then(Scheme::from_metadata) {
+        Some(scheme) if is_mbtiles(input_path) => scheme,
+        _ => Scheme::Tms,
+    };
+    let zooms = source.zoom_info()?;
+    let Some(max_zoom) = zooms.iter().map(|zoom| zoom.zoom).max() else {
+        return Err(anyhow!("No tiles in {}", input_path));
+    };
+
+    // Estimated bytes per block at each zoom up to the deepest block zoom,
+    // where a block holds a tile and all its descendants
+    let top = max_zoom.min(MAX_BLOCK_ZOOM);
+    let mut blocks: Vec<HashMap<(i32, i32), u64>> = vec![HashMap::new(); top as usize + 1];
+    for info in &zooms {
+        let (level, shift) = (info.zoom.min(top), (info.zoom - top).max(0));
+        let sizes = &mut blocks[level as usize];
+        source.for_each_tile_size(&info.range(), &mut |x, y, bytes| {
+            *sizes.entry((x >> shift, y >> shift)).or_default() += bytes + TILE_OVERHEAD;
+            Ok(())
+        })?;
+    }
+    // Zoom levels above `top` are own tiles only so far, roll descendants up
+    let own: Vec<u64> = blocks.iter().map(|sizes| sizes.values().sum()).collect();
+    for level in (1..=top as usize).rev() {
+        let children: Vec<((i32, i32), u64)> = blocks[level].iter().map(|(&(x, y), &bytes)| ((x >> 1, y >> 1), bytes)).collect();
+        for (parent, bytes) in children {
+            *blocks[level - 1].entry(parent).or_default() += bytes;
+        }
+    }
+
+    let mut chosen = None;
+    for level in 0..=top {
+        let above: u64 = own[..level as usize].iter().sum();
+        let largest = blocks[level as usize].values().copied().max().unwrap_or(0);
+        if above <= budget && largest <= budget {
+            chosen = Some(level);
+            break;
+        }
+    }
+    let Some(level) = chosen else {
+        let largest = blocks[top as usize].values().copied().max().unwrap_or(0);
+        return Err(anyhow!(
+            "Can't split {} into parts of {} bytes: one zoom {} tile and its descendants hold {} bytes",
+            input_path, max_bytes, top, largest
+        ));
+    };
+
+    // Pack blocks in Z-order, starting after the zoom levels above the block zoom
+    let mut order: Vec<((i32, i32), u64)> = blocks[level as usize].iter().map(|(&block, &bytes)| (block, bytes)).collect();
+    order.sort_unstable_by_key(|&((x, y), _)| morton(x, y));
+    let above: u64 = own[..level as usize].iter().sum();
+    let mut groups: Vec<Vec<(i32, i32)>> = vec![Vec::new()];
+    let mut filled = above;
+    for (block, bytes) in order {
+        let current = groups.last_mut().expect("groups start non-empty");
+        if filled + bytes > budget && (!current.is_empty() || filled > 0) {
+            groups.push(Vec::new());
+            filled = 0;
+        }
+        filled += bytes;
+        groups.last_mut().expect("groups start non-empty").push(block);
+    }
+    tracing::debug!(block_zoom = level, parts = groups.len(), "split planned");
+
+    let mut parts = Vec::new();
+    for (index, group) in groups.iter().enumerate() {
+        let mut ranges = Vec::new();
+        if index == 0 {
+            for info in zooms.iter().filter(|info| info.zoom < level) {
+                ranges.push(TileRange::full(info.zoom)?);
+            }
+        }
+        for info in zooms.iter().filter(|info| info.zoom >= level) {
+            let shift = info.zoom - level;
+            for &(x, y) in group {
+                let range = TileRange {
+                    zoom: info.zoom,
+                    x_min: x << shift,
+                    x_max: ((x + 1) << shift) - 1,
+                    y_min: y << shift,
+                    y_max: ((y + 1) << shift) - 1,
+                };
+                ranges.push(range.to_scheme(scheme));
+            }
+        }
+        if ranges.is_empty() {
+            continue;
+        }
+        let mut options = ExtractOptions::new(Area::Ranges(ranges));
+        options.mode = mode;
+        parts.push(write_part(input_path, output_dir, &format!("part{}", index + 1), &options, progress)?);
+    }
+    Ok(parts)
+}
+
+/// Extract one part to `<stem>.<label>.<ext>` in `output_dir`
+fn write_part(
+    input_path: &str,
+    output_dir: &str,
+    label: &str,
+    options: &ExtractOptions,
+    progress: &dyn Progress,
+) -> Result<SplitPart> {
+    let input = Path::new(input_path);
+    let stem = input.file_stem().map_or("tiles".into(), |stem| stem.to_string_lossy());
+    let extension = input.extension().map_or("mbtiles".into(), |ext| ext.to_string_lossy());
+    let path = Path::new(output_dir).join(format!("{}.{}.{}", stem, label, extension));
+    let path = path.to_string_lossy().into_owned();
+
+    let tiles = extract_with_progress(input_path, &path, options, progress)?;
+    let bytes = std::fs::metadata(&path)?.len();
+    let zooms = crate::info::info(&path)?.zooms;
+    let min_zoom = zooms.first().map_or(0, |zoom| zoom.zoom);
+    let max_zoom = zooms.last().map_or(0, |zoom| zoom.zoom);
+    tracing::info!(path = path.as_str(), min_zoom, max_zoom, tiles, bytes, "part written");
+    Ok(SplitPart { path, min_zoom, max_zoom, tiles, bytes })
+}
+
+/// Position of a tile on the Z-order curve, interleaving the bits of x and y
+fn morton(x: i32, y: i32) -> u64 {
+    let spread = |v: i32| {
+        let mut v = v as u64 & 0xFFFF_FFFF;
+        v = (v | v << 16) & 0x0000_FFFF_0000_FFFF;
+        v = (v | v << 8) & 0x00FF_00FF_00FF_00FF;
+        v = (v | v << 4) & 0x0F0F_0F0F_0F0F_0F0F;
+        v = (v | v << 2) & 0x3333_3333_3333_3333;
+        (v | v << 1) & 0x5555_5555_5555_5555
+    };
+    spread(x) | spread(y) << 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    use crate::mbtiles::{MbtilesReader, MbtilesWriter};
+    use crate::tile::Tile;
+
+    const MAX_BYTES: u64 = 250_000;
+
+    fn temp_dir(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("mbtiles-split-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    /// Every tile of zoom levels 0 to 4 with 2000 bytes of data starting with
+    /// its TMS coordinates, keyed by TMS coordinates
+    fn tiles() -> BTreeMap<(i32, i32, i32), Vec<u8>> {
+        let mut tiles = BTreeMap::new();
+        for zoom in 0..=4 {
+            for x in 0..1 << zoom {
+                for y in 0..1 << zoom {
+                    let mut data = format!("{}/{}/{}", zoom, x, y).into_bytes();
+                    data.resize(2000, (x * 31 + y) as u8);
+                    tiles.insert((zoom, x, y), data);
+                }
+            }
+        }
+        tiles
+    }
+
+    fn split(dir: &str, scheme: Scheme) {
+        let input = Path::new(dir).join("tiles.mbtiles").to_string_lossy().into_owned();
+        let writer = MbtilesWriter::create(&input).unwrap();
+        writer.insert_metadata("name", "split").unwrap();
+        if scheme == Scheme::Xyz {
+            writer.insert_metadata("scheme", "xyz").unwrap();
+        }
+        let expected = tiles();
+        for (&(zoom, x, y), data) in &expected {
+            writer.insert_tile(&Tile { zoom, x, y: scheme.from_tms(zoom, y), data: data.clone() }).unwrap();
+        }
+        drop(writer);
+
+        let output = Path::new(dir).join("parts");
+        std::fs::create_dir(&output).unwrap();
+        let parts = split_by_size(&input, MAX_BYTES, &output.to_string_lossy(), OutputMode::Create).unwrap();
+        assert!(parts.len() > 1, "{:?}", parts);
+
+        // Parts are TMS, fit the limit and together hold every tile once
+        let mut found = BTreeMap::new();
+        for part in &parts {
+            assert!(part.bytes <= MAX_BYTES, "{} has {} bytes", part.path, part.bytes);
+            assert_eq!(std::fs::metadata(&part.path).unwrap().len(), part.bytes);
+            let reader = MbtilesReader::open(&part.path).unwrap();
+            assert_eq!(reader.metadata_value("scheme").unwrap(), None);
+            for zoom in reader.zoom_levels().unwrap() {
+                reader
+                    .for_each_tile(&TileRange::full(zoom).unwrap(), |tile| {
+                        let previous = found.insert((tile.zoom, tile.x, tile.y), tile.data);
+                        assert!(previous.is_none(), "{}/{}/{} is in two parts", tile.zoom, tile.x, tile.y);
+                        Ok(())
+                    })
+                    .unwrap();
+            }
+        }
+        assert_eq!(parts.iter().map(|part| part.tiles).sum::<usize>(), expected.len());
+        assert!(found == expected);
+
+        // The low zoom levels go into the first part
+        assert_eq!(parts[0].min_zoom, 0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn parts_fit_the_limit_and_hold_every_tile() {
+        split(&temp_dir("tms"), Scheme::Tms);
+    }
+
+    #[test]
+    fn xyz_input_is_split_by_tms_blocks() {
+        split(&temp_dir("xyz"), Scheme::Xyz);
+    }
+}
@@ -0,0 +1,486 @@
+use std::cell::RefCell;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::thread::{self, JoinHandle};
+
+use anyhow::{Context, Result, anyhow};
+
+use crate::bbox::TileRange;
+use crate::info::ZoomInfo;
+use crate::mbtiles::{HASH_SAMPLE_TILES, MbtilesSchema, NORMALIZED_SELECT, tile_hash};
+use crate::source::{SourceKind, TileSource};
+use crate::tile::Tile;
+
+/// Line `sqlite3` prints after the rows of every query
+const END_OF_ROWS: &str = "END";
+
+/// True if `path` is an `ssh://` URL
+pub(crate) fn is_url(path: &str) -> bool {
+    path.get(..6).is_some_and(|scheme| scheme.eq_ignore_ascii_case("ssh://"))
+}
+
+/// Read access to an MBTiles file on another host, given as
+/// `ssh://[user@]host[:port]/path`, with `/~/` starting a path relative to
+/// the home directory. Queries go to `sqlite3` on that host, run through the
+/// `ssh` command so its configuration and agent apply; blobs and text come
+/// back hex encoded, one row per line.
+pub struct SshReader {
+    url: String,
+    /// The `ssh` command and its arguments up to the remote command
+    ssh: Vec<String>,
+    /// Path of the file on the remote host
+    path: String,
+    schema: MbtilesSchema,
+    /// Idle `sqlite3` sessions. Queries nested in the callback of another
+    /// start a session of their own.
+    sessions: RefCell<Vec<Session>>,
+}
+
+impl SshReader {
+    pub fn open(url: &str) -> Result<Self> {
+        Self::open_with(url, "ssh")
+    }
+
+    /// Like [`Self::open`], connecting with `program` instead of `ssh`
+    fn open_with(url: &str, program: &str) -> Result<Self> {
+        let (ssh, path) = parse_url(url, program)?;
+        let mut reader =
+            SshReader { url: url.to_string(), ssh, path, schema: MbtilesSchema::Flat, sessions: RefCell::new(Vec::new()) };
+
+        let mut found = None;
+        reader.query(
+            "SELECT (SELECT type FROM sqlite_master WHERE name = 'tiles'),
+                    (SELECT COUNT(*) FROM sqlite_master WHERE name IN ('map', 'images') AND type IN ('table', 'view'))",
+            &mut |fields| {
+                found = Some((fields[0].to_string(), fields[1] == "2"));
+                Ok(())
+            },
+        )?;
+        reader.schema = match found {
+            Some((tiles, _)) if tiles == "table" => MbtilesSchema::Flat,
+            Some((_, true)) => MbtilesSchema::Normalized,
+            Some((tiles, false)) if !tiles.is_empty() => {
+                return Err(anyhow!("Failed to read {}: tiles is a view over tables other than map and images", url));
+            }
+            _ => return Err(anyhow!("Failed to read {}: No tiles table or map/images tables found", url)),
+        };
+        Ok(reader)
+    }
+
+    /// Run `sql` remotely, calling `f` with the fields of every result row
+    fn query(&self, sql: &str, f: &mut dyn FnMut(&[&str]) -> Result<()>) -> Result<()> {
+        let idle = self.sessions.borrow_mut().pop();
+        let mut session = match idle {
+            Some(session) => session,
+            None => self.start()?,
+        };
+        // A session that failed may have rows left unread, so it's dropped
+        session.query(sql, f)?;
+        self.sessions.borrow_mut().push(session);
+        Ok(())
+    }
+
+    fn start(&self) -> Result<Session> {
+        let remote = format!("sqlite3 -init /dev/null -batch -bail -readonly -list -noheader -separator '|' {}", quote(&self.path));
+        let mut child = Command::new(&self.ssh[0])
+            .args(&self.ssh[1..])
+            .arg(remote)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context(format!("Failed to run {} for {}", self.ssh[0], self.url))?;
+        let mut stderr = child.stderr.take().expect("piped stderr");
+        let stderr = thread::spawn(move || {
+            let mut message = String::new();
+            let _ = stderr.read_to_string(&mut message);
+            message
+        });
+        let stdin = child.stdin.take().expect("piped stdin");
+        let stdout = BufReader::new(child.stdout.take().expect("piped stdout"));
+        Ok(Session { url: self.url.clone(), child, stdin, stdout, stderr: Some(stderr) })
+    }
+
+    /// Table of the tiles, `map` where only coordinates are needed and the
+    /// `images` join can be skipped
+    fn index_table(&self) -> &'static str {
+        match self.schema {
+            MbtilesSchema::Flat => "tiles",
+            MbtilesSchema::Normalized => "map",
+        }
+    }
+
+    /// Table with the tile data, a join for normalized files as they may
+    /// lack the `tiles` view
+    fn data_table(&self) -> String {
+        match self.schema {
+            MbtilesSchema::Flat => "tiles".to_string(),
+            MbtilesSchema::Normalized => format!("({})", NORMALIZED_SELECT),
+        }
+    }
+
+    /// Hashes of the normalized tiles inside `range`, from their `tile_id`s
+    /// once a sample of them proves to be the md5 of their tiles, as
+    /// [`crate::MbtilesReader`] does
+    fn for_each_normalized_hash(
+        &self,
+        range: &TileRange,
+        f: &mut dyn FnMut(i32, i32, String, Option<Vec<u8>>) -> Result<()>,
+    ) -> Result<()> {
+        let md5_like = "length(map.tile_id) = 32 AND map.tile_id NOT GLOB '*[^0-9A-Fa-f]*'";
+        let mut trusted = true;
+        self.query(
+            &format!(
+                "SELECT map.tile_id, hex(images.tile_data) FROM map JOIN images ON images.tile_id = map.tile_id
+                 WHERE map.zoom_level = {} AND {} ORDER BY random() LIMIT {}",
+                range.zoom, md5_like, HASH_SAMPLE_TILES
+            ),
+            &mut |fields| {
+                trusted &= fields[0].eq_ignore_ascii_case(&tile_hash(&unhex(fields[1])?));
+                Ok(())
+            },
+        )?;
+        self.query(
+            &format!(
+                "SELECT tile_column, tile_row, hex(tile_id), {0} AND {1},
+                        CASE WHEN {0} AND {1} THEN NULL
+                             ELSE hex((SELECT tile_data FROM images WHERE images.tile_id = map.tile_id)) END
+                 FROM map WHERE {2}",
+                i32::from(trusted),
+                md5_like,
+                within(range)
+            ),
+            &mut |fields| {
+                let (x, y) = (fields[0].parse()?, fields[1].parse()?);
+                if fields[3] == "1" {
+                    let id = String::from_utf8(unhex(fields[2])?)?;
+                    f(x, y, id.to_ascii_lowercase(), None)
+                } else {
+                    let data = unhex(fields[4])?;
+                    f(x, y, tile_hash(&data), Some(data))
+                }
+            },
+        )
+    }
+}
+
+/// The `program` command line up to the remote command and the remote path
+/// of the `ssh://` URL `url`
+fn parse_url(url: &str, program: &str) -> Result<(Vec<String>, String)> {
+    let invalid = || anyhow!("Invalid ssh URL, expected ssh://[user@]host[:port]/path: {}", url);
+    if !is_url(url) {
+        return Err(invalid());
+    }
+    let (authority, path) = url[6..].split_once('/').ok_or_else(invalid)?;
+    let (destination, port) = match authority.rsplit_once(':') {
+        Some((destination, port)) => (destination, Some(port.parse::<u16>().map_err(|_| invalid())?)),
+        None => (authority, None),
+    };
+    if destination.is_empty() || destination.starts_with('-') || path.is_empty() {
+        return Err(invalid());
+    }
+    let path = path.strip_prefix("~/").map_or_else(|| format!("/{}", path), str::to_string);
+
+    let mut ssh = vec![program.to_string(), "-o".to_string(), "BatchMode=yes".to_string()];
+    if let Some(port) = port {
+        ssh.extend(["-p".to_string(), port.to_string()]);
+    }
+    ssh.extend(["--".to_string(), destination.to_string()]);
+    Ok((ssh, path))
+}
+
+/// `WHERE` condition selecting the tiles inside `range`
+fn within(range: &TileRange) -> String {
+    format!(
+        "zoom_level = {} AND tile_column BETWEEN {} AND {} AND tile_row BETWEEN {} AND {}",
+        range.zoom, range.x_min, range.x_max, range.y_min, range.y_max
+    )
+}
+
+impl TileSource for SshReader {
+    fn kind(&self) -> SourceKind {
+        SourceKind::Mbtiles(self.schema)
+    }
+
+    fn metadata(&self) -> Result<Vec<(String, String)>> {
+        let mut metadata = Vec::new();
+        self.query("SELECT hex(name), hex(value) FROM metadata ORDER BY name", &mut |fields| {
+            metadata.push((String::from_utf8(unhex(fields[0])?)?, String::from_utf8(unhex(fields[1])?)?));
+            Ok(())
+        })?;
+        Ok(metadata)
+    }
+
+    fn zoom_levels(&self) -> Result<Vec<i32>> {
+        let mut zooms = Vec::new();
+        self.query(&format!("SELECT DISTINCT zoom_level FROM {} ORDER BY zoom_level", self.index_table()), &mut |fields| {
+            zooms.push(fields[0].parse()?);
+            Ok(())
+        })?;
+        Ok(zooms)
+    }
+
+    fn tile(&self, zoom: i32, x: i32, y: i32) -> Result<Option<Vec<u8>>> {
+        let mut data = None;
+        self.query(
+            &format!(
+                "SELECT hex(tile_data) FROM {} WHERE zoom_level = {} AND tile_column = {} AND tile_row = {}",
+                self.data_table(),
+                zoom,
+                x,
+                y
+            ),
+            &mut |fields| {
+                data = Some(unhex(fields[0])?);
+                Ok(())
+            },
+        )?;
+        Ok(data)
+    }
+
+    fn for_each_tile(&self, range: &TileRange, f: &mut dyn FnMut(Tile) -> Result<()>) -> Result<()> {
+        self.query(
+            &format!("SELECT tile_column, tile_row, hex(tile_data) FROM {} WHERE {}", self.data_table(), within(range)),
+            &mut |fields| f(Tile { zoom: range.zoom, x: fields[0].parse()?, y: fields[1].parse()?, data: unhex(fields[2])? }),
+        )
+    }
+
+    fn for_each_tile_size(&self, range: &TileRange, f: &mut dyn FnMut(i32, i32, u64) -> Result<()>) -> Result<()> {
+        self.query(
+            &format!("SELECT tile_column, tile_row, length(tile_data) FROM {} WHERE {}", self.data_table(), within(range)),
+            &mut |fields| f(fields[0].parse()?, fields[1].parse()?, fields[2].parse()?),
+        )
+    }
+
+    fn for_each_tile_hash(
+        &self,
+        range: &TileRange,
+        f: &mut dyn FnMut(i32, i32, String, Option<Vec<u8>>) -> Result<()>,
+    ) -> Result<()> {
+        match self.schema {
+            MbtilesSchema::Flat => self.for_each_tile(range, &mut |tile| f(tile.x, tile.y, tile_hash(&tile.data), Some(tile.data))),
+            MbtilesSchema::Normalized => self.for_each_normalized_hash(range, f),
+        }
+    }
+
+    fn count_tiles(&self, range: &TileRange) -> Result<u64> {
+        let mut count = 0;
+        self.query(&format!("SELECT COUNT(*) FROM {} WHERE {}", self.index_table(), within(range)), &mut |fields| {
+            count = fields[0].parse()?;
+            Ok(())
+        })?;
+        Ok(count)
+    }
+
+    fn zoom_info(&self) -> Result<Vec<ZoomInfo>> {
+        let mut zooms = Vec::new();
+        self.query(
+            &format!(
+                "SELECT zoom_level, COUNT(*), coalesce(SUM(length(tile_data)), 0),
+                        MIN(tile_column), MAX(tile_column), MIN(tile_row), MAX(tile_row)
+                 FROM {} GROUP BY zoom_level ORDER BY zoom_level",
+                self.data_table()
+            ),
+            &mut |fields| {
+                zooms.push(ZoomInfo {
+                    zoom: fields[0].parse()?,
+                    tiles: fields[1].parse()?,
+                    bytes: fields[2].parse()?,
+                    x_min: fields[3].parse()?,
+                    x_max: fields[4].parse()?,
+                    y_min: fields[5].parse()?,
+                    y_max: fields[6].parse()?,
+                });
+                Ok(())
+            },
+        )?;
+        Ok(zooms)
+    }
+
+    fn sample_tile(&self) -> Result<Option<Vec<u8>>> {
+        let mut data = None;
+        self.query(&format!("SELECT hex(tile_data) FROM {} LIMIT 1", self.data_table()), &mut |fields| {
+            data = Some(unhex(fields[0])?);
+            Ok(())
+        })?;
+        Ok(data)
+    }
+}
+
+/// One `sqlite3` process on the remote host, reading SQL from its stdin
+struct Session {
+    url: String,
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    /// Collects what `ssh` and `sqlite3` report, read once the session ends
+    stderr: Option<JoinHandle<String>>,
+}
+
+impl Session {
+    fn query(&mut self, sql: &str, f: &mut dyn FnMut(&[&str]) -> Result<()>) -> Result<()> {
+        if write!(self.stdin, "{};\nSELECT '{}';\n", sql, END_OF_ROWS).and_then(|_| self.stdin.flush()).is_err() {
+            return Err(self.ended());
+        }
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.stdout.read_line(&mut line).context(format!("Failed to read {} over ssh", self.url))? == 0 {
+                return Err(self.ended());
+            }
+            let row = line.trim_end_matches(['\n', '\r']);
+            if row == END_OF_ROWS {
+                return Ok(());
+            }
+            f(&row.split('|').collect::<Vec<_>>())?;
+        }
+    }
+
+    /// Error for a session that ended, with what the remote side reported
+    fn ended(&mut self) -> anyhow::Error {
+        let _ = self.child.wait();
+        let message = self.stderr.take().and_then(|stderr| stderr.join().ok()).unwrap_or_default();
+        match message.trim() {
+            "" => anyhow!("Failed to read {} over ssh: connection closed", self.url),
+            message => anyhow!("Failed to read {} over ssh: {}", self.url, message),
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// `value` quoted for a POSIX shell
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+fn unhex(hex: &str) -> Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return Err(anyhow!("Invalid hex from sqlite3: {}", hex));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| anyhow!("Invalid hex from sqlite3: {}", hex)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mbtiles::{MbtilesReader, MbtilesWriter};
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("mbtiles-ssh-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path.to_string_lossy().into_owned()
+    }
+
+    /// Stands in for `ssh`, running the remote command locally
+    fn fake_ssh() -> String {
+        let path = temp_path("ssh");
+        std::fs::write(&path, "#!/bin/sh\nwhile [ \"$1\" != -- ]; do shift; done\nshift 2\nexec sh -c \"$1\"\n").unwrap();
+        std::process::Command::new("chmod").arg("+x").arg(&path).status().unwrap();
+        path
+    }
+
+    fn have_sqlite3() -> bool {
+        Command::new("sqlite3").arg("-version").output().is_ok_and(|output| output.status.success())
+    }
+
+    fn write(path: &str, schema: MbtilesSchema) {
+        let writer = MbtilesWriter::create_with_schema(path, schema).unwrap();
+        for (zoom, x, y) in [(0, 0, 0), (1, 0, 1), (1, 1, 1), (2, 3, 0)] {
+            let data = format!("{}/{}/{}", zoom, x, y).into_bytes().into_iter().chain([0, b'|', b'\n', 255]).collect();
+            writer.insert_tile(&Tile { zoom, x, y, data }).unwrap();
+        }
+        writer.insert_metadata("name", "a | b").unwrap();
+        writer.insert_metadata("description", "two\nlines").unwrap();
+    }
+
+    #[test]
+    fn urls() {
+        let (ssh, path) = parse_url("ssh://me@example.com:2222/~/tiles.mbtiles", "ssh").unwrap();
+        assert_eq!(ssh, ["ssh", "-o", "BatchMode=yes", "-p", "2222", "--", "me@example.com"]);
+        assert_eq!(path, "tiles.mbtiles");
+        let (ssh, path) = parse_url("SSH://host/srv/it's.mbtiles", "ssh").unwrap();
+        assert_eq!(ssh, ["ssh", "-o", "BatchMode=yes", "--", "host"]);
+        assert_eq!(path, "/srv/it's.mbtiles");
+        assert_eq!(quote(&path), r"'/srv/it'\''s.mbtiles'");
+        for url in ["ssh://host", "ssh://host/", "ssh:///a.mbtiles", "ssh://-oProxyCommand=x/a", "ssh://host:ab/a", "http://host/a"] {
+            assert!(parse_url(url, "ssh").is_err(), "{}", url);
+        }
+    }
+
+    #[test]
+    fn reads_like_a_local_file() {
+        if !have_sqlite3() {
+            return;
+        }
+        let ssh = fake_ssh();
+        for schema in [MbtilesSchema::Flat, MbtilesSchema::Normalized] {
+            let path = temp_path(&format!("{}.mbtiles", schema == MbtilesSchema::Flat));
+            write(&path, schema);
+            let local = MbtilesReader::open(&path).unwrap();
+            let remote = SshReader::open_with(&format!("ssh://host{}", path), &ssh).unwrap();
+
+            assert_eq!(remote.kind(), SourceKind::Mbtiles(schema));
+            assert_eq!(remote.metadata().unwrap(), local.metadata().unwrap());
+            assert_eq!(remote.zoom_levels().unwrap(), [0, 1, 2]);
+            assert_eq!(remote.tile(1, 1, 1).unwrap(), local.tile(1, 1, 1).unwrap());
+            assert_eq!(remote.tile(1, 2, 2).unwrap(), None);
+            assert!(remote.sample_tile().unwrap().is_some());
+            let range = TileRange::full(1).unwrap();
+            assert_eq!(remote.count_tiles(&range).unwrap(), 2);
+            let info = |zooms: Vec<ZoomInfo>| zooms.iter().map(|z| (z.zoom, z.tiles, z.bytes, z.x_max, z.y_min)).collect::<Vec<_>>();
+            assert_eq!(info(remote.zoom_info().unwrap()), info(local.zoom_info().unwrap()));
+
+            let mut tiles = Vec::new();
+            remote.for_each_tile(&range, &mut |tile| {
+                tiles.push((tile.x, tile.y, tile.data));
+                Ok(())
+            })
+            .unwrap();
+            tiles.sort();
+            assert_eq!(tiles, [(0, 1, local.tile(1, 0, 1).unwrap().unwrap()), (1, 1, local.tile(1, 1, 1).unwrap().unwrap())]);
+
+            // Reading tiles while hashing, as sync does, needs a second session
+            let mut hashes = Vec::new();
+            remote
+                .for_each_tile_hash(&range, &mut |x, y, hash, data| {
+                    assert_eq!(data.is_none(), schema == MbtilesSchema::Normalized);
+                    let data = remote.tile(1, x, y)?.unwrap();
+                    assert_eq!(hash, tile_hash(&data));
+                    hashes.push((x, y));
+                    Ok(())
+                })
+                .unwrap();
+            assert_eq!(hashes.len(), 2);
+            assert_eq!(remote.sessions.borrow().len(), 2);
+            std::fs::remove_file(path).unwrap();
+        }
+        std::fs::remove_file(ssh).unwrap();
+    }
+
+    #[test]
+    fn remote_errors_are_reported() {
+        if !have_sqlite3() {
+            return;
+        }
+        let ssh = fake_ssh();
+        let error = SshReader::open_with("ssh://host/nonexistent/tiles.mbtiles", &ssh).err().unwrap().to_string();
+        assert!(error.starts_with("Failed to read ssh://host/nonexistent/tiles.mbtiles over ssh: "), "{}", error);
+        assert!(!error.ends_with("connection closed"), "{}", error);
+
+        let path = temp_path("empty.mbtiles");
+        drop(rusqlite::Connection::open(&path).unwrap().execute_batch("CREATE TABLE metadata (name, value)"));
+        let error = SshReader::open_with(&format!("ssh://host{}", path), &ssh).err().unwrap().to_string();
+        assert!(error.contains("No tiles table"), "{}", error);
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(ssh).unwrap();
+    }
+}
@@ -0,0 +1,206 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use std::collections::BTreeMap;
+
+use anyhow::{Result, anyhow};
+
+use crate::bbox::TileRange;
+use crate::mvt::VectorTile;
+use crate::progress::{NoProgress, Progress};
+use crate::source::open_source;
+use crate::tile::{decompress, detect_format, TileFormat};
+
+/// Tile size distribution for one zoom level
+#[derive(Debug, Clone)]
+pub struct ZoomStats {
+    pub zoom: i32,
+    pub tiles: u64,
+    pub bytes: u64,
+    pub min: u64,
+    pub max: u64,
+    pub mean: f64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+}
+
+/// Size of one tile, in TMS coordinates
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TileSize {
+    pub bytes: u64,
+    pub zoom: i32,
+    pub x: i32,
+    pub y: i32,
+}
+
+/// One of the largest tiles with what it is made of
+#[derive(Debug, Clone)]
+pub struct LargeTile {
+    pub size: TileSize,
+    /// Layers of a vector tile, largest first. Empty for raster tiles.
+    pub layers: Vec<LayerStats>,
+}
+
+/// Result of `mbtile stats`
+#[derive(Debug, Clone)]
+pub struct Stats {
+    pub zooms: Vec<ZoomStats>,
+    /// Largest tiles, biggest first
+    pub largest: Vec<LargeTile>,
+}
+
+/// Compute per-zoom size statistics and the `top` largest tiles of the
+/// tileset at `input_path`. Only tile sizes are read, apart from the data of
+/// the largest vector tiles, which are decoded to break them down by layer.
+pub fn stats(input_path: &str, top: usize) -> Result<Stats> {
+    let source = open_source(input_path)?;
+
+    let mut zooms = Vec::new();
+    // Min-heap of the largest tiles seen so far
+    let mut largest: BinaryHeap<Reverse<TileSize>> = BinaryHeap::with_capacity(top + 1);
+
+    for zoom in source.zoom_levels()? {
+        let mut sizes = Vec::new();
+        source.for_each_tile_size(&TileRange::full(zoom)?, &mut |x, y, bytes| {
+            sizes.push(bytes);
+            if top > 0 {
+                largest.push(Reverse(TileSize { bytes, zoom, x, y }));
+                if largest.len() > top {
+                    largest.pop();
+                }
+            }
+            Ok(())
+        })?;
+        if sizes.is_empty() {
+            continue;
+        }
+
+        sizes.sort_unstable();
+        let bytes: u64 = sizes.iter().sum();
+        zooms.push(ZoomStats {
+            zoom,
+            tiles: sizes.len() as u64,
+            bytes,
+            min: sizes[0],
+            max: sizes[sizes.len() - 1],
+            mean: bytes as f64 / sizes.len() as f64,
+            p50: percentile(&sizes, 50.0),
+            p90: percentile(&sizes, 90.0),
+            p99: percentile(&sizes, 99.0),
+        });
+    }
+
+    let mut large_tiles = Vec::with_capacity(largest.len());
+    for Reverse(size) in largest.into_sorted_vec() {
+        let data = source.tile(size.zoom, size.x, size.y)?.unwrap_or_default();
+        // A tile that fails to decode is still listed, just not broken down
+        let raw = decompress(&data).unwrap_or_default();
+        let layers = match detect_format(&raw) {
+            TileFormat::Pbf => tile_layers(&raw).unwrap_or_default(),
+            _ => Vec::new(),
+        };
+        large_tiles.push(LargeTile { size, layers });
+    }
+    Ok(Stats { zooms, largest: large_tiles })
+}
+
+/// Layers of one uncompressed vector tile, largest first
+fn tile_layers(raw: &[u8]) -> Result<Vec<LayerStats>> {
+    let mut layers: Vec<LayerStats> = VectorTile::decode_layer_sizes(raw)?
+        .into_iter()
+        .map(|(layer, bytes)| LayerStats {
+            name: layer.name,
+            tiles: 1,
+            features: layer.features.len() as u64,
+            bytes: bytes as u64,
+            max: bytes as u64,
+        })
+        .collect();
+    layers.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.name.cmp(&b.name)));
+    Ok(layers)
+}
+
+/// Size of one vector tile layer across the decoded tiles of a zoom level
+#[derive(Debug, Clone, Default)]
+pub struct LayerStats {
+    pub name: String,
+    /// Decoded tiles containing the layer
+    pub tiles: u64,
+    pub features: u64,
+    /// Uncompressed bytes of the layer, summed over the decoded tiles
+    pub bytes: u64,
+    /// Uncompressed bytes of the layer in its largest tile
+    pub max: u64,
+}
+
+/// Per-layer breakdown of one zoom level
+#[derive(Debug, Clone)]
+pub struct ZoomLayers {
+    pub zoom: i32,
+    /// Tiles at this zoom level
+    pub tiles: u64,
+    /// Tiles decoded, fewer than `tiles` when sampling
+    pub decoded: u64,
+    /// Largest layers first
+    pub layers: Vec<LayerStats>,
+}
+
+/// Decode the vector tiles of `input_path` and add up feature counts and
+/// uncompressed bytes per layer and zoom level. With `sample`, at most that
+/// many tiles spread evenly over each zoom level are decoded.
+pub fn layer_stats(input_path: &str, sample: Option<u64>) -> Result<Vec<ZoomLayers>> {
+    layer_stats_with_progress(input_path, sample, &NoProgress)
+}
+
+/// Like [`layer_stats`], reporting progress per tile read
+pub fn layer_stats_with_progress(input_path: &str, sample: Option<u64>, progress: &dyn Progress) -> Result<Vec<ZoomLayers>> {
+    let source = open_source(input_path)?;
+    let zooms = source.zoom_levels()?;
+    let mut counts = Vec::with_capacity(zooms.len());
+    for &zoom in &zooms {
+        counts.push(source.count_tiles(&TileRange::full(zoom)?)?);
+    }
+    progress.start(counts.iter().sum());
+
+    let mut result = Vec::with_capacity(zooms.len());
+    for (zoom, tiles) in zooms.into_iter().zip(counts) {
+        // Decode every `stride`th tile
+        let stride = sample.map_or(1, |sample| tiles.div_ceil(sample.max(1)).max(1));
+        let mut layers: BTreeMap<String, LayerStats> = BTreeMap::new();
+        let (mut seen, mut decoded) = (0u64, 0u64);
+        source.for_each_tile(&TileRange::full(zoom)?, &mut |tile| {
+            seen += 1;
+            progress.advance(1);
+            if !(seen - 1).is_multiple_of(stride) {
+                return Ok(());
+            }
+            let raw = decompress(&tile.data)?;
+            let format = detect_format(&raw);
+            if format != TileFormat::Pbf {
+                return Err(anyhow!("Layer stats need vector tiles, found {} in {}", format.as_str(), input_path));
+            }
+            for (layer, bytes) in VectorTile::decode_layer_sizes(&raw)? {
+                let stats = layers.entry(layer.name.clone()).or_insert_with(|| LayerStats { name: layer.name, ..Default::default() });
+                stats.tiles += 1;
+                stats.features += layer.features.len() as u64;
+                stats.bytes += bytes as u64;
+                stats.max = stats.max.max(bytes as u64);
+            }
+            decoded += 1;
+            Ok(())
+        })?;
+
+        let mut layers: Vec<LayerStats> = layers.into_values().collect();
+        layers.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.name.cmp(&b.name)));
+        result.push(ZoomLayers { zoom, tiles, decoded, layers });
+    }
+    progress.finish();
+    Ok(result)
+}
+
+/// Nearest-rank percentile of ascending, non-empty `sorted`
+fn percentile(sorted: &[u64], p: f64) -> u64 {
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
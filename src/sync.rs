@@ -0,0 +1,257 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+use anyhow::{Result, anyhow};
+use rusqlite::{OptionalExtension, params};
+
+use crate::bbox::TileRange;
+use crate::http;
+use crate::mbtiles::MbtilesWriter;
+use crate::progress::{NoProgress, Progress};
+use crate::source::{is_mbtiles, open_source};
+use crate::ssh;
+use crate::tile::Tile;
+
+/// Tiles and metadata a [`sync`] brought in line with the source
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SyncReport {
+    pub added: u64,
+    pub changed: u64,
+    pub removed: u64,
+    pub unchanged: u64,
+    /// Metadata values set or removed
+    pub metadata: u64,
+    /// Bytes of tile data written to the target
+    pub bytes: u64,
+}
+
+/// Make the MBTiles file at `target_path` a copy of the tileset at
+/// `source_path`, writing only the tiles whose content hash differs. With
+/// `delete`, tiles and metadata the source lacks are removed from the target.
+/// The changes go in one transaction, so readers of the target see either
+/// the old or the new tileset. A missing target is created.
+///
+/// The source may be an `http://` or `https://` URL read with range
+/// requests, or an `ssh://[user@]host[:port]/path` URL of an MBTiles file
+/// read through `sqlite3` on that host (see [`crate::SshReader`]). A
+/// normalized MBTiles source whose `tile_id`s check out as the md5 of a
+/// sample of its tiles is compared by those ids, so only its `map` table and
+/// the tiles that differ are transferred; other sources are read in full to
+/// hash them. The target must be a local file.
+pub fn sync(source_path: &str, target_path: &str, delete: bool) -> Result<SyncReport> {
+    sync_with_progress(source_path, target_path, delete, &NoProgress)
+}
+
+/// Like [`sync`], reporting each tile read from either side to `progress`
+pub fn sync_with_progress(source_path: &str, target_path: &str, delete: bool, progress: &dyn Progress) -> Result<SyncReport> {
+    if http::is_url(target_path) || ssh::is_url(target_path) {
+        return Err(anyhow!("Can only sync into a local file: {}", target_path));
+    }
+    if !is_mbtiles(target_path) {
+        return Err(anyhow!("Only MBTiles files can be updated in place, sync into one: {}", target_path));
+    }
+    let source = open_source(source_path)?;
+    if !Path::new(target_path).exists() {
+        drop(MbtilesWriter::create(target_path)?);
+    }
+    // The target is only read through the writer's connection: once the
+    // transaction spills its page cache it holds an exclusive lock that
+    // shuts out any other connection
+    let writer = MbtilesWriter::open(target_path)?;
+    let conn = writer.connection();
+
+    let target_zooms = writer.zoom_info()?;
+    let zooms: BTreeSet<i32> = source.zoom_levels()?.into_iter().chain(target_zooms.iter().map(|z| z.zoom)).collect();
+    let mut total = target_zooms.iter().map(|z| z.tiles).sum();
+    for &zoom in &zooms {
+        total += source.count_tiles(&TileRange::full(zoom)?)?;
+    }
+    progress.start(total);
+
+    // Hashes of the target tiles of one zoom level at a time, in a temporary
+    // table that SQLite spills to disk rather than memory. Rows left over
+    // after the source pass are tiles the source no longer has.
+    conn.execute_batch(
+        "CREATE TEMP TABLE sync_target (x INTEGER, y INTEGER, hash TEXT, PRIMARY KEY (x, y)) WITHOUT ROWID"
+    )?;
+
+    let tx = conn.unchecked_transaction()?;
+    let mut report = SyncReport::default();
+    for zoom in zooms {
+        let range = TileRange::full(zoom)?;
+        writer.for_each_tile_hash(&range, &mut |x, y, hash, _| {
+            conn.prepare_cached("INSERT INTO sync_target (x, y, hash) VALUES (?, ?, ?)")?
+                .execute(params![x, y, hash])?;
+            progress.advance(1);
+            Ok(())
+        })?;
+
+        source.for_each_tile_hash(&range, &mut |x, y, hash, data| {
+            progress.advance(1);
+            let existing: Option<String> = conn
+                .prepare_cached("DELETE FROM sync_target WHERE x = ? AND y = ? RETURNING hash")?
+                .query_row(params![x, y], |row| row.get(0))
+                .optional()?;
+            match existing {
+                Some(existing) if existing == hash => {
+                    report.unchanged += 1;
+                    return Ok(());
+                }
+                Some(_) => report.changed += 1,
+                None => report.added += 1,
+            }
+            let data = match data {
+                Some(data) => data,
+                None => source
+                    .tile(zoom, x, y)?
+                    .ok_or_else(|| anyhow!("Tile {}/{}/{} is missing from {}", zoom, x, y, source_path))?,
+            };
+            report.bytes += data.len() as u64;
+            writer.replace_tile(&Tile { zoom, x, y, data })
+        })?;
+
+        if delete {
+            let mut stmt = conn.prepare_cached("SELECT x, y FROM sync_target")?;
+            let mut rows = stmt.query([])?;
+            while let Some(row) = rows.next()? {
+                writer.delete_tile(zoom, row.get(0)?, row.get(1)?)?;
+                report.removed += 1;
+            }
+        }
+        conn.execute_batch("DELETE FROM sync_target")?;
+    }
+    writer.prune_images()?;
+
+    let wanted: BTreeMap<String, String> = source.metadata()?.into_iter().collect();
+    let current: BTreeMap<String, String> = writer.metadata()?.into_iter().collect();
+    for (name, value) in &wanted {
+        if current.get(name) != Some(value) {
+            writer.set_metadata(name, value)?;
+            report.metadata += 1;
+        }
+    }
+    if delete {
+        for name in current.keys().filter(|name| !wanted.contains_key(*name)) {
+            writer.delete_metadata(name)?;
+            report.metadata += 1;
+        }
+    }
+    tx.commit()?;
+    progress.finish();
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mbtiles::{MbtilesReader, MbtilesSchema};
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("mbtiles-sync-{}-{}.mbtiles", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path.to_string_lossy().into_owned()
+    }
+
+    /// 20 KB that differ per tile and `version`
+    fn tile(zoom: i32, x: i32, y: i32, version: u8) -> Tile {
+        let data = format!("{}/{}/{} v{} ", zoom, x, y, version).into_bytes().into_iter().cycle().take(20_000).collect();
+        Tile { zoom, x, y, data }
+    }
+
+    fn write(path: &str, schema: MbtilesSchema, tiles: impl IntoIterator<Item = Tile>, metadata: &[(&str, &str)]) {
+        let writer = MbtilesWriter::create_with_schema(path, schema).unwrap();
+        let tx = writer.connection().unchecked_transaction().unwrap();
+        for tile in tiles {
+            writer.insert_tile(&tile).unwrap();
+        }
+        for (name, value) in metadata {
+            writer.insert_metadata(name, value).unwrap();
+        }
+        tx.commit().unwrap();
+    }
+
+    fn pyramid(max_zoom: i32) -> impl Iterator<Item = (i32, i32, i32)> {
+        (0..=max_zoom).flat_map(|z| (0..1 << z).flat_map(move |x| (0..1 << z).map(move |y| (z, x, y))))
+    }
+
+    fn assert_same_tiles(a: &str, b: &str) {
+        let (a, b) = (MbtilesReader::open(a).unwrap(), MbtilesReader::open(b).unwrap());
+        let zooms = a.zoom_levels().unwrap();
+        assert_eq!(zooms, b.zoom_levels().unwrap());
+        for zoom in zooms {
+            let range = TileRange::full(zoom).unwrap();
+            assert_eq!(a.count_tiles(&range).unwrap(), b.count_tiles(&range).unwrap());
+            a.for_each_tile(&range, |tile| {
+                assert_eq!(b.tile(tile.zoom, tile.x, tile.y).unwrap(), Some(tile.data));
+                Ok(())
+            })
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn sync_larger_than_the_page_cache() {
+        // 341 tiles of 20 KB, several times SQLite's default 2 MB cache, so
+        // the transaction spills and locks the target before the last zoom
+        let (source, target) = (temp_path("big-source"), temp_path("big-target"));
+        write(&source, MbtilesSchema::Flat, pyramid(4).map(|(z, x, y)| tile(z, x, y, 1)), &[("name", "new")]);
+        let old = (0..16).flat_map(|x| (0..16).map(move |y| tile(4, x, y, if x < 8 { 0 } else { 1 })));
+        write(&target, MbtilesSchema::Flat, old.chain([tile(5, 0, 0, 0)]), &[("name", "old"), ("stale", "x")]);
+
+        let report = sync(&source, &target, true).unwrap();
+        assert_eq!((report.added, report.changed, report.unchanged, report.removed), (85, 128, 128, 1));
+        assert_eq!(report.metadata, 2);
+        assert_eq!(report.bytes, (85 + 128) * 20_000);
+        assert_same_tiles(&source, &target);
+        let reader = MbtilesReader::open(&target).unwrap();
+        assert_eq!(reader.metadata().unwrap(), vec![("name".to_string(), "new".to_string())]);
+        drop(reader);
+
+        let report = sync(&source, &target, true).unwrap();
+        assert_eq!((report.added, report.changed, report.unchanged, report.removed), (0, 0, 341, 0));
+        assert_eq!((report.metadata, report.bytes), (0, 0));
+
+        std::fs::remove_file(source).unwrap();
+        std::fs::remove_file(target).unwrap();
+    }
+
+    #[test]
+    fn normalized_source_is_compared_by_tile_id() {
+        let (source, target) = (temp_path("normalized-source"), temp_path("normalized-target"));
+        write(&source, MbtilesSchema::Normalized, pyramid(2).map(|(z, x, y)| tile(z, x, y, 1)), &[]);
+        write(&target, MbtilesSchema::Flat, pyramid(2).map(|(z, x, y)| tile(z, x, y, (x + y) as u8 % 2)), &[]);
+
+        let report = sync(&source, &target, false).unwrap();
+        assert_eq!((report.added, report.changed, report.unchanged, report.removed), (0, 11, 10, 0));
+        assert_same_tiles(&source, &target);
+
+        std::fs::remove_file(source).unwrap();
+        std::fs::remove_file(target).unwrap();
+    }
+
+    #[test]
+    fn tile_ids_that_are_not_md5s_are_not_trusted() {
+        let (source, target) = (temp_path("opaque-source"), temp_path("opaque-target"));
+        write(&source, MbtilesSchema::Normalized, pyramid(2).map(|(z, x, y)| tile(z, x, y, 1)), &[]);
+        // Opaque ids that look like hex, then a target edited in place so
+        // half its ids are stale
+        let conn = rusqlite::Connection::open(&source).unwrap();
+        conn.execute_batch(
+            "UPDATE map SET tile_id = (SELECT printf('%032d', rowid) FROM images WHERE images.tile_id = map.tile_id);
+             UPDATE images SET tile_id = printf('%032d', rowid);",
+        )
+        .unwrap();
+        drop(conn);
+        std::fs::copy(&source, &target).unwrap();
+        let conn = rusqlite::Connection::open(&target).unwrap();
+        conn.execute_batch("UPDATE images SET tile_data = CAST(X'00' || tile_data AS BLOB) WHERE rowid % 2 = 0").unwrap();
+        drop(conn);
+
+        let report = sync(&source, &target, false).unwrap();
+        assert_eq!((report.added, report.changed, report.unchanged, report.removed), (0, 10, 11, 0));
+        assert_same_tiles(&source, &target);
+
+        std::fs::remove_file(source).unwrap();
+        std::fs::remove_file(target).unwrap();
+    }
+}
@@ -0,0 +1,420 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result, anyhow};
+use serde_json::{Map, Value};
+
+use crate::bbox::TileRange;
+use crate::coord::MAX_ZOOM;
+use crate::info::ZoomInfo;
+use crate::sink::TileSink;
+use crate::source::{SourceKind, TileSource};
+use crate::tile::{detect_format, Tile, TileFormat};
+
+/// Size of a tar header and of the blocks entries are padded to
+const BLOCK: usize = 512;
+
+/// Name of the entry holding the metadata, as in a directory export
+const METADATA_ENTRY: &str = "metadata.json";
+
+/// Writes tiles as `{z}/{x}/{y}.{ext}` entries (XYZ rows) of a ustar
+/// archive, to a file or, for the path `-`, to stdout. Entries go out as the
+/// tiles come, followed by a `metadata.json` entry once all are written.
+pub struct TarWriter {
+    out: Box<dyn Write>,
+    metadata: Map<String, Value>,
+    /// Modification time given to every entry
+    mtime: u64,
+}
+
+impl TarWriter {
+    pub fn create(path: &str) -> Result<Self> {
+        let out: Box<dyn Write> = if path == "-" {
+            Box::new(BufWriter::new(io::stdout()))
+        } else {
+            Box::new(BufWriter::new(File::create(path).context(format!("Failed to create output file: {}", path))?))
+        };
+        let mtime = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        Ok(TarWriter { out, metadata: Map::new(), mtime })
+    }
+
+    fn write_entry(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        self.out.write_all(&header(name, data.len() as u64, self.mtime)?)?;
+        self.out.write_all(data)?;
+        let padding = (BLOCK - data.len() % BLOCK) % BLOCK;
+        self.out.write_all(&[0; BLOCK][..padding])?;
+        Ok(())
+    }
+}
+
+impl TileSink for TarWriter {
+    fn write_metadata(&mut self, name: &str, value: &str) -> Result<()> {
+        self.metadata.insert(name.to_string(), Value::String(value.to_string()));
+        Ok(())
+    }
+
+    fn write_tile(&mut self, tile: &Tile) -> Result<()> {
+        let extension = self
+            .metadata
+            .get("format")
+            .and_then(Value::as_str)
+            .and_then(TileFormat::from_metadata)
+            .unwrap_or_else(|| detect_format(&tile.data))
+            .as_str();
+        if !(0..=MAX_ZOOM).contains(&tile.zoom) {
+            return Err(anyhow!("Invalid zoom level {}, expected 0 to {}", tile.zoom, MAX_ZOOM));
+        }
+        let y = (1 << tile.zoom) - 1 - tile.y;
+        self.write_entry(&format!("{}/{}/{}.{}", tile.zoom, tile.x, y, extension), &tile.data)
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        let metadata = std::mem::take(&mut self.metadata);
+        let json = serde_json::to_string_pretty(&Value::Object(metadata))?;
+        self.write_entry(METADATA_ENTRY, json.as_bytes())?;
+        // Two empty blocks end the archive
+        self.out.write_all(&[0; 2 * BLOCK])?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// ustar header of a regular file entry
+fn header(name: &str, size: u64, mtime: u64) -> Result<[u8; BLOCK]> {
+    if name.len() > 100 {
+        return Err(anyhow!("Tar entry name too long: {}", name));
+    }
+    let mut header = [0u8; BLOCK];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    header[100..108].copy_from_slice(b"0000644\0");
+    header[108..116].copy_from_slice(b"0000000\0");
+    header[116..124].copy_from_slice(b"0000000\0");
+    header[124..136].copy_from_slice(format!("{:011o}\0", size).as_bytes());
+    header[136..148].copy_from_slice(format!("{:011o}\0", mtime).as_bytes());
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    // The checksum is computed with its own field as spaces
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u32 = header.iter().map(|&b| b as u32).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    Ok(header)
+}
+
+/// Read access to a tar archive of `{z}/{x}/{y}.{ext}` entries (XYZ rows),
+/// such as [`TarWriter`] produces. Entries may sit below a common directory;
+/// others are ignored, except a `metadata.json` whose members become the
+/// metadata.
+pub struct TarReader {
+    file: RefCell<File>,
+    /// Size of the archive, bounding every read
+    len: u64,
+    /// Copy of stdin when reading `-`
+    _spool: Option<StdinSpool>,
+    tiles: Entries,
+    metadata: BTreeMap<String, String>,
+}
+
+impl TarReader {
+    /// Index the archive at `path`. The path `-` reads the archive from
+    /// stdin, first copied to a temporary file since tiles are read out of
+    /// order.
+    pub fn open(path: &str) -> Result<Self> {
+        let spool = if path == "-" { Some(StdinSpool::create()?) } else { None };
+        let file_path = spool.as_ref().map_or(path, |spool| spool.path());
+        let mut file = File::open(file_path).context(format!("Failed to open input file: {}", path))?;
+        let len = file.metadata()?.len();
+        let (tiles, mut metadata) = index(&mut file, len).map_err(|e| anyhow!("Failed to read tar archive {}: {}", path, e))?;
+        if path != "-" {
+            let name = Path::new(path).file_stem().map_or("tiles".into(), |stem| stem.to_string_lossy().into_owned());
+            metadata.entry("name".to_string()).or_insert(name);
+        }
+        Ok(TarReader { file: RefCell::new(file), len, _spool: spool, tiles, metadata })
+    }
+
+    fn read(&self, (offset, length): (u64, u64)) -> Result<Vec<u8>> {
+        if offset.checked_add(length).is_none_or(|end| end > self.len) {
+            return Err(anyhow!("Tar archive is truncated"));
+        }
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = vec![0u8; length as usize];
+        file.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    /// Entries of the tiles inside `range`
+    fn locate(&self, range: &TileRange) -> impl Iterator<Item = (i32, i32, (u64, u64))> + '_ {
+        let start = (range.zoom, range.x_min, range.y_min);
+        let end = (range.zoom, range.x_max, range.y_max);
+        let rows = range.y_min..=range.y_max;
+        self.tiles
+            .range(start..=end)
+            .filter(move |&(&(_, _, y), _)| rows.contains(&y))
+            .map(|(&(_, x, y), &entry)| (x, y, entry))
+    }
+}
+
+/// A tar stream read from stdin into a temporary file, for readers that
+/// need to seek. The file is removed on drop.
+pub(crate) struct StdinSpool {
+    path: String,
+}
+
+impl StdinSpool {
+    pub(crate) fn create() -> Result<Self> {
+        let path = std::env::temp_dir().join(format!("mbtiles-stdin-{}.tar", std::process::id()));
+        let path = path.to_string_lossy().into_owned();
+        let mut file = File::options()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .context(format!("Failed to create temporary file: {}", path))?;
+        let spool = StdinSpool { path };
+        io::copy(&mut io::stdin().lock(), &mut file).context("Failed to read stdin")?;
+        Ok(spool)
+    }
+
+    pub(crate) fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+impl Drop for StdinSpool {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl TileSource for TarReader {
+    fn kind(&self) -> SourceKind {
+        SourceKind::Tar
+    }
+
+    /// Rows are always TMS once read, so a `scheme` member is dropped
+    fn metadata(&self) -> Result<Vec<(String, String)>> {
+        let mut metadata = self.metadata.clone();
+        metadata.remove("scheme");
+        if let Some(data) = self.sample_tile()? {
+            metadata.entry("format".to_string()).or_insert_with(|| detect_format(&data).to_string());
+        }
+        let zooms = self.zoom_info()?;
+        if let (Some(min), Some(max)) = (zooms.first(), zooms.last()) {
+            metadata.entry("minzoom".to_string()).or_insert_with(|| min.zoom.to_string());
+            metadata.entry("maxzoom".to_string()).or_insert_with(|| max.zoom.to_string());
+            let extent = TileRange { zoom: max.zoom, x_min: max.x_min, x_max: max.x_max, y_min: max.y_min, y_max: max.y_max };
+            metadata.entry("bounds".to_string()).or_insert_with(|| extent.bounds().to_metadata());
+        }
+        Ok(metadata.into_iter().collect())
+    }
+
+    fn zoom_levels(&self) -> Result<Vec<i32>> {
+        Ok(self.zoom_info()?.into_iter().map(|zoom| zoom.zoom).collect())
+    }
+
+    fn tile(&self, zoom: i32, x: i32, y: i32) -> Result<Option<Vec<u8>>> {
+        self.tiles.get(&(zoom, x, y)).map(|&entry| self.read(entry)).transpose()
+    }
+
+    fn for_each_tile(&self, range: &TileRange, f: &mut dyn FnMut(Tile) -> Result<()>) -> Result<()> {
+        for (x, y, entry) in self.locate(range) {
+            f(Tile { zoom: range.zoom, x, y, data: self.read(entry)? })?;
+        }
+        Ok(())
+    }
+
+    fn for_each_tile_size(&self, range: &TileRange, f: &mut dyn FnMut(i32, i32, u64) -> Result<()>) -> Result<()> {
+        for (x, y, (_, length)) in self.locate(range) {
+            f(x, y, length)?;
+        }
+        Ok(())
+    }
+
+    fn count_tiles(&self, range: &TileRange) -> Result<u64> {
+        Ok(self.locate(range).count() as u64)
+    }
+
+    fn zoom_info(&self) -> Result<Vec<ZoomInfo>> {
+        let mut zooms: Vec<ZoomInfo> = Vec::new();
+        for (&(zoom, x, y), &(_, length)) in &self.tiles {
+            match zooms.last_mut() {
+                Some(info) if info.zoom == zoom => {
+                    info.tiles += 1;
+                    info.bytes += length;
+                    info.x_max = x;
+                    info.y_min = info.y_min.min(y);
+                    info.y_max = info.y_max.max(y);
+                }
+                _ => zooms.push(ZoomInfo { zoom, tiles: 1, bytes: length, x_min: x, x_max: x, y_min: y, y_max: y }),
+            }
+        }
+        Ok(zooms)
+    }
+
+    fn sample_tile(&self) -> Result<Option<Vec<u8>>> {
+        self.tiles.values().next().map(|&entry| self.read(entry)).transpose()
+    }
+}
+
+/// Tile entries of an archive by (zoom, column, TMS row), as offset and
+/// length of their data
+type Entries = BTreeMap<(i32, i32, i32), (u64, u64)>;
+
+/// Walk the headers of an archive of `len` bytes, collecting tile entries and
+/// the members of any `metadata.json`
+fn index(file: &mut File, len: u64) -> Result<(Entries, BTreeMap<String, String>)> {
+    let mut tiles = Entries::new();
+    let mut metadata = BTreeMap::new();
+    let mut offset = 0u64;
+    let mut header = [0u8; BLOCK];
+    loop {
+        file.seek(SeekFrom::Start(offset))?;
+        if read_block(file, &mut header)? == 0 || header.iter().all(|&b| b == 0) {
+            break;
+        }
+        let size = parse_octal(&header[124..136]).ok_or_else(|| anyhow!("Malformed tar header at byte {}", offset))?;
+        let data_offset = offset + BLOCK as u64;
+        if data_offset.checked_add(size).is_none_or(|end| end > len) {
+            return Err(anyhow!("Truncated tar entry at byte {}", offset));
+        }
+        let regular = matches!(header[156], b'0' | 0);
+        let mut name = String::from_utf8_lossy(until_nul(&header[..100])).into_owned();
+        if &header[257..262] == b"ustar" && header[345] != 0 {
+            name = format!("{}/{}", String::from_utf8_lossy(until_nul(&header[345..500])), name);
+        }
+
+        if regular {
+            let parts: Vec<&str> = name.trim_start_matches("./").split('/').collect();
+            if parts.last() == Some(&METADATA_ENTRY) {
+                let mut data = vec![0u8; size as usize];
+                file.read_exact(&mut data)?;
+                let json: Value = serde_json::from_slice(&data).context(format!("Invalid {}", name))?;
+                if let Value::Object(members) = json {
+                    for (name, value) in members {
+                        let value = match value {
+                            Value::String(s) => s,
+                            other => other.to_string(),
+                        };
+                        metadata.insert(name, value);
+                    }
+                }
+            } else if let [.., z, x, y] = parts[..] {
+                let y = y.split('.').next().unwrap_or(y);
+                if let (Ok(z), Ok(x), Ok(y)) = (z.parse::<i32>(), x.parse::<i32>(), y.parse::<i32>())
+                    && (0..=MAX_ZOOM).contains(&z)
+                {
+                    tiles.insert((z, x, (1 << z) - 1 - y), (data_offset, size));
+                }
+            }
+        }
+        offset = data_offset + size.div_ceil(BLOCK as u64) * BLOCK as u64;
+    }
+    Ok((tiles, metadata))
+}
+
+/// Fill `block` from `file`, returning 0 at the end of the file
+fn read_block(file: &mut File, block: &mut [u8; BLOCK]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < BLOCK {
+        match file.read(&mut block[filled..])? {
+            0 if filled == 0 => return Ok(0),
+            0 => return Err(anyhow!("Truncated tar header")),
+            read => filled += read,
+        }
+    }
+    Ok(filled)
+}
+
+fn until_nul(field: &[u8]) -> &[u8] {
+    field.split(|&b| b == 0).next().unwrap_or(field)
+}
+
+fn parse_octal(field: &[u8]) -> Option<u64> {
+    let text = std::str::from_utf8(until_nul(field)).ok()?.trim();
+    if text.is_empty() { Some(0) } else { u64::from_str_radix(text, 8).ok() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("mbtiles-tar-{}-{}", std::process::id(), name));
+        path.to_string_lossy().into_owned()
+    }
+
+    fn write(path: &str, tiles: &[Tile]) {
+        let mut writer = Box::new(TarWriter::create(path).unwrap());
+        writer.write_metadata("format", "png").unwrap();
+        writer.write_metadata("name", "round trip").unwrap();
+        for tile in tiles {
+            writer.write_tile(tile).unwrap();
+        }
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = temp_path("round-trip.tar");
+        let tiles = [
+            Tile { zoom: 0, x: 0, y: 0, data: b"zero".to_vec() },
+            Tile { zoom: 2, x: 1, y: 3, data: vec![7; 513] },
+            Tile { zoom: 2, x: 3, y: 0, data: Vec::new() },
+        ];
+        write(&path, &tiles);
+
+        // Entries are named by XYZ row
+        let (entries, _) = index(&mut File::open(&path).unwrap(), fs::metadata(&path).unwrap().len()).unwrap();
+        assert_eq!(entries.keys().copied().collect::<Vec<_>>(), [(0, 0, 0), (2, 1, 3), (2, 3, 0)]);
+        let archive = fs::read(&path).unwrap();
+        assert_eq!(&archive[2 * BLOCK..2 * BLOCK + 9], b"2/1/0.png");
+        assert_eq!(archive.len() % BLOCK, 0);
+
+        let reader = TarReader::open(&path).unwrap();
+        for tile in &tiles {
+            assert_eq!(reader.tile(tile.zoom, tile.x, tile.y).unwrap().as_ref(), Some(&tile.data));
+        }
+        assert_eq!(reader.tile(2, 0, 0).unwrap(), None);
+        let mut read = Vec::new();
+        reader
+            .for_each_tile(&TileRange::full(2).unwrap(), &mut |tile| {
+                read.push((tile.x, tile.y, tile.data.len()));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(read, [(1, 3, 513), (3, 0, 0)]);
+        let metadata: BTreeMap<_, _> = reader.metadata().unwrap().into_iter().collect();
+        assert_eq!(metadata["name"], "round trip");
+        assert_eq!(metadata["format"], "png");
+        assert_eq!((metadata["minzoom"].as_str(), metadata["maxzoom"].as_str()), ("0", "2"));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn truncated_and_oversized_entries_fail() {
+        let path = temp_path("truncated.tar");
+        write(&path, &[Tile { zoom: 1, x: 0, y: 0, data: vec![1; 2000] }]);
+        let archive = fs::read(&path).unwrap();
+
+        fs::write(&path, &archive[..BLOCK + 1000]).unwrap();
+        let error = TarReader::open(&path).err().unwrap().to_string();
+        assert!(error.contains("Truncated tar entry at byte 0"), "{}", error);
+        fs::write(&path, &archive[..BLOCK / 2]).unwrap();
+        assert!(TarReader::open(&path).is_err());
+
+        // Sizes asking for far more than the archive holds, for a tile and
+        // for the metadata read while indexing
+        for name in ["1/0/1.png", METADATA_ENTRY] {
+            let mut huge = header(name, 0o77777777777, 0).unwrap().to_vec();
+            huge.extend([0; 3 * BLOCK]);
+            fs::write(&path, &huge).unwrap();
+            let error = TarReader::open(&path).err().unwrap().to_string();
+            assert!(error.contains("Truncated tar entry"), "{}", error);
+        }
+        fs::remove_file(path).unwrap();
+    }
+}
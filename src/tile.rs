@@ -0,0 +1,175 @@
+use std::fmt;
+use std::io::{Read, Write};
+
+use anyhow::{Context, Result};
+use flate2::read::{GzDecoder, ZlibDecoder};
+use flate2::write::{GzEncoder, ZlibEncoder};
+
+use crate::coord::TileCoord;
+
+/// A single tile addressed in the TMS scheme used by MBTiles
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tile {
+    pub zoom: i32,
+    pub x: i32,
+    pub y: i32,
+    pub data: Vec<u8>,
+}
+
+/// Tile formats allowed by the MBTiles spec's `format` metadata key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TileFormat {
+    Png,
+    Jpg,
+    Webp,
+    Pbf,
+}
+
+impl TileFormat {
+    /// Parse the value of the `format` metadata key
+    pub fn from_metadata(value: &str) -> Option<Self> {
+        match value {
+            "png" => Some(TileFormat::Png),
+            "jpg" | "jpeg" => Some(TileFormat::Jpg),
+            "webp" => Some(TileFormat::Webp),
+            "pbf" | "mvt" => Some(TileFormat::Pbf),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TileFormat::Png => "png",
+            TileFormat::Jpg => "jpg",
+            TileFormat::Webp => "webp",
+            TileFormat::Pbf => "pbf",
+        }
+    }
+
+    /// MIME type to serve tiles of this format with
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            TileFormat::Png => "image/png",
+            TileFormat::Jpg => "image/jpeg",
+            TileFormat::Webp => "image/webp",
+            TileFormat::Pbf => "application/x-protobuf",
+        }
+    }
+}
+
+impl fmt::Display for TileFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Compression wrapped around a tile blob (used for vector tiles)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zlib,
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+            Compression::Zlib => "zlib",
+        })
+    }
+}
+
+/// Tile row numbering: TMS counts rows from the south, XYZ from the north
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scheme {
+    Xyz,
+    Tms,
+}
+
+impl Scheme {
+    /// Convert a row between this scheme and TMS (the flip is its own inverse)
+    pub fn to_tms(&self, zoom: i32, y: i32) -> i32 {
+        match self {
+            Scheme::Tms => y,
+            Scheme::Xyz => (1 << zoom) - 1 - y,
+        }
+    }
+
+    pub fn from_tms(&self, zoom: i32, y: i32) -> i32 {
+        self.to_tms(zoom, y)
+    }
+
+    /// Parse the non-standard `scheme` metadata key some writers add
+    pub fn from_metadata(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "xyz" => Some(Scheme::Xyz),
+            "tms" => Some(Scheme::Tms),
+            _ => None,
+        }
+    }
+}
+
+/// Guess the tile format from the magic bytes of a tile blob.
+/// Anything that isn't a known image is assumed to be a vector tile.
+pub fn detect_format(data: &[u8]) -> TileFormat {
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        TileFormat::Png
+    } else if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        TileFormat::Jpg
+    } else if data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        TileFormat::Webp
+    } else {
+        TileFormat::Pbf
+    }
+}
+
+/// Detect gzip or zlib compression from the magic bytes of a tile blob
+pub fn detect_compression(data: &[u8]) -> Compression {
+    if data.starts_with(&[0x1F, 0x8B]) {
+        Compression::Gzip
+    } else if data.len() >= 2 && data[0] == 0x78 && (u16::from(data[0]) << 8 | u16::from(data[1])) % 31 == 0 {
+        Compression::Zlib
+    } else {
+        Compression::None
+    }
+}
+
+/// Convert a TMS tile corner back to lon/lat
+pub fn tile_to_lon_lat(x: i32, tms_y: i32, zoom: i32) -> (f64, f64) {
+    // The south-west corner of a TMS tile is the north-west corner of the XYZ tile below it
+    TileCoord::new(zoom, x, (1 << zoom) - tms_y).lon_lat()
+}
+
+/// Undo gzip or zlib compression detected from the magic bytes. Blobs that
+/// aren't compressed are returned unchanged.
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    match detect_compression(data) {
+        Compression::None => return Ok(data.to_vec()),
+        Compression::Gzip => GzDecoder::new(data).read_to_end(&mut out).context("Failed to gunzip tile")?,
+        Compression::Zlib => ZlibDecoder::new(data).read_to_end(&mut out).context("Failed to inflate tile")?,
+    };
+    Ok(out)
+}
+
+/// Compress `data` with `compression` at the default level
+pub fn compress(data: &[u8], compression: Compression) -> Result<Vec<u8>> {
+    Ok(match compression {
+        Compression::None => data.to_vec(),
+        Compression::Gzip => gzip(data, flate2::Compression::default().level())?,
+        Compression::Zlib => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data)?;
+            encoder.finish()?
+        }
+    })
+}
+
+/// Gzip `data` at `level` (0-9)
+pub fn gzip(data: &[u8], level: u32) -> Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::new(level));
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
@@ -0,0 +1,115 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+/// Bytes counted for each entry on top of its data, for the key and bookkeeping
+const ENTRY_OVERHEAD: u64 = 64;
+
+/// A tile as (file path, file version, zoom, column, TMS row)
+type CacheKey = (String, u64, i32, i32, i32);
+
+/// Tile data shared by the server workers, dropping the least recently
+/// used tiles once the entries exceed the byte budget
+pub(crate) struct TileCache {
+    budget: u64,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Data and last use of each entry
+    entries: HashMap<CacheKey, (Arc<Vec<u8>>, u64)>,
+    /// Entries by last use, oldest first
+    recent: BTreeMap<u64, CacheKey>,
+    clock: u64,
+    stats: CacheStats,
+}
+
+/// Counters of a [`TileCache`]
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub entries: u64,
+    pub bytes: u64,
+}
+
+impl TileCache {
+    pub(crate) fn new(budget: u64) -> Self {
+        TileCache { budget, inner: Mutex::new(Inner::default()) }
+    }
+
+    pub(crate) fn budget(&self) -> u64 {
+        self.budget
+    }
+
+    /// The cached data of a tile, marking it as just used
+    pub(crate) fn get(&self, path: &str, version: u64, zoom: i32, x: i32, y: i32) -> Option<Arc<Vec<u8>>> {
+        let mut inner = self.inner.lock().expect("tile cache lock");
+        let inner = &mut *inner;
+        let key = (path.to_string(), version, zoom, x, y);
+        let Some((data, used)) = inner.entries.get_mut(&key) else {
+            inner.stats.misses += 1;
+            return None;
+        };
+        inner.clock += 1;
+        inner.recent.remove(used);
+        *used = inner.clock;
+        inner.recent.insert(inner.clock, key);
+        inner.stats.hits += 1;
+        Some(Arc::clone(data))
+    }
+
+    /// Cache a tile read from `version` of the file at `path`, evicting old
+    /// ones to stay within the budget. Tiles larger than the whole budget
+    /// aren't cached.
+    pub(crate) fn insert(&self, path: &str, version: u64, zoom: i32, x: i32, y: i32, data: Arc<Vec<u8>>) {
+        let cost = data.len() as u64 + ENTRY_OVERHEAD;
+        if cost > self.budget {
+            return;
+        }
+        let mut inner = self.inner.lock().expect("tile cache lock");
+        let key = (path.to_string(), version, zoom, x, y);
+        inner.clock += 1;
+        let used = inner.clock;
+        if let Some((old, old_used)) = inner.entries.insert(key.clone(), (data, used)) {
+            inner.recent.remove(&old_used);
+            inner.stats.bytes -= old.len() as u64 + ENTRY_OVERHEAD;
+            inner.stats.entries -= 1;
+        }
+        inner.recent.insert(used, key);
+        inner.stats.bytes += cost;
+        inner.stats.entries += 1;
+
+        while inner.stats.bytes > self.budget {
+            let Some((_, oldest)) = inner.recent.pop_first() else { break };
+            if let Some((data, _)) = inner.entries.remove(&oldest) {
+                inner.stats.bytes -= data.len() as u64 + ENTRY_OVERHEAD;
+                inner.stats.entries -= 1;
+                inner.stats.evictions += 1;
+            }
+        }
+    }
+
+    /// Drop the tiles of file paths and versions for which `keep` returns false
+    pub(crate) fn retain(&self, keep: impl Fn(&str, u64) -> bool) {
+        let mut inner = self.inner.lock().expect("tile cache lock");
+        let inner = &mut *inner;
+        let (mut bytes, mut entries) = (0, 0);
+        inner.entries.retain(|(path, version, _, _, _), (data, _)| {
+            let kept = keep(path, *version);
+            if !kept {
+                bytes += data.len() as u64 + ENTRY_OVERHEAD;
+                entries += 1;
+            }
+            kept
+        });
+        inner.recent.retain(|_, (path, version, _, _, _)| keep(path, *version));
+        inner.stats.bytes -= bytes;
+        inner.stats.entries -= entries;
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        self.inner.lock().expect("tile cache lock").stats
+    }
+}
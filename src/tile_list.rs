@@ -0,0 +1,104 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result, anyhow};
+
+use crate::bbox::{BoundingBox, TileRange};
+use crate::coord::TileCoord;
+use crate::tile::Scheme;
+
+/// An explicit set of tiles to extract, read from `z/x/y` or `z,x,y` lines
+/// in XYZ numbering or from quadkeys.
+#[derive(Debug, Clone)]
+pub struct TileList {
+    /// Per zoom, the (row, column) of each tile in TMS, sorted and unique
+    tiles: BTreeMap<i32, Vec<(i32, i32)>>,
+}
+
+impl TileList {
+    pub fn from_file(path: &str) -> Result<Self> {
+        let text = std::fs::read_to_string(path).context(format!("Failed to read tile list: {}", path))?;
+        Self::parse(&text).map_err(|e| anyhow!("Invalid tile list {}: {}", path, e))
+    }
+
+    /// Parse one tile per line, either z/x/y or a quadkey. Blank lines and
+    /// lines starting with `#` are skipped.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut tiles: BTreeMap<i32, Vec<(i32, i32)>> = BTreeMap::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parts: Vec<&str> = line.split(['/', ',']).map(str::trim).collect();
+            let (zoom, x, y) = match parts[..] {
+                [z, x, y] => {
+                    let parse = |s: &str| s.parse::<i32>().map_err(|_| anyhow!("Line {}: invalid number {:?}", number + 1, s));
+                    (parse(z)?, parse(x)?, parse(y)?)
+                }
+                [key] => {
+                    let tile = TileCoord::from_quadkey(key).map_err(|e| anyhow!("Line {}: {}", number + 1, e))?;
+                    (tile.zoom, tile.x, tile.y)
+                }
+                _ => return Err(anyhow!("Line {}: expected z/x/y or a quadkey, got {:?}", number + 1, line)),
+            };
+            if !(0..=30).contains(&zoom) || x < 0 || y < 0 || x >= 1 << zoom || y >= 1 << zoom {
+                return Err(anyhow!("Line {}: tile {}/{}/{} is out of range", number + 1, zoom, x, y));
+            }
+            tiles.entry(zoom).or_default().push((Scheme::Xyz.to_tms(zoom, y), x));
+        }
+        if tiles.is_empty() {
+            return Err(anyhow!("Tile list is empty"));
+        }
+        for list in tiles.values_mut() {
+            list.sort_unstable();
+            list.dedup();
+        }
+        Ok(TileList { tiles })
+    }
+
+    pub fn len(&self) -> usize {
+        self.tiles.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiles.is_empty()
+    }
+
+    /// The listed tiles as (zoom, column, TMS row), by zoom
+    pub fn tiles(&self) -> impl Iterator<Item = (i32, i32, i32)> + '_ {
+        self.tiles.iter().flat_map(|(&zoom, tiles)| tiles.iter().map(move |&(y, x)| (zoom, x, y)))
+    }
+
+    /// Ranges covering exactly the listed tiles at `zoom`, one per run of
+    /// adjacent tiles in a row
+    pub fn tile_ranges(&self, zoom: i32) -> Vec<TileRange> {
+        let mut ranges: Vec<TileRange> = Vec::new();
+        for &(y, x) in self.tiles.get(&zoom).into_iter().flatten() {
+            match ranges.last_mut() {
+                Some(last) if last.y_min == y && last.x_max + 1 == x => last.x_max = x,
+                _ => ranges.push(TileRange::single(zoom, x, y)),
+            }
+        }
+        ranges
+    }
+
+    /// Bounding box enclosing every listed tile
+    pub fn bbox(&self) -> BoundingBox {
+        let mut bbox: Option<BoundingBox> = None;
+        for (&zoom, tiles) in &self.tiles {
+            for &(y, x) in tiles {
+                let b = TileRange::single(zoom, x, y).bounds();
+                bbox = Some(match bbox {
+                    Some(a) => BoundingBox {
+                        north: a.north.max(b.north),
+                        east: a.east.max(b.east),
+                        south: a.south.min(b.south),
+                        west: a.west.min(b.west),
+                    },
+                    None => b,
+                });
+            }
+        }
+        bbox.expect("tile lists are never empty")
+    }
+}
@@ -0,0 +1,73 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Result, anyhow};
+use serde_json::{Map, Value, json};
+
+use crate::source::{open_source, TileSource};
+
+/// Metadata keys copied into TileJSON as strings when present
+const STRING_KEYS: [&str; 5] = ["name", "description", "version", "attribution", "legend"];
+
+/// Build a TileJSON 3.0.0 document for the tileset at `path`, with
+/// `url_template` (e.g. `https://example.com/{z}/{x}/{y}.pbf`) as its tile URL
+pub fn tilejson(path: &str, url_template: &str) -> Result<Value> {
+    tilejson_for_source(open_source(path)?.as_ref(), url_template)
+}
+
+/// Build a TileJSON document from an already opened source.
+///
+/// `vector_layers` comes from the MBTiles `json` key. Zoom levels fall back
+/// to those containing tiles when the metadata lacks them.
+pub fn tilejson_for_source(source: &dyn TileSource, url_template: &str) -> Result<Value> {
+    let metadata: BTreeMap<String, String> = source.metadata()?.into_iter().collect();
+    let mut doc = Map::new();
+    doc.insert("tilejson".into(), json!("3.0.0"));
+    doc.insert("tiles".into(), json!([url_template]));
+    doc.insert("scheme".into(), json!("xyz"));
+
+    for key in STRING_KEYS {
+        if let Some(value) = metadata.get(key) {
+            doc.insert(key.into(), json!(value));
+        }
+    }
+
+    let zooms = source.zoom_levels()?;
+    for (key, fallback) in [("minzoom", zooms.first()), ("maxzoom", zooms.last())] {
+        let zoom = match metadata.get(key) {
+            Some(value) => Some(value.trim().parse::<u8>().map_err(|_| anyhow!("Invalid {} metadata: {}", key, value))?),
+            None => fallback.map(|&z| z as u8),
+        };
+        if let Some(zoom) = zoom {
+            doc.insert(key.into(), json!(zoom));
+        }
+    }
+
+    if let Some(bounds) = metadata.get("bounds") {
+        doc.insert("bounds".into(), json!(parse_numbers(bounds, 4, "bounds")?));
+    }
+    if let Some(center) = metadata.get("center") {
+        doc.insert("center".into(), json!(parse_numbers(center, 3, "center")?));
+    }
+
+    if let Some(json) = metadata.get("json") {
+        let parsed: Value = serde_json::from_str(json).map_err(|e| anyhow!("Invalid json metadata: {}", e))?;
+        if let Some(layers) = parsed.get("vector_layers") {
+            doc.insert("vector_layers".into(), layers.clone());
+        }
+    }
+
+    Ok(Value::Object(doc))
+}
+
+/// Parse a comma separated list of exactly `count` numbers
+fn parse_numbers(value: &str, count: usize, key: &str) -> Result<Vec<f64>> {
+    let numbers = value
+        .split(',')
+        .map(|part| part.trim().parse::<f64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| anyhow!("Invalid {} metadata: {}", key, value))?;
+    if numbers.len() != count {
+        return Err(anyhow!("Invalid {} metadata: expected {} numbers, got {}", key, count, value));
+    }
+    Ok(numbers)
+}
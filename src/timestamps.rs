@@ -0,0 +1,115 @@
+use std::collections::HashSet;
+
+use anyhow::{Result, anyhow};
+use rusqlite::{Connection, params};
+
+use crate::mbtiles::{MbtilesSchema, MbtilesWriter};
+
+/// Sidecar table of when each tile was first written and last changed
+const TIMESTAMPS_TABLE: &str = "tile_timestamps";
+
+/// The current time in whole seconds since the Unix epoch, in SQL
+const NOW: &str = "CAST(strftime('%s', 'now') AS INTEGER)";
+
+/// Start recording when each tile of the MBTiles file at `path` is written,
+/// in a `tile_timestamps` table with `created_at` and `updated_at` in
+/// seconds since the Unix epoch. Triggers keep it current whatever writes
+/// the file, this tool or another; deleted tiles lose their row. Tiles
+/// already in the file are stamped now. Returns how many that was, None if
+/// timestamps were already on.
+pub fn enable_timestamps(path: &str) -> Result<Option<u64>> {
+    let writer = MbtilesWriter::open(path)?;
+    let conn = writer.connection();
+    if timestamps_enabled(conn)? {
+        return Ok(None);
+    }
+    let tx = conn.unchecked_transaction()?;
+    tx.execute_batch(&format!(
+        "CREATE TABLE {} (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER,
+             created_at INTEGER, updated_at INTEGER, PRIMARY KEY (zoom_level, tile_column, tile_row));
+         CREATE INDEX tile_timestamps_updated ON {} (updated_at);",
+        TIMESTAMPS_TABLE, TIMESTAMPS_TABLE
+    ))?;
+    let stamped = tx.execute(
+        &format!(
+            "INSERT INTO {} SELECT zoom_level, tile_column, tile_row, {now}, {now} FROM tiles",
+            TIMESTAMPS_TABLE,
+            now = NOW
+        ),
+        [],
+    )?;
+    create_triggers(&tx, writer.schema())?;
+    tx.commit()?;
+    Ok(Some(stamped as u64))
+}
+
+/// Stop recording tile timestamps and drop those recorded. Returns whether
+/// they were on.
+pub fn disable_timestamps(path: &str) -> Result<bool> {
+    let writer = MbtilesWriter::open(path)?;
+    let conn = writer.connection();
+    if !timestamps_enabled(conn)? {
+        return Ok(false);
+    }
+    conn.execute_batch(&format!(
+        "DROP TRIGGER IF EXISTS tile_timestamps_insert;
+         DROP TRIGGER IF EXISTS tile_timestamps_update;
+         DROP TRIGGER IF EXISTS tile_timestamps_delete;
+         DROP TABLE {};",
+        TIMESTAMPS_TABLE
+    ))?;
+    Ok(true)
+}
+
+pub(crate) fn timestamps_enabled(conn: &Connection) -> Result<bool> {
+    Ok(conn
+        .prepare("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?")?
+        .exists(params![TIMESTAMPS_TABLE])?)
+}
+
+/// Install the triggers filling the timestamps table on the table holding
+/// the tile coordinates in `schema`
+pub(crate) fn create_triggers(conn: &Connection, schema: MbtilesSchema) -> Result<()> {
+    let table = match schema {
+        MbtilesSchema::Flat => "tiles",
+        MbtilesSchema::Normalized => "map",
+    };
+    // A REPLACE runs no delete trigger, so the insert trigger keeps the
+    // original created_at of a tile written over
+    conn.execute_batch(&format!(
+        "CREATE TRIGGER tile_timestamps_insert AFTER INSERT ON {table} BEGIN
+             INSERT INTO {ts} VALUES (NEW.zoom_level, NEW.tile_column, NEW.tile_row, {now}, {now})
+             ON CONFLICT (zoom_level, tile_column, tile_row) DO UPDATE SET updated_at = excluded.updated_at;
+         END;
+         CREATE TRIGGER tile_timestamps_update AFTER UPDATE ON {table} BEGIN
+             DELETE FROM {ts} WHERE zoom_level = OLD.zoom_level AND tile_column = OLD.tile_column
+                 AND tile_row = OLD.tile_row
+                 AND (OLD.zoom_level, OLD.tile_column, OLD.tile_row) IS NOT (NEW.zoom_level, NEW.tile_column, NEW.tile_row);
+             INSERT INTO {ts} VALUES (NEW.zoom_level, NEW.tile_column, NEW.tile_row, {now}, {now})
+             ON CONFLICT (zoom_level, tile_column, tile_row) DO UPDATE SET updated_at = excluded.updated_at;
+         END;
+         CREATE TRIGGER tile_timestamps_delete AFTER DELETE ON {table} BEGIN
+             DELETE FROM {ts} WHERE zoom_level = OLD.zoom_level AND tile_column = OLD.tile_column
+                 AND tile_row = OLD.tile_row;
+         END;",
+        table = table,
+        ts = TIMESTAMPS_TABLE,
+        now = NOW
+    ))?;
+    Ok(())
+}
+
+/// Coordinates, as stored, of the tiles created (or with `created` false,
+/// created or changed) at or after `since` seconds since the Unix epoch
+pub(crate) fn tiles_since(conn: &Connection, since: i64, created: bool) -> Result<HashSet<(i32, i32, i32)>> {
+    if !timestamps_enabled(conn)? {
+        return Err(anyhow!("No tile timestamps recorded, enable them with `mbtiles timestamps enable`"));
+    }
+    let column = if created { "created_at" } else { "updated_at" };
+    let mut stmt = conn.prepare(&format!(
+        "SELECT zoom_level, tile_column, tile_row FROM {} WHERE {} >= ?",
+        TIMESTAMPS_TABLE, column
+    ))?;
+    let tiles = stmt.query_map(params![since], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+    Ok(tiles.collect::<Result<_, _>>()?)
+}
@@ -0,0 +1,374 @@
+use anyhow::{Result, anyhow};
+use serde_json::Value;
+
+use crate::mvt::{Layer, VectorTile};
+use crate::bbox::TileRange;
+use crate::filter::Filter;
+use crate::merge::merge_fields;
+use crate::progress::{NoProgress, Progress};
+use crate::raster::{RasterConversion, Watermark};
+use crate::sink::OutputFormat;
+use crate::source::open_source;
+use crate::tile::{compress, decompress, detect_compression, detect_format, gzip, TileFormat};
+
+/// Which layers of a vector tile to keep
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LayerFilter {
+    /// Keep only the named layers
+    Keep(Vec<String>),
+    /// Keep every layer except the named ones
+    Drop(Vec<String>),
+}
+
+impl LayerFilter {
+    pub fn keeps(&self, layer: &str) -> bool {
+        match self {
+            LayerFilter::Keep(names) => names.iter().any(|name| name == layer),
+            LayerFilter::Drop(names) => !names.iter().any(|name| name == layer),
+        }
+    }
+}
+
+/// Compression to re-encode vector tiles with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TileCompression {
+    None,
+    /// Gzip at a level from 0 to 9
+    Gzip(u32),
+}
+
+impl TileCompression {
+    /// Parse `none`, `gzip` or `gzip:LEVEL`
+    pub fn parse(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            None if s == "none" => Ok(TileCompression::None),
+            None if s == "gzip" => Ok(TileCompression::Gzip(6)),
+            Some(("gzip", level)) => match level.parse::<u32>() {
+                Ok(level) if level <= 9 => Ok(TileCompression::Gzip(level)),
+                _ => Err(anyhow!("Invalid gzip level: {} (expected 0-9)", level)),
+            },
+            _ => Err(anyhow!("Invalid tile compression: {} (expected none, gzip or gzip:LEVEL)", s)),
+        }
+    }
+}
+
+/// Changes made to each tile and the matching metadata while copying
+#[derive(Debug, Clone, Default)]
+pub struct TileTransform {
+    pub layers: Option<LayerFilter>,
+    /// Feature attributes to remove; `*` matches any run of characters,
+    /// so `name:*` drops every translated name
+    pub drop_attributes: Vec<String>,
+    /// Keep only features matching this expression. Layers left without
+    /// features are removed.
+    pub filter: Option<Filter>,
+    /// Remove features matching this expression, like a negated `filter`
+    pub drop_features: Option<Filter>,
+    /// Simplify vector geometry with this tolerance, see
+    /// [`VectorTile::simplify`]
+    pub simplify: Option<f64>,
+    /// Rescale vector geometry to layers of this extent, see
+    /// [`VectorTile::rescale`]
+    pub extent: Option<u32>,
+    /// Layer renames as (old, new) pairs, made after the other changes so
+    /// `layers` and the filters refer to the original names. Layers renamed
+    /// to the same name are merged.
+    pub rename_layers: Vec<(String, String)>,
+    /// Re-encode vector tiles with this compression instead of their own.
+    /// Raster tiles are left alone.
+    pub compression: Option<TileCompression>,
+    /// Re-encode raster tiles in another image format
+    pub raster: Option<RasterConversion>,
+    /// Draw this onto every raster tile, before any `raster` conversion
+    /// encodes it
+    pub watermark: Option<Watermark>,
+    /// Fail on tiles not in this format once the other changes are made,
+    /// and declare it as the `format` metadata
+    pub enforce_format: Option<TileFormat>,
+}
+
+impl TileTransform {
+    /// True if tiles pass through unchanged
+    pub fn is_identity(&self) -> bool {
+        self.layers.is_none()
+            && self.drop_attributes.is_empty()
+            && self.filter.is_none()
+            && self.drop_features.is_none()
+            && self.simplify.is_none()
+            && self.extent.is_none()
+            && self.rename_layers.is_empty()
+            && self.compression.is_none()
+            && self.raster.is_none()
+            && self.watermark.is_none()
+            && self.enforce_format.is_none()
+    }
+
+    /// True if vector tiles need decoding, not just recompressing
+    fn edits_features(&self) -> bool {
+        self.layers.is_some()
+            || !self.drop_attributes.is_empty()
+            || self.filter.is_some()
+            || self.drop_features.is_some()
+            || self.simplify.is_some()
+            || self.extent.is_some()
+            || !self.rename_layers.is_empty()
+    }
+
+    /// New name of the layer `name`
+    fn renamed<'a>(&'a self, name: &'a str) -> &'a str {
+        self.rename_layers.iter().find(|(old, _)| old == name).map_or(name, |(_, new)| new)
+    }
+
+    fn drops_attribute(&self, key: &str) -> bool {
+        self.drop_attributes.iter().any(|pattern| wildcard_match(pattern, key))
+    }
+
+    /// Transform one tile blob at `zoom`. Vector tiles keep the compression
+    /// they came with unless `compression` is set.
+    pub fn apply(&self, zoom: i32, data: Vec<u8>) -> Result<Vec<u8>> {
+        if self.is_identity() {
+            return Ok(data);
+        }
+        let data = self.change(zoom, data)?;
+        if let Some(format) = self.enforce_format {
+            let found = detect_format(&data);
+            if found != format {
+                return Err(anyhow!("Found a {} tile at zoom {}, expected only {}", found, zoom, format));
+            }
+        }
+        Ok(data)
+    }
+
+    fn change(&self, zoom: i32, data: Vec<u8>) -> Result<Vec<u8>> {
+        if let Some(watermark) = &self.watermark {
+            return watermark.apply(&data, self.raster.as_ref());
+        }
+        if let Some(conversion) = &self.raster {
+            return conversion.apply(data);
+        }
+
+        let compression = detect_compression(&data);
+        let mut raw = decompress(&data)?;
+        if detect_format(&raw) != TileFormat::Pbf {
+            if self.edits_features() {
+                return Err(anyhow!("Filtering layers, attributes or features and changing geometry require vector tiles"));
+            }
+            return Ok(data);
+        }
+
+        if self.edits_features() {
+            let mut tile = VectorTile::decode(&raw)?;
+            if let Some(filter) = &self.layers {
+                tile.layers.retain(|layer| filter.keeps(&layer.name));
+            }
+            if self.filter.is_some() || self.drop_features.is_some() {
+                for layer in &mut tile.layers {
+                    let keep: Vec<bool> = layer
+                        .features
+                        .iter()
+                        .map(|feature| {
+                            self.filter.as_ref().is_none_or(|filter| filter.matches(zoom, layer, feature))
+                                && !self.drop_features.as_ref().is_some_and(|drop| drop.matches(zoom, layer, feature))
+                        })
+                        .collect();
+                    let mut keep = keep.into_iter();
+                    layer.features.retain(|_| keep.next().unwrap_or(true));
+                    // Drop keys and values only the removed features used
+                    strip_attributes(layer, |_| false);
+                }
+                tile.layers.retain(|layer| !layer.features.is_empty());
+            }
+            if let Some(tolerance) = self.simplify {
+                tile.simplify(tolerance)?;
+            }
+            if let Some(extent) = self.extent {
+                tile.rescale(extent)?;
+            }
+            if !self.drop_attributes.is_empty() {
+                for layer in &mut tile.layers {
+                    strip_attributes(layer, |key| self.drops_attribute(key));
+                }
+            }
+            if !self.rename_layers.is_empty() {
+                let mut layers: Vec<Layer> = Vec::with_capacity(tile.layers.len());
+                for mut layer in tile.layers {
+                    layer.name = self.renamed(&layer.name).to_string();
+                    match layers.iter_mut().find(|existing| existing.name == layer.name) {
+                        Some(existing) => existing.append(layer)?,
+                        None => layers.push(layer),
+                    }
+                }
+                tile.layers = layers;
+            }
+            raw = tile.encode();
+        }
+
+        match self.compression {
+            Some(TileCompression::None) => Ok(raw),
+            Some(TileCompression::Gzip(level)) => gzip(&raw, level),
+            None => compress(&raw, compression),
+        }
+    }
+
+    /// Updated value of the metadata key `name`
+    pub fn apply_metadata(&self, name: &str, value: &str) -> Result<String> {
+        if name == "format" && let Some(format) = self.enforce_format {
+            return Ok(format.as_str().to_string());
+        }
+        if name == "format" && let Some(conversion) = &self.raster {
+            return Ok(conversion.to.as_str().to_string());
+        }
+        if name != "json" || !self.edits_features() {
+            return Ok(value.to_string());
+        }
+
+        let keeps = |layer: &Value, id_key: &str| {
+            let id = layer.get(id_key).and_then(Value::as_str);
+            self.layers.as_ref().is_none_or(|filter| id.is_none_or(|id| filter.keeps(id)))
+        };
+
+        let mut json: Value = serde_json::from_str(value).map_err(|e| anyhow!("Invalid json metadata: {}", e))?;
+        if let Some(Value::Array(layers)) = json.get_mut("vector_layers") {
+            layers.retain(|layer| keeps(layer, "id"));
+            for layer in layers.iter_mut() {
+                if let Some(Value::Object(fields)) = layer.get_mut("fields") {
+                    fields.retain(|field, _| !self.drops_attribute(field));
+                }
+            }
+            if !self.rename_layers.is_empty() {
+                let mut renamed: Vec<Value> = Vec::with_capacity(layers.len());
+                for mut layer in std::mem::take(layers) {
+                    if let Some(id) = layer.get("id").and_then(Value::as_str) {
+                        layer["id"] = self.renamed(id).into();
+                    }
+                    match renamed.iter_mut().find(|existing| existing.get("id") == layer.get("id")) {
+                        Some(existing) => merge_fields(existing, &layer),
+                        None => renamed.push(layer),
+                    }
+                }
+                *layers = renamed;
+            }
+        }
+        if let Some(tilestats) = json.get_mut("tilestats") {
+            if let Some(Value::Array(layers)) = tilestats.get_mut("layers") {
+                layers.retain(|layer| keeps(layer, "layer"));
+                for layer in layers.iter_mut() {
+                    if let Some(id) = layer.get("layer").and_then(Value::as_str) {
+                        layer["layer"] = self.renamed(id).into();
+                    }
+                    if let Some(Value::Array(attributes)) = layer.get_mut("attributes") {
+                        attributes.retain(|attribute| {
+                            let name = attribute.get("attribute").and_then(Value::as_str);
+                            name.is_none_or(|name| !self.drops_attribute(name))
+                        });
+                    }
+                    let count = layer.get("attributes").and_then(Value::as_array).map(Vec::len);
+                    if let (Some(count), Some(object)) = (count, layer.as_object_mut()) {
+                        object.insert("attributeCount".to_string(), count.into());
+                    }
+                }
+            }
+            let count = tilestats.get("layers").and_then(Value::as_array).map(Vec::len);
+            if let (Some(count), Some(object)) = (count, tilestats.as_object_mut()) {
+                object.insert("layerCount".to_string(), count.into());
+            }
+        }
+        Ok(json.to_string())
+    }
+}
+
+/// Copy every tile of `input_path` through `transform` into a new tileset
+/// at `output_path`, keeping the metadata. Returns the number of tiles.
+pub fn transform(input_path: &str, output_path: &str, transform: &TileTransform) -> Result<u64> {
+    transform_with_progress(input_path, output_path, transform, &NoProgress)
+}
+
+/// Like [`transform`], reporting each tile written to `progress`
+pub fn transform_with_progress(
+    input_path: &str,
+    output_path: &str,
+    transform: &TileTransform,
+    progress: &dyn Progress,
+) -> Result<u64> {
+    let source = open_source(input_path)?;
+    let mut sink = OutputFormat::from_path(output_path).create_sink(output_path)?;
+
+    let zooms = source.zoom_levels()?;
+    let mut total = 0;
+    for &zoom in &zooms {
+        total += source.count_tiles(&TileRange::full(zoom)?)?;
+    }
+    progress.start(total);
+
+    let mut written = 0;
+    for zoom in zooms {
+        source.for_each_tile(&TileRange::full(zoom)?, &mut |mut tile| {
+            tile.data = transform.apply(tile.zoom, tile.data)?;
+            written += 1;
+            progress.advance(1);
+            sink.write_tile(&tile)
+        })?;
+    }
+
+    for (name, value) in source.metadata()? {
+        sink.write_metadata(&name, &transform.apply_metadata(&name, &value)?)?;
+    }
+    sink.finish()?;
+    progress.finish();
+    Ok(written)
+}
+
+/// Remove tags whose key matches `drop` and renumber the key and value
+/// tables so they only hold entries still referenced
+fn strip_attributes(layer: &mut Layer, drop: impl Fn(&str) -> bool) {
+    let mut key_map = vec![None; layer.keys.len()];
+    let mut value_map = vec![None; layer.values.len()];
+    let mut keys = Vec::new();
+    let mut values = Vec::new();
+
+    for feature in &mut layer.features {
+        let mut tags = Vec::with_capacity(feature.tags.len());
+        for pair in feature.tags.chunks_exact(2) {
+            let (k, v) = (pair[0] as usize, pair[1] as usize);
+            let (Some(key), Some(value)) = (layer.keys.get(k), layer.values.get(v)) else {
+                continue;
+            };
+            if drop(key) {
+                continue;
+            }
+            let new_k = *key_map[k].get_or_insert_with(|| {
+                keys.push(key.clone());
+                keys.len() as u32 - 1
+            });
+            let new_v = *value_map[v].get_or_insert_with(|| {
+                values.push(value.clone());
+                values.len() as u32 - 1
+            });
+            tags.extend([new_k, new_v]);
+        }
+        feature.tags = tags;
+    }
+
+    layer.keys = keys;
+    layer.values = values;
+}
+
+/// Match `text` against `pattern`, where `*` matches any run of characters
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
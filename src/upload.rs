@@ -0,0 +1,419 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, mpsc};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow};
+
+use crate::http::{self, HttpResponse};
+use crate::progress::{NoProgress, Progress};
+use crate::sigv4::{Request, hex, query_string, sha256, sign, uri_encode};
+use crate::tile::{detect_compression, Compression, TileFormat};
+
+/// Wait before the first retry of a failed request, doubled for each next one
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Keys signing requests to S3 and S3 compatible stores
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    /// Token of temporary credentials
+    pub session_token: Option<String>,
+}
+
+/// Where and how [`upload_dir`] sends files
+#[derive(Debug, Clone)]
+pub struct UploadOptions {
+    pub credentials: Credentials,
+    /// Region of the bucket, part of every signature
+    pub region: String,
+    /// Base URL of the store, e.g. `http://localhost:9000` for MinIO. By
+    /// default `https://s3.<region>.amazonaws.com` for `s3://` targets and
+    /// Cloud Storage's XML API at `https://storage.googleapis.com`, taking
+    /// HMAC keys, for `gs://`.
+    pub endpoint: Option<String>,
+    /// Requests in flight at once, whole files or parts of large ones
+    pub concurrency: usize,
+    /// Attempts after the first for a request failing with a network error,
+    /// a 429 or a 5xx response
+    pub retries: u32,
+    /// Files larger than this are sent as multipart uploads in parts of this
+    /// size, at least 5 MiB. Each request in flight holds one part in memory.
+    pub part_size: u64,
+}
+
+impl UploadOptions {
+    pub fn new(credentials: Credentials) -> Self {
+        UploadOptions {
+            credentials,
+            region: "us-east-1".to_string(),
+            endpoint: None,
+            concurrency: 16,
+            retries: 3,
+            part_size: 16 << 20,
+        }
+    }
+}
+
+/// Files and bytes an upload sent
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UploadReport {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+/// Smallest part S3 accepts in a multipart upload, all but the last
+const MIN_PART_SIZE: u64 = 5 << 20;
+
+/// Most parts one multipart upload may have
+const MAX_PARTS: u64 = 10_000;
+
+/// Upload every file below `dir`, as written by [`crate::export_dir`], to
+/// `target` (`s3://bucket/prefix` or `gs://bucket/prefix`) keeping their
+/// relative paths. Tiles get the `Content-Type` of their format and vector
+/// tiles stored compressed a matching `Content-Encoding`. Files larger than
+/// [`UploadOptions::part_size`] go up as multipart uploads, their parts sent
+/// in parallel alongside the other files; if the upload fails, the multipart
+/// uploads it started are aborted.
+pub fn upload_dir(dir: &str, target: &str, options: &UploadOptions) -> Result<UploadReport> {
+    upload_dir_with_progress(dir, target, options, &NoProgress)
+}
+
+/// Like [`upload_dir`], reporting the number of files up front and then
+/// each file as it is sent
+pub fn upload_dir_with_progress(
+    dir: &str,
+    target: &str,
+    options: &UploadOptions,
+    progress: &dyn Progress,
+) -> Result<UploadReport> {
+    let (scheme, rest) = target.split_once("://").ok_or_else(|| anyhow!("Not an s3:// or gs:// URL: {}", target))?;
+    let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+    if bucket.is_empty() {
+        return Err(anyhow!("No bucket in {}", target));
+    }
+    let endpoint = match (&options.endpoint, scheme) {
+        (Some(endpoint), "s3" | "gs") => endpoint.trim_end_matches('/').to_string(),
+        (None, "s3") => format!("https://s3.{}.amazonaws.com", options.region),
+        (None, "gs") => "https://storage.googleapis.com".to_string(),
+        _ => return Err(anyhow!("Unsupported upload target {}, use s3:// or gs://", target)),
+    };
+    if options.part_size < MIN_PART_SIZE {
+        return Err(anyhow!("Upload parts must be at least 5 MiB, not {} bytes", options.part_size));
+    }
+    let (host, _) = http::split_url(&endpoint)?;
+    let store = Store { endpoint: &endpoint, host, bucket, options };
+    let prefix = prefix.trim_matches('/');
+
+    let mut paths = Vec::new();
+    list_files(Path::new(dir), &mut paths)?;
+    paths.sort();
+    let mut files = Vec::with_capacity(paths.len());
+    for path in paths {
+        let relative = path.strip_prefix(dir).unwrap_or(&path);
+        let relative: Vec<String> = relative.components().map(|part| part.as_os_str().to_string_lossy().into_owned()).collect();
+        let key = if prefix.is_empty() { relative.join("/") } else { format!("{}/{}", prefix, relative.join("/")) };
+        let size = fs::metadata(&path).context(format!("Failed to read {}", path.display()))?.len();
+        files.push(UploadFile { path, key, size });
+    }
+    progress.start(files.len() as u64);
+
+    let mut multiparts = Vec::new();
+    let result = (|| -> Result<UploadReport> {
+        for file in files.iter().filter(|file| file.size > options.part_size) {
+            multiparts.push(Multipart::create(&store, file)?);
+        }
+        let mut queue = VecDeque::new();
+        for file in files.iter().filter(|file| file.size <= options.part_size) {
+            queue.push_back(Job::File(file));
+        }
+        for multipart in &multiparts {
+            queue.extend((1..=multipart.parts()).map(|number| Job::Part(multipart, number)));
+        }
+        run_jobs(&store, queue, progress)
+    })();
+    progress.finish();
+    if result.is_err() {
+        for multipart in multiparts.iter().filter(|multipart| !multipart.completed.load(Ordering::Relaxed)) {
+            if let Err(e) = multipart.abort(&store) {
+                tracing::warn!(file = %multipart.file.path.display(), error = %e, "failed to abort multipart upload");
+            }
+        }
+    }
+    result
+}
+
+/// A file to upload and its key in the bucket
+struct UploadFile {
+    path: PathBuf,
+    key: String,
+    size: u64,
+}
+
+/// One request's worth of work for an upload thread
+enum Job<'a> {
+    /// A file sent with a single PUT
+    File(&'a UploadFile),
+    /// Part `number`, counted from 1, of a multipart upload
+    Part(&'a Multipart<'a>, u64),
+}
+
+/// Send `jobs` from `options.concurrency` threads, stopping at the first failure
+fn run_jobs(store: &Store, jobs: VecDeque<Job>, progress: &dyn Progress) -> Result<UploadReport> {
+    let queue = Mutex::new(jobs);
+    let failed = AtomicBool::new(false);
+    let concurrency = store.options.concurrency.max(1);
+    let mut report = UploadReport::default();
+    thread::scope(|scope| -> Result<()> {
+        let (sender, receiver) = mpsc::sync_channel(concurrency * 4);
+        for _ in 0..concurrency {
+            let sender = sender.clone();
+            let (queue, failed) = (&queue, &failed);
+            scope.spawn(move || {
+                while !failed.load(Ordering::Relaxed) {
+                    let Some(job) = queue.lock().expect("queue lock").pop_front() else { break };
+                    // Bytes sent and whether that finished a file
+                    let sent = match job {
+                        Job::File(file) => upload_file(store, file).map(|bytes| (bytes, true)),
+                        Job::Part(multipart, number) => multipart.upload_part(store, number),
+                    };
+                    if sender.send(sent).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(sender);
+
+        for sent in receiver {
+            match sent {
+                Ok((bytes, finished)) => {
+                    report.bytes += bytes;
+                    if finished {
+                        report.files += 1;
+                        progress.advance(1);
+                    }
+                }
+                Err(e) => {
+                    failed.store(true, Ordering::Relaxed);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    })?;
+    Ok(report)
+}
+
+/// PUT one file to its key. Returns its size.
+fn upload_file(store: &Store, file: &UploadFile) -> Result<u64> {
+    let data = fs::read(&file.path).context(format!("Failed to read {}", file.path.display()))?;
+    let headers = content_headers(&file.path, &data);
+    let what = format!("upload {}", file.path.display());
+    store.send("PUT", &file.key, &[], &headers, &data, &what)?;
+    Ok(data.len() as u64)
+}
+
+/// A file sent in parts, completed by whichever thread uploads the last one
+struct Multipart<'a> {
+    file: &'a UploadFile,
+    upload_id: String,
+    part_size: u64,
+    /// ETag of each part uploaded so far
+    etags: Mutex<Vec<Option<String>>>,
+    remaining: AtomicU64,
+    completed: AtomicBool,
+}
+
+impl<'a> Multipart<'a> {
+    fn create(store: &Store, file: &'a UploadFile) -> Result<Self> {
+        let mut head = Vec::with_capacity(16);
+        fs::File::open(&file.path)
+            .and_then(|f| f.take(16).read_to_end(&mut head))
+            .context(format!("Failed to read {}", file.path.display()))?;
+        let headers = content_headers(&file.path, &head);
+        let what = format!("start the multipart upload of {}", file.path.display());
+        let response = store.send("POST", &file.key, &[("uploads", "")], &headers, &[], &what)?;
+        let upload_id = xml_element(&String::from_utf8_lossy(&response.body), "UploadId")
+            .ok_or_else(|| anyhow!("Failed to {}: no UploadId in the response", what))?;
+
+        // Parts grow past `part_size` for files that would need too many
+        let part_size = store.options.part_size.max(file.size.div_ceil(MAX_PARTS));
+        let parts = file.size.div_ceil(part_size);
+        Ok(Multipart {
+            file,
+            upload_id,
+            part_size,
+            etags: Mutex::new(vec![None; parts as usize]),
+            remaining: AtomicU64::new(parts),
+            completed: AtomicBool::new(false),
+        })
+    }
+
+    fn parts(&self) -> u64 {
+        self.file.size.div_ceil(self.part_size)
+    }
+
+    /// Upload part `number`, completing the upload if it was the last one
+    /// left. Returns its size and whether the file is done.
+    fn upload_part(&self, store: &Store, number: u64) -> Result<(u64, bool)> {
+        let offset = (number - 1) * self.part_size;
+        let length = self.part_size.min(self.file.size - offset);
+        let mut data = Vec::with_capacity(length as usize);
+        fs::File::open(&self.file.path)
+            .and_then(|mut f| {
+                f.seek(SeekFrom::Start(offset))?;
+                f.take(length).read_to_end(&mut data)
+            })
+            .context(format!("Failed to read {}", self.file.path.display()))?;
+        if data.len() as u64 != length {
+            return Err(anyhow!("{} changed while it was uploaded", self.file.path.display()));
+        }
+
+        let number_text = number.to_string();
+        let query = [("partNumber", number_text.as_str()), ("uploadId", self.upload_id.as_str())];
+        let what = format!("upload part {} of {}", number, self.file.path.display());
+        let response = store.send("PUT", &self.file.key, &query, &[], &data, &what)?;
+        let etag = response.header("ETag").ok_or_else(|| anyhow!("Failed to {}: no ETag in the response", what))?;
+        self.etags.lock().expect("etags lock")[number as usize - 1] = Some(etag.to_string());
+
+        let last = self.remaining.fetch_sub(1, Ordering::AcqRel) == 1;
+        if last {
+            self.complete(store)?;
+        }
+        Ok((length, last))
+    }
+
+    fn complete(&self, store: &Store) -> Result<()> {
+        let mut body = String::from("<CompleteMultipartUpload>");
+        for (i, etag) in self.etags.lock().expect("etags lock").iter().enumerate() {
+            let etag = etag.as_deref().expect("every part uploaded");
+            body.push_str(&format!("<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>", i + 1, xml_escape(etag)));
+        }
+        body.push_str("</CompleteMultipartUpload>");
+
+        let what = format!("complete the multipart upload of {}", self.file.path.display());
+        let headers = [("Content-Type".to_string(), "application/xml".to_string())];
+        let response = store.send("POST", &self.file.key, &[("uploadId", &self.upload_id)], &headers, body.as_bytes(), &what)?;
+        // S3 may report a failed completion in the body of a 200 response
+        let answer = String::from_utf8_lossy(&response.body);
+        if let Some(error) = xml_element(&answer, "Error") {
+            return Err(anyhow!("Failed to {}: {}", what, error));
+        }
+        self.completed.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn abort(&self, store: &Store) -> Result<()> {
+        let what = format!("abort the multipart upload of {}", self.file.path.display());
+        store.send("DELETE", &self.file.key, &[("uploadId", &self.upload_id)], &[], &[], &what)?;
+        Ok(())
+    }
+}
+
+/// The bucket requests go to
+struct Store<'a> {
+    endpoint: &'a str,
+    host: &'a str,
+    bucket: &'a str,
+    options: &'a UploadOptions,
+}
+
+impl Store<'_> {
+    /// Send a signed request for `key` with `query`, retrying network
+    /// errors, 429 and 5xx responses. Returns the first 2xx response.
+    fn send(
+        &self,
+        method: &str,
+        key: &str,
+        query: &[(&str, &str)],
+        headers: &[(String, String)],
+        body: &[u8],
+        what: &str,
+    ) -> Result<HttpResponse> {
+        let path = format!("/{}/{}", self.bucket, key);
+        let payload_hash = hex(&sha256(body));
+        let mut url = format!("{}{}", self.endpoint, uri_encode(&path));
+        if !query.is_empty() {
+            url = format!("{}?{}", url, query_string(query));
+        }
+        let request = Request { method, host: self.host, path: &path, query, payload_hash: &payload_hash };
+
+        let mut delay = RETRY_DELAY;
+        let mut attempt = 0;
+        loop {
+            let mut signed = sign(&self.options.credentials, &self.options.region, &request);
+            signed.extend_from_slice(headers);
+            let error = match http::send_body(method, &url, &signed, body) {
+                Ok(response) if (200..300).contains(&response.status) => return Ok(response),
+                Ok(response) => {
+                    let error = anyhow!(
+                        "Failed to {} to {}: HTTP {} {}",
+                        what,
+                        url,
+                        response.status,
+                        String::from_utf8_lossy(&response.body).trim()
+                    );
+                    if response.status != 429 && response.status < 500 {
+                        return Err(error);
+                    }
+                    error
+                }
+                Err(e) => anyhow!("Failed to {}: {}", what, e),
+            };
+            if attempt >= self.options.retries {
+                return Err(error);
+            }
+            tracing::warn!(url = url.as_str(), attempt, error = %error, "upload request failed, retrying");
+            thread::sleep(delay);
+            delay *= 2;
+            attempt += 1;
+        }
+    }
+}
+
+/// `Content-Type` and `Content-Encoding` for `file` starting with `head`
+fn content_headers(file: &Path, head: &[u8]) -> Vec<(String, String)> {
+    let extension = file.extension().and_then(|e| e.to_str()).unwrap_or("").to_ascii_lowercase();
+    let format = TileFormat::from_metadata(&extension);
+    let content_type = match (format, extension.as_str()) {
+        (Some(format), _) => format.content_type(),
+        (None, "json") => "application/json",
+        _ => "application/octet-stream",
+    };
+    let mut headers = vec![("Content-Type".to_string(), content_type.to_string())];
+    match (format, detect_compression(head)) {
+        (Some(TileFormat::Pbf), Compression::Gzip) => headers.push(("Content-Encoding".to_string(), "gzip".to_string())),
+        (Some(TileFormat::Pbf), Compression::Zlib) => headers.push(("Content-Encoding".to_string(), "deflate".to_string())),
+        _ => {}
+    }
+    headers
+}
+
+/// Text of the first `<name>` element in `xml`
+fn xml_element(xml: &str, name: &str) -> Option<String> {
+    let start = xml.find(&format!("<{}>", name))? + name.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", name))?;
+    Some(xml[start..end].replace("&quot;", "\"").replace("&lt;", "<").replace("&gt;", ">").replace("&amp;", "&"))
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+fn list_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir).context(format!("Failed to list {}", dir.display()))? {
+        let path = entry?.path();
+        if path.is_dir() {
+            list_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,315 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use rusqlite::params;
+use serde_json::Value;
+
+use crate::mbtiles::MbtilesReader;
+use crate::tile::{detect_compression, detect_format, TileFormat};
+use crate::vector_layers::scan_vector_layers;
+
+/// Maximum number of offending tiles listed per problem
+const MAX_EXAMPLES: usize = 10;
+
+/// Vector tiles decoded per zoom level by [`validate`] to compare layers
+/// against `vector_layers`
+pub const DEFAULT_LAYER_SAMPLE: u64 = 100;
+
+/// Outcome of checking a file against the MBTiles 1.3 spec
+#[derive(Debug, Clone, Default)]
+pub struct ValidationReport {
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+impl ValidationReport {
+    pub fn is_valid(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// Optional checks of [`validate_with`]
+#[derive(Debug, Clone)]
+pub struct ValidateOptions {
+    /// Vector tiles decoded per zoom level to check they hold the layers
+    /// `vector_layers` describes. 0 skips the check.
+    pub layer_sample: u64,
+    /// Flag tiles larger than this many bytes
+    pub max_tile_size: Option<u64>,
+    /// Report tiles over `max_tile_size` as warnings rather than errors
+    pub oversize_warning: bool,
+}
+
+impl Default for ValidateOptions {
+    fn default() -> Self {
+        ValidateOptions { layer_sample: DEFAULT_LAYER_SAMPLE, max_tile_size: None, oversize_warning: false }
+    }
+}
+
+/// Check schema, required metadata, tile coordinate ranges and tile blob
+/// formats of the MBTiles file at `path`
+pub fn validate(path: &str) -> Result<ValidationReport> {
+    validate_with(path, &ValidateOptions::default())
+}
+
+/// Like [`validate`], with the extra checks of `options`
+pub fn validate_with(path: &str, options: &ValidateOptions) -> Result<ValidationReport> {
+    let reader = MbtilesReader::open(path)?;
+    let mut report = ValidationReport::default();
+
+    let schema_ok = check_schema(&reader, "metadata", &["name", "value"], &mut report)?
+        & check_schema(&reader, "tiles", &["zoom_level", "tile_column", "tile_row", "tile_data"], &mut report)?;
+    if !schema_ok {
+        return Ok(report);
+    }
+
+    let format = check_metadata(&reader, &mut report)?;
+    check_coordinates(&reader, &mut report)?;
+    if let Some(format) = format {
+        check_blobs(&reader, format, &mut report)?;
+    }
+    if format == Some(TileFormat::Pbf) && options.layer_sample > 0 {
+        check_vector_layers(path, &reader, options.layer_sample, &mut report)?;
+    }
+    if let Some(max) = options.max_tile_size {
+        check_tile_sizes(&reader, max, options.oversize_warning, &mut report)?;
+    }
+
+    Ok(report)
+}
+
+/// Verify `table` exists as a table or view with at least `columns`
+fn check_schema(reader: &MbtilesReader, table: &str, columns: &[&str], report: &mut ValidationReport) -> Result<bool> {
+    let conn = reader.connection();
+    let mut stmt = conn.prepare("SELECT name FROM pragma_table_info(?)")?;
+    let existing = stmt.query_map(params![table], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if existing.is_empty() {
+        report.errors.push(format!("Missing required table or view: {}", table));
+        return Ok(false);
+    }
+
+    let mut ok = true;
+    for column in columns {
+        if !existing.iter().any(|c| c == column) {
+            report.errors.push(format!("Table {} is missing column {}", table, column));
+            ok = false;
+        }
+    }
+    Ok(ok)
+}
+
+fn check_metadata(reader: &MbtilesReader, report: &mut ValidationReport) -> Result<Option<TileFormat>> {
+    for key in ["name", "format", "bounds", "minzoom", "maxzoom"] {
+        if reader.metadata_value(key)?.is_none() {
+            report.errors.push(format!("Missing required metadata key: {}", key));
+        }
+    }
+
+    let format = match reader.metadata_value("format")? {
+        Some(value) => match TileFormat::from_metadata(&value) {
+            Some(format) => Some(format),
+            None => {
+                report.errors.push(format!("Unknown format in metadata: {}", value));
+                None
+            }
+        },
+        None => None,
+    };
+
+    if format == Some(TileFormat::Pbf) {
+        match reader.metadata_value("json")? {
+            None => report.errors.push("Missing metadata key json, required for vector tilesets".to_string()),
+            Some(json) => {
+                let parsed: Result<serde_json::Value, _> = serde_json::from_str(&json);
+                match parsed {
+                    Ok(value) if value["vector_layers"].is_array() => {}
+                    Ok(_) => report.errors.push("Metadata json has no vector_layers array".to_string()),
+                    Err(e) => report.errors.push(format!("Metadata json is not valid JSON: {}", e)),
+                }
+            }
+        }
+    }
+
+    if let Some(bounds) = reader.metadata_value("bounds")? {
+        let values: Vec<Option<f64>> = bounds.split(',').map(|v| v.trim().parse().ok()).collect();
+        match values[..] {
+            [Some(west), Some(south), Some(east), Some(north)] => {
+                if !(-180.0..=180.0).contains(&west) || !(-180.0..=180.0).contains(&east)
+                    || !(-90.0..=90.0).contains(&south) || !(-90.0..=90.0).contains(&north)
+                {
+                    report.errors.push(format!("Metadata bounds out of range: {}", bounds));
+                } else if south > north {
+                    report.errors.push(format!("Metadata bounds has south > north: {}", bounds));
+                }
+            }
+            _ => report.errors.push(format!("Metadata bounds must be W,S,E,N numbers: {}", bounds)),
+        }
+    }
+
+    let zooms = reader.zoom_levels()?;
+    for (key, actual) in [("minzoom", zooms.first()), ("maxzoom", zooms.last())] {
+        let Some(value) = reader.metadata_value(key)? else { continue };
+        match value.trim().parse::<i32>() {
+            Err(_) => report.errors.push(format!("Metadata {} is not an integer: {}", key, value)),
+            Ok(declared) => {
+                if let Some(&actual) = actual
+                    && actual != declared
+                {
+                    report.warnings.push(format!(
+                        "Metadata {} is {} but tiles exist at zoom {}", key, declared, actual
+                    ));
+                }
+            }
+        }
+    }
+
+    if zooms.is_empty() {
+        report.warnings.push("Tileset contains no tiles".to_string());
+    }
+
+    Ok(format)
+}
+
+/// Every tile must satisfy 0 <= column, row < 2^zoom
+fn check_coordinates(reader: &MbtilesReader, report: &mut ValidationReport) -> Result<()> {
+    let mut stmt = reader.connection().prepare(
+        "SELECT zoom_level, tile_column, tile_row FROM tiles
+         WHERE zoom_level < 0 OR zoom_level > 30
+            OR tile_column < 0 OR tile_column >= (1 << zoom_level)
+            OR tile_row < 0 OR tile_row >= (1 << zoom_level)"
+    )?;
+    let bad = stmt.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?, row.get::<_, i64>(2)?)))?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for (zoom, x, y) in bad.iter().take(MAX_EXAMPLES) {
+        report.errors.push(format!("Tile {}/{}/{} is outside the valid range for its zoom", zoom, x, y));
+    }
+    if bad.len() > MAX_EXAMPLES {
+        report.errors.push(format!("... and {} more tiles with invalid coordinates", bad.len() - MAX_EXAMPLES));
+    }
+    Ok(())
+}
+
+/// Every blob's magic bytes must agree with the declared format, and
+/// vector tiles should share one compression
+fn check_blobs(reader: &MbtilesReader, format: TileFormat, report: &mut ValidationReport) -> Result<()> {
+    let mut stmt = reader.connection().prepare("SELECT zoom_level, tile_column, tile_row, tile_data FROM tiles")?;
+    let mut rows = stmt.query([])?;
+
+    let mut mismatched = 0;
+    // Tiles per "format" or "format (compression)", for the mixed formats warning
+    let mut kinds: BTreeMap<String, u64> = BTreeMap::new();
+    while let Some(row) = rows.next()? {
+        let data: Option<Vec<u8>> = row.get(3)?;
+        let detected = match data.as_deref() {
+            None | Some([]) => None,
+            Some(data) => Some(detect_format(data)),
+        };
+        if let (Some(detected), Some(data)) = (detected, data.as_deref()) {
+            let kind = match detected {
+                TileFormat::Pbf => format!("{} ({})", detected, detect_compression(data)),
+                _ => detected.to_string(),
+            };
+            *kinds.entry(kind).or_default() += 1;
+        }
+        if detected == Some(format) {
+            continue;
+        }
+
+        mismatched += 1;
+        if mismatched <= MAX_EXAMPLES {
+            let (zoom, x, y): (i64, i64, i64) = (row.get(0)?, row.get(1)?, row.get(2)?);
+            let found = detected.map_or("empty blob".to_string(), |f| f.to_string());
+            report.errors.push(format!("Tile {}/{}/{} is {} but metadata format is {}", zoom, x, y, found, format));
+        }
+    }
+    if mismatched > MAX_EXAMPLES {
+        report.errors.push(format!("... and {} more tiles not matching format {}", mismatched - MAX_EXAMPLES, format));
+    }
+    if kinds.len() > 1 {
+        let counts: Vec<String> = kinds.iter().map(|(kind, tiles)| format!("{} {}", kind, tiles)).collect();
+        report.warnings.push(format!("Mixed tile formats: {}", counts.join(", ")));
+    }
+    Ok(())
+}
+
+/// Flag tiles larger than `max` bytes, largest first
+fn check_tile_sizes(reader: &MbtilesReader, max: u64, warn: bool, report: &mut ValidationReport) -> Result<()> {
+    let mut stmt = reader.connection().prepare(
+        "SELECT zoom_level, tile_column, tile_row, length(tile_data) AS size FROM tiles
+         WHERE length(tile_data) > ? ORDER BY size DESC"
+    )?;
+    let oversized = stmt
+        .query_map(params![max as i64], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?, row.get::<_, i64>(2)?, row.get::<_, i64>(3)?)))?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let problems = if warn { &mut report.warnings } else { &mut report.errors };
+    for (zoom, x, y, size) in oversized.iter().take(MAX_EXAMPLES) {
+        problems.push(format!("Tile {}/{}/{} is {} bytes, over the limit of {}", zoom, x, y, size, max));
+    }
+    if oversized.len() > MAX_EXAMPLES {
+        problems.push(format!("... and {} more tiles over {} bytes", oversized.len() - MAX_EXAMPLES, max));
+    }
+    Ok(())
+}
+
+/// Decode a sample of tiles per zoom level and compare the layers, zoom
+/// ranges and attributes found with `vector_layers`
+fn check_vector_layers(path: &str, reader: &MbtilesReader, sample: u64, report: &mut ValidationReport) -> Result<()> {
+    let Some(declared) = reader.metadata_value("json")?.and_then(|json| serde_json::from_str::<Value>(&json).ok()) else {
+        return Ok(());
+    };
+    let Some(declared) = declared.get("vector_layers").and_then(Value::as_array) else {
+        return Ok(());
+    };
+    let found = match scan_vector_layers(path, Some(sample)) {
+        Ok(found) => found,
+        Err(e) => {
+            report.errors.push(format!("Could not decode vector tiles to check vector_layers: {}", e));
+            return Ok(());
+        }
+    };
+
+    for layer in &found {
+        let Some(entry) = declared.iter().find(|entry| entry.get("id").and_then(Value::as_str) == Some(&layer.id)) else {
+            report.errors.push(format!(
+                "Layer {} is in tiles at zoom {}-{} but missing from vector_layers",
+                layer.id, layer.min_zoom, layer.max_zoom
+            ));
+            continue;
+        };
+        let zoom = |key: &str| entry.get(key).and_then(Value::as_i64);
+        if let (Some(min), Some(max)) = (zoom("minzoom"), zoom("maxzoom"))
+            && (i64::from(layer.min_zoom) < min || i64::from(layer.max_zoom) > max)
+        {
+            report.warnings.push(format!(
+                "Layer {} is in tiles at zoom {}-{} but vector_layers declares {}-{}",
+                layer.id, layer.min_zoom, layer.max_zoom, min, max
+            ));
+        }
+        let fields = entry.get("fields").and_then(Value::as_object);
+        let missing: Vec<&str> = layer
+            .fields
+            .keys()
+            .filter(|field| fields.is_none_or(|fields| !fields.contains_key(*field)))
+            .map(String::as_str)
+            .collect();
+        if !missing.is_empty() {
+            report.warnings.push(format!("Layer {} has attributes missing from vector_layers fields: {}", layer.id, missing.join(", ")));
+        }
+    }
+
+    // Only a warning, the sample may have missed a sparse layer
+    for entry in declared {
+        let Some(id) = entry.get("id").and_then(Value::as_str) else {
+            report.errors.push(format!("vector_layers entry has no id: {}", entry));
+            continue;
+        };
+        if !found.iter().any(|layer| layer.id == id) {
+            report.warnings.push(format!("Layer {} is in vector_layers but in none of the tiles checked", id));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,144 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Result, anyhow};
+use serde_json::{Map, Value as Json, json};
+
+use crate::bbox::TileRange;
+use crate::mbtiles::MbtilesWriter;
+use crate::mvt::{Value, VectorTile};
+use crate::progress::{NoProgress, Progress};
+use crate::source::{is_mbtiles, open_source};
+use crate::tile::{decompress, detect_format, TileFormat};
+
+/// A layer found in the tiles, as described by a `vector_layers` entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VectorLayer {
+    pub id: String,
+    pub min_zoom: i32,
+    pub max_zoom: i32,
+    /// Attribute name to `"String"`, `"Number"`, `"Boolean"` or `"Mixed"`
+    /// when features disagree
+    pub fields: BTreeMap<String, String>,
+}
+
+impl VectorLayer {
+    /// The `vector_layers` entry for this layer
+    pub fn to_json(&self) -> Json {
+        json!({
+            "id": self.id,
+            "description": "",
+            "minzoom": self.min_zoom,
+            "maxzoom": self.max_zoom,
+            "fields": self.fields,
+        })
+    }
+}
+
+/// Decode the vector tiles of `input_path` and collect their layers, zoom
+/// ranges and attribute types, ordered by layer name. With `sample`, at most
+/// that many tiles spread evenly over each zoom level are decoded.
+pub fn scan_vector_layers(input_path: &str, sample: Option<u64>) -> Result<Vec<VectorLayer>> {
+    scan_vector_layers_with_progress(input_path, sample, &NoProgress)
+}
+
+/// Like [`scan_vector_layers`], reporting progress per tile read
+pub fn scan_vector_layers_with_progress(
+    input_path: &str,
+    sample: Option<u64>,
+    progress: &dyn Progress,
+) -> Result<Vec<VectorLayer>> {
+    let source = open_source(input_path)?;
+    let zooms = source.zoom_levels()?;
+    let mut counts = Vec::with_capacity(zooms.len());
+    for &zoom in &zooms {
+        counts.push(source.count_tiles(&TileRange::full(zoom)?)?);
+    }
+    progress.start(counts.iter().sum());
+
+    let mut layers: BTreeMap<String, VectorLayer> = BTreeMap::new();
+    for (zoom, tiles) in zooms.into_iter().zip(counts) {
+        // Decode every `stride`th tile
+        let stride = sample.map_or(1, |sample| tiles.div_ceil(sample.max(1)).max(1));
+        let mut seen = 0u64;
+        source.for_each_tile(&TileRange::full(zoom)?, &mut |tile| {
+            seen += 1;
+            progress.advance(1);
+            if !(seen - 1).is_multiple_of(stride) {
+                return Ok(());
+            }
+            let raw = decompress(&tile.data)?;
+            let format = detect_format(&raw);
+            if format != TileFormat::Pbf {
+                return Err(anyhow!("Vector layers need vector tiles, found {} in {}", format.as_str(), input_path));
+            }
+            for layer in VectorTile::decode(&raw)?.layers {
+                let entry = layers.entry(layer.name.clone()).or_insert_with(|| VectorLayer {
+                    id: layer.name.clone(),
+                    min_zoom: zoom,
+                    max_zoom: zoom,
+                    fields: BTreeMap::new(),
+                });
+                entry.min_zoom = entry.min_zoom.min(zoom);
+                entry.max_zoom = entry.max_zoom.max(zoom);
+                for feature in &layer.features {
+                    for (key, value) in layer.properties(feature) {
+                        let kind = match value {
+                            Value::String(_) => "String",
+                            Value::Bool(_) => "Boolean",
+                            _ => "Number",
+                        };
+                        match entry.fields.get_mut(key) {
+                            Some(existing) if existing != kind => *existing = "Mixed".to_string(),
+                            Some(_) => {}
+                            None => {
+                                entry.fields.insert(key.to_string(), kind.to_string());
+                            }
+                        }
+                    }
+                }
+            }
+            Ok(())
+        })?;
+    }
+    progress.finish();
+    Ok(layers.into_values().collect())
+}
+
+/// The `json` metadata value of `input_path` with `vector_layers` replaced
+/// by `layers`, keeping its other members. `tilestats` is dropped rather
+/// than left describing other layers.
+pub fn vector_layers_json(input_path: &str, layers: &[VectorLayer]) -> Result<String> {
+    let metadata = open_source(input_path)?.metadata()?;
+    let mut json = match metadata.iter().find(|(name, _)| name == "json") {
+        Some((_, value)) => match serde_json::from_str(value) {
+            Ok(Json::Object(object)) => object,
+            // Unparseable values are what this repairs
+            _ => Map::new(),
+        },
+        None => Map::new(),
+    };
+    json.insert("vector_layers".to_string(), layers.iter().map(VectorLayer::to_json).collect());
+    json.remove("tilestats");
+    Ok(Json::Object(json).to_string())
+}
+
+/// Scan the tiles of the MBTiles file at `path` and store the
+/// [`vector_layers_json`] of the layers found. Returns the layers.
+pub fn generate_vector_layers(path: &str, sample: Option<u64>) -> Result<Vec<VectorLayer>> {
+    generate_vector_layers_with_progress(path, sample, &NoProgress)
+}
+
+/// Like [`generate_vector_layers`], reporting progress per tile read
+pub fn generate_vector_layers_with_progress(
+    path: &str,
+    sample: Option<u64>,
+    progress: &dyn Progress,
+) -> Result<Vec<VectorLayer>> {
+    if !is_mbtiles(path) {
+        return Err(anyhow!("Can only store metadata in MBTiles files, not {}", path));
+    }
+    let layers = scan_vector_layers_with_progress(path, sample, progress)?;
+    let value = vector_layers_json(path, &layers)?;
+    MbtilesWriter::open(path)?.set_metadata("json", &value)?;
+    Ok(layers)
+}